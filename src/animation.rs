@@ -0,0 +1,101 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use crate::Scene;
+
+// How far back the frame rate is counted, ms
+const FPS_WINDOW: f64 = 1000.0;
+// Weight of the newest frame in the average draw time
+const SMOOTHING: f64 = 0.1;
+
+type Callback = Closure<dyn FnMut(f64)>;
+
+/// How rendering is keeping up, for a stats display.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FrameStats {
+    /// Frames drawn in the last second
+    pub fps: u32,
+    /// Time taken to draw a frame, averaged, ms. That's the CPU's share:
+    /// the GPU carries on after we hand it the work.
+    pub frame_time: f64,
+    /// Frames drawn, and frames skipped as nothing had changed
+    pub drawn: u32,
+    pub skipped: u32,
+}
+
+/// Timings of the frames drawn so far.
+#[derive(Default)]
+pub struct FrameTimes {
+    // When the frames of the last second were drawn, ms
+    recent: VecDeque<f64>,
+    stats: FrameStats,
+}
+
+impl FrameTimes {
+    pub fn drawn(&mut self, now: f64, took: f64) {
+        self.recent.push_back(now);
+        self.stats.frame_time = if self.stats.drawn == 0 { took } else { self.stats.frame_time + SMOOTHING * (took - self.stats.frame_time) };
+        self.stats.drawn += 1;
+    }
+
+    pub fn skipped(&mut self) {
+        self.stats.skipped += 1;
+    }
+
+    pub fn stats(&mut self, now: f64) -> FrameStats {
+        while self.recent.front().is_some_and(|&t| now - t > FPS_WINDOW) {
+            self.recent.pop_front();
+        }
+        FrameStats { fps: self.recent.len() as u32, ..self.stats }
+    }
+}
+
+/// A requestAnimationFrame loop drawing `scene`, until dropped.
+pub struct Animation {
+    window: web_sys::Window,
+    // The frame asked for next, if any
+    request: Rc<Cell<Option<i32>>>,
+    // The callback asks for the next frame with itself, so holds itself
+    // here; dropping it breaks the cycle
+    callback: Rc<RefCell<Option<Callback>>>,
+}
+
+impl Animation {
+    pub fn start(scene: Rc<RefCell<Scene>>) -> Result<Animation, JsValue> {
+        let window = web_sys::window().ok_or("No window")?;
+        let request = Rc::new(Cell::new(None));
+        let callback: Rc<RefCell<Option<Callback>>> = Rc::new(RefCell::new(None));
+
+        let (next, pending, win) = (callback.clone(), request.clone(), window.clone());
+        *callback.borrow_mut() = Some(Closure::new(move |now: f64| {
+            pending.set(None);
+            if let Err(e) = scene.borrow_mut().frame(now, false) {
+                // It would only fail again next frame
+                web_sys::console::error_1(&format!("Rendering stopped: {}", e).into());
+                return;
+            }
+            if let Some(callback) = next.borrow().as_ref() {
+                pending.set(win.request_animation_frame(callback.as_ref().unchecked_ref()).ok());
+            }
+        }));
+        let first = window.request_animation_frame(callback.borrow().as_ref().expect("just set").as_ref().unchecked_ref())?;
+        request.set(Some(first));
+        Ok(Animation { window, request, callback })
+    }
+
+    /// Whether the loop is still going, as it stops on a rendering error.
+    pub fn running(&self) -> bool {
+        self.request.get().is_some()
+    }
+}
+
+impl Drop for Animation {
+    fn drop(&mut self) {
+        if let Some(request) = self.request.take() {
+            let _ = self.window.cancel_animation_frame(request);
+        }
+        self.callback.borrow_mut().take();
+    }
+}
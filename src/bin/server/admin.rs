@@ -0,0 +1,237 @@
+use std::io;
+use axum::Json;
+use axum::body::{Body, Bytes};
+use axum::extract::{Path, Query};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::StreamExt;
+use serde_json::json;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{info, warn};
+use crate::alerts::{self, Notification};
+use crate::archive;
+use crate::cache;
+use crate::config::CONFIG;
+use crate::disk;
+use crate::metrics;
+use crate::prefetch;
+use crate::schedule::{self, TriggerError};
+use crate::Params;
+
+/// `GET /api/cache/stats`
+pub async fn handle_stats() -> Response {
+    let (entries, bytes, oldest, newest) = cache::stats();
+    let hits = metrics::CACHE_HITS.get();
+    let misses = metrics::CACHE_MISSES.get();
+    let lookups = hits + misses;
+    Json(json!({
+        "entries": entries,
+        "bytes": bytes,
+        "max_bytes": disk::effective_max_size(),
+        "hits": hits,
+        "misses": misses,
+        "hit_rate": if lookups > 0 { hits as f64 / lookups as f64 } else { 0.0 },
+        "oldest_access": oldest,
+        "newest_access": newest,
+    }))
+    .into_response()
+}
+
+/// `GET /api/cache/list?sat=19`
+pub async fn handle_list(Query(params): Query<Params>) -> Response {
+    let tiles = cache::list(params.get("sat").map(String::as_str));
+    Json(json!({ "count": tiles.len(), "tiles": tiles })).into_response()
+}
+
+/// `POST /api/cache/purge?sat=19&t=20240101120000`, or `?all=true`. A
+/// request with no filter is refused rather than wiping the cache.
+pub async fn handle_purge(Query(params): Query<Params>) -> Response {
+    let sat = params.get("sat").cloned();
+    let timestamp = params.get("t").cloned();
+    let all = params.get("all").is_some_and(|v| v == "true" || v == "1");
+    if sat.is_none() && timestamp.is_none() && !all {
+        return bad_request("specify sat, t, or all=true");
+    }
+
+    // Deleting thousands of files shouldn't stall a runtime worker
+    let purged = tokio::task::spawn_blocking(move || cache::purge(sat.as_deref(), timestamp.as_deref())).await;
+    let (tiles, bytes) = purged.unwrap_or((0, 0));
+    Json(json!({ "purged": tiles, "bytes": bytes })).into_response()
+}
+
+// A timestamp prefix of up to 14 digits, filled out with `pad` so that a
+// bare date as `from` starts at midnight and as `to` covers the whole day
+fn window_bound(value: Option<&String>, pad: char) -> Option<String> {
+    let value = value?;
+    if value.len() < 8 || value.len() > 14 || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let mut bound = value.clone();
+    bound.extend(std::iter::repeat_n(pad, 14 - value.len()));
+    Some(bound)
+}
+
+fn bad_request(message: &str) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({ "error": message }))).into_response()
+}
+
+/// Queue the prefetch job `params` describe, as `/api/prefetch` takes them,
+/// returning its id.
+pub fn queue_prefetch(params: &Params) -> Result<u64, &'static str> {
+    let sat = params.get("sat").cloned().unwrap_or_else(|| CONFIG.default_satellite.clone());
+    let (Some(from), Some(to)) = (window_bound(params.get("from"), '0'), window_bound(params.get("to"), '9')) else {
+        return Err("from and to must be timestamps, e.g. 20240101120000");
+    };
+    if from > to {
+        return Err("from must not be after to");
+    }
+    let days = (from[..8].parse().ok()).zip(to[..8].parse().ok()).and_then(|(f, t)| prefetch::days_between(f, t));
+    if days.is_none() {
+        return Err("time window too long");
+    }
+    let zoom = params.get("z").and_then(|z| z.parse().ok()).unwrap_or(CONFIG.prefetch_max_zoom);
+    Ok(prefetch::enqueue(sat, from, to, zoom))
+}
+
+/// `POST /api/prefetch?sat=19&from=20240101120000&to=20240101180000&z=4`
+/// queues a download of every tile of every frame in the window.
+pub async fn handle_prefetch(Query(params): Query<Params>) -> Response {
+    if CONFIG.offline {
+        return (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "error": "offline" }))).into_response();
+    }
+    match queue_prefetch(&params) {
+        Ok(id) => (
+            StatusCode::ACCEPTED,
+            Json(json!({ "id": id, "status": format!("{}/api/prefetch/{}", CONFIG.base_path, id) })),
+        )
+            .into_response(),
+        Err(message) => bad_request(message),
+    }
+}
+
+/// `GET /api/prefetch`
+pub async fn handle_prefetch_jobs() -> Response {
+    Json(json!({ "jobs": prefetch::jobs() })).into_response()
+}
+
+/// `GET /api/prefetch/{id}`
+pub async fn handle_prefetch_status(Path(id): Path<u64>) -> Response {
+    match prefetch::job(id) {
+        Some(job) => Json(job).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(json!({ "error": "no such job" }))).into_response(),
+    }
+}
+
+/// `GET /api/schedule`
+pub async fn handle_schedule() -> Response {
+    Json(json!({ "jobs": schedule::jobs() })).into_response()
+}
+
+/// `POST /api/schedule/{name}/run` runs a scheduled job now.
+pub async fn handle_run_job(Path(name): Path<String>) -> Response {
+    match schedule::trigger(&name) {
+        Ok(()) => (StatusCode::ACCEPTED, Json(json!({ "name": name, "status": format!("{}/api/schedule", CONFIG.base_path) }))).into_response(),
+        Err(TriggerError::NoSuchJob) => (StatusCode::NOT_FOUND, Json(json!({ "error": "no such job" }))).into_response(),
+        Err(TriggerError::Running) => (StatusCode::CONFLICT, Json(json!({ "error": "already running" }))).into_response(),
+    }
+}
+
+/// `POST /api/alerts/test` sends a notification through ntfy and
+/// Pushover, to check they're set up.
+pub async fn handle_test_alert() -> Response {
+    let notification = Notification {
+        title: "peepsat test".to_string(),
+        message: "Alerts from this server will arrive here".to_string(),
+        link: None,
+        tags: "satellite",
+    };
+    match alerts::send(&notification).await {
+        Ok(()) => Json(json!({ "sent": true })).into_response(),
+        Err(message) => (StatusCode::BAD_GATEWAY, Json(json!({ "error": message }))).into_response(),
+    }
+}
+
+/// `GET /api/cache/export?sat=19&from=20240101&to=20240102` streams the
+/// matching tiles as a tar archive that `/api/cache/import` takes back.
+pub async fn handle_export(Query(params): Query<Params>) -> Response {
+    let sat = params.get("sat").cloned();
+    let from = window_bound(params.get("from"), '0');
+    let to = window_bound(params.get("to"), '9');
+    if (params.contains_key("from") && from.is_none()) || (params.contains_key("to") && to.is_none()) {
+        return bad_request("from and to must be timestamps, e.g. 20240101120000");
+    }
+    let entries = cache::export_entries(sat.as_deref(), from.as_deref(), to.as_deref());
+    info!(?sat, ?from, ?to, tiles = entries.len(), "Exporting cache");
+
+    let (mut writer, reader) = tokio::io::duplex(64 * 1024);
+    tokio::spawn(async move {
+        for entry in entries {
+            // Evicted since the listing was taken
+            let Some(data) = cache::read_stored(&entry.key, entry.format).await else {
+                continue;
+            };
+            let name = format!("{}.{}", entry.key, entry.format.extension());
+            match archive::write_entry(&mut writer, &name, &data, entry.created).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::InvalidInput => warn!(name, "Skipping tile with a name too long for tar"),
+                Err(_) => {
+                    warn!("Export aborted by the client");
+                    return;
+                }
+            }
+        }
+        let _ = archive::finish(&mut writer).await;
+    });
+
+    let filename = format!("peepsat-{}.tar", sat.as_deref().unwrap_or("cache"));
+    (
+        [
+            (header::CONTENT_TYPE, "application/x-tar".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+        ],
+        Body::from_stream(ReaderStream::new(reader)),
+    )
+        .into_response()
+}
+
+/// `POST /api/cache/import` with a tar archive from `/api/cache/export` as
+/// the body. Tiles are validated and added to the cache as they arrive.
+pub async fn handle_import(body: Body) -> Response {
+    let stream = body.into_data_stream().map(|chunk| chunk.map_err(io::Error::other));
+    let mut reader = StreamReader::new(stream);
+    let (mut imported, mut skipped, mut bytes) = (0u64, 0u64, 0u64);
+    loop {
+        let entry = match archive::next_entry(&mut reader, crate::MAX_TILE_BYTES as u64).await {
+            Ok(Some(entry)) => entry,
+            Ok(None) => break,
+            Err(e) => {
+                warn!(error = %e, imported, "Cache import failed");
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(json!({ "error": e.to_string(), "imported": imported, "skipped": skipped })),
+                )
+                    .into_response();
+            }
+        };
+        // `tar cf x.tar .` names files ./19_...png
+        let name = entry.name.trim_start_matches("./");
+        let tile = name.rsplit_once('.').and_then(|(key, _)| {
+            let format = cache::TileFormat::from_path(std::path::Path::new(name))?;
+            Some((key.to_string(), format))
+        });
+        match (tile, entry.data) {
+            (Some((key, format)), Some(data)) => {
+                let len = data.len() as u64;
+                if cache::import_tile(&key, format, Bytes::from(data)).await {
+                    imported += 1;
+                    bytes += len;
+                } else {
+                    skipped += 1;
+                }
+            }
+            _ => skipped += 1,
+        }
+    }
+    info!(imported, skipped, bytes, "Cache import finished");
+    Json(json!({ "imported": imported, "skipped": skipped, "bytes": bytes })).into_response()
+}
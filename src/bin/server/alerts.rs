@@ -0,0 +1,290 @@
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use crate::config::{Alert, CONFIG};
+use crate::dates::{iso, now, parse_time};
+use crate::events::{announced_satellites, public_base};
+use crate::frames::Layer;
+use crate::reproject::frame_timestamp;
+use crate::upstream::{self, HTTP_CLIENT};
+use crate::{satellites, storms, Params};
+
+// A mesoscale sector is about 1000 km square; this is half its side in
+// degrees of latitude
+const MESOSCALE_HALF_SIDE: f64 = 4.5;
+
+/// A message for ntfy or Pushover.
+pub struct Notification {
+    pub title: String,
+    pub message: String,
+    /// Opened when the notification is tapped
+    pub link: Option<String>,
+    /// ntfy tags, which it shows as emoji
+    pub tags: &'static str,
+}
+
+// What an alert has seen so far: the satellites gone stale, the sectors
+// covering its area or the storms listed. Conditions are announced when
+// they start (and stale feeds when they recover), not on every check.
+#[derive(Default)]
+struct Seen {
+    active: HashSet<String>,
+    primed: bool,
+}
+
+fn alert_name(alert: &Alert) -> &str {
+    alert.name.as_deref().unwrap_or(&alert.kind)
+}
+
+async fn send_ntfy(url: &str, notification: &Notification) -> Result<(), String> {
+    let mut request = HTTP_CLIENT
+        .post(url)
+        .header("Title", &notification.title)
+        .header("Tags", notification.tags)
+        .body(notification.message.clone());
+    if let Some(link) = &notification.link {
+        request = request.header("Click", link);
+    }
+    if let Some(token) = &CONFIG.ntfy_token {
+        request = request.bearer_auth(token);
+    }
+    match request.send().await {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => Err(format!("status {}", response.status().as_u16())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+async fn send_pushover(token: &str, user: &str, notification: &Notification) -> Result<(), String> {
+    let mut form = vec![
+        ("token", token.to_string()),
+        ("user", user.to_string()),
+        ("title", notification.title.clone()),
+        ("message", notification.message.clone()),
+    ];
+    if let Some(link) = &notification.link {
+        form.push(("url", link.clone()));
+    }
+    let url = format!("{}/1/messages.json", CONFIG.pushover_url.trim_end_matches('/'));
+    match HTTP_CLIENT.post(url).form(&form).send().await {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => Err(format!("status {}", response.status().as_u16())),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Send `notification` to ntfy and Pushover, whichever are configured.
+/// Fails if none is, or every one that is fails.
+pub async fn send(notification: &Notification) -> Result<(), String> {
+    let mut errors = Vec::new();
+    let mut sent = false;
+    if let Some(url) = &CONFIG.ntfy_url {
+        match send_ntfy(url, notification).await {
+            Ok(()) => sent = true,
+            Err(e) => errors.push(format!("ntfy: {}", e)),
+        }
+    }
+    if let (Some(token), Some(user)) = (&CONFIG.pushover_token, &CONFIG.pushover_user) {
+        match send_pushover(token, user, notification).await {
+            Ok(()) => sent = true,
+            Err(e) => errors.push(format!("Pushover: {}", e)),
+        }
+    }
+    for error in &errors {
+        warn!(title = notification.title, error, "Notification failed");
+    }
+    match (sent, errors.is_empty()) {
+        (true, _) => Ok(()),
+        (false, true) => Err("no ntfy_url or Pushover credentials configured".to_string()),
+        (false, false) => Err(errors.join("; ")),
+    }
+}
+
+fn satellite_name(sat: &str) -> &str {
+    satellites::find(sat).map_or(sat, |s| s.name)
+}
+
+// Satellites whose newest frame is older than `max_age_minutes`, with
+// notifications for those newly so and those recovered
+async fn check_stale(alert: &Alert, seen: &mut Seen) -> Option<Vec<Notification>> {
+    let max_age = alert.max_age_minutes.unwrap_or(60) as i64 * 60;
+    let mut notifications = Vec::new();
+    for sat in announced_satellites(&alert.satellites) {
+        let params = Params::from([("sat".to_string(), sat.clone())]);
+        let Some(layer) = Layer::from_params(&params) else {
+            continue;
+        };
+        // A list that can't be had says nothing either way
+        let Some(newest) = frame_timestamp(&layer, &params, None).await.ok().and_then(|t| parse_time(&t)) else {
+            debug!(sat, "Alert: no frame list");
+            continue;
+        };
+        let age = now() - newest;
+        let link = Some(format!("{}/?sat={}", public_base(), sat));
+        if age > max_age && seen.active.insert(sat.clone()) {
+            notifications.push(Notification {
+                title: format!("{} feed stale", satellite_name(&sat)),
+                message: format!("No new {} frame for {} minutes; the newest is from {}", satellite_name(&sat), age / 60, iso(newest)),
+                link,
+                tags: "warning",
+            });
+        } else if age <= max_age && seen.active.remove(&sat) {
+            notifications.push(Notification {
+                title: format!("{} feed back", satellite_name(&sat)),
+                message: format!("New {} frame at {}", satellite_name(&sat), iso(newest)),
+                link,
+                tags: "white_check_mark",
+            });
+        }
+    }
+    Some(notifications)
+}
+
+// Whether a mesoscale sector centred at `lat`, `lon` overlaps `area`
+// ([west, south, east, north]; west > east crosses the antimeridian)
+fn covers(lat: f64, lon: f64, [west, south, east, north]: [f64; 4]) -> bool {
+    let half_lon = MESOSCALE_HALF_SIDE / lat.to_radians().cos().max(0.1);
+    if lat + MESOSCALE_HALF_SIDE < south || lat - MESOSCALE_HALF_SIDE > north {
+        return false;
+    }
+    let east = if east < west { east + 360.0 } else { east };
+    [lon - 360.0, lon, lon + 360.0].iter().any(|&lon| lon + half_lon >= west && lon - half_lon <= east)
+}
+
+// Where each mesoscale sector is, by satellite and sector
+async fn mesoscale_positions(url: &str) -> Option<HashMap<String, HashMap<String, [f64; 2]>>> {
+    let response = match upstream::get(&HTTP_CLIENT, &[url.to_string()]).await {
+        Ok(r) if r.status().is_success() => r,
+        Ok(r) => {
+            warn!(status = r.status().as_u16(), "Mesoscale positions unavailable");
+            return None;
+        }
+        Err(e) => {
+            warn!(error = %e, "Mesoscale positions failed");
+            return None;
+        }
+    };
+    serde_json::from_slice(&response.bytes().await.ok()?).ok()
+}
+
+// Mesoscale sectors of the alert's satellites newly covering its area
+async fn check_mesoscale(alert: &Alert, seen: &mut Seen) -> Option<Vec<Notification>> {
+    let (Some(url), Some(area)) = (&CONFIG.mesoscale_positions_url, alert.area) else {
+        return None;
+    };
+    let positions = mesoscale_positions(url).await?;
+    let mut covering = HashSet::new();
+    let mut notifications = Vec::new();
+    for sat in announced_satellites(&alert.satellites) {
+        let Some(satellite) = satellites::find(&sat) else {
+            continue;
+        };
+        for sector in satellite.sectors.iter().filter(|s| s.id.starts_with("mesoscale")) {
+            let Some(&[lat, lon]) = positions.get(&sat).and_then(|p| p.get(sector.id)) else {
+                continue;
+            };
+            if !covers(lat, lon, area) {
+                continue;
+            }
+            let key = format!("{}/{}", sat, sector.id);
+            if !seen.active.contains(&key) {
+                notifications.push(Notification {
+                    title: format!("{} {} over {}", satellite.name, sector.id.replace('_', " "), alert_name(alert)),
+                    message: format!("{}'s {} sector, centred at {:.1}, {:.1}, now covers {}", satellite.name, sector.id, lat, lon, alert_name(alert)),
+                    link: Some(format!("{}/?sat={}&sector={}", public_base(), sat, sector.id)),
+                    tags: "satellite",
+                });
+            }
+            covering.insert(key);
+        }
+    }
+    seen.active = covering;
+    Some(notifications)
+}
+
+fn classification_name(code: &str) -> &str {
+    match code {
+        "TD" => "Tropical Depression",
+        "TS" => "Tropical Storm",
+        "HU" => "Hurricane",
+        "STD" => "Subtropical Depression",
+        "STS" => "Subtropical Storm",
+        "PTC" => "Potential Tropical Cyclone",
+        "PC" => "Post-tropical Cyclone",
+        _ => code,
+    }
+}
+
+// Storms in the alert's basins that NHC didn't list last time
+async fn check_storms(alert: &Alert, seen: &mut Seen) -> Option<Vec<Notification>> {
+    let storms = storms::storms().await.ok()?;
+    let basins = if alert.basins.is_empty() { &CONFIG.storm_basins } else { &alert.basins };
+    let mut listed = HashSet::new();
+    let mut notifications = Vec::new();
+    let features = storms["features"].as_array().cloned().unwrap_or_default();
+    for storm in features.iter().filter(|f| f["properties"]["kind"] == "position") {
+        let field = |name: &str| storm["properties"][name].as_str().unwrap_or_default().to_string();
+        let (id, basin) = (field("id"), field("basin"));
+        if !basins.is_empty() && !basins.contains(&basin) {
+            continue;
+        }
+        if !seen.active.contains(&id) {
+            let coordinates = &storm["geometry"]["coordinates"];
+            notifications.push(Notification {
+                title: format!("New storm: {} {}", classification_name(&field("classification")), field("name")),
+                message: format!(
+                    "NHC now lists {} {} ({}) at {}, {}",
+                    classification_name(&field("classification")),
+                    field("name"),
+                    id,
+                    coordinates[1].as_f64().map_or_else(String::new, |lat| format!("{:.1}", lat)),
+                    coordinates[0].as_f64().map_or_else(String::new, |lon| format!("{:.1}", lon)),
+                ),
+                link: Some(format!("{}/", public_base())),
+                tags: "cyclone",
+            });
+        }
+        listed.insert(id);
+    }
+    seen.active = listed;
+    Some(notifications)
+}
+
+async fn check(alert: &Alert, seen: &mut Seen) -> Vec<Notification> {
+    let notifications = match alert.kind.as_str() {
+        "stale" => check_stale(alert, seen).await,
+        "mesoscale" => check_mesoscale(alert, seen).await,
+        "storm" => check_storms(alert, seen).await,
+        _ => None,
+    };
+    // Nothing to go on this time, which mustn't become the baseline
+    let Some(notifications) = notifications else {
+        return Vec::new();
+    };
+    // What's already so at startup sets the baseline, except that a feed
+    // already stale is worth hearing about
+    let primed = std::mem::replace(&mut seen.primed, true);
+    if primed || alert.kind == "stale" { notifications } else { Vec::new() }
+}
+
+/// Every `alert_interval` seconds, check each `[[alerts]]` condition and
+/// send a notification through ntfy or Pushover when one starts to hold.
+pub fn spawn_checker() {
+    if CONFIG.alerts.is_empty() || CONFIG.offline {
+        return;
+    }
+    info!(alerts = CONFIG.alerts.len(), "Checking alert conditions");
+    tokio::spawn(async {
+        let mut seen: Vec<Seen> = CONFIG.alerts.iter().map(|_| Seen::default()).collect();
+        let mut interval = tokio::time::interval(Duration::from_secs(CONFIG.alert_interval.max(1)));
+        loop {
+            interval.tick().await;
+            for (alert, seen) in CONFIG.alerts.iter().zip(&mut seen) {
+                for notification in check(alert, seen).await {
+                    info!(alert = alert_name(alert), title = notification.title, "Alert");
+                    let _ = send(&notification).await;
+                }
+            }
+        }
+    });
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::time::SystemTime;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// Just enough of the ustar format for a flat list of tile files, which is
+// what `tar` and every archive tool reads and writes by default
+const BLOCK: usize = 512;
+const NAME_LEN: usize = 100;
+
+fn octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}", value, width = field.len() - 1);
+    field[..digits.len()].copy_from_slice(digits.as_bytes());
+    field[digits.len()] = 0;
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let text = std::str::from_utf8(field).ok()?;
+    let text = text.trim_matches(|c: char| c == '\0' || c == ' ');
+    if text.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(text, 8).ok()
+}
+
+fn checksum(header: &[u8; BLOCK]) -> u64 {
+    // The checksum field itself counts as spaces
+    header.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 }).sum()
+}
+
+fn header(name: &str, size: u64, modified: SystemTime) -> io::Result<[u8; BLOCK]> {
+    if name.len() > NAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "name too long for tar"));
+    }
+    let mtime = modified.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let mut header = [0u8; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    octal(&mut header[136..148], mtime);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    let sum = format!("{:06o}\0 ", checksum(&header));
+    header[148..156].copy_from_slice(sum.as_bytes());
+    Ok(header)
+}
+
+fn padding(size: u64) -> usize {
+    (BLOCK - (size as usize % BLOCK)) % BLOCK
+}
+
+/// Append one file to a tar stream.
+pub async fn write_entry<W: AsyncWrite + Unpin>(out: &mut W, name: &str, data: &[u8], modified: SystemTime) -> io::Result<()> {
+    out.write_all(&header(name, data.len() as u64, modified)?).await?;
+    out.write_all(data).await?;
+    out.write_all(&[0u8; BLOCK][..padding(data.len() as u64)]).await
+}
+
+/// End a tar stream.
+pub async fn finish<W: AsyncWrite + Unpin>(out: &mut W) -> io::Result<()> {
+    out.write_all(&[0u8; 2 * BLOCK]).await?;
+    out.flush().await
+}
+
+/// One file read from a tar stream: its name, and its contents unless it
+/// was larger than the caller's limit.
+pub struct Entry {
+    pub name: String,
+    pub data: Option<Vec<u8>>,
+}
+
+/// The next regular file in a tar stream, skipping directories and other
+/// entry types; `None` at the end of the archive.
+pub async fn next_entry<R: AsyncRead + Unpin>(input: &mut R, max_size: u64) -> io::Result<Option<Entry>> {
+    loop {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "not a tar archive");
+        let mut header = [0u8; BLOCK];
+        let mut filled = 0;
+        while filled < BLOCK {
+            match input.read(&mut header[filled..]).await? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        // Some writers leave out the trailing zero blocks
+        if filled == 0 || header.iter().all(|&b| b == 0) {
+            return Ok(None);
+        }
+        if filled < BLOCK {
+            return Err(invalid());
+        }
+        if parse_octal(&header[148..156]) != Some(checksum(&header)) {
+            return Err(invalid());
+        }
+        let size = parse_octal(&header[124..136]).ok_or_else(invalid)?;
+        let end = header[..NAME_LEN].iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+        let mut name = String::from_utf8_lossy(&header[..end]).into_owned();
+        // ustar keeps long names' leading directories in a separate field
+        if &header[257..262] == b"ustar" && header[345] != 0 {
+            let end = header[345..500].iter().position(|&b| b == 0).unwrap_or(155);
+            name = format!("{}/{}", String::from_utf8_lossy(&header[345..345 + end]), name);
+        }
+
+        let regular = header[156] == b'0' || header[156] == 0;
+        let data = if regular && size <= max_size {
+            let mut data = vec![0u8; size as usize];
+            input.read_exact(&mut data).await?;
+            Some(data)
+        } else {
+            tokio::io::copy(&mut (&mut *input).take(size), &mut tokio::io::sink()).await?;
+            None
+        };
+        let mut pad = [0u8; BLOCK];
+        input.read_exact(&mut pad[..padding(size)]).await?;
+        if regular {
+            return Ok(Some(Entry { name, data }));
+        }
+    }
+}
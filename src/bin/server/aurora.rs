@@ -0,0 +1,154 @@
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use axum::body::Bytes;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use image::{ImageOutputFormat, Rgba, RgbaImage};
+use serde::Deserialize;
+use tracing::{debug, warn, Span};
+use crate::cache::TileFormat;
+use crate::config::CONFIG;
+use crate::upstream::{self, HTTP_CLIENT};
+use crate::{bad_gateway, geos, offline, satellites, tile_response, Params, Tile};
+
+// OVATION runs every five minutes
+const REFRESH: Duration = Duration::from_secs(300);
+// Probabilities (percent) below this aren't drawn
+const MIN_PROBABILITY: u8 = 4;
+// ...and this one and up are drawn at full strength
+const FULL_PROBABILITY: f64 = 50.0;
+
+/// Sent with each tile: when the forecast it shows is for.
+pub const FORECAST_HEADER: header::HeaderName = header::HeaderName::from_static("x-peepsat-forecast-time");
+
+#[derive(Deserialize)]
+struct Ovation {
+    #[serde(rename = "Forecast Time")]
+    forecast_time: String,
+    /// [longitude 0-359, latitude -90-90, probability %]
+    coordinates: Vec<(f64, f64, f64)>,
+}
+
+/// Aurora probability on a one-degree grid.
+struct Grid {
+    forecast_time: String,
+    /// 360 longitudes east from 0° for each of 181 latitudes from -90°
+    values: Vec<u8>,
+}
+
+impl Grid {
+    fn at(&self, lat: f64, lon: f64) -> u8 {
+        let row = (lat.round() + 90.0).clamp(0.0, 180.0) as usize;
+        let column = lon.round().rem_euclid(360.0) as usize % 360;
+        self.values[row * 360 + column]
+    }
+}
+
+lazy_static::lazy_static! {
+    // The latest OVATION grid and when it was fetched
+    static ref GRID: Mutex<Option<(Instant, Arc<Grid>)>> = Mutex::new(None);
+}
+
+// The current grid, fetched again once it's `REFRESH` old. If SWPC can't be
+// reached, the last one keeps being used.
+async fn grid() -> Result<Arc<Grid>, &'static str> {
+    let cached = GRID.lock().unwrap().clone();
+    if let Some((fetched, grid)) = &cached {
+        if fetched.elapsed() < REFRESH {
+            return Ok(grid.clone());
+        }
+    }
+    let url = format!("{}/json/ovation_aurora_latest.json", CONFIG.swpc_url);
+    debug!(url, "Fetching aurora forecast");
+    let fetched = match upstream::get(&HTTP_CLIENT, &[url]).await {
+        Ok(r) if r.status().is_success() => match r.bytes().await {
+            Ok(body) => serde_json::from_slice::<Ovation>(&body).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        },
+        Ok(r) => Err(format!("status {}", r.status().as_u16())),
+        Err(e) => Err(e.to_string()),
+    };
+    let ovation = match fetched {
+        Ok(ovation) => ovation,
+        Err(e) => {
+            warn!(error = %e, "Aurora forecast unavailable");
+            return cached.map(|(_, grid)| grid).ok_or("Aurora forecast unavailable");
+        }
+    };
+    let mut values = vec![0u8; 360 * 181];
+    for (lon, lat, probability) in ovation.coordinates {
+        let row = (lat.round() + 90.0).clamp(0.0, 180.0) as usize;
+        let column = lon.round().rem_euclid(360.0) as usize % 360;
+        values[row * 360 + column] = probability.clamp(0.0, 100.0) as u8;
+    }
+    let grid = Arc::new(Grid { forecast_time: ovation.forecast_time, values });
+    *GRID.lock().unwrap() = Some((Instant::now(), grid.clone()));
+    Ok(grid)
+}
+
+// Green through yellow to red as the probability rises, fading in from
+// transparent
+fn colour(probability: u8) -> Rgba<u8> {
+    if probability < MIN_PROBABILITY {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let strength = (probability as f64 / FULL_PROBABILITY).min(1.0);
+    let red = (strength * 2.0).min(1.0) * 255.0;
+    let green = (2.0 - strength * 2.0).min(1.0) * 255.0;
+    Rgba([red as u8, green as u8, 60, (60.0 + strength * 160.0) as u8])
+}
+
+/// One tile of the current OVATION aurora forecast in a satellite's
+/// full-disk grid, with the same `sat`, `x` (row), `y` (column) and `z` as
+/// `/slider-tile`. It's always the latest forecast, whatever frame it's
+/// drawn over.
+pub async fn handle_aurora_tile(Query(params): Query<Params>, headers: HeaderMap) -> Response {
+    let sat = params.get("sat").cloned().unwrap_or_else(|| CONFIG.default_satellite.clone());
+    let Some(satellite) = satellites::find(&sat) else {
+        return (StatusCode::BAD_REQUEST, "Unknown satellite").into_response();
+    };
+    let zoom: u32 = params.get("z").and_then(|s| s.parse().ok()).unwrap_or(0).min(satellite.max_zoom);
+    let x: u32 = params.get("x").and_then(|s| s.parse().ok()).unwrap_or(0);
+    let y: u32 = params.get("y").and_then(|s| s.parse().ok()).unwrap_or(0);
+    if x >= 1 << zoom || y >= 1 << zoom {
+        return (StatusCode::BAD_REQUEST, "Tile out of range").into_response();
+    }
+    let span = Span::current();
+    span.record("sat", sat.as_str());
+    span.record("x", x);
+    span.record("y", y);
+    span.record("z", zoom);
+    if offline::enabled() {
+        return offline::unavailable();
+    }
+
+    let grid = match grid().await {
+        Ok(grid) => grid,
+        Err(message) => return bad_gateway(message),
+    };
+    let tile = Tile { sat: &sat, sector: "aurora", product: "ovation", timestamp: "0", zoom, x, y };
+    let coordinates = geos::tile_coordinates(&tile, satellite);
+    let tile_size = satellite.tile_size;
+    let forecast_time = grid.forecast_time.clone();
+    let render = move || {
+        let image = RgbaImage::from_fn(tile_size, tile_size, |px, py| match coordinates[(py * tile_size + px) as usize] {
+            Some((lat, lon)) => colour(grid.at(lat, lon)),
+            None => Rgba([0, 0, 0, 0]),
+        });
+        let mut png = Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgba8(image).write_to(&mut png, ImageOutputFormat::Png).expect("PNG encoding to memory");
+        png.into_inner()
+    };
+    let png = match tokio::task::spawn_blocking(render).await {
+        Ok(png) => Bytes::from(png),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to render tile").into_response(),
+    };
+    // Revalidated by ETag; a new forecast makes a new tile
+    let mut response = tile_response(png, TileFormat::Png, "MISS", "0", &headers);
+    if let Ok(value) = HeaderValue::from_str(&forecast_time) {
+        response.headers_mut().insert(FORECAST_HEADER, value);
+    }
+    response
+}
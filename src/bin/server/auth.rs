@@ -0,0 +1,78 @@
+use axum::extract::Request;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use base64::Engine;
+use subtle::ConstantTimeEq;
+use tracing::warn;
+use crate::config::CONFIG;
+
+/// Whether any credentials are configured. Without them the proxy is open.
+pub fn enabled() -> bool {
+    CONFIG.auth_token.is_some() || CONFIG.basic_auth.is_some()
+}
+
+fn secret_matches(given: &str, expected: &str) -> bool {
+    given.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// `?token=` lets the frontend authenticate `<img>` loads, which can't
+/// carry an Authorization header.
+fn query_token(query: Option<&str>) -> Option<String> {
+    query?.split('&').find_map(|pair| {
+        let value = pair.strip_prefix("token=")?;
+        urlencoding::decode(value).ok().map(|v| v.into_owned())
+    })
+}
+
+fn authorized(headers: &HeaderMap, query: Option<&str>) -> bool {
+    if let Some(expected) = &CONFIG.auth_token {
+        if let Some(token) = query_token(query) {
+            if secret_matches(&token, expected) {
+                return true;
+            }
+        }
+    }
+
+    let Some(value) = headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    let Some((scheme, credentials)) = value.split_once(' ') else {
+        return false;
+    };
+    let credentials = credentials.trim();
+    if scheme.eq_ignore_ascii_case("bearer") {
+        if let Some(expected) = &CONFIG.auth_token {
+            return secret_matches(credentials, expected);
+        }
+    } else if scheme.eq_ignore_ascii_case("basic") {
+        if let Some(expected) = &CONFIG.basic_auth {
+            let decoded = base64::engine::general_purpose::STANDARD.decode(credentials);
+            return match decoded.ok().and_then(|d| String::from_utf8(d).ok()) {
+                Some(given) => secret_matches(&given, expected),
+                None => false,
+            };
+        }
+    }
+    false
+}
+
+/// Reject requests to the proxy endpoints that lack valid credentials.
+pub async fn require_auth(request: Request, next: Next) -> Response {
+    if !enabled() || authorized(request.headers(), request.uri().query()) {
+        return next.run(request).await;
+    }
+
+    warn!(path = request.uri().path(), "Rejected unauthenticated request");
+    let challenge = if CONFIG.basic_auth.is_some() {
+        "Basic realm=\"peepsat\", charset=\"UTF-8\""
+    } else {
+        "Bearer realm=\"peepsat\""
+    };
+    (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, challenge)],
+        "401 Unauthorized",
+    )
+        .into_response()
+}
@@ -0,0 +1,264 @@
+use std::io::Cursor;
+use std::sync::Arc;
+use axum::body::Bytes;
+use axum::extract::Query;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use image::{ImageOutputFormat, RgbImage, Rgba, RgbaImage};
+use tracing::{debug, info, warn, Span};
+use crate::cache::{cache_key, get_cached_tile, put_cached_tile, TileFormat};
+use crate::config::CONFIG;
+use crate::dates::{self, civil_from_days, parse_time};
+use crate::upstream::{self, Priority, HTTP_CLIENT};
+use crate::{bad_gateway, cached_tile_response, coalesce, geos, offline, satellites, tile_response, Params, Tile, UpstreamTile, MAX_TILE_BYTES};
+
+// Base tiles are cached as "base/{layer}/{sat}_{date}_{z}_{x}_{y}"
+const CACHE_SECTOR: &str = "base";
+
+/// A static world image drawn under or into the satellite imagery,
+/// rendered into each satellite's full-disk grid.
+struct BaseLayer {
+    /// Name used in requests and cache keys
+    id: &'static str,
+    source: Source,
+    /// Hidden where the sun is up at the frame time
+    night_only: bool,
+}
+
+enum Source {
+    /// A GIBS layer with a single date (YYYYMMDD), through its EPSG:4326 WMS
+    Gibs { identifier: &'static str, date: u32 },
+    /// Blue Marble Next Generation: one whole-world image for each month
+    /// of 2004, picked by the frame's month
+    BlueMarble,
+}
+
+const BASE_LAYERS: &[BaseLayer] = &[
+    BaseLayer { id: "night_lights", source: Source::Gibs { identifier: "VIIRS_Black_Marble", date: 20160101 }, night_only: true },
+    BaseLayer { id: "blue_marble", source: Source::BlueMarble, night_only: false },
+];
+
+// Visible Earth image records of the monthly Blue Marble, January first
+const BLUE_MARBLE_RECORDS: [u32; 12] = [73580, 73605, 73630, 73655, 73701, 73726, 73751, 73776, 73801, 73826, 73884, 73909];
+// The 5400x2700 JPEGs run to several MB
+const WORLD_IMAGE_BYTES: usize = 64 * 1024 * 1024;
+
+lazy_static::lazy_static! {
+    // The last Blue Marble month downloaded, decoded; about 44 MB
+    static ref WORLD: tokio::sync::Mutex<Option<(u32, Arc<RgbImage>)>> = tokio::sync::Mutex::new(None);
+}
+
+// Sun this far below the horizon, degrees, counts as fully dark
+const DARK_ELEVATION: f64 = 6.0;
+
+/// One base-layer tile in a satellite's full-disk grid, with the same `sat`,
+/// `x` (row), `y` (column) and `z` as `/slider-tile`. The frame time `t`
+/// picks Blue Marble's month, and fades night lights out where the sun is
+/// up: transparent by day, opaque once the sun is `DARK_ELEVATION` below
+/// the horizon.
+pub async fn handle_base_tile(Query(params): Query<Params>, headers: HeaderMap) -> Response {
+    let Some(layer) = params.get("layer").and_then(|id| BASE_LAYERS.iter().find(|l| l.id == id)) else {
+        return (StatusCode::BAD_REQUEST, "Unknown layer").into_response();
+    };
+    let sat = params.get("sat").cloned().unwrap_or_else(|| CONFIG.default_satellite.clone());
+    let Some(satellite) = satellites::find(&sat) else {
+        return (StatusCode::BAD_REQUEST, "Unknown satellite").into_response();
+    };
+    let zoom: u32 = params.get("z").and_then(|s| s.parse().ok()).unwrap_or(0).min(satellite.max_zoom);
+    let x: u32 = params.get("x").and_then(|s| s.parse().ok()).unwrap_or(0);
+    let y: u32 = params.get("y").and_then(|s| s.parse().ok()).unwrap_or(0);
+    if x >= 1 << zoom || y >= 1 << zoom {
+        return (StatusCode::BAD_REQUEST, "Tile out of range").into_response();
+    }
+    let time = match params.get("t") {
+        Some(t) => match parse_time(t) {
+            Some(time) => Some(time),
+            None => return (StatusCode::BAD_REQUEST, "Invalid timestamp").into_response(),
+        },
+        None => None,
+    };
+
+    let span = Span::current();
+    span.record("sat", sat.as_str());
+    span.record("x", x);
+    span.record("y", y);
+    span.record("z", zoom);
+
+    let date = match layer.source {
+        Source::Gibs { date, .. } => date,
+        Source::BlueMarble => {
+            let (_, month, _) = civil_from_days(time.unwrap_or_else(dates::now).div_euclid(86400));
+            20040001 + month as u32 * 100
+        }
+    }
+    .to_string();
+    let tile = Tile { sat: &sat, sector: CACHE_SECTOR, product: layer.id, timestamp: &date, zoom, x, y };
+    let key = cache_key(&sat, CACHE_SECTOR, layer.id, &date, zoom, x, y);
+    let (data, format, cache_status) = match get_cached_tile(&key).await {
+        Some((data, format)) => (data, format, "HIT"),
+        None if offline::enabled() => return offline::unavailable(),
+        None => match coalesce(&key, fetch_tile(layer, &tile, &key)).await {
+            Ok((status, bytes)) if status.is_success() => (bytes, TileFormat::Png, "MISS"),
+            Ok((status, bytes)) => return (status, bytes).into_response(),
+            Err(()) => return bad_gateway("Failed"),
+        },
+    };
+    span.record("cache", cache_status);
+
+    let Some(time) = time.filter(|_| layer.night_only) else {
+        return if cache_status == "HIT" {
+            cached_tile_response(data, format, &date, &headers).await
+        } else {
+            tile_response(data, format, cache_status, &date, &headers)
+        };
+    };
+    let coordinates = geos::tile_coordinates(&tile, satellite);
+    let timestamp = params.get("t").cloned().unwrap_or_default();
+    match tokio::task::spawn_blocking(move || night_only(&data, &coordinates, time)).await {
+        Ok(Ok(masked)) => tile_response(Bytes::from(masked), TileFormat::Png, cache_status, &timestamp, &headers),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to render tile").into_response(),
+    }
+}
+
+// Render a base tile and cache it
+async fn fetch_tile(layer: &BaseLayer, tile: &Tile<'_>, key: &str) -> UpstreamTile {
+    let Some(satellite) = satellites::find(tile.sat) else {
+        return Ok((StatusCode::NOT_FOUND, Bytes::new()));
+    };
+    let tile_size = satellite.tile_size;
+    let coordinates = geos::tile_coordinates(tile, satellite);
+    let (map, bbox) = if coordinates.iter().all(Option::is_none) {
+        (Arc::new(RgbImage::new(1, 1)), (-90.0, -180.0, 90.0, 180.0))
+    } else {
+        match layer.source {
+            Source::Gibs { identifier, date } => {
+                let bbox = geos::bounds(&coordinates);
+                match gibs_map(identifier, date, bbox, tile_size, key).await {
+                    Ok(map) => (Arc::new(map), bbox),
+                    Err(response) => return response,
+                }
+            }
+            Source::BlueMarble => match world(tile.timestamp[4..6].parse().unwrap_or(1), key).await {
+                Ok(world) => (world, (-90.0, -180.0, 90.0, 180.0)),
+                Err(response) => return response,
+            },
+        }
+    };
+
+    let png = match tokio::task::spawn_blocking(move || geos::render(&map, bbox, &coordinates, tile_size)).await {
+        Ok(png) => Bytes::from(png),
+        Err(_) => return Err(()),
+    };
+    put_cached_tile(key, &png).await;
+    Ok((StatusCode::OK, png))
+}
+
+// Download an image, or what to answer if that fails
+async fn download(url: String, limit: usize, key: &str) -> Result<Bytes, UpstreamTile> {
+    let _slot = upstream::acquire(Priority::Interactive).await;
+    debug!(key, url, "Fetching base layer image");
+    let response = match upstream::get(&HTTP_CLIENT, &[url]).await {
+        Ok(r) => r,
+        Err(e) => {
+            warn!(key, error = %e, "Base layer request failed");
+            return Err(Err(()));
+        }
+    };
+    let status = response.status();
+    let body = match upstream::read_limited(response, limit).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(key, error = %e, "Base layer download failed");
+            return Err(Err(()));
+        }
+    };
+    if !status.is_success() {
+        return Err(Ok((status, body)));
+    }
+    Ok(body)
+}
+
+// Decode a downloaded image; WMS errors come back as XML with a 200
+fn decode(body: &[u8], key: &str) -> Result<RgbImage, UpstreamTile> {
+    image::load_from_memory(body).map(|image| image.to_rgb8()).map_err(|e| {
+        warn!(key, error = %e, "Base layer source returned no image");
+        Err(())
+    })
+}
+
+// The part of a GIBS layer inside `bbox`, from its EPSG:4326 WMS
+async fn gibs_map(identifier: &str, date: u32, bbox: (f64, f64, f64, f64), size: u32, key: &str) -> Result<RgbImage, UpstreamTile> {
+    let url = format!(
+        "{}/wms/epsg4326/best/wms.cgi?SERVICE=WMS&VERSION=1.3.0&REQUEST=GetMap&LAYERS={}&STYLES=&CRS=EPSG:4326&BBOX={},{},{},{}&WIDTH={}&HEIGHT={}&FORMAT=image/png&TIME={:04}-{:02}-{:02}",
+        CONFIG.gibs_url, identifier, bbox.0, bbox.1, bbox.2, bbox.3, size, size, date / 10000, date / 100 % 100, date % 100,
+    );
+    let body = download(url, MAX_TILE_BYTES, key).await?;
+    decode(&body, key)
+}
+
+// The whole-world Blue Marble image for `month` (1-12), downloaded once
+// and kept until another month is asked for
+async fn world(month: usize, key: &str) -> Result<Arc<RgbImage>, UpstreamTile> {
+    let mut world = WORLD.lock().await;
+    if let Some((cached, image)) = &*world {
+        if *cached as usize == month {
+            return Ok(image.clone());
+        }
+    }
+    let record = BLUE_MARBLE_RECORDS[month.clamp(1, 12) - 1];
+    let url = format!(
+        "{}/images/imagerecords/73000/{}/world.topo.bathy.2004{:02}.3x5400x2700.jpg",
+        CONFIG.blue_marble_url, record, month,
+    );
+    let body = download(url, WORLD_IMAGE_BYTES, key).await?;
+    let key = key.to_string();
+    let image = match tokio::task::spawn_blocking(move || decode(&body, &key)).await {
+        Ok(image) => Arc::new(image?),
+        Err(_) => return Err(Err(())),
+    };
+    info!(month, width = image.width(), height = image.height(), "Loaded Blue Marble");
+    *world = Some((month as u32, image.clone()));
+    Ok(image)
+}
+
+/// Latitude and longitude of the point with the sun overhead at `time`
+/// (seconds since the epoch), good to a fraction of a degree.
+pub fn subsolar_point(time: i64) -> (f64, f64) {
+    let days = (time as f64 - 946_728_000.0) / 86400.0;
+    let mean_longitude = 280.460 + 0.9856474 * days;
+    let anomaly = (357.528 + 0.9856003 * days).to_radians();
+    let ecliptic = (mean_longitude + 1.915 * anomaly.sin() + 0.020 * (2.0 * anomaly).sin()).to_radians();
+    let obliquity = (23.439 - 0.0000004 * days).to_radians();
+    let declination = (obliquity.sin() * ecliptic.sin()).asin();
+    let right_ascension = (obliquity.cos() * ecliptic.sin()).atan2(ecliptic.cos());
+    let sidereal = (280.46061837 + 360.98564736629 * days).to_radians();
+    let lon = (right_ascension - sidereal).to_degrees();
+    (declination.to_degrees(), (lon + 540.0).rem_euclid(360.0) - 180.0)
+}
+
+/// The sun's elevation above the horizon in degrees at `lat`, `lon`, with
+/// the sun overhead at `sun` as `subsolar_point` gives it.
+pub fn sun_elevation(lat: f64, lon: f64, sun: (f64, f64)) -> f64 {
+    let (sin_lat, cos_lat) = lat.to_radians().sin_cos();
+    let (sin_sun, cos_sun) = sun.0.to_radians().sin_cos();
+    (sin_lat * sin_sun + cos_lat * cos_sun * (lon - sun.1).to_radians().cos()).asin().to_degrees()
+}
+
+// The tile with an alpha channel that hides it wherever the sun is up
+fn night_only(tile: &[u8], coordinates: &[Option<(f64, f64)>], time: i64) -> Result<Vec<u8>, String> {
+    let image = image::load_from_memory(tile).map_err(|e| e.to_string())?.to_rgb8();
+    let sun = subsolar_point(time);
+    let size = image.width();
+    let masked = RgbaImage::from_fn(size, image.height(), |px, py| {
+        let [r, g, b] = image.get_pixel(px, py).0;
+        let Some(&Some((lat, lon))) = coordinates.get((py * size + px) as usize) else {
+            return Rgba([0, 0, 0, 0]);
+        };
+        let elevation = sun_elevation(lat, lon, sun);
+        let darkness = (-elevation / DARK_ELEVATION).clamp(0.0, 1.0);
+        Rgba([r, g, b, (darkness * 255.0) as u8])
+    });
+    let mut out = Cursor::new(Vec::new());
+    image::DynamicImage::ImageRgba8(masked).write_to(&mut out, ImageOutputFormat::Png).map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
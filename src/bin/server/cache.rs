@@ -0,0 +1,1174 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use axum::body::Bytes;
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+use crate::config::CONFIG;
+use crate::disk;
+use crate::eviction::{self, EvictionPolicy};
+use crate::hot_cache;
+use crate::metrics;
+use crate::redis::{self, Reply};
+use crate::s3;
+use crate::transcode;
+
+// Bump when the on-disk index layout changes; older files are rebuilt
+const INDEX_VERSION: u32 = 3;
+const INDEX_FILE: &str = "index.bin";
+// The Redis hash servers sharing a bucket keep the index in, by key
+const SHARED_INDEX: &str = "index";
+// Entries per command when sharing index changes
+const SHARE_BATCH: usize = 500;
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+// IEND chunk: zero length, type, CRC. Missing when a write was cut short.
+const PNG_TRAILER: &[u8] = b"\0\0\0\0IEND\xae\x42\x60\x82";
+// "RIFF", little-endian size of everything after it, "WEBP"
+const WEBP_HEADER_LEN: usize = 12;
+
+/// How a tile is stored on disk. Upstream tiles are PNG; with
+/// `cache_format = "webp"` they're re-encoded to save space.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum TileFormat {
+    Png,
+    Webp,
+}
+
+impl TileFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            TileFormat::Png => "image/png",
+            TileFormat::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            TileFormat::Png => "png",
+            TileFormat::Webp => "webp",
+        }
+    }
+
+    pub fn from_path(path: &Path) -> Option<TileFormat> {
+        match path.extension()?.to_str()? {
+            "png" => Some(TileFormat::Png),
+            "webp" => Some(TileFormat::Webp),
+            _ => None,
+        }
+    }
+
+    fn other(self) -> TileFormat {
+        match self {
+            TileFormat::Png => TileFormat::Webp,
+            TileFormat::Webp => TileFormat::Png,
+        }
+    }
+}
+
+// LRU cache tracking
+#[derive(Serialize, Deserialize)]
+pub struct CacheEntry {
+    // Derived from the key, so a moved cache directory still works
+    #[serde(skip)]
+    path: PathBuf,
+    pub format: TileFormat,
+    pub size: u64,
+    pub created: SystemTime,
+    pub last_access: SystemTime,
+    pub hits: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexFile {
+    version: u32,
+    entries: HashMap<String, CacheEntry>,
+}
+
+lazy_static::lazy_static! {
+    pub static ref CACHE_DIR: PathBuf = {
+        let cache_dir = CONFIG.cache_dir.clone();
+        fs::create_dir_all(&cache_dir).ok();
+        cache_dir
+    };
+    pub static ref CACHE_INDEX: Mutex<HashMap<String, CacheEntry>> = Mutex::new(HashMap::new());
+    static ref POLICY: Box<dyn EvictionPolicy> = eviction::from_config();
+    // Tiles upstream didn't have (yet), with the status to repeat and when to ask again
+    static ref NEGATIVE_CACHE: Mutex<HashMap<String, (StatusCode, Instant)>> = Mutex::new(HashMap::new());
+    // Tiles deleted from the bucket since the index was last merged with
+    // the copy there (or shared through Redis), which mustn't bring them back
+    static ref DELETED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+    // Tiles stored or read since the index was last shared through Redis
+    static ref CHANGED: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+// Set whenever CACHE_INDEX changes in a way worth writing back
+static INDEX_DIRTY: AtomicBool = AtomicBool::new(false);
+
+pub const DEFAULT_SECTOR: &str = "full_disk";
+pub const DEFAULT_PRODUCT: &str = "geocolor";
+
+/// "{sat}_{timestamp}_{zoom}_{x}_{y}", prefixed with "{sector}/{product}/"
+/// for anything but full-disk GeoColor. Those keep the unprefixed form
+/// they had before other products existed, so older caches stay valid.
+pub fn cache_key(sat: &str, sector: &str, product: &str, timestamp: &str, zoom: u32, x: u32, y: u32) -> String {
+    if sector == DEFAULT_SECTOR && product == DEFAULT_PRODUCT {
+        format!("{}_{}_{}_{}_{}", sat, timestamp, zoom, x, y)
+    } else {
+        format!("{}/{}/{}_{}_{}_{}_{}", sector, product, sat, timestamp, zoom, x, y)
+    }
+}
+
+// The "{sector}/{product}" prefix of a key, if it has one, and the rest
+fn split_key(key: &str) -> (Option<(&str, &str)>, &str) {
+    let mut parts = key.splitn(3, '/');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(sector), Some(product), Some(tile)) => (Some((sector, product)), tile),
+        _ => (None, key),
+    }
+}
+
+/// Sector and product of a cache key.
+pub fn key_layer(key: &str) -> (&str, &str) {
+    split_key(key).0.unwrap_or((DEFAULT_SECTOR, DEFAULT_PRODUCT))
+}
+
+// FNV-1a; only needs to spread keys evenly across shards
+fn key_hash(key: &str) -> u64 {
+    key.bytes().fold(0xcbf29ce484222325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100000001b3))
+}
+
+// Two levels of 256 directories keep each one small even for very large
+// caches: tiles/3f/a2/19_20240101000000_4_1_2.png. Other products get a
+// tree of their own: tiles/conus/band_13/5c/07/19_20240101000000_4_1_2.png.
+// Objects in the bucket are named the same, without the "tiles/".
+fn relative_path(key: &str, format: TileFormat) -> String {
+    let hash = key_hash(key);
+    let (layer, tile) = split_key(key);
+    let root = match layer {
+        Some((sector, product)) => format!("{}/{}/", sector, product),
+        None => String::new(),
+    };
+    format!("{}{:02x}/{:02x}/{}.{}", root, hash >> 56, (hash >> 48) & 0xff, tile, format.extension())
+}
+
+fn cache_path(key: &str, format: TileFormat) -> PathBuf {
+    CACHE_DIR.join(relative_path(key, format))
+}
+
+fn index_path() -> PathBuf {
+    CACHE_DIR.join(INDEX_FILE)
+}
+
+fn webp_header_valid(head: &[u8], len: u64) -> bool {
+    head.len() >= WEBP_HEADER_LEN
+        && &head[0..4] == b"RIFF"
+        && &head[8..12] == b"WEBP"
+        && u32::from_le_bytes([head[4], head[5], head[6], head[7]]) as u64 + 8 == len
+}
+
+/// Whether `data` looks like a complete tile in `format`. Catches zero-byte
+/// files, truncated writes and error pages without the cost of a full decode.
+pub fn is_valid_tile(data: &[u8], format: TileFormat) -> bool {
+    match format {
+        TileFormat::Png => data.starts_with(PNG_SIGNATURE) && data.ends_with(PNG_TRAILER),
+        TileFormat::Webp => webp_header_valid(data, data.len() as u64),
+    }
+}
+
+// The same check reading only the ends of the file
+fn file_is_valid_tile(path: &Path, format: TileFormat, len: u64) -> bool {
+    if len < (PNG_SIGNATURE.len() + PNG_TRAILER.len()) as u64 {
+        return false;
+    }
+    let check = || -> std::io::Result<bool> {
+        let mut file = fs::File::open(path)?;
+        if format == TileFormat::Webp {
+            let mut head = [0u8; WEBP_HEADER_LEN];
+            file.read_exact(&mut head)?;
+            return Ok(webp_header_valid(&head, len));
+        }
+        let mut head = [0u8; PNG_SIGNATURE.len()];
+        file.read_exact(&mut head)?;
+        let mut tail = [0u8; PNG_TRAILER.len()];
+        file.seek(SeekFrom::End(-(PNG_TRAILER.len() as i64)))?;
+        file.read_exact(&mut tail)?;
+        Ok(head == PNG_SIGNATURE && tail == PNG_TRAILER)
+    };
+    check().unwrap_or(false)
+}
+
+fn detect_format(data: &[u8]) -> TileFormat {
+    if data.starts_with(b"RIFF") {
+        TileFormat::Webp
+    } else {
+        TileFormat::Png
+    }
+}
+
+// The format tiles are written in
+fn preferred_format() -> TileFormat {
+    if CONFIG.cache_format == "webp" {
+        TileFormat::Webp
+    } else {
+        TileFormat::Png
+    }
+}
+
+/// The stored tile `key` in `format`, from disk or the bucket, unchecked.
+pub async fn read_stored(key: &str, format: TileFormat) -> Option<Bytes> {
+    if !s3::enabled() {
+        return tokio::fs::read(cache_path(key, format)).await.ok().map(Bytes::from);
+    }
+    match s3::get(&relative_path(key, format)).await {
+        Ok(data) => data,
+        Err(e) => {
+            warn!(key, error = %e, "Cache bucket read failed");
+            None
+        }
+    }
+}
+
+// Store a tile, replacing any copy in the other format
+async fn write_stored(key: &str, format: TileFormat, data: &Bytes) -> bool {
+    if s3::enabled() {
+        if let Err(e) = s3::put(&relative_path(key, format), data.clone()).await {
+            warn!(key, error = %e, "Cache bucket write failed");
+            return false;
+        }
+        // Only worth a request when the index says there is one
+        let previous = CACHE_INDEX.lock().ok().and_then(|index| index.get(key).map(|e| e.format));
+        if previous == Some(format.other()) {
+            let _ = s3::delete(&relative_path(key, format.other())).await;
+        }
+        return true;
+    }
+    let path = cache_path(key, format);
+    if let Some(shard) = path.parent() {
+        let _ = tokio::fs::create_dir_all(shard).await;
+    }
+    if tokio::fs::write(&path, data).await.is_err() {
+        return false;
+    }
+    let _ = tokio::fs::remove_file(cache_path(key, format.other())).await;
+    true
+}
+
+// Delete a stored tile. Callers hold the index lock, so deletes from the
+// bucket go on in the background.
+fn remove_stored(key: &str, format: TileFormat) -> std::io::Result<()> {
+    if !s3::enabled() {
+        return fs::remove_file(cache_path(key, format));
+    }
+    let name = relative_path(key, format);
+    if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+        runtime.spawn(async move {
+            if let Err(e) = s3::delete(&name).await {
+                warn!(name, error = %e, "Cache bucket delete failed");
+            }
+        });
+    }
+    DELETED.lock().unwrap().insert(key.to_string());
+    Ok(())
+}
+
+async fn read_tile_file(key: &str) -> Result<(Bytes, TileFormat), ()> {
+    // Try the format the index knows about first; after a cache_format
+    // change both kinds can be on disk. Each miss in the bucket costs a
+    // request, so only that one is tried there.
+    let known = CACHE_INDEX.lock().ok().and_then(|index| index.get(key).map(|e| e.format));
+    let first = known.unwrap_or_else(preferred_format);
+    let formats = if s3::enabled() { vec![first] } else { vec![first, first.other()] };
+    for format in formats {
+        match read_stored(key, format).await {
+            Some(data) if !is_valid_tile(&data, format) => {
+                // Treated as a miss so the caller fetches a fresh copy
+                discard_corrupt(key, format);
+                return Err(());
+            }
+            Some(data) => return Ok((data, format)),
+            None => continue,
+        }
+    }
+    Err(())
+}
+
+// Note an entry that changed, for sharing with other servers
+fn touched(key: &str) {
+    if redis::shares_cache() {
+        CHANGED.lock().unwrap().insert(key.to_string());
+    }
+}
+
+fn discard_corrupt(key: &str, format: TileFormat) {
+    warn!(key, "Discarding corrupt cached tile");
+    metrics::CACHE_CORRUPT.inc();
+    let _ = remove_stored(key, format);
+}
+
+/// The cached tile for `key` and the format it's stored in.
+pub async fn get_cached_tile(key: &str) -> Option<(Bytes, TileFormat)> {
+    let data = match hot_cache::get(key) {
+        Some(data) => {
+            metrics::HOT_CACHE_HITS.inc();
+            Ok((data.clone(), detect_format(&data)))
+        }
+        None => {
+            let result = read_tile_file(key).await;
+            if let Ok((data, _)) = &result {
+                hot_cache::insert(key, data.clone());
+            }
+            result
+        }
+    };
+    if let Ok((data, format)) = data {
+        // Update last access time in index
+        if let Ok(mut index) = CACHE_INDEX.lock() {
+            let now = SystemTime::now();
+            match index.get_mut(key) {
+                Some(entry) => {
+                    entry.last_access = now;
+                    entry.hits += 1;
+                    INDEX_DIRTY.store(true, Ordering::Relaxed);
+                    touched(key);
+                }
+                // Stored by another server sharing the bucket
+                None if s3::enabled() => {
+                    let entry = CacheEntry { path: cache_path(key, format), format, size: data.len() as u64, created: now, last_access: now, hits: 1 };
+                    index.insert(key.to_string(), entry);
+                    INDEX_DIRTY.store(true, Ordering::Relaxed);
+                    touched(key);
+                    update_cache_gauges(&index);
+                }
+                None => {}
+            }
+        }
+        metrics::CACHE_HITS.inc();
+        return Some((data, format));
+    }
+    // The file can vanish behind our back (manual cleanup, stale index) or
+    // have just been discarded as corrupt
+    if let Ok(mut index) = CACHE_INDEX.lock() {
+        if index.remove(key).is_some() {
+            INDEX_DIRTY.store(true, Ordering::Relaxed);
+            update_cache_gauges(&index);
+        }
+    }
+    metrics::CACHE_MISSES.inc();
+    None
+}
+
+// Re-encode a PNG as WebP, keeping the PNG if that doesn't save space
+async fn transcode_for_cache(key: &str, data: &Bytes) -> (Bytes, TileFormat) {
+    let png = data.clone();
+    match tokio::task::spawn_blocking(move || transcode::to_webp(&png)).await {
+        Ok(Ok(webp)) if webp.len() < data.len() => {
+            debug!(key, png = data.len(), webp = webp.len(), "Transcoded tile to WebP");
+            (Bytes::from(webp), TileFormat::Webp)
+        }
+        Ok(Ok(_)) => (data.clone(), TileFormat::Png),
+        Ok(Err(e)) => {
+            warn!(key, error = %e, "WebP transcoding failed, caching PNG");
+            (data.clone(), TileFormat::Png)
+        }
+        Err(_) => (data.clone(), TileFormat::Png),
+    }
+}
+
+pub async fn put_cached_tile(key: &str, data: &Bytes) {
+    if !is_valid_tile(data, TileFormat::Png) {
+        warn!(key, len = data.len(), "Not caching tile that isn't a complete PNG");
+        return;
+    }
+    let (data, format) = match preferred_format() {
+        TileFormat::Webp => transcode_for_cache(key, data).await,
+        TileFormat::Png => (data.clone(), TileFormat::Png),
+    };
+    store_tile(key, data, format).await;
+}
+
+// Write a tile in `format`, index it, and make room for it
+async fn store_tile(key: &str, data: Bytes, format: TileFormat) {
+    let path = cache_path(key, format);
+    if write_stored(key, format, &data).await {
+        hot_cache::insert(key, data.clone());
+        let size = data.len() as u64;
+        if let Ok(mut index) = CACHE_INDEX.lock() {
+            let now = SystemTime::now();
+            index.insert(key.to_string(), CacheEntry {
+                path: path.clone(),
+                format,
+                size,
+                created: now,
+                last_access: now,
+                hits: 0,
+            });
+            INDEX_DIRTY.store(true, Ordering::Relaxed);
+            touched(key);
+
+            // A satellite over its own quota gives up its own tiles first
+            let (sat, _) = key_parts(key);
+            if let Some(quota) = CONFIG.satellite_quota(sat) {
+                let sat_size: u64 = index.iter().filter(|(k, _)| key_parts(k).0 == sat).map(|(_, e)| e.size).sum();
+                if sat_size > quota {
+                    evict(&mut index, Some(sat), sat_size - quota);
+                }
+            }
+
+            // Check if we need to evict old entries
+            let total_size: u64 = index.values().map(|e| e.size).sum();
+            let max_size = disk::effective_max_size();
+            if total_size > max_size {
+                evict(&mut index, None, total_size - max_size);
+            }
+            update_cache_gauges(&index);
+        }
+    }
+}
+
+/// Whether `key` is well-formed: "{sat}_{timestamp}_{zoom}_{x}_{y}" with
+/// an optional "{sector}/{product}/" prefix of plain names. Keys from
+/// outside (archives) must pass this before they become paths.
+pub fn is_valid_key(key: &str) -> bool {
+    let plain = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_');
+    let (layer, tile) = split_key(key);
+    if layer.is_some_and(|(sector, product)| !plain(sector) || !plain(product)) {
+        return false;
+    }
+    let parts: Vec<&str> = tile.split('_').collect();
+    parts.len() == 5
+        && !parts[0].is_empty()
+        && parts[0].bytes().all(|b| b.is_ascii_alphanumeric())
+        && parts[1..].iter().all(|p| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Add a tile from an imported archive, kept in the format it came in.
+/// Returns false if `data` isn't a complete tile.
+pub async fn import_tile(key: &str, format: TileFormat, data: Bytes) -> bool {
+    if !is_valid_key(key) || !is_valid_tile(&data, format) {
+        return false;
+    }
+    store_tile(key, data, format).await;
+    true
+}
+
+/// A cached tile picked for export: key, format and when it was cached.
+pub struct ExportEntry {
+    pub key: String,
+    pub format: TileFormat,
+    pub created: SystemTime,
+}
+
+/// Cached tiles of `sat` (or all) with from <= timestamp <= to, by key.
+pub fn export_entries(sat: Option<&str>, from: Option<&str>, to: Option<&str>) -> Vec<ExportEntry> {
+    let Ok(index) = CACHE_INDEX.lock() else {
+        return Vec::new();
+    };
+    let mut entries: Vec<ExportEntry> = index
+        .iter()
+        .filter(|(key, _)| {
+            let timestamp = key_parts(key).1;
+            key_matches(key, sat, None)
+                && from.is_none_or(|from| timestamp >= from)
+                && to.is_none_or(|to| timestamp <= to)
+        })
+        .map(|(key, entry)| ExportEntry {
+            key: key.clone(),
+            format: entry.format,
+            created: entry.created,
+        })
+        .collect();
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    entries
+}
+
+/// The cached key for the same tile (layer, satellite, zoom, x and y) at
+/// the latest timestamp before `key`'s. For timestamp "0" (latest frame),
+/// the newest cached one.
+pub fn nearest_earlier(key: &str) -> Option<String> {
+    let (layer, tile) = split_key(key);
+    let mut parts = tile.splitn(3, '_');
+    let (sat, timestamp, position) = (parts.next()?, parts.next()?, parts.next()?);
+    let index = CACHE_INDEX.lock().ok()?;
+    index
+        .keys()
+        .filter_map(|candidate| {
+            let (candidate_layer, candidate_tile) = split_key(candidate);
+            let mut parts = candidate_tile.splitn(3, '_');
+            let (candidate_sat, candidate_timestamp, candidate_position) = (parts.next()?, parts.next()?, parts.next()?);
+            let earlier = timestamp == "0" || candidate_timestamp < timestamp;
+            (candidate_layer == layer && candidate_sat == sat && candidate_position == position && earlier)
+                .then_some((candidate_timestamp, candidate))
+        })
+        .max()
+        .map(|(_, candidate)| candidate.clone())
+}
+
+/// Whether `key` is in the cache, without counting it as a hit or miss.
+pub fn is_cached(key: &str) -> bool {
+    CACHE_INDEX.lock().is_ok_and(|index| index.contains_key(key))
+}
+
+pub fn get_negative(key: &str) -> Option<StatusCode> {
+    let mut negative = NEGATIVE_CACHE.lock().unwrap();
+    match negative.get(key) {
+        Some(&(status, expires)) if expires > Instant::now() => Some(status),
+        Some(_) => {
+            negative.remove(key);
+            None
+        }
+        None => None,
+    }
+}
+
+// Remember a missing tile so animation loops don't re-ask upstream for
+// frames that haven't been published yet
+pub fn put_negative(key: &str, status: StatusCode) {
+    if CONFIG.negative_cache_ttl == 0 {
+        return;
+    }
+    let now = Instant::now();
+    let mut negative = NEGATIVE_CACHE.lock().unwrap();
+    negative.retain(|_, (_, expires)| *expires > now);
+    negative.insert(key.to_string(), (status, now + Duration::from_secs(CONFIG.negative_cache_ttl)));
+}
+
+// Delete what the configured policy picks from the tiles of `sat`, or the
+// whole cache. Entries whose file is already gone are dropped from the
+// index all the same.
+fn evict(index: &mut HashMap<String, CacheEntry>, sat: Option<&str>, bytes_to_free: u64) {
+    let candidates: Vec<_> = index.iter().filter(|(key, _)| key_matches(key, sat, None)).collect();
+    let keys = POLICY.select(&candidates, bytes_to_free);
+    if keys.is_empty() {
+        return;
+    }
+
+    let mut freed = 0u64;
+    for key in keys {
+        let Some(entry) = index.get(&key) else {
+            continue;
+        };
+        match remove_stored(&key, entry.format) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => continue,
+            _ => {}
+        }
+        freed += entry.size;
+        index.remove(&key);
+        hot_cache::remove(&key);
+        metrics::CACHE_EVICTIONS.inc();
+        debug!(key = %key, "Cache evicted");
+    }
+    INDEX_DIRTY.store(true, Ordering::Relaxed);
+    info!(freed, ?sat, policy = %CONFIG.eviction_policy, "Cache eviction complete");
+}
+
+/// Periodic pass letting time-based policies expire entries even when the
+/// cache is under its size limit.
+pub fn sweep() {
+    if let Ok(mut index) = CACHE_INDEX.lock() {
+        let total_size: u64 = index.values().map(|e| e.size).sum();
+        evict(&mut index, None, total_size.saturating_sub(disk::effective_max_size()));
+        update_cache_gauges(&index);
+    }
+}
+
+/// A cached tile as reported by the admin API.
+#[derive(Serialize)]
+pub struct TileInfo {
+    pub key: String,
+    pub sat: String,
+    pub sector: String,
+    pub product: String,
+    pub timestamp: String,
+    pub size: u64,
+    /// Unix seconds
+    pub last_access: u64,
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+/// Satellite and timestamp of a cache key. Satellite names have no
+/// underscores.
+pub fn key_parts(key: &str) -> (&str, &str) {
+    let mut parts = split_key(key).1.splitn(3, '_');
+    (parts.next().unwrap_or(""), parts.next().unwrap_or(""))
+}
+
+fn key_matches(key: &str, sat: Option<&str>, timestamp: Option<&str>) -> bool {
+    let (key_sat, key_timestamp) = key_parts(key);
+    sat.is_none_or(|s| s == key_sat) && timestamp.is_none_or(|t| t == key_timestamp)
+}
+
+/// Timestamps with at least one cached tile for `sat` in `sector` and
+/// `product`, newest first.
+pub fn cached_frames(sat: &str, sector: &str, product: &str) -> Vec<String> {
+    let Ok(index) = CACHE_INDEX.lock() else {
+        return Vec::new();
+    };
+    let mut frames: Vec<String> = index
+        .keys()
+        .filter(|key| key_parts(key).0 == sat && key_layer(key) == (sector, product))
+        .map(|key| key_parts(key).1.to_string())
+        .collect();
+    frames.sort_unstable_by(|a, b| b.cmp(a));
+    frames.dedup();
+    frames
+}
+
+/// How many of the 4^zoom tiles of one frame at `zoom` are cached.
+pub fn cached_tiles(sat: &str, sector: &str, product: &str, timestamp: &str, zoom: u32) -> u64 {
+    let Ok(index) = CACHE_INDEX.lock() else {
+        return 0;
+    };
+    let side = 1u32 << zoom;
+    (0..side * side)
+        .filter(|i| index.contains_key(&cache_key(sat, sector, product, timestamp, zoom, i % side, i / side)))
+        .count() as u64
+}
+
+/// Cached tiles, optionally restricted to one satellite, sorted by key.
+pub fn list(sat: Option<&str>) -> Vec<TileInfo> {
+    let Ok(index) = CACHE_INDEX.lock() else {
+        return Vec::new();
+    };
+    let mut tiles: Vec<_> = index
+        .iter()
+        .filter(|(key, _)| key_matches(key, sat, None))
+        .map(|(key, entry)| {
+            let (sat, timestamp) = key_parts(key);
+            let (sector, product) = key_layer(key);
+            TileInfo {
+                key: key.clone(),
+                sat: sat.to_string(),
+                sector: sector.to_string(),
+                product: product.to_string(),
+                timestamp: timestamp.to_string(),
+                size: entry.size,
+                last_access: unix_secs(entry.last_access),
+            }
+        })
+        .collect();
+    tiles.sort_by(|a, b| a.key.cmp(&b.key));
+    tiles
+}
+
+/// Totals for the admin API: (entries, bytes, oldest and newest access).
+pub fn stats() -> (usize, u64, Option<u64>, Option<u64>) {
+    let Ok(index) = CACHE_INDEX.lock() else {
+        return (0, 0, None, None);
+    };
+    let bytes = index.values().map(|e| e.size).sum();
+    let oldest = index.values().map(|e| unix_secs(e.last_access)).min();
+    let newest = index.values().map(|e| unix_secs(e.last_access)).max();
+    (index.len(), bytes, oldest, newest)
+}
+
+/// Delete cached tiles matching the filters (all of them when both are
+/// `None`). Returns the number of tiles and bytes removed.
+pub fn purge(sat: Option<&str>, timestamp: Option<&str>) -> (usize, u64) {
+    let (tiles, bytes) = purge_where(|key| key_matches(key, sat, timestamp));
+    info!(?sat, ?timestamp, tiles, bytes, "Cache purged");
+    (tiles, bytes)
+}
+
+/// Delete cached tiles of frames older than the 14-digit `timestamp`, of
+/// `sat` or every satellite. Returns the number of tiles and bytes removed.
+pub fn purge_before(sat: Option<&str>, timestamp: &str) -> (usize, u64) {
+    let (tiles, bytes) = purge_where(|key| {
+        let (key_sat, key_timestamp) = key_parts(key);
+        sat.is_none_or(|s| s == key_sat) && key_timestamp < timestamp
+    });
+    info!(?sat, before = timestamp, tiles, bytes, "Cache pruned");
+    (tiles, bytes)
+}
+
+fn purge_where(matches: impl Fn(&str) -> bool) -> (usize, u64) {
+    let Ok(mut index) = CACHE_INDEX.lock() else {
+        return (0, 0);
+    };
+    let keys: Vec<String> = index.keys().filter(|k| matches(k)).cloned().collect();
+    let mut bytes = 0;
+    for key in &keys {
+        if let Some(entry) = index.remove(key) {
+            let _ = remove_stored(key, entry.format);
+            bytes += entry.size;
+        }
+    }
+    NEGATIVE_CACHE.lock().unwrap().retain(|key, _| !matches(key));
+    hot_cache::retain(|key| !matches(key));
+    INDEX_DIRTY.store(true, Ordering::Relaxed);
+    update_cache_gauges(&index);
+    (keys.len(), bytes)
+}
+
+fn update_cache_gauges(index: &HashMap<String, CacheEntry>) {
+    metrics::CACHE_ENTRIES.set(index.len() as i64);
+    metrics::CACHE_SIZE_BYTES.set(index.values().map(|e| e.size).sum::<u64>() as i64);
+}
+
+fn load_index_file() -> Option<HashMap<String, CacheEntry>> {
+    decode_index(&fs::read(index_path()).ok()?)
+}
+
+fn decode_index(data: &[u8]) -> Option<HashMap<String, CacheEntry>> {
+    match bincode::deserialize::<IndexFile>(data) {
+        Ok(file) if file.version == INDEX_VERSION => {
+            let mut entries = file.entries;
+            for (key, entry) in entries.iter_mut() {
+                entry.path = cache_path(key, entry.format);
+            }
+            Some(entries)
+        }
+        Ok(file) => {
+            info!(version = file.version, "Cache index has an old format, rebuilding");
+            None
+        }
+        Err(e) => {
+            warn!(error = %e, "Cache index is unreadable, rebuilding");
+            None
+        }
+    }
+}
+
+// Caches from before sharding kept every tile directly in the cache
+// directory; move those into their shards once
+fn migrate_flat_cache() {
+    let Ok(entries) = fs::read_dir(&*CACHE_DIR) else {
+        return;
+    };
+    let mut moved = 0;
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Some(format) = TileFormat::from_path(&path) else {
+            continue;
+        };
+        if !entry.file_type().is_ok_and(|t| t.is_file()) {
+            continue;
+        }
+        let Some(key) = path.file_stem().map(|s| s.to_string_lossy().to_string()) else {
+            continue;
+        };
+        let target = cache_path(&key, format);
+        let result = fs::create_dir_all(target.parent().unwrap()).and_then(|_| fs::rename(&path, &target));
+        match result {
+            Ok(()) => moved += 1,
+            Err(e) => warn!(?path, error = %e, "Failed to move tile into its shard"),
+        }
+    }
+    if moved > 0 {
+        info!(moved, "Migrated flat cache into sharded directories");
+    }
+}
+
+// Shard directories are named by two hex digits; anything else at the top
+// level is a sector holding per-product trees
+fn is_shard_name(name: &str) -> bool {
+    name.len() == 2 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn subdirs(path: &Path) -> impl Iterator<Item = fs::DirEntry> {
+    fs::read_dir(path).into_iter().flatten().flatten().filter(|e| e.file_type().is_ok_and(|t| t.is_dir()))
+}
+
+// Tiles in the two shard levels under `root`, keyed with `prefix`
+fn scan_shards(root: &Path, prefix: &str, on_disk: &mut HashMap<String, (PathBuf, TileFormat, fs::Metadata)>) {
+    let shards = subdirs(root)
+        .filter(|e| is_shard_name(&e.file_name().to_string_lossy()))
+        .flat_map(|e| subdirs(&e.path()));
+    for shard in shards {
+        for entry in fs::read_dir(shard.path()).into_iter().flatten().flatten() {
+            let path = entry.path();
+            let Some(format) = TileFormat::from_path(&path) else {
+                continue;
+            };
+            if let (Ok(meta), Some(stem)) = (entry.metadata(), path.file_stem()) {
+                let key = format!("{}{}", prefix, stem.to_string_lossy());
+                if meta.is_file() && !file_is_valid_tile(&path, format, meta.len()) {
+                    discard_corrupt(&key, format);
+                } else if meta.is_file() {
+                    on_disk.insert(key, (path, format, meta));
+                }
+            }
+        }
+    }
+}
+
+// Directory scan; files the index doesn't know get their mtime as last access
+fn scan_cache_dir(index: &mut HashMap<String, CacheEntry>) {
+    let mut on_disk = HashMap::new();
+    scan_shards(&CACHE_DIR, "", &mut on_disk);
+    for sector in subdirs(&CACHE_DIR).filter(|e| !is_shard_name(&e.file_name().to_string_lossy())) {
+        for product in subdirs(&sector.path()) {
+            let prefix = format!("{}/{}/", sector.file_name().to_string_lossy(), product.file_name().to_string_lossy());
+            scan_shards(&product.path(), &prefix, &mut on_disk);
+        }
+    }
+
+    index.retain(|key, _| on_disk.contains_key(key));
+    for (key, (path, format, meta)) in on_disk {
+        let modified = meta.modified().unwrap_or(SystemTime::now());
+        index.entry(key).or_insert_with(|| CacheEntry {
+            path,
+            format,
+            size: meta.len(),
+            created: modified,
+            last_access: modified,
+            hits: 0,
+        });
+    }
+}
+
+// The key and format of a tile object in the bucket, named as on disk
+fn object_key(name: &str) -> Option<(String, TileFormat)> {
+    let format = TileFormat::from_path(Path::new(name))?;
+    let parts: Vec<&str> = name.split('/').collect();
+    let (prefix, shards, file) = match parts.as_slice() {
+        [first, second, file] => (String::new(), [first, second], file),
+        [sector, product, first, second, file] => (format!("{}/{}/", sector, product), [first, second], file),
+        _ => return None,
+    };
+    if !shards.iter().all(|shard| is_shard_name(shard)) {
+        return None;
+    }
+    let key = format!("{}{}", prefix, file.rsplit_once('.')?.0);
+    is_valid_key(&key).then_some((key, format))
+}
+
+// The index saved in the bucket, or else one made by listing it
+async fn load_bucket_index() {
+    let saved = match s3::get(INDEX_FILE).await {
+        Ok(data) => data.and_then(|data| decode_index(&data)),
+        Err(e) => {
+            warn!(error = %e, "Cache index unreadable from the bucket, listing it instead");
+            None
+        }
+    };
+    let (entries, message) = match saved {
+        Some(entries) => (entries, "Cache index loaded from the bucket"),
+        None => match s3::list().await {
+            Ok(objects) => {
+                let entries = objects
+                    .into_iter()
+                    .filter_map(|object| {
+                        let (key, format) = object_key(&object.name)?;
+                        let entry = CacheEntry {
+                            path: cache_path(&key, format),
+                            format,
+                            size: object.size,
+                            created: object.modified,
+                            last_access: object.modified,
+                            hits: 0,
+                        };
+                        Some((key, entry))
+                    })
+                    .collect();
+                INDEX_DIRTY.store(true, Ordering::Relaxed);
+                (entries, "Cache index rebuilt from the bucket")
+            }
+            Err(e) => {
+                warn!(error = %e, "Cache bucket listing failed");
+                return;
+            }
+        },
+    };
+    if let Ok(mut index) = CACHE_INDEX.lock() {
+        // Tiles stored while this was loading stay
+        for (key, entry) in entries {
+            index.entry(key).or_insert(entry);
+        }
+        update_cache_gauges(&index);
+        log_index_stats(message, &index);
+    }
+}
+
+pub fn init_cache_index() {
+    if s3::enabled() {
+        info!(bucket = CONFIG.s3_bucket, prefix = CONFIG.s3_prefix, "Caching tiles in S3");
+        // Tiles are read from the bucket whether indexed or not, so nothing
+        // needs to wait for this. With Redis, the index is loaded from there
+        // once subscribed.
+        if !redis::shares_cache() {
+            tokio::spawn(load_bucket_index());
+        }
+        return;
+    }
+    migrate_flat_cache();
+
+    // A saved index makes startup instant and keeps real access times; the
+    // directory is still reconciled in the background in case the last
+    // flush missed some writes
+    if let Some(entries) = load_index_file() {
+        if let Ok(mut index) = CACHE_INDEX.lock() {
+            *index = entries;
+            update_cache_gauges(&index);
+            log_index_stats("Cache index loaded", &index);
+        }
+        std::thread::spawn(|| {
+            // Scan without the lock held, then merge what changed meanwhile
+            let mut scanned = HashMap::new();
+            scan_cache_dir(&mut scanned);
+            if let Ok(mut index) = CACHE_INDEX.lock() {
+                let before = index.len();
+                index.retain(|key, entry| scanned.contains_key(key) || entry.path.exists());
+                for (key, entry) in scanned {
+                    index.entry(key).or_insert(entry);
+                }
+                if index.len() != before {
+                    INDEX_DIRTY.store(true, Ordering::Relaxed);
+                    update_cache_gauges(&index);
+                    log_index_stats("Cache index reconciled", &index);
+                }
+            }
+        });
+        return;
+    }
+
+    // Scan cache directory and rebuild index on startup
+    if let Ok(mut index) = CACHE_INDEX.lock() {
+        scan_cache_dir(&mut index);
+        INDEX_DIRTY.store(true, Ordering::Relaxed);
+        update_cache_gauges(&index);
+        log_index_stats("Cache initialized", &index);
+    }
+}
+
+fn log_index_stats(message: &str, index: &HashMap<String, CacheEntry>) {
+    let total: u64 = index.values().map(|e| e.size).sum();
+    info!(entries = index.len(), size_mb = format!("{:.1}", total as f64 / 1024.0 / 1024.0), "{}", message);
+}
+
+/// Save the index: to `cache_dir` if it changed since the last flush,
+/// merged with the copy in the bucket, or shared through Redis.
+pub async fn flush_index() {
+    if redis::shares_cache() {
+        share_index().await;
+    } else if s3::enabled() {
+        sync_bucket_index().await;
+    } else {
+        let _ = tokio::task::spawn_blocking(write_index_file).await;
+    }
+}
+
+// Servers sharing a bucket each merge their index with the copy there:
+// tiles the others stored are picked up, the latest access of each is
+// kept, and tiles deleted here since last time stay gone
+async fn sync_bucket_index() {
+    let deleted = std::mem::take(&mut *DELETED.lock().unwrap());
+    let remote = match s3::get(INDEX_FILE).await {
+        Ok(data) => data.and_then(|data| decode_index(&data)).unwrap_or_default(),
+        Err(e) => {
+            warn!(error = %e, "Cache index unreadable from the bucket");
+            DELETED.lock().unwrap().extend(deleted);
+            return;
+        }
+    };
+    let data = {
+        let Ok(mut index) = CACHE_INDEX.lock() else {
+            return;
+        };
+        let before = index.len();
+        for (key, entry) in remote {
+            if !deleted.contains(&key) {
+                merge_entry(&mut index, key, entry);
+            }
+        }
+        if index.len() != before {
+            update_cache_gauges(&index);
+        }
+        if !INDEX_DIRTY.swap(false, Ordering::Relaxed) && deleted.is_empty() {
+            return;
+        }
+        bincode::serialize(&IndexFileRef { version: INDEX_VERSION, entries: &index })
+    };
+    let result = match data {
+        Ok(data) => s3::put(INDEX_FILE, Bytes::from(data)).await,
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        warn!(error = %e, "Failed to save cache index to the bucket");
+        INDEX_DIRTY.store(true, Ordering::Relaxed);
+    }
+}
+
+// Take in another server's entry for a tile, keeping the latest access
+// of the two and the format and size of whichever copy was stored last
+fn merge_entry(index: &mut HashMap<String, CacheEntry>, key: String, mut entry: CacheEntry) {
+    if let Some(local) = index.get(&key) {
+        entry.last_access = entry.last_access.max(local.last_access);
+        entry.hits = entry.hits.max(local.hits);
+        if local.created >= entry.created {
+            (entry.format, entry.size, entry.created) = (local.format, local.size, local.created);
+        }
+    }
+    entry.path = cache_path(&key, entry.format);
+    index.insert(key, entry);
+}
+
+/// Load the index servers sharing the bucket keep in Redis, merged with
+/// this one. Entries it lacks were deleted by another server, unless
+/// they're yet to be shared; if it's empty, it's filled from the bucket.
+pub async fn load_shared_index() {
+    let fields = match redis::command(&[b"HGETALL", redis::key(SHARED_INDEX).as_bytes()]).await {
+        Ok(Reply::Array(fields)) => fields,
+        Ok(_) => Vec::new(),
+        Err(e) => {
+            warn!(error = %e, "Cache index unreadable from Redis");
+            return;
+        }
+    };
+    let shared: HashMap<String, CacheEntry> = fields
+        .chunks(2)
+        .filter_map(|pair| match pair {
+            [Reply::Data(key), Reply::Data(entry)] => Some((String::from_utf8(key.clone()).ok()?, bincode::deserialize(entry).ok()?)),
+            _ => None,
+        })
+        .collect();
+    if shared.is_empty() {
+        // The first server to use it, or Redis lost its data
+        load_bucket_index().await;
+        let keys: Vec<String> = CACHE_INDEX.lock().map(|index| index.keys().cloned().collect()).unwrap_or_default();
+        CHANGED.lock().unwrap().extend(keys);
+        return;
+    }
+    let pending = CHANGED.lock().unwrap().clone();
+    if let Ok(mut index) = CACHE_INDEX.lock() {
+        index.retain(|key, _| shared.contains_key(key) || pending.contains(key));
+        for (key, entry) in shared {
+            merge_entry(&mut index, key, entry);
+        }
+        update_cache_gauges(&index);
+        log_index_stats("Cache index loaded from Redis", &index);
+    }
+}
+
+/// Apply index changes another server published through Redis.
+pub fn apply_shared_update(payload: &[u8]) {
+    let update = bincode::deserialize::<(String, Vec<(String, Vec<u8>)>, Vec<String>)>(payload);
+    let Ok((origin, changed, deleted)) = update else {
+        warn!("Unreadable cache index update from Redis");
+        return;
+    };
+    if origin == *redis::INSTANCE {
+        return;
+    }
+    let Ok(mut index) = CACHE_INDEX.lock() else {
+        return;
+    };
+    for key in &deleted {
+        index.remove(key);
+        // Else a hit would put it back in the index
+        hot_cache::remove(key);
+    }
+    for (key, entry) in changed {
+        if let Ok(entry) = bincode::deserialize(&entry) {
+            merge_entry(&mut index, key, entry);
+        }
+    }
+    update_cache_gauges(&index);
+}
+
+// Servers sharing a bucket and Redis keep the index in a hash there: each
+// flush writes the entries changed here, removes those deleted, and tells
+// the other servers
+async fn share_index() {
+    let changed = std::mem::take(&mut *CHANGED.lock().unwrap());
+    let deleted = std::mem::take(&mut *DELETED.lock().unwrap());
+    INDEX_DIRTY.store(false, Ordering::Relaxed);
+    if changed.is_empty() && deleted.is_empty() {
+        return;
+    }
+    if let Err(e) = send_index_changes(&changed, &deleted).await {
+        warn!(error = %e, "Failed to share the cache index through Redis");
+        CHANGED.lock().unwrap().extend(changed);
+        DELETED.lock().unwrap().extend(deleted);
+    }
+}
+
+async fn send_index_changes(changed: &HashSet<String>, deleted: &HashSet<String>) -> Result<(), String> {
+    let (entries, deleted) = {
+        let Ok(index) = CACHE_INDEX.lock() else {
+            return Ok(());
+        };
+        let entries: Vec<(&String, Vec<u8>)> = changed.iter().filter_map(|key| Some((key, bincode::serialize(index.get(key)?).ok()?))).collect();
+        // Deleted, then stored again
+        let deleted: Vec<&String> = deleted.iter().filter(|key| !index.contains_key(*key)).collect();
+        (entries, deleted)
+    };
+    let hash = redis::key(SHARED_INDEX);
+    let publish = |changed: &[(&String, Vec<u8>)], deleted: &[&String]| {
+        let update = bincode::serialize(&(&*redis::INSTANCE, changed, deleted));
+        async move { redis::publish(redis::INDEX_CHANNEL, &update.map_err(|e| e.to_string())?).await }
+    };
+    for batch in entries.chunks(SHARE_BATCH) {
+        let mut args: Vec<&[u8]> = vec![b"HSET", hash.as_bytes()];
+        for (key, entry) in batch {
+            args.extend([key.as_bytes(), entry.as_slice()]);
+        }
+        redis::command(&args).await?;
+        publish(batch, &[]).await?;
+    }
+    for batch in deleted.chunks(SHARE_BATCH) {
+        let mut args: Vec<&[u8]> = vec![b"HDEL", hash.as_bytes()];
+        args.extend(batch.iter().map(|key| key.as_bytes()));
+        redis::command(&args).await?;
+        publish(&[], batch).await?;
+    }
+    debug!(changed = entries.len(), deleted = deleted.len(), "Cache index shared through Redis");
+    Ok(())
+}
+
+// Written to a temporary file and renamed so a crash never leaves a torn
+// index
+fn write_index_file() {
+    if !INDEX_DIRTY.swap(false, Ordering::Relaxed) {
+        return;
+    }
+    let data = {
+        let Ok(index) = CACHE_INDEX.lock() else {
+            return;
+        };
+        bincode::serialize(&IndexFileRef { version: INDEX_VERSION, entries: &index })
+    };
+    let data = match data {
+        Ok(data) => data,
+        Err(e) => {
+            warn!(error = %e, "Failed to serialize cache index");
+            return;
+        }
+    };
+    let tmp = CACHE_DIR.join(format!("{}.tmp", INDEX_FILE));
+    if let Err(e) = fs::write(&tmp, &data).and_then(|_| fs::rename(&tmp, index_path())) {
+        warn!(error = %e, "Failed to write cache index");
+        INDEX_DIRTY.store(true, Ordering::Relaxed);
+    } else {
+        debug!(bytes = data.len(), "Cache index flushed");
+    }
+}
+
+// Same layout as IndexFile, for serializing without cloning the map
+#[derive(Serialize)]
+struct IndexFileRef<'a> {
+    version: u32,
+    entries: &'a HashMap<String, CacheEntry>,
+}
+
+/// Every `index_flush_interval` seconds, run the eviction sweep and flush
+/// the index.
+pub fn spawn_index_flusher() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(CONFIG.index_flush_interval.max(1)));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let _ = tokio::task::spawn_blocking(sweep).await;
+            flush_index().await;
+        }
+    });
+}
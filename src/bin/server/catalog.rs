@@ -0,0 +1,61 @@
+use axum::Json;
+use serde_json::{json, Value};
+use crate::cache::DEFAULT_SECTOR;
+use crate::config::CONFIG;
+use crate::geos;
+use crate::satellites::{self, Satellite, LIGHTNING_PRODUCT, SATELLITES};
+
+/// How a product of `satellite` is described, here and by `/slider-products`.
+pub fn product(satellite: &Satellite, id: &str) -> Value {
+    json!({
+        "id": id,
+        "name": satellites::product_name(id),
+        // Drawn over another product rather than on its own
+        "overlay": id == LIGHTNING_PRODUCT,
+        "zoom_reduction": satellites::product_zoom_reduction(satellite.key, id),
+    })
+}
+
+// One satellite's entry: its full disk and sectors, and its products with
+// how many zoom levels short of a sector's grid each stops
+fn entry(satellite: &Satellite) -> Value {
+    let mut sectors = vec![json!({
+        "id": DEFAULT_SECTOR,
+        "max_zoom": satellite.max_zoom,
+        "tile_size": satellite.tile_size,
+        "cadence_seconds": satellite.cadence,
+    })];
+    sectors.extend(satellite.sectors.iter().map(|sector| {
+        json!({
+            "id": sector.id,
+            "max_zoom": sector.max_zoom,
+            "tile_size": sector.tile_size,
+            "cadence_seconds": sector.cadence,
+        })
+    }));
+    let products: Vec<Value> = satellites::products(satellite).iter().map(|p| product(satellite, p)).collect();
+    json!({
+        "id": satellite.key,
+        "name": satellite.name,
+        "region": satellite.region,
+        "status": satellite.status.as_str(),
+        "longitude": satellite.longitude,
+        "scan_angle": geos::scan_half_angle(satellite),
+        "max_zoom": satellite.max_zoom,
+        "tile_size": satellite.tile_size,
+        "cadence_seconds": satellite.cadence,
+        "sectors": sectors,
+        "products": products,
+        "full_disk_images": satellite.star_images,
+    })
+}
+
+/// `GET /api/catalog`: every satellite in the registry with its sectors,
+/// products, grids and cadence, so clients needn't hard-code them.
+pub async fn handle_catalog() -> Json<Value> {
+    let satellites: Vec<Value> = SATELLITES.iter().map(entry).collect();
+    Json(json!({
+        "default_satellite": CONFIG.default_satellite,
+        "satellites": satellites,
+    }))
+}
@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use axum::body::Bytes;
+use axum::http::{header, HeaderMap};
+use tracing::{debug, warn};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn header_value(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    fn compress(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut out = Vec::new();
+                let params = brotli::enc::BrotliEncoderParams { quality: 11, ..Default::default() };
+                brotli::BrotliCompress(&mut &data[..], &mut out, &params)?;
+                Ok(out)
+            }
+            Encoding::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+// Compressed static assets keyed by source path and encoding, tagged with
+// the source mtime so edits during development are picked up.
+type Precompressed = HashMap<(PathBuf, Encoding), (SystemTime, Bytes)>;
+
+lazy_static::lazy_static! {
+    static ref PRECOMPRESSED: Mutex<Precompressed> = Mutex::new(HashMap::new());
+}
+
+/// Pick the best encoding the client accepts, preferring brotli.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let accept = headers.get(header::ACCEPT_ENCODING)?.to_str().ok()?;
+    let accepts = |name: &str| {
+        accept.split(',').any(|part| {
+            let mut pieces = part.split(';');
+            let coding = pieces.next().unwrap_or("").trim();
+            let rejected = pieces.any(|p| matches!(p.trim(), "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+            (coding == name || coding == "*") && !rejected
+        })
+    };
+    if accepts("br") {
+        Some(Encoding::Brotli)
+    } else if accepts("gzip") {
+        Some(Encoding::Gzip)
+    } else {
+        None
+    }
+}
+
+/// Text-like assets worth compressing; images are already compressed.
+pub fn is_compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/")
+        || content_type.starts_with("application/javascript")
+        || content_type.starts_with("application/json")
+        || content_type.starts_with("application/wasm")
+        || content_type.starts_with("image/svg+xml")
+}
+
+/// Compressed bytes for the static asset at `path`. Embedded assets pass
+/// their contents in `embedded`. For files on disk, a `.br`/`.gz` sibling
+/// that is at least as new as the source is used as-is; otherwise the asset
+/// is compressed once and kept in memory until it changes.
+pub async fn precompressed(
+    path: &Path,
+    modified: SystemTime,
+    encoding: Encoding,
+    embedded: Option<Bytes>,
+) -> Option<Bytes> {
+    let key = (path.to_path_buf(), encoding);
+    if let Some((mtime, data)) = PRECOMPRESSED.lock().unwrap().get(&key) {
+        if *mtime == modified {
+            return Some(data.clone());
+        }
+    }
+
+    let mut sibling = path.as_os_str().to_owned();
+    sibling.push(".");
+    sibling.push(encoding.extension());
+    let sibling = PathBuf::from(sibling);
+    let sibling_fresh = embedded.is_none()
+        && match tokio::fs::metadata(&sibling).await.and_then(|m| m.modified()) {
+            Ok(sibling_modified) => sibling_modified >= modified,
+            Err(_) => false,
+        };
+
+    let data = if sibling_fresh {
+        debug!(path = ?sibling, "Using precompressed asset");
+        tokio::fs::read(&sibling).await.ok()?
+    } else {
+        let source = match embedded {
+            Some(data) => data,
+            None => Bytes::from(tokio::fs::read(path).await.ok()?),
+        };
+        let result = tokio::task::spawn_blocking(move || encoding.compress(&source)).await;
+        match result {
+            Ok(Ok(data)) => {
+                debug!(?path, encoding = encoding.header_value(), len = data.len(), "Compressed static asset");
+                data
+            }
+            Ok(Err(e)) => {
+                warn!(?path, error = %e, "Compression failed");
+                return None;
+            }
+            Err(_) => return None,
+        }
+    };
+
+    let data = Bytes::from(data);
+    PRECOMPRESSED.lock().unwrap().insert(key, (modified, data.clone()));
+    Some(data)
+}
@@ -0,0 +1,679 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+use clap::Parser;
+use serde::Deserialize;
+
+lazy_static::lazy_static! {
+    pub static ref CONFIG: Config = Config::load();
+}
+
+/// Command-line options. Anything given here overrides `config.toml`.
+#[derive(Parser)]
+#[command(name = "server", about = "PeepSat satellite imagery proxy")]
+struct Cli {
+    /// Config file (default: ~/.peepsat/config.toml)
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Address to bind to
+    #[arg(long)]
+    bind: Option<String>,
+    /// Port to listen on
+    #[arg(long)]
+    port: Option<u16>,
+    /// "host:port" or "unix:/path/to.sock"; overrides --bind and --port
+    #[arg(long)]
+    listen: Option<String>,
+    /// Tile cache directory
+    #[arg(long)]
+    cache_dir: Option<PathBuf>,
+    /// Tile cache size limit in MB
+    #[arg(long)]
+    cache_size_mb: Option<u64>,
+    /// Upstream request timeout in seconds
+    #[arg(long)]
+    upstream_timeout: Option<u64>,
+    /// Satellite used when a request doesn't name one
+    #[arg(long)]
+    default_satellite: Option<String>,
+    /// Serve the frontend from this directory instead of the embedded copy
+    #[arg(long)]
+    static_dir: Option<PathBuf>,
+    /// URL prefix when served behind a reverse proxy, e.g. /peepsat
+    #[arg(long)]
+    base_path: Option<String>,
+    /// Serve only cached imagery and never contact upstream
+    #[arg(long)]
+    offline: bool,
+    /// Log filter, e.g. "info" or "debug"
+    #[arg(long)]
+    log_level: Option<String>,
+    /// Log output format: "text" or "json"
+    #[arg(long)]
+    log_format: Option<String>,
+    /// PEM certificate chain; enables HTTPS together with --tls-key
+    #[arg(long)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key for --tls-cert
+    #[arg(long)]
+    tls_key: Option<PathBuf>,
+    /// With TLS enabled, also listen on this port and redirect to HTTPS
+    #[arg(long)]
+    http_redirect_port: Option<u16>,
+}
+
+/// A URL each new frame is POSTed to, as `[[webhooks]]` in the config file.
+#[derive(Deserialize)]
+pub struct Webhook {
+    pub url: String,
+    /// Key for the HMAC-SHA256 of each body, sent as X-Peepsat-Signature
+    #[serde(default)]
+    pub secret: Option<String>,
+    /// Satellites announced; as for mqtt_satellites if empty
+    #[serde(default)]
+    pub satellites: Vec<String>,
+    /// Product announced, where a satellite has it; each one's default otherwise
+    #[serde(default)]
+    pub product: Option<String>,
+    /// "json" (the frame as /api/events describes it), or "slack" or "discord"
+    /// for a chat message
+    #[serde(default = "default_webhook_format")]
+    pub format: String,
+}
+
+fn default_webhook_format() -> String {
+    "json".to_string()
+}
+
+/// A condition to be notified of, as `[[alerts]]` in the config file.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct Alert {
+    /// "mesoscale" (a mesoscale sector starts covering `area`), "stale" (a
+    /// satellite's newest frame is older than `max_age_minutes`) or "storm"
+    /// (NHC lists a new storm)
+    pub kind: String,
+    /// Names the alert in messages; the kind if unset
+    pub name: Option<String>,
+    /// mesoscale, stale: satellites watched; as for mqtt_satellites if empty
+    pub satellites: Vec<String>,
+    /// mesoscale: [west, south, east, north] in degrees
+    pub area: Option<[f64; 4]>,
+    /// stale: how old the newest frame may get
+    pub max_age_minutes: Option<u64>,
+    /// storm: basins watched; storm_basins if empty
+    pub basins: Vec<String>,
+}
+
+/// A recurring task, as `[[schedule]]` in the config file.
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ScheduledJob {
+    /// Unique; how the admin API refers to it
+    pub name: String,
+    /// "poll" (look up the newest frame times), "prefetch" (download the
+    /// newest frames), "timelapse" (make the previous UTC day's timelapse)
+    /// or "prune" (drop old tiles and timelapses)
+    pub task: String,
+    /// How often it runs, e.g. "5m" or "2h"
+    pub every: Option<String>,
+    /// Or the UTC time it runs each day, "HH:MM"
+    pub at: Option<String>,
+    /// Satellites it covers; as for mqtt_satellites if empty (prune: all of them)
+    pub satellites: Vec<String>,
+    /// prefetch: deepest zoom level; prefetch_max_zoom if unset
+    pub zoom: Option<u32>,
+    /// timelapse: as /api/timelapse takes them
+    pub product: Option<String>,
+    pub format: Option<String>,
+    pub size: Option<u32>,
+    pub fps: Option<u32>,
+    pub step: Option<String>,
+    /// prune: days of frames and timelapses kept; 7 if unset
+    pub keep_days: Option<u32>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub bind: String,
+    pub port: u16,
+    /// "host:port" or "unix:/path/to.sock"; overrides `bind` and `port`
+    pub listen: Option<String>,
+    pub cache_dir: PathBuf,
+    pub cache_size_mb: u64,
+    pub upstream_timeout: u64,
+    pub default_satellite: String,
+    /// Serve the frontend from disk instead of the copy embedded at build time
+    pub static_dir: Option<PathBuf>,
+    /// URL prefix when proxied under a subpath, e.g. "/peepsat"
+    pub base_path: String,
+    /// Answer only from the cache, never contacting upstream
+    pub offline: bool,
+    /// Free space to leave on the cache's filesystem, in MB; the cache
+    /// shrinks below `cache_size_mb` to keep it. 0 disables the check
+    pub min_free_disk_mb: u64,
+    /// "png" stores tiles as fetched; "webp" re-encodes them losslessly to fit more
+    pub cache_format: String,
+    /// Send PNG tiles and images as WebP to clients whose Accept takes it,
+    /// re-encoding each response; `format=webp` works regardless
+    pub transcode_webp: bool,
+    /// Likewise for AVIF, which is smaller still but far slower to encode
+    pub transcode_avif: bool,
+    /// Quality of transcoded WebP and AVIF, 0-100; 100 sends WebP losslessly
+    pub transcode_quality: u8,
+    /// In-memory cache of recently served tiles, in MB; 0 disables
+    pub memory_cache_mb: u64,
+    /// Where tiles are kept: "disk" (cache_dir) or "s3", a bucket a fleet of
+    /// servers can share; cache_dir still holds timelapses and the like
+    pub cache_backend: String,
+    /// S3 or S3-compatible (MinIO, R2, ...) endpoint, e.g. "https://s3.eu-west-1.amazonaws.com"
+    pub s3_endpoint: Option<String>,
+    pub s3_bucket: Option<String>,
+    pub s3_region: String,
+    /// Prepended to every object name, e.g. "peepsat/"
+    pub s3_prefix: String,
+    /// Access key ID ($PEEPSAT_S3_ACCESS_KEY)
+    pub s3_access_key: Option<String>,
+    /// Secret access key ($PEEPSAT_S3_SECRET_KEY)
+    pub s3_secret_key: Option<String>,
+    /// Redis shared by a fleet of servers, "redis://[user:password@]host[:port][/db]":
+    /// a new frame is announced once for all of them and, with the s3
+    /// backend, the cache index and tile downloads are shared as well
+    pub redis_url: Option<String>,
+    /// Its password ($PEEPSAT_REDIS_PASSWORD), if not in redis_url
+    pub redis_password: Option<String>,
+    /// Prepended to every key and channel, so deployments can share a Redis
+    pub redis_prefix: String,
+    /// Optional per-satellite limits in MB within `cache_size_mb`, e.g. { himawari = 100 }
+    pub satellite_quota_mb: HashMap<String, u64>,
+    /// Which tiles go first when the cache is full: "lru", "lfu", "ttl" or "newest"
+    pub eviction_policy: String,
+    /// With the "ttl" policy, how long a tile stays cached
+    pub eviction_ttl_hours: u64,
+    /// With the "newest" policy, frames kept per satellite
+    pub keep_frames: usize,
+    /// Satellites whose newest frame is downloaded in the background, e.g. ["19", "himawari"]
+    pub prefetch_satellites: Vec<String>,
+    /// Seconds between checks for a new frame to prefetch
+    pub prefetch_interval: u64,
+    /// Deepest zoom level prefetched; each level has four times the tiles
+    pub prefetch_max_zoom: u32,
+    /// Seconds between checks for new frames of layers /api/events clients follow
+    pub events_interval: u64,
+    /// MQTT broker new frames are published to, "host:port" (port 1883 if left out)
+    pub mqtt_broker: Option<String>,
+    /// Each satellite's frames are published, retained, to "{mqtt_topic}/{sat}"
+    pub mqtt_topic: String,
+    pub mqtt_username: Option<String>,
+    /// Its password ($PEEPSAT_MQTT_PASSWORD)
+    pub mqtt_password: Option<String>,
+    /// Satellites published; prefetch_satellites, or else the default satellite, if empty
+    pub mqtt_satellites: Vec<String>,
+    /// Where clients reach this server, for links in feeds, MQTT messages and webhooks, e.g. "http://peepsat.lan:8000"
+    pub public_url: Option<String>,
+    pub webhooks: Vec<Webhook>,
+    pub schedule: Vec<ScheduledJob>,
+    /// ntfy topic URL alerts are posted to, e.g. "https://ntfy.sh/my-peepsat"
+    pub ntfy_url: Option<String>,
+    /// Access token for the topic ($PEEPSAT_NTFY_TOKEN)
+    pub ntfy_token: Option<String>,
+    /// Pushover application token ($PEEPSAT_PUSHOVER_TOKEN); alerts go to
+    /// Pushover when it and pushover_user are set
+    pub pushover_token: Option<String>,
+    pub pushover_user: Option<String>,
+    pub pushover_url: String,
+    pub alerts: Vec<Alert>,
+    /// Seconds between checks of the alert conditions
+    pub alert_interval: u64,
+    /// JSON listing where mesoscale sectors are, for "mesoscale" alerts:
+    /// { "19": { "mesoscale_01": [lat, lon] } }, each sector's centre
+    pub mesoscale_positions_url: Option<String>,
+    /// Seconds between writes of the cache index to disk
+    pub index_flush_interval: u64,
+    /// How long to remember upstream 404s and empty tiles, in seconds; 0 disables
+    pub negative_cache_ttl: u64,
+    /// How long frame and date lists are served from memory, in seconds; 0 disables
+    pub json_cache_ttl: u64,
+    /// How much longer an expired list may be served while it's refreshed
+    pub json_max_stale: u64,
+    /// Browser cache lifetime for timestamped tiles, in seconds
+    pub tile_max_age: u64,
+    /// Tile downloads in flight at once across all clients; 0 for no limit
+    pub upstream_concurrency: usize,
+    /// Extra attempts per upstream URL after a 5xx, 429, or network error
+    pub upstream_retries: u32,
+    /// First retry delay; doubles on each further attempt
+    pub retry_base_delay_ms: u64,
+    /// SLIDER mirrors tried in order when the requested CDN keeps failing
+    pub fallback_cdns: Vec<String>,
+    /// Upstream per satellite for requests to SLIDER: "slider", "nict"
+    /// (Himawari only), "eumetsat" (Meteosats) or "noaa" (GOES-16/18/19,
+    /// with the netcdf feature), e.g. { himawari = "nict" }
+    pub satellite_sources: HashMap<String, String>,
+    /// EUMETSAT Data Store API consumer key, for the "eumetsat" source
+    pub eumetsat_consumer_key: Option<String>,
+    /// Its consumer secret ($PEEPSAT_EUMETSAT_SECRET)
+    pub eumetsat_consumer_secret: Option<String>,
+    pub eumetsat_api_url: String,
+    pub eumetsat_view_url: String,
+    /// S3 endpoint of NOAA's GOES buckets, for the "noaa" source
+    pub noaa_goes_url: String,
+    /// Which ABI files the "noaa" source renders: "l1b" radiances or "l2"
+    /// Cloud and Moisture Imagery
+    pub noaa_goes_level: String,
+    /// NASA GIBS, for /gibs-tile and the night-lights base layer
+    pub gibs_url: String,
+    /// NASA Visible Earth, for the Blue Marble base layer
+    pub blue_marble_url: String,
+    /// RainViewer's API, for the radar overlay
+    pub rainviewer_url: String,
+    /// NOAA SWPC's services, for the aurora forecast
+    pub swpc_url: String,
+    /// NASA FIRMS, for the fire hotspots
+    pub firms_url: String,
+    /// NOAA's National Hurricane Center, for /api/storms
+    pub nhc_url: String,
+    /// How long the active-storm list is kept before it's fetched again, in seconds
+    pub storms_refresh: u64,
+    /// Basins /api/storms covers unless asked: "al", "ep", "cp"; empty for all
+    pub storm_basins: Vec<String>,
+    /// Origins allowed to read API responses cross-origin; "*" for any
+    pub cors_origins: Vec<String>,
+    /// How long browsers may cache a preflight result, in seconds
+    pub cors_max_age: u64,
+    /// Sustained proxy requests per second per client; 0 disables limiting
+    pub rate_limit_per_sec: f64,
+    /// Requests a client may make in a burst before being limited
+    pub rate_limit_burst: u32,
+    /// Take the client address from X-Forwarded-For (only behind a proxy)
+    pub trust_forwarded_for: bool,
+    pub log_level: String,
+    /// "text" or "json"
+    pub log_format: String,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub http_redirect_port: Option<u16>,
+    /// Bearer token required by the proxy endpoints ($PEEPSAT_AUTH_TOKEN)
+    pub auth_token: Option<String>,
+    /// "user:password" for Basic auth on the proxy endpoints ($PEEPSAT_BASIC_AUTH)
+    pub basic_auth: Option<String>,
+    /// File the config was loaded from, if any
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: "0.0.0.0".to_string(),
+            port: 8000,
+            listen: None,
+            cache_dir: peepsat_dir().join("tiles"),
+            cache_size_mb: 500,
+            upstream_timeout: 30,
+            default_satellite: "19".to_string(),
+            static_dir: None,
+            base_path: String::new(),
+            offline: false,
+            min_free_disk_mb: 1024,
+            cache_format: "png".to_string(),
+            transcode_webp: false,
+            transcode_avif: false,
+            transcode_quality: 80,
+            memory_cache_mb: 64,
+            cache_backend: "disk".to_string(),
+            s3_endpoint: None,
+            s3_bucket: None,
+            s3_region: "us-east-1".to_string(),
+            s3_prefix: String::new(),
+            s3_access_key: None,
+            s3_secret_key: None,
+            redis_url: None,
+            redis_password: None,
+            redis_prefix: "peepsat:".to_string(),
+            satellite_quota_mb: HashMap::new(),
+            eviction_policy: "lru".to_string(),
+            eviction_ttl_hours: 7 * 24,
+            keep_frames: 48,
+            prefetch_satellites: Vec::new(),
+            prefetch_interval: 300,
+            prefetch_max_zoom: 4,
+            events_interval: 30,
+            mqtt_broker: None,
+            mqtt_topic: "peepsat".to_string(),
+            mqtt_username: None,
+            mqtt_password: None,
+            mqtt_satellites: Vec::new(),
+            public_url: None,
+            webhooks: Vec::new(),
+            schedule: Vec::new(),
+            ntfy_url: None,
+            ntfy_token: None,
+            pushover_token: None,
+            pushover_user: None,
+            pushover_url: "https://api.pushover.net".to_string(),
+            alerts: Vec::new(),
+            alert_interval: 300,
+            mesoscale_positions_url: None,
+            index_flush_interval: 60,
+            negative_cache_ttl: 60,
+            json_cache_ttl: 60,
+            json_max_stale: 600,
+            tile_max_age: 7 * 24 * 3600,
+            upstream_concurrency: 16,
+            upstream_retries: 2,
+            retry_base_delay_ms: 250,
+            fallback_cdns: vec!["https://slider.cira.colostate.edu".to_string()],
+            satellite_sources: HashMap::new(),
+            eumetsat_consumer_key: None,
+            eumetsat_consumer_secret: None,
+            eumetsat_api_url: "https://api.eumetsat.int".to_string(),
+            eumetsat_view_url: "https://view.eumetsat.int".to_string(),
+            noaa_goes_url: "https://s3.amazonaws.com".to_string(),
+            noaa_goes_level: "l1b".to_string(),
+            gibs_url: "https://gibs.earthdata.nasa.gov".to_string(),
+            blue_marble_url: "https://eoimages.gsfc.nasa.gov".to_string(),
+            rainviewer_url: "https://api.rainviewer.com".to_string(),
+            swpc_url: "https://services.swpc.noaa.gov".to_string(),
+            firms_url: "https://firms.modaps.eosdis.nasa.gov".to_string(),
+            nhc_url: "https://www.nhc.noaa.gov".to_string(),
+            storms_refresh: 600,
+            storm_basins: Vec::new(),
+            cors_origins: vec!["*".to_string()],
+            cors_max_age: 3600,
+            rate_limit_per_sec: 0.0,
+            rate_limit_burst: 200,
+            trust_forwarded_for: false,
+            log_level: "info".to_string(),
+            log_format: "text".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            http_redirect_port: None,
+            auth_token: None,
+            basic_auth: None,
+            source: None,
+        }
+    }
+}
+
+impl Config {
+    fn load() -> Config {
+        let cli = Cli::parse();
+
+        let path = cli.config.clone().unwrap_or_else(|| peepsat_dir().join("config.toml"));
+        let mut config = match fs::read_to_string(&path) {
+            Ok(text) => match toml::from_str(&text) {
+                Ok(config) => Config { source: Some(path.clone()), ..config },
+                Err(e) => {
+                    eprintln!("Invalid config file {:?}: {}", path, e);
+                    std::process::exit(1);
+                }
+            },
+            // A missing default config is fine, a missing explicit one is not
+            Err(_) if cli.config.is_none() => Config::default(),
+            Err(e) => {
+                eprintln!("Failed to read config file {:?}: {}", path, e);
+                std::process::exit(1);
+            }
+        };
+
+        if let Some(bind) = cli.bind {
+            config.bind = bind;
+        }
+        if let Some(port) = cli.port {
+            config.port = port;
+        }
+        if cli.listen.is_some() {
+            config.listen = cli.listen;
+        }
+        if let Some(listen) = config.listen.clone().filter(|l| !l.starts_with("unix:")) {
+            let parsed = listen
+                .rsplit_once(':')
+                .and_then(|(host, port)| Some((host.trim_matches(['[', ']']), port.parse().ok()?)));
+            match parsed {
+                Some((host, port)) => {
+                    config.bind = host.to_string();
+                    config.port = port;
+                }
+                None => {
+                    eprintln!("Invalid listen address {:?}; expected host:port or unix:/path", listen);
+                    std::process::exit(1);
+                }
+            }
+        }
+        if let Some(base_path) = cli.base_path {
+            config.base_path = base_path;
+        }
+        // "/peepsat/", "peepsat" and "/peepsat" all mean "/peepsat"; "/" means none
+        let base_path = config.base_path.trim_matches('/');
+        config.base_path = if base_path.is_empty() {
+            String::new()
+        } else {
+            format!("/{}", base_path)
+        };
+        if let Some(cache_dir) = cli.cache_dir {
+            config.cache_dir = cache_dir;
+        }
+        if let Some(cache_size_mb) = cli.cache_size_mb {
+            config.cache_size_mb = cache_size_mb;
+        }
+        if let Some(upstream_timeout) = cli.upstream_timeout {
+            config.upstream_timeout = upstream_timeout;
+        }
+        if let Some(default_satellite) = cli.default_satellite {
+            config.default_satellite = default_satellite;
+        }
+        if cli.static_dir.is_some() {
+            config.static_dir = cli.static_dir;
+        }
+        if cli.offline {
+            config.offline = true;
+        }
+        if let Some(log_level) = cli.log_level {
+            config.log_level = log_level;
+        }
+        if let Some(log_format) = cli.log_format {
+            config.log_format = log_format;
+        }
+        if cli.tls_cert.is_some() {
+            config.tls_cert = cli.tls_cert;
+        }
+        if cli.tls_key.is_some() {
+            config.tls_key = cli.tls_key;
+        }
+        if cli.http_redirect_port.is_some() {
+            config.http_redirect_port = cli.http_redirect_port;
+        }
+        // Secrets come from the environment rather than argv, where other
+        // users could read them
+        if let Ok(token) = std::env::var("PEEPSAT_AUTH_TOKEN") {
+            config.auth_token = Some(token);
+        }
+        if let Ok(credentials) = std::env::var("PEEPSAT_BASIC_AUTH") {
+            config.basic_auth = Some(credentials);
+        }
+        if let Ok(secret) = std::env::var("PEEPSAT_EUMETSAT_SECRET") {
+            config.eumetsat_consumer_secret = Some(secret);
+        }
+        if let Ok(password) = std::env::var("PEEPSAT_MQTT_PASSWORD") {
+            config.mqtt_password = Some(password);
+        }
+        if let Ok(key) = std::env::var("PEEPSAT_S3_ACCESS_KEY") {
+            config.s3_access_key = Some(key);
+        }
+        if let Ok(key) = std::env::var("PEEPSAT_S3_SECRET_KEY") {
+            config.s3_secret_key = Some(key);
+        }
+        if let Ok(password) = std::env::var("PEEPSAT_REDIS_PASSWORD") {
+            config.redis_password = Some(password);
+        }
+        if let Ok(token) = std::env::var("PEEPSAT_NTFY_TOKEN") {
+            config.ntfy_token = Some(token);
+        }
+        if let Ok(token) = std::env::var("PEEPSAT_PUSHOVER_TOKEN") {
+            config.pushover_token = Some(token);
+        }
+        if config.auth_token.as_deref() == Some("") || config.basic_auth.as_deref() == Some("") {
+            eprintln!("auth_token and basic_auth must not be empty");
+            std::process::exit(1);
+        }
+        if config.basic_auth.as_deref().is_some_and(|c| !c.contains(':')) {
+            eprintln!("basic_auth must be \"user:password\"");
+            std::process::exit(1);
+        }
+        if !["png", "webp"].contains(&config.cache_format.as_str()) {
+            eprintln!("Unknown cache_format {:?}; expected png or webp", config.cache_format);
+            std::process::exit(1);
+        }
+        // MQTT has no password without a username
+        if config.mqtt_password.is_some() && config.mqtt_username.is_none() {
+            eprintln!("mqtt_password needs mqtt_username");
+            std::process::exit(1);
+        }
+        if config.transcode_quality > 100 {
+            eprintln!("transcode_quality must be 0-100");
+            std::process::exit(1);
+        }
+        if !["disk", "s3"].contains(&config.cache_backend.as_str()) {
+            eprintln!("Unknown cache_backend {:?}; expected disk or s3", config.cache_backend);
+            std::process::exit(1);
+        }
+        if config.cache_backend == "s3"
+            && [&config.s3_endpoint, &config.s3_bucket, &config.s3_access_key, &config.s3_secret_key].iter().any(|v| v.is_none())
+        {
+            eprintln!("The s3 cache backend needs s3_endpoint, s3_bucket, s3_access_key and s3_secret_key");
+            std::process::exit(1);
+        }
+        if let Some(url) = &config.redis_url {
+            let valid = reqwest::Url::parse(url).is_ok_and(|parsed| {
+                let db = parsed.path().trim_start_matches('/');
+                parsed.scheme() == "redis" && parsed.host_str().is_some() && db.bytes().all(|b| b.is_ascii_digit())
+            });
+            if !valid {
+                eprintln!("Invalid redis_url {:?}; expected redis://[user:password@]host[:port][/db]", url);
+                std::process::exit(1);
+            }
+        }
+        if !["l1b", "l2"].contains(&config.noaa_goes_level.as_str()) {
+            eprintln!("Unknown noaa_goes_level {:?}; expected l1b or l2", config.noaa_goes_level);
+            std::process::exit(1);
+        }
+        if !["lru", "lfu", "ttl", "newest"].contains(&config.eviction_policy.as_str()) {
+            eprintln!("Unknown eviction_policy {:?}; expected lru, lfu, ttl or newest", config.eviction_policy);
+            std::process::exit(1);
+        }
+        if let Some(basin) = config.storm_basins.iter().find(|b| !["al", "ep", "cp"].contains(&b.as_str())) {
+            eprintln!("Unknown storm basin {:?}; expected al, ep or cp", basin);
+            std::process::exit(1);
+        }
+        for (sat, source) in &config.satellite_sources {
+            let supported = match source.as_str() {
+                "slider" => true,
+                "nict" => sat == "himawari",
+                "eumetsat" => crate::eumetsat::supports(sat),
+                "noaa" => crate::noaa::supports(sat),
+                _ => false,
+            };
+            if !supported {
+                eprintln!("Unknown source {:?} for satellite {:?}; expected slider, nict for himawari, eumetsat for meteosat9/10/12, or noaa for 16/18/19", source, sat);
+                std::process::exit(1);
+            }
+            if source == "noaa" && !cfg!(feature = "netcdf") {
+                eprintln!("The noaa source needs a server built with --features netcdf");
+                std::process::exit(1);
+            }
+            if source == "eumetsat" && (config.eumetsat_consumer_key.is_none() || config.eumetsat_consumer_secret.is_none()) {
+                eprintln!("The eumetsat source needs eumetsat_consumer_key and eumetsat_consumer_secret");
+                std::process::exit(1);
+            }
+        }
+        for webhook in &config.webhooks {
+            if !webhook.url.starts_with("http://") && !webhook.url.starts_with("https://") {
+                eprintln!("Webhook URL {:?} must be http:// or https://", webhook.url);
+                std::process::exit(1);
+            }
+            if !["json", "slack", "discord"].contains(&webhook.format.as_str()) {
+                eprintln!("Unknown webhook format {:?}; expected json, slack or discord", webhook.format);
+                std::process::exit(1);
+            }
+        }
+        for (i, job) in config.schedule.iter().enumerate() {
+            if job.name.is_empty() || config.schedule[..i].iter().any(|other| other.name == job.name) {
+                eprintln!("Each scheduled job needs a name of its own; {:?} is empty or taken", job.name);
+                std::process::exit(1);
+            }
+            if !["poll", "prefetch", "timelapse", "prune"].contains(&job.task.as_str()) {
+                eprintln!("Unknown task {:?} for scheduled job {:?}; expected poll, prefetch, timelapse or prune", job.task, job.name);
+                std::process::exit(1);
+            }
+            let valid = match (&job.every, &job.at) {
+                (Some(every), None) => crate::frames::parse_step(every).is_some(),
+                (None, Some(at)) => crate::schedule::parse_at(at).is_some(),
+                _ => false,
+            };
+            if !valid {
+                eprintln!("Scheduled job {:?} needs either every (e.g. \"5m\") or at (\"HH:MM\" UTC)", job.name);
+                std::process::exit(1);
+            }
+        }
+        for alert in &config.alerts {
+            let valid = match alert.kind.as_str() {
+                "mesoscale" => alert.area.is_some_and(|[west, south, east, north]| south < north && west != east) && config.mesoscale_positions_url.is_some(),
+                "stale" => alert.max_age_minutes.is_some_and(|minutes| minutes > 0),
+                "storm" => alert.basins.iter().all(|b| ["al", "ep", "cp"].contains(&b.as_str())),
+                _ => false,
+            };
+            if !valid {
+                eprintln!(
+                    "Invalid alert {:?}; expected kind = \"mesoscale\" with area = [west, south, east, north] and mesoscale_positions_url, \"stale\" with max_age_minutes, or \"storm\" with basins among al, ep and cp",
+                    alert.name.as_deref().unwrap_or(&alert.kind)
+                );
+                std::process::exit(1);
+            }
+        }
+        if !config.alerts.is_empty() && config.ntfy_url.is_none() && (config.pushover_token.is_none() || config.pushover_user.is_none()) {
+            eprintln!("Alerts need ntfy_url, or pushover_token and pushover_user, to be sent anywhere");
+            std::process::exit(1);
+        }
+        if config.tls_cert.is_some() != config.tls_key.is_some() {
+            eprintln!("--tls-cert and --tls-key must be given together");
+            std::process::exit(1);
+        }
+        config
+    }
+
+    pub fn listen_addr(&self) -> String {
+        if self.bind.contains(':') {
+            format!("[{}]:{}", self.bind, self.port)
+        } else {
+            format!("{}:{}", self.bind, self.port)
+        }
+    }
+
+    pub fn unix_socket(&self) -> Option<PathBuf> {
+        self.listen.as_deref()?.strip_prefix("unix:").map(PathBuf::from)
+    }
+
+    pub fn tls_enabled(&self) -> bool {
+        self.tls_cert.is_some() && self.tls_key.is_some()
+    }
+
+    pub fn cache_max_size(&self) -> u64 {
+        self.cache_size_mb * 1024 * 1024
+    }
+
+    pub fn satellite_quota(&self, sat: &str) -> Option<u64> {
+        self.satellite_quota_mb.get(sat).map(|mb| mb * 1024 * 1024)
+    }
+
+    pub fn upstream_timeout(&self) -> Duration {
+        Duration::from_secs(self.upstream_timeout)
+    }
+}
+
+fn peepsat_dir() -> PathBuf {
+    let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+    PathBuf::from(home).join(".peepsat")
+}
@@ -0,0 +1,44 @@
+use std::time::Duration;
+use axum::http::{header, HeaderValue, Method};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::warn;
+use crate::config::CONFIG;
+
+/// CORS policy for every response, including errors and preflights.
+/// `cors_origins = ["*"]` allows any origin; an empty list sends no CORS
+/// headers, so only same-origin pages can read responses.
+pub fn layer() -> CorsLayer {
+    let origins = &CONFIG.cors_origins;
+    let allow_origin = if origins.iter().any(|o| o == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().filter_map(|origin| match HeaderValue::from_str(origin) {
+            Ok(value) => Some(value),
+            Err(_) => {
+                warn!(origin, "Ignoring invalid CORS origin");
+                None
+            }
+        }))
+    };
+
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        // POST, PUT and DELETE for the job, admin and palette endpoints
+        .allow_methods([Method::GET, Method::HEAD, Method::POST, Method::PUT, Method::DELETE])
+        .allow_headers([header::AUTHORIZATION, header::CONTENT_TYPE, header::IF_NONE_MATCH, header::RANGE])
+        .expose_headers([
+            header::ETAG,
+            header::CONTENT_RANGE,
+            header::HeaderName::from_static("x-cache"),
+            crate::offline::HEADER,
+            header::HeaderName::from_static("x-peepsat-stale"),
+            crate::satellites::STATUS_HEADER,
+            crate::SOURCE_HEADER,
+            crate::aurora::FORECAST_HEADER,
+            // Full disks, mosaics and timelapse jobs alike
+            crate::fulldisk::MISSING_HEADER,
+            crate::upsample::HEADER,
+            crate::mosaic::FRAMES_HEADER,
+        ])
+        .max_age(Duration::from_secs(CONFIG.cors_max_age))
+}
@@ -0,0 +1,72 @@
+use std::time::SystemTime;
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+/// Year, month and day of a count of days since 1970-01-01.
+pub fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+/// Seconds since the epoch, now.
+pub fn now() -> i64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs()) as i64
+}
+
+/// ISO 8601 UTC, as the Data Store and WMS TIME want it.
+pub fn iso(seconds: i64) -> String {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let time = seconds.rem_euclid(86400);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// An HTTP date, as Last-Modified wants it: "Wed, 16 Oct 2024 12:00:00 GMT".
+pub fn http_date(seconds: i64) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let days = seconds.div_euclid(86400);
+    let (year, month, day) = civil_from_days(days);
+    let time = seconds.rem_euclid(86400);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        time / 3600,
+        time / 60 % 60,
+        time % 60
+    )
+}
+
+/// Seconds since the epoch of a YYYYMMDDHHMMSS timestamp, or the start of
+/// an ISO 8601 time such as "2024-01-01T00:00:09.123Z".
+pub fn parse_time(text: &str) -> Option<i64> {
+    let digits: String = text.chars().filter(char::is_ascii_digit).take(14).collect();
+    if digits.len() < 14 {
+        return None;
+    }
+    let field = |range: std::ops::Range<usize>| digits[range].parse::<i64>().ok();
+    let days = days_from_civil(field(0..4)?, field(4..6)?, field(6..8)?);
+    Some(days * 86400 + field(8..10)? * 3600 + field(10..12)? * 60 + field(12..14)?)
+}
+
+/// The YYYYMMDDHHMMSS timestamp of a time.
+pub fn timestamp(seconds: i64) -> String {
+    iso(seconds).chars().filter(char::is_ascii_digit).collect()
+}
@@ -0,0 +1,96 @@
+use std::io::Cursor;
+use axum::body::Bytes;
+use axum::http::StatusCode;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage, ImageOutputFormat, Rgb, RgbImage};
+use tracing::{debug, warn};
+use crate::basemap::{subsolar_point, sun_elevation};
+use crate::cache::{put_cached_tile, DEFAULT_PRODUCT};
+use crate::dates::parse_time;
+use crate::fulldisk::source_tile_bytes;
+use crate::satellites::{self, DAY_NIGHT_IR_BAND};
+use crate::{geos, Tile, UpstreamTile};
+
+// Sun elevations, degrees, between which the IR fades into GeoColor. The
+// IR takes over a little before sunset, as GeoColor darkens toward the
+// terminator.
+const NIGHT_ELEVATION: f64 = -6.0;
+const DAY_ELEVATION: f64 = 6.0;
+
+// Colours of SLIDER's band 13 brightness (cold cloud tops brightest) from
+// dark to bright: warm land and sea a dark blue, cloud grey to white, and
+// the coldest tops, deep convection, blue
+const IR_COLOURS: &[(u8, [u8; 3])] = &[
+    (0, [10, 14, 40]),
+    (96, [28, 40, 80]),
+    (160, [150, 155, 170]),
+    (208, [235, 235, 240]),
+    (224, [110, 190, 255]),
+    (240, [40, 90, 230]),
+    (255, [20, 40, 160]),
+];
+
+// A band 13 brightness through IR_COLOURS
+fn ir_colour(value: u8) -> [f64; 3] {
+    let upper = IR_COLOURS.iter().position(|&(stop, _)| stop >= value).unwrap_or(IR_COLOURS.len() - 1).max(1);
+    let ((low, from), (high, to)) = (IR_COLOURS[upper - 1], IR_COLOURS[upper]);
+    let f = value.saturating_sub(low) as f64 / (high - low) as f64;
+    [0, 1, 2].map(|i| from[i] as f64 + (to[i] as f64 - from[i] as f64) * f)
+}
+
+// GeoColor where the sun is up and the colourised IR where it isn't, as a
+// PNG. Space off the limb is left as GeoColor has it.
+fn blend(day: &[u8], night: &[u8], coordinates: &[Option<(f64, f64)>], time: i64) -> Result<Vec<u8>, String> {
+    let day = image::load_from_memory(day).map_err(|e| e.to_string())?.to_rgb8();
+    let mut night: GrayImage = image::load_from_memory(night).map_err(|e| e.to_string())?.to_luma8();
+    if night.dimensions() != day.dimensions() {
+        night = imageops::resize(&night, day.width(), day.height(), FilterType::Triangle);
+    }
+    let sun = subsolar_point(time);
+    let width = day.width();
+    let blended = RgbImage::from_fn(width, day.height(), |px, py| {
+        let colour = day.get_pixel(px, py).0;
+        let Some(&Some((lat, lon))) = coordinates.get((py * width + px) as usize) else {
+            return Rgb(colour);
+        };
+        let elevation = sun_elevation(lat, lon, sun);
+        let daylight = ((elevation - NIGHT_ELEVATION) / (DAY_ELEVATION - NIGHT_ELEVATION)).clamp(0.0, 1.0);
+        let ir = ir_colour(night.get_pixel(px, py).0[0]);
+        let mix = |i: usize| (colour[i] as f64 * daylight + ir[i] * (1.0 - daylight)).round() as u8;
+        Rgb([mix(0), mix(1), mix(2)])
+    });
+    let mut out = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(blended).write_to(&mut out, ImageOutputFormat::Png).map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
+
+/// One day/night tile, blended from the GeoColor and band 13 tiles of the
+/// same frame (from the cache or upstream) by the sun's elevation at each
+/// pixel at the frame time, and cached under `key`. `Err` if either tile
+/// couldn't be had.
+pub async fn render(cdn: &str, tile: &Tile<'_>, date: &str, key: &str) -> UpstreamTile {
+    let (Some(satellite), Some(time)) = (satellites::find(tile.sat), parse_time(tile.timestamp)) else {
+        return Err(());
+    };
+    let ir_product = format!("band_{:02}", DAY_NIGHT_IR_BAND);
+    let day = Tile { product: DEFAULT_PRODUCT, ..*tile };
+    let night = Tile { product: &ir_product, ..*tile };
+    let (day, night) = tokio::join!(source_tile_bytes(cdn, day, date), source_tile_bytes(cdn, night, date));
+    let (Some(day), Some(night)) = (day, night) else {
+        debug!(key, "No GeoColor or IR tile for day/night blend");
+        return Err(());
+    };
+    let coordinates = geos::tile_coordinates(tile, satellite);
+    match tokio::task::spawn_blocking(move || blend(&day, &night, &coordinates, time)).await {
+        Ok(Ok(png)) => {
+            let png = Bytes::from(png);
+            put_cached_tile(key, &png).await;
+            Ok((StatusCode::OK, png))
+        }
+        Ok(Err(e)) => {
+            warn!(key, error = %e, "Day/night blend failed");
+            Err(())
+        }
+        Err(_) => Err(()),
+    }
+}
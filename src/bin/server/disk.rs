@@ -0,0 +1,77 @@
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+use crate::cache::{self, CACHE_DIR};
+use crate::config::CONFIG;
+use crate::metrics;
+use crate::s3;
+
+// How often free space is checked
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+// What the cache may use given the disk's free space; starts unconstrained
+static DISK_LIMIT: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+fn free_bytes(path: &Path) -> Option<u64> {
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and `stat` is a valid statvfs to fill
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// The cache size limit in effect: `cache_size_mb`, lowered when the disk
+/// is short of space.
+pub fn effective_max_size() -> u64 {
+    CONFIG.cache_max_size().min(DISK_LIMIT.load(Ordering::Relaxed))
+}
+
+/// Recompute the limit so that, once the cache is trimmed to it, at least
+/// `min_free_disk_mb` stays free on the cache's filesystem.
+pub fn check() {
+    let headroom = CONFIG.min_free_disk_mb * 1024 * 1024;
+    // Tiles in a bucket don't take up this disk
+    if headroom == 0 || s3::enabled() {
+        return;
+    }
+    let Some(free) = free_bytes(&CACHE_DIR) else {
+        return;
+    };
+    let (_, used, _, _) = cache::stats();
+    let limit = (used + free).saturating_sub(headroom);
+    let previous = DISK_LIMIT.swap(limit, Ordering::Relaxed);
+
+    let constrained = limit < CONFIG.cache_max_size();
+    let was_constrained = previous < CONFIG.cache_max_size();
+    if constrained && (!was_constrained || limit < previous.saturating_sub(previous / 10)) {
+        warn!(free, limit, "Disk is low on space, shrinking the cache limit");
+    } else if !constrained && was_constrained {
+        info!(free, "Disk space recovered, cache limit back to cache_size_mb");
+    }
+    metrics::CACHE_LIMIT_BYTES.set(effective_max_size() as i64);
+}
+
+/// Check free space every 30 seconds and evict down to the new limit.
+pub fn spawn_watchdog() {
+    metrics::CACHE_LIMIT_BYTES.set(effective_max_size() as i64);
+    if CONFIG.min_free_disk_mb == 0 || s3::enabled() {
+        return;
+    }
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            interval.tick().await;
+            let _ = tokio::task::spawn_blocking(|| {
+                check();
+                cache::sweep();
+            })
+            .await;
+        }
+    });
+}
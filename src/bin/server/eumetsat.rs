@@ -0,0 +1,222 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use axum::body::Bytes;
+use axum::http::StatusCode;
+use image::RgbImage;
+use serde::Deserialize;
+use tracing::{debug, warn};
+use crate::config::CONFIG;
+use crate::dates::{iso, parse_time, timestamp};
+use crate::upstream::{self, Priority, HTTP_CLIENT};
+use crate::{cache, geos, satellites, Tile, UpstreamTile, MAX_TILE_BYTES};
+
+// Frames listed by slider-latest, searched for this far back
+const SEARCH_HOURS: i64 = 24;
+
+/// Where a Meteosat's imagery lives on EUMETSAT's services.
+struct Source {
+    sat: &'static str,
+    /// Data Store collection, searched for the times of available frames
+    collection: &'static str,
+    /// EUMETView WMS layer rendered into tiles
+    layer: &'static str,
+    /// Minutes between full disks; frame times are rounded down to this
+    repeat_cycle: u32,
+}
+
+const SOURCES: &[Source] = &[
+    Source { sat: "meteosat12", collection: "EO:EUM:DAT:0662", layer: "mtg_fd:rgb_geocolour", repeat_cycle: 10 },
+    Source { sat: "meteosat10", collection: "EO:EUM:DAT:MSG:HRSEVIRI", layer: "msg_fes:rgb_naturalenhncd", repeat_cycle: 15 },
+    Source { sat: "meteosat9", collection: "EO:EUM:DAT:MSG:HRSEVIRI-IODC", layer: "msg_iodc:rgb_naturalenhncd", repeat_cycle: 15 },
+];
+
+/// Whether `satellite_sources` can send `sat` to EUMETSAT.
+pub fn supports(sat: &str) -> bool {
+    SOURCES.iter().any(|s| s.sat == sat)
+}
+
+/// Whether `cdn` is EUMETView, i.e. a satellite configured for EUMETSAT.
+pub fn is_source(cdn: &str) -> bool {
+    cdn == CONFIG.eumetsat_view_url
+}
+
+lazy_static::lazy_static! {
+    // Data Store access token and when it stops being valid
+    static ref TOKEN: Mutex<Option<(String, Instant)>> = Mutex::new(None);
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+// A Data Store token, fetched with the consumer key and secret and reused
+// until shortly before it expires
+async fn token() -> Result<String, &'static str> {
+    if let Some((token, expires)) = TOKEN.lock().unwrap().clone() {
+        if Instant::now() < expires {
+            return Ok(token);
+        }
+    }
+    let (Some(key), Some(secret)) = (&CONFIG.eumetsat_consumer_key, &CONFIG.eumetsat_consumer_secret) else {
+        return Err("EUMETSAT credentials not configured");
+    };
+    let response = HTTP_CLIENT
+        .post(format!("{}/token", CONFIG.eumetsat_api_url))
+        .basic_auth(key, Some(secret))
+        .form(&[("grant_type", "client_credentials")])
+        .send()
+        .await;
+    let response = match response {
+        Ok(r) if r.status().is_success() => r,
+        Ok(r) => {
+            warn!(status = r.status().as_u16(), "EUMETSAT token request refused");
+            return Err("EUMETSAT authentication failed");
+        }
+        Err(e) => {
+            warn!(error = %e, "EUMETSAT token request failed");
+            return Err("EUMETSAT authentication failed");
+        }
+    };
+    let body = response.bytes().await.map_err(|_| "EUMETSAT authentication failed")?;
+    let parsed: TokenResponse = serde_json::from_slice(&body).map_err(|_| "EUMETSAT authentication failed")?;
+    debug!(expires_in = parsed.expires_in, "Got EUMETSAT token");
+    let expires = Instant::now() + Duration::from_secs(parsed.expires_in.saturating_sub(60));
+    *TOKEN.lock().unwrap() = Some((parsed.access_token.clone(), expires));
+    Ok(parsed.access_token)
+}
+
+#[derive(Deserialize)]
+struct SearchResults {
+    features: Vec<Feature>,
+}
+
+#[derive(Deserialize)]
+struct Feature {
+    properties: FeatureProperties,
+}
+
+#[derive(Deserialize)]
+struct FeatureProperties {
+    /// Sensing period, "start/end"
+    date: String,
+}
+
+// Repeat-cycle start times of the frames sensed in the last SEARCH_HOURS,
+// newest first
+async fn frame_times(sat: &str) -> Result<Vec<i64>, &'static str> {
+    let source = SOURCES.iter().find(|s| s.sat == sat).ok_or("Not an EUMETSAT satellite")?;
+    let token = token().await?;
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs() as i64);
+    let url = format!(
+        "{}/data/search-products/1.0.0/os?format=json&pi={}&dtstart={}&dtend={}&c=500",
+        CONFIG.eumetsat_api_url,
+        urlencoding::encode(source.collection),
+        iso(now - SEARCH_HOURS * 3600),
+        iso(now),
+    );
+    debug!(url, "Searching EUMETSAT Data Store");
+    let response = match HTTP_CLIENT.get(&url).bearer_auth(&token).send().await {
+        Ok(r) if r.status().is_success() => r,
+        Ok(r) => {
+            warn!(sat, status = r.status().as_u16(), "EUMETSAT search refused");
+            // Let the next request fetch a fresh token in case it was revoked
+            if r.status() == StatusCode::UNAUTHORIZED {
+                *TOKEN.lock().unwrap() = None;
+            }
+            return Err("Failed");
+        }
+        Err(e) => {
+            warn!(sat, error = %e, "EUMETSAT search failed");
+            return Err("Failed");
+        }
+    };
+    let body = response.bytes().await.map_err(|_| "Failed")?;
+    let results: SearchResults = serde_json::from_slice(&body).map_err(|_| "Failed")?;
+    let cycle = source.repeat_cycle as i64 * 60;
+    let mut times: Vec<i64> = results
+        .features
+        .iter()
+        .filter_map(|f| parse_time(f.properties.date.split('/').next()?))
+        .map(|t| t - t.rem_euclid(cycle))
+        .collect();
+    times.sort_unstable_by(|a, b| b.cmp(a));
+    times.dedup();
+    Ok(times)
+}
+
+/// `latest_times.json` for `sat`, as SLIDER would send it.
+pub async fn latest(sat: &str) -> Result<Bytes, &'static str> {
+    let times: Vec<String> = frame_times(sat).await?.into_iter().map(timestamp).collect();
+    Ok(Bytes::from(format!(r#"{{"timestamps_int":[{}]}}"#, times.join(","))))
+}
+
+/// `available_dates.json` for `sat`, covering the days of the frames found.
+pub async fn dates(sat: &str) -> Result<Bytes, &'static str> {
+    let mut dates: Vec<String> = frame_times(sat).await?.into_iter().map(|t| timestamp(t)[..8].to_string()).collect();
+    dates.dedup();
+    Ok(Bytes::from(format!(r#"{{"dates_int":[{}]}}"#, dates.join(","))))
+}
+
+/// Render one tile of `sat` at `tile.timestamp` from EUMETView and cache
+/// it, in the same geostationary grid SLIDER uses for that satellite.
+pub async fn fetch_tile(tile: &Tile<'_>, key: &str, priority: Priority) -> UpstreamTile {
+    let (Some(satellite), Some(source)) = (satellites::find(tile.sat), SOURCES.iter().find(|s| s.sat == tile.sat)) else {
+        return Ok((StatusCode::NOT_FOUND, Bytes::new()));
+    };
+    let Some(time) = parse_time(tile.timestamp) else {
+        return Ok((StatusCode::BAD_REQUEST, Bytes::from_static(b"Invalid timestamp")));
+    };
+    let tile_size = satellite.tile_size;
+    let coordinates = geos::tile_coordinates(tile, satellite);
+    let bbox = geos::bounds(&coordinates);
+
+    let map = if coordinates.iter().all(Option::is_none) {
+        // All space: nothing to ask for
+        RgbImage::new(1, 1)
+    } else {
+        let _slot = upstream::acquire(priority).await;
+        // WMS 1.3 takes EPSG:4326 boxes latitude first
+        let url = format!(
+            "{}/geoserver/ows?service=WMS&version=1.3.0&request=GetMap&layers={}&styles=&crs=EPSG:4326&bbox={},{},{},{}&width={}&height={}&format=image/png&time={}",
+            CONFIG.eumetsat_view_url, source.layer, bbox.0, bbox.1, bbox.2, bbox.3, tile_size, tile_size, iso(time),
+        );
+        debug!(key, url, "Fetching EUMETView map");
+        let response = match upstream::get(&HTTP_CLIENT, &[url]).await {
+            Ok(r) => r,
+            Err(e) => {
+                warn!(key, error = %e, "EUMETView request failed");
+                return Err(());
+            }
+        };
+        let status = response.status();
+        let body = match upstream::read_limited(response, MAX_TILE_BYTES).await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!(key, error = %e, "EUMETView download failed");
+                return Err(());
+            }
+        };
+        if !status.is_success() {
+            return Ok((status, body));
+        }
+        // GeoServer reports errors as XML with a 200
+        match image::load_from_memory(&body) {
+            Ok(map) => map.to_rgb8(),
+            Err(e) => {
+                warn!(key, error = %e, "EUMETView returned no image");
+                return Err(());
+            }
+        }
+    };
+
+    let png = match tokio::task::spawn_blocking(move || geos::render(&map, bbox, &coordinates, tile_size)).await {
+        Ok(png) => Bytes::from(png),
+        Err(_) => return Err(()),
+    };
+    cache::put_cached_tile(key, &png).await;
+    Ok((StatusCode::OK, png))
+}
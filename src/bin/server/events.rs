@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Mutex;
+use std::time::Duration;
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use futures_util::future::join_all;
+use futures_util::{stream, StreamExt};
+use serde_json::{json, Value};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn, Span};
+use crate::config::CONFIG;
+use crate::dates::{iso, parse_time};
+use crate::frames::{params_for, Layer};
+use crate::redis;
+use crate::reproject::frame_timestamp;
+use crate::satellites::{self, Satellite};
+use crate::Params;
+
+// Satellites one connection may follow
+const MAX_SATS: usize = 16;
+// Layers followed across all connections; the cdn parameter is part of
+// the key, so clients can add them at will
+const MAX_WATCHED: usize = 256;
+// New frames held for connections that fall behind
+const CHANNEL_SIZE: usize = 64;
+// How long the first server to find a frame keeps its claim to announce
+// it; by then every server has seen it
+const CLAIM_TTL: Duration = Duration::from_secs(6 * 3600);
+
+// A layer someone is following, and the newest frame seen for it
+struct Watched {
+    params: Params,
+    timestamp: Option<String>,
+    listeners: usize,
+}
+
+/// A new frame of one of the layers being followed.
+#[derive(Clone)]
+pub struct NewFrame {
+    key: String,
+    pub timestamp: String,
+    /// The frame as `/api/events` describes it
+    pub data: Value,
+    /// Whether this server announces it, rather than another sharing Redis
+    /// that found it first; only then are webhooks and MQTT sent it
+    pub claimed: bool,
+}
+
+lazy_static::lazy_static! {
+    // By layer_key
+    static ref WATCHED: Mutex<HashMap<String, Watched>> = Mutex::new(HashMap::new());
+    static ref NEW_FRAMES: broadcast::Sender<NewFrame> = broadcast::channel(CHANNEL_SIZE).0;
+}
+
+fn layer_key(layer: &Layer) -> String {
+    format!("{} {} {} {}", layer.cdn, layer.sat, layer.sector, layer.product)
+}
+
+fn frame_event(layer: &Layer, timestamp: &str) -> Value {
+    json!({
+        "sat": layer.sat,
+        "sector": layer.sector,
+        "product": layer.product,
+        "timestamp": timestamp.parse::<u64>().ok(),
+        "date": timestamp.get(..8).and_then(|d| d.parse::<u32>().ok()),
+        "time": parse_time(timestamp).map(iso),
+    })
+}
+
+/// One connection's layers, no longer followed once it's dropped.
+pub struct Subscription {
+    keys: Vec<String>,
+}
+
+impl Subscription {
+    /// Follow `layers`, as from `layers`; `None` if the server is already
+    /// following as many as it will.
+    pub fn new(layers: &[(Layer, Params)]) -> Option<Subscription> {
+        let mut subscription = Subscription { keys: Vec::new() };
+        let mut watched = WATCHED.lock().unwrap();
+        for (layer, params) in layers {
+            let key = layer_key(layer);
+            if subscription.keys.contains(&key) {
+                continue;
+            }
+            if !watched.contains_key(&key) && watched.len() >= MAX_WATCHED {
+                drop(watched);
+                return None;
+            }
+            let entry = watched.entry(key.clone()).or_insert_with(|| Watched { params: params.clone(), timestamp: None, listeners: 0 });
+            entry.listeners += 1;
+            subscription.keys.push(key);
+        }
+        Some(subscription)
+    }
+
+    /// Whether `frame` is of one of the layers followed.
+    pub fn wants(&self, frame: &NewFrame) -> bool {
+        self.keys.contains(&frame.key)
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut watched = WATCHED.lock().unwrap();
+        for key in &self.keys {
+            if let Some(entry) = watched.get_mut(key) {
+                entry.listeners -= 1;
+                if entry.listeners == 0 {
+                    watched.remove(key);
+                }
+            }
+        }
+    }
+}
+
+// Look for a new frame of every layer being followed, announcing those
+// that changed since the last look
+async fn poll() {
+    let layers: Vec<(String, Params)> = WATCHED.lock().unwrap().iter().map(|(key, w)| (key.clone(), w.params.clone())).collect();
+    join_all(layers.into_iter().map(|(key, params)| async move {
+        let layer = Layer::from_params(&params)?;
+        let timestamp = match frame_timestamp(&layer, &params, None).await {
+            Ok(timestamp) => timestamp,
+            Err(response) => {
+                debug!(sat = layer.sat, status = response.status().as_u16(), "Events: no frame list");
+                return None;
+            }
+        };
+        let previous = {
+            let mut watched = WATCHED.lock().unwrap();
+            let entry = watched.get_mut(&key)?;
+            entry.timestamp.replace(timestamp.clone())
+        };
+        if previous.is_some_and(|previous| previous < timestamp) {
+            info!(sat = layer.sat, product = layer.product, timestamp, "New frame");
+            let data = frame_event(&layer, &timestamp);
+            announce(NewFrame { key, timestamp, data, claimed: true }).await;
+        }
+        Some(())
+    }))
+    .await;
+}
+
+// Pass a new frame on to listeners. Servers sharing Redis announce it
+// once: the first to find it tells the others, which then pass that on
+// rather than what they find themselves
+async fn announce(frame: NewFrame) {
+    if redis::enabled() {
+        match redis::set_once(&format!("frame:{}:{}", frame.key, frame.timestamp), CLAIM_TTL).await {
+            Ok(false) => {
+                debug!(timestamp = frame.timestamp, "Frame already announced by another server");
+                return;
+            }
+            Ok(true) => {
+                let message = json!({ "origin": *redis::INSTANCE, "key": frame.key, "timestamp": frame.timestamp, "data": frame.data });
+                if let Err(e) = redis::publish(redis::FRAMES_CHANNEL, message.to_string().as_bytes()).await {
+                    warn!(error = %e, "Failed to announce new frame through Redis");
+                }
+            }
+            Err(e) => warn!(error = %e, "Redis unavailable, announcing new frame alone"),
+        }
+    }
+    // Nobody listening is fine
+    let _ = NEW_FRAMES.send(frame);
+}
+
+/// A new frame another server announced through Redis, passed on if a
+/// layer followed here and not already past it.
+pub fn remote_frame(payload: &[u8]) {
+    let Ok(message) = serde_json::from_slice::<Value>(payload) else {
+        return;
+    };
+    if message["origin"].as_str() == Some(redis::INSTANCE.as_str()) {
+        return;
+    }
+    let (Some(key), Some(timestamp)) = (message["key"].as_str(), message["timestamp"].as_str()) else {
+        return;
+    };
+    {
+        let mut watched = WATCHED.lock().unwrap();
+        let Some(entry) = watched.get_mut(key) else {
+            return;
+        };
+        if entry.timestamp.as_deref().is_some_and(|seen| seen > timestamp) {
+            return;
+        }
+        entry.timestamp = Some(timestamp.to_string());
+    }
+    debug!(timestamp, "New frame from another server");
+    let data = message["data"].clone();
+    let _ = NEW_FRAMES.send(NewFrame { key: key.to_string(), timestamp: timestamp.to_string(), data, claimed: false });
+}
+
+/// Every `events_interval` seconds, look for a new frame of each layer
+/// `/api/events` and `/ws` clients are following.
+pub fn spawn_watcher() {
+    tokio::spawn(async {
+        let mut interval = tokio::time::interval(Duration::from_secs(CONFIG.events_interval.max(1)));
+        loop {
+            interval.tick().await;
+            poll().await;
+        }
+    });
+}
+
+// The next new frame of one of `subscription`'s layers
+async fn next_event(
+    (mut receiver, subscription): (broadcast::Receiver<NewFrame>, Subscription),
+) -> Option<(Result<Event, Infallible>, (broadcast::Receiver<NewFrame>, Subscription))> {
+    loop {
+        match receiver.recv().await {
+            Ok(frame) if subscription.wants(&frame) => {
+                let event = Event::default().event("frame").id(frame.timestamp).data(frame.data.to_string());
+                return Some((Ok(event), (receiver, subscription)));
+            }
+            Ok(_) => continue,
+            // Frames missed while behind are superseded by the next anyway
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// The layers `params` ask to follow: one per satellite in `sats` (or
+/// just `sat`, or the default satellite), `sector` and `product` applying
+/// where a satellite has them.
+pub fn layers(params: &Params) -> Result<Vec<(Layer, Params)>, String> {
+    let chosen: Vec<Option<&Satellite>> = match params.get("sats") {
+        Some(sats) => {
+            let chosen = sats
+                .split(',')
+                .filter(|s| !s.is_empty())
+                .map(|sat| satellites::find(sat).map(Some).ok_or_else(|| format!("Unknown satellite {}", sat)))
+                .collect::<Result<Vec<_>, _>>()?;
+            if chosen.is_empty() || chosen.len() > MAX_SATS {
+                return Err(format!("sats must name 1 to {} satellites", MAX_SATS));
+            }
+            chosen
+        }
+        None => vec![None],
+    };
+    chosen
+        .into_iter()
+        .map(|satellite| {
+            let params = satellite.map_or_else(|| params.clone(), |satellite| params_for(satellite, params));
+            let layer = Layer::from_params(&params).ok_or("Invalid sector or product")?;
+            Ok((layer, params))
+        })
+        .collect()
+}
+
+/// The newest frame of each of `layers`, described as in `/api/events`,
+/// which the watcher then looks for frames after. Subscribe first, so
+/// none falls between the two.
+pub async fn newest(layers: &[(Layer, Params)]) -> Vec<NewFrame> {
+    let found = join_all(layers.iter().map(|(layer, params)| async move {
+        let timestamp = frame_timestamp(layer, params, None).await.ok()?;
+        let key = layer_key(layer);
+        if let Some(entry) = WATCHED.lock().unwrap().get_mut(&key) {
+            entry.timestamp.get_or_insert_with(|| timestamp.clone());
+        }
+        let data = frame_event(layer, &timestamp);
+        Some(NewFrame { key, timestamp, data, claimed: true })
+    }))
+    .await;
+    found.into_iter().flatten().collect()
+}
+
+/// The satellites to announce new frames of elsewhere: `chosen`, or else
+/// `prefetch_satellites`, or else the default satellite.
+pub fn announced_satellites(chosen: &[String]) -> Vec<String> {
+    if !chosen.is_empty() {
+        chosen.to_vec()
+    } else if !CONFIG.prefetch_satellites.is_empty() {
+        CONFIG.prefetch_satellites.clone()
+    } else {
+        vec![CONFIG.default_satellite.clone()]
+    }
+}
+
+/// Where links sent elsewhere point: `public_url`, or localhost.
+pub fn public_base() -> String {
+    let base = CONFIG.public_url.clone().unwrap_or_else(|| format!("http://localhost:{}{}", CONFIG.port, CONFIG.base_path));
+    base.trim_end_matches('/').to_string()
+}
+
+/// A frame as `/api/events` describes it, plus a `preview_url` and an
+/// `image_url` to look at it, made from `public_url` (or localhost).
+pub fn with_links(data: &Value) -> Value {
+    let base = public_base();
+    let field = |name: &str| data.get(name).map(|v| v.as_str().map_or_else(|| v.to_string(), str::to_string)).unwrap_or_default();
+    let query = format!("sat={}&sector={}&product={}&t={}", field("sat"), field("sector"), field("product"), field("timestamp"));
+    let mut data = data.clone();
+    data["preview_url"] = Value::String(format!("{}/api/preview?{}", base, query));
+    data["image_url"] = Value::String(format!("{}/api/fulldisk?{}&format=jpeg", base, query));
+    data
+}
+
+/// New frames as the watcher finds them.
+pub fn new_frames() -> broadcast::Receiver<NewFrame> {
+    NEW_FRAMES.subscribe()
+}
+
+/// `GET /api/events?sats=19,18&product=band_13`: a Server-Sent Events
+/// stream with a `frame` event whenever a new frame of one of `sats` (or
+/// `sat`, or the default satellite) is listed, so pages can update without
+/// polling. `sector` and `product` apply where a satellite has them, as
+/// for `/api/frames/sync`. Each satellite's current newest frame is sent
+/// on connecting. New frames are looked for every `events_interval`
+/// seconds.
+pub async fn handle_events(Query(params): Query<Params>) -> Response {
+    let layers = match layers(&params) {
+        Ok(layers) => layers,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    Span::current().record("sat", layers[0].0.sat.as_str());
+
+    let receiver = new_frames();
+    let Some(subscription) = Subscription::new(&layers) else {
+        return (StatusCode::SERVICE_UNAVAILABLE, "Too many layers being followed").into_response();
+    };
+    let current = newest(&layers).await;
+    info!(sats = layers.len(), "Event stream opened");
+
+    let first = stream::iter(current.into_iter().map(|frame| Ok(Event::default().event("frame").id(frame.timestamp).data(frame.data.to_string()))));
+    let stream = first.chain(stream::unfold((receiver, subscription), next_event));
+    Sse::new(stream).keep_alive(KeepAlive::default()).into_response()
+}
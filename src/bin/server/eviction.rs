@@ -0,0 +1,123 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::{Duration, SystemTime};
+use crate::cache::{key_layer, key_parts, CacheEntry};
+use crate::config::CONFIG;
+
+/// The entries a policy chooses from: the whole cache, or one satellite's
+/// share of it when enforcing a quota.
+pub type Candidates<'a> = [(&'a String, &'a CacheEntry)];
+
+/// Decides which cached tiles to delete.
+pub trait EvictionPolicy: Send + Sync {
+    /// Keys to evict, in order, so that at least `bytes_to_free` bytes are
+    /// released. Also called with 0 on a timer, when policies that expire
+    /// entries on their own (TTL, newest frames) get to drop them.
+    fn select(&self, entries: &Candidates, bytes_to_free: u64) -> Vec<String>;
+}
+
+// Take entries in the given order until enough bytes are covered
+fn take_bytes<'a>(
+    ordered: impl IntoIterator<Item = &'a (&'a String, &'a CacheEntry)>,
+    bytes_to_free: u64,
+) -> Vec<String> {
+    let mut freed = 0;
+    let mut keys = Vec::new();
+    for (key, entry) in ordered {
+        if freed >= bytes_to_free {
+            break;
+        }
+        freed += entry.size;
+        keys.push((*key).clone());
+    }
+    keys
+}
+
+/// Least recently used first.
+pub struct Lru;
+
+impl EvictionPolicy for Lru {
+    fn select(&self, entries: &Candidates, bytes_to_free: u64) -> Vec<String> {
+        let mut entries = entries.to_vec();
+        entries.sort_by_key(|(_, e)| e.last_access);
+        take_bytes(&entries, bytes_to_free)
+    }
+}
+
+/// Least frequently used first; ties go to the least recently used.
+pub struct Lfu;
+
+impl EvictionPolicy for Lfu {
+    fn select(&self, entries: &Candidates, bytes_to_free: u64) -> Vec<String> {
+        let mut entries = entries.to_vec();
+        entries.sort_by_key(|(_, e)| (e.hits, e.last_access));
+        take_bytes(&entries, bytes_to_free)
+    }
+}
+
+/// Tiles expire a fixed time after they were cached; if that isn't enough,
+/// the oldest go first.
+pub struct Ttl {
+    pub max_age: Duration,
+}
+
+impl EvictionPolicy for Ttl {
+    fn select(&self, entries: &Candidates, bytes_to_free: u64) -> Vec<String> {
+        let cutoff = SystemTime::now().checked_sub(self.max_age).unwrap_or(SystemTime::UNIX_EPOCH);
+        let mut entries = entries.to_vec();
+        entries.sort_by_key(|(_, e)| e.created);
+        let expired = entries.iter().take_while(|(_, e)| e.created < cutoff).count();
+        let freed: u64 = entries[..expired].iter().map(|(_, e)| e.size).sum();
+        let mut selected: Vec<String> = entries[..expired].iter().map(|(k, _)| (*k).clone()).collect();
+        selected.extend(take_bytes(&entries[expired..], bytes_to_free.saturating_sub(freed)));
+        selected
+    }
+}
+
+// Frames are counted separately for each satellite, sector and product
+fn series(key: &str) -> (&str, &str, &str) {
+    let (sector, product) = key_layer(key);
+    (key_parts(key).0, sector, product)
+}
+
+/// Keeps the newest `frames` timestamps of each satellite, so scrubbing
+/// back through a recent loop never hits upstream. Older frames are
+/// dropped; if the budget still isn't met, the oldest kept frames go next.
+pub struct NewestFrames {
+    pub frames: usize,
+}
+
+impl EvictionPolicy for NewestFrames {
+    fn select(&self, entries: &Candidates, bytes_to_free: u64) -> Vec<String> {
+        let mut timestamps: HashMap<_, BTreeSet<&str>> = HashMap::new();
+        for (key, _) in entries {
+            timestamps.entry(series(key)).or_default().insert(key_parts(key).1);
+        }
+        // Timestamps are fixed-width digits, so string order is time order
+        let kept: HashMap<_, BTreeSet<&str>> = timestamps
+            .into_iter()
+            .map(|(series, all)| (series, all.into_iter().rev().take(self.frames).collect()))
+            .collect();
+
+        let (mut outside, mut inside): (Vec<_>, Vec<_>) = entries
+            .iter()
+            .copied()
+            .partition(|(key, _)| !kept[&series(key)].contains(key_parts(key).1));
+        outside.sort_by_key(|(k, _)| key_parts(k).1);
+        inside.sort_by_key(|(k, _)| key_parts(k).1);
+
+        let freed: u64 = outside.iter().map(|(_, e)| e.size).sum();
+        let mut selected: Vec<String> = outside.iter().map(|(k, _)| (*k).clone()).collect();
+        selected.extend(take_bytes(&inside, bytes_to_free.saturating_sub(freed)));
+        selected
+    }
+}
+
+/// The policy named by `eviction_policy` in the config.
+pub fn from_config() -> Box<dyn EvictionPolicy> {
+    match CONFIG.eviction_policy.as_str() {
+        "lfu" => Box::new(Lfu),
+        "ttl" => Box::new(Ttl { max_age: Duration::from_secs(CONFIG.eviction_ttl_hours * 3600) }),
+        "newest" => Box::new(NewestFrames { frames: CONFIG.keep_frames }),
+        _ => Box::new(Lru),
+    }
+}
@@ -0,0 +1,356 @@
+use std::io::Cursor;
+use axum::extract::Query;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use image::{DynamicImage, ImageOutputFormat};
+use tracing::{info, Span};
+use crate::cache::DEFAULT_SECTOR;
+use crate::config::CONFIG;
+use crate::dates::{iso, now, parse_time};
+use crate::frames::{day, frames_between, parse_step, thin, time_bounds, Layer};
+use crate::fulldisk::{JPEG_QUALITY, MISSING_HEADER, STITCH_SLOT};
+use crate::prefetch::days_between;
+use crate::reproject::{self, frame_timestamp, mercator_y, reproject, Projection, View, MAX_LATITUDE};
+use crate::wmts::DISK_REACH;
+use crate::{geotiff, offline, satellites, zip, Params};
+
+// Widest export, about 256 MB as RGBA
+const MAX_SIDE: u32 = 8192;
+const DEFAULT_WIDTH: u32 = 2048;
+// The most full-disk tiles resampled for one export: a whole disk at zoom 3
+const MAX_SOURCE_TILES: usize = 64;
+// The most frames in one KMZ
+const MAX_FRAMES: usize = 24;
+
+/// What every export takes: the frame (`t`, the newest by default), the
+/// area (`bbox=minlon,minlat,maxlon,maxlat`, the whole disk by default),
+/// the projection (`crs=4326` or `3857`) and the width in pixels.
+pub struct Request {
+    pub layer: Layer,
+    pub params: Params,
+    pub time: Option<i64>,
+    pub view: View,
+}
+
+impl Request {
+    /// From the query parameters, or why they won't do.
+    pub fn from_params(params: &Params) -> Result<Request, String> {
+        let mut params = params.clone();
+        params.insert("sector".to_string(), DEFAULT_SECTOR.to_string());
+        let Some(layer) = Layer::from_params(&params) else {
+            return Err(String::from("Unknown satellite or product"));
+        };
+        let Some(satellite) = satellites::find(&layer.sat) else {
+            return Err(String::from("Unknown satellite"));
+        };
+        let time = match params.get("t") {
+            Some(t) => Some(parse_time(t).ok_or_else(|| String::from("t must be a frame timestamp, e.g. 20241016120000"))?),
+            None => None,
+        };
+        let projection = match params.get("crs").map(String::as_str) {
+            None | Some("4326") | Some("EPSG:4326") => Projection::Geographic,
+            Some("3857") | Some("EPSG:3857") => Projection::Mercator,
+            Some(_) => return Err(String::from("crs must be 4326 or 3857")),
+        };
+        let bbox = match params.get("bbox") {
+            Some(text) => {
+                let values: Vec<f64> = text.split(',').filter_map(|v| v.trim().parse().ok()).collect();
+                let [west, south, east, north] = values[..] else {
+                    return Err(String::from("bbox must be minlon,minlat,maxlon,maxlat"));
+                };
+                if west >= east || south >= north || east - west > 360.0 || south < -90.0 || north > 90.0 {
+                    return Err(String::from("bbox must be minlon,minlat,maxlon,maxlat"));
+                }
+                (west, south, east, north)
+            }
+            None => (satellite.longitude - DISK_REACH, -DISK_REACH, satellite.longitude + DISK_REACH, DISK_REACH),
+        };
+        let bbox = match projection {
+            Projection::Geographic => bbox,
+            Projection::Mercator => (bbox.0, bbox.1.max(-MAX_LATITUDE), bbox.2, bbox.3.min(MAX_LATITUDE)),
+        };
+
+        // Square pixels, within MAX_SIDE either way
+        let (west, south, east, north) = bbox;
+        let aspect = match projection {
+            Projection::Geographic => (north - south) / (east - west),
+            Projection::Mercator => (mercator_y(north) - mercator_y(south)) / (east - west).to_radians(),
+        };
+        let mut width: u32 = match params.get("width") {
+            Some(w) => w.parse().ok().filter(|&w| (1..=MAX_SIDE).contains(&w)).ok_or_else(|| String::from("width must be 1 to 8192"))?,
+            None => DEFAULT_WIDTH,
+        };
+        if width as f64 * aspect > MAX_SIDE as f64 {
+            width = (MAX_SIDE as f64 / aspect) as u32;
+        }
+        let height = ((width as f64 * aspect).round() as u32).max(1);
+        let view = View { projection, bbox, width: width.max(1), height };
+        Ok(Request { layer, params, time, view })
+    }
+}
+
+/// `GET /api/export/geotiff?sat=19&t=20241016120000&bbox=-100,15,-60,35`:
+/// a frame reprojected onto `bbox` as a Cloud-Optimized GeoTIFF in
+/// EPSG:4326 (or 3857 with `crs=3857`), georeferenced so GIS tools place
+/// it, and transparent where the satellite can't see. See [`Request`] for
+/// the rest of the parameters, which include `product` and `cdn`.
+pub async fn handle_geotiff(Query(params): Query<Params>) -> Response {
+    let request = match Request::from_params(&params) {
+        Ok(request) => request,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    let Request { layer, params, time, view } = request;
+    Span::current().record("sat", layer.sat.as_str());
+    let timestamp = match frame_timestamp(&layer, &params, time).await {
+        Ok(timestamp) => timestamp,
+        Err(response) => return response,
+    };
+
+    let Ok(_slot) = STITCH_SLOT.acquire().await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response();
+    };
+    let reprojection = match reproject(&layer, &timestamp, view, MAX_SOURCE_TILES).await {
+        Ok(reprojection) => reprojection,
+        Err(response) => return response,
+    };
+    let missing = reprojection.missing;
+    let image = reprojection.image;
+    let tiff = match tokio::task::spawn_blocking(move || geotiff::cloud_optimized(image, view.projection, view.bbox)).await {
+        Ok(tiff) => tiff,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image").into_response(),
+    };
+    info!(sat = layer.sat, timestamp, width = view.width, height = view.height, missing, bytes = tiff.len(), "Exported GeoTIFF");
+
+    let cache_control = if missing == 0 && time.is_some() {
+        format!("public, max-age={}, immutable", CONFIG.tile_max_age)
+    } else {
+        "no-cache".to_string()
+    };
+    let filename = format!("peepsat-{}-{}-{}.tif", layer.sat, layer.product, timestamp);
+    let response = (
+        [
+            (header::CONTENT_TYPE, "image/tiff; application=geotiff; profile=cloud-optimized".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+            (header::CACHE_CONTROL, cache_control),
+            (MISSING_HEADER, missing.to_string()),
+        ],
+        tiff,
+    )
+        .into_response();
+    let response = if offline::enabled() { offline::mark(response) } else { response };
+    layer.mark(response)
+}
+
+// KML wants longitudes within ±180, a box across the antimeridian having
+// its east edge west of its west edge
+fn wrap(lon: f64) -> f64 {
+    (lon + 540.0).rem_euclid(360.0) - 180.0
+}
+
+// A KML document of one ground overlay per frame, each image being
+// "files/{timestamp}.png" and shown from its frame's time until the
+// next's, or for a single frame just marked with its time
+fn kml(title: &str, bbox: (f64, f64, f64, f64), frames: &[(i64, String)], cadence: i64) -> String {
+    let (west, south, east, north) = bbox;
+    let (west, east) = if east - west >= 360.0 { (-180.0, 180.0) } else { (wrap(west), wrap(east)) };
+    // Without float noise such as 6.100000000000023
+    let (west, south, east, north) = [west, south, east, north].map(|v| (v * 1e6).round() / 1e6).into();
+    let mut kml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<kml xmlns=\"http://www.opengis.net/kml/2.2\">\n<Document>\n  <name>{}</name>\n",
+        title
+    );
+    for (i, (time, timestamp)) in frames.iter().enumerate() {
+        let when = if frames.len() == 1 {
+            format!("<TimeStamp><when>{}</when></TimeStamp>", iso(*time))
+        } else {
+            let end = frames.get(i + 1).map_or(time + cadence, |(next, _)| *next);
+            format!("<TimeSpan><begin>{}</begin><end>{}</end></TimeSpan>", iso(*time), iso(end))
+        };
+        kml.push_str(&format!(
+            "  <GroundOverlay>\n    <name>{}</name>\n    {}\n    <Icon><href>files/{}.png</href></Icon>\n    \
+             <LatLonBox><north>{}</north><south>{}</south><east>{}</east><west>{}</west></LatLonBox>\n  </GroundOverlay>\n",
+            iso(*time),
+            when,
+            timestamp,
+            north,
+            south,
+            east,
+            west
+        ));
+    }
+    kml.push_str("</Document>\n</kml>\n");
+    kml
+}
+
+/// `GET /api/export/kmz?sat=19&t=20241016120000&bbox=-100,15,-60,35`: a
+/// frame reprojected onto `bbox` as a Google Earth ground overlay, in a
+/// KMZ. With `from` and `to` (and optionally `step`) as for
+/// `/api/frames/range` instead of `t`, it's up to 24 frames, each shown
+/// for its own time span so Google Earth's time slider animates them.
+/// Takes the same parameters as the GeoTIFF export, always in EPSG:4326.
+pub async fn handle_kmz(Query(params): Query<Params>) -> Response {
+    let request = match Request::from_params(&params) {
+        Ok(request) => request,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    let Request { layer, params, time, view } = request;
+    if view.projection != Projection::Geographic {
+        return (StatusCode::BAD_REQUEST, "KMZ overlays are always EPSG:4326").into_response();
+    }
+    Span::current().record("sat", layer.sat.as_str());
+
+    let timestamps = match (params.get("from"), params.get("to")) {
+        (None, None) => match frame_timestamp(&layer, &params, time).await {
+            Ok(timestamp) => vec![timestamp],
+            Err(response) => return response,
+        },
+        (Some(from), Some(to)) => {
+            let (Some((from, _)), Some((_, to))) = (time_bounds(from), time_bounds(to)) else {
+                return (StatusCode::BAD_REQUEST, "from and to must be times, e.g. 2024-09-26T00:00Z").into_response();
+            };
+            if from > to || days_between(day(from), day(to)).is_none() {
+                return (StatusCode::BAD_REQUEST, "from and to must be in order and at most a week apart").into_response();
+            }
+            let mut timestamps = match frames_between(&layer, &params, from, to).await {
+                Ok((timestamps, _)) => timestamps,
+                Err(response) => return response,
+            };
+            if let Some(step) = params.get("step") {
+                let Some(step) = parse_step(step) else {
+                    return (StatusCode::BAD_REQUEST, "Invalid step").into_response();
+                };
+                thin(&mut timestamps, from, step);
+            }
+            if timestamps.is_empty() {
+                return (StatusCode::NOT_FOUND, "No frames in that time range").into_response();
+            }
+            if timestamps.len() > MAX_FRAMES {
+                let message = format!("{} frames in that range, more than {}; narrow it or add a step", timestamps.len(), MAX_FRAMES);
+                return (StatusCode::BAD_REQUEST, message).into_response();
+            }
+            timestamps.iter().map(u64::to_string).collect()
+        }
+        _ => return (StatusCode::BAD_REQUEST, "from and to go together").into_response(),
+    };
+
+    let Ok(_slot) = STITCH_SLOT.acquire().await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response();
+    };
+    let mut frames = Vec::with_capacity(timestamps.len());
+    let mut files = vec![(String::new(), Vec::new())];
+    let mut missing = 0;
+    for timestamp in timestamps {
+        let reprojection = match reproject(&layer, &timestamp, view, MAX_SOURCE_TILES).await {
+            Ok(reprojection) => reprojection,
+            Err(response) => return response,
+        };
+        missing += reprojection.missing;
+        let image = reprojection.image;
+        let Ok(png) = tokio::task::spawn_blocking(move || reproject::png(image)).await else {
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image").into_response();
+        };
+        files.push((format!("files/{}.png", timestamp), png));
+        frames.push((parse_time(&timestamp).unwrap_or(0), timestamp));
+    }
+
+    let title = match satellites::find(&layer.sat) {
+        Some(satellite) => format!("{} {}", satellite.name, satellites::product_name(&layer.product)),
+        None => layer.sat.clone(),
+    };
+    let cadence = satellites::sector_cadence(&layer.sat, &layer.sector).unwrap_or(600) as i64;
+    // Google Earth reads the first file as the document
+    files[0] = ("doc.kml".to_string(), kml(&title, view.bbox, &frames, cadence).into_bytes());
+    let kmz = zip::stored(&files, frames.last().map_or_else(now, |(time, _)| *time));
+    info!(sat = layer.sat, frames = frames.len(), width = view.width, height = view.height, missing, bytes = kmz.len(), "Exported KMZ");
+
+    let (first, last) = (&frames[0].1, &frames[frames.len() - 1].1);
+    let filename = if first == last {
+        format!("peepsat-{}-{}-{}.kmz", layer.sat, layer.product, first)
+    } else {
+        format!("peepsat-{}-{}-{}-{}.kmz", layer.sat, layer.product, first, last)
+    };
+    // Only a single frame asked for by time is sure to stay the same
+    let cache_control = if missing == 0 && time.is_some() && !params.contains_key("from") {
+        format!("public, max-age={}, immutable", CONFIG.tile_max_age)
+    } else {
+        "no-cache".to_string()
+    };
+    let response = (
+        [
+            (header::CONTENT_TYPE, "application/vnd.google-earth.kmz".to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}\"", filename)),
+            (header::CACHE_CONTROL, cache_control),
+            (MISSING_HEADER, missing.to_string()),
+        ],
+        kmz,
+    )
+        .into_response();
+    let response = if offline::enabled() { offline::mark(response) } else { response };
+    layer.mark(response)
+}
+
+/// `GET /api/crop?sat=19&t=20241016120000&bbox=-98,18,-80,31&width=1920`:
+/// a frame reprojected onto `bbox` and scaled to `width` pixels across,
+/// as one ready-made image; `format` is `png` (the default, transparent
+/// where the satellite can't see), `webp` or `avif` (likewise) or `jpeg`
+/// (black there). Takes the same parameters as the GeoTIFF export.
+pub async fn handle_crop(Query(params): Query<Params>) -> Response {
+    let jpeg = match params.get("format").map(String::as_str) {
+        // WebP and AVIF are the PNG re-encoded by transcode::layer
+        None | Some("png") | Some("webp") | Some("avif") => false,
+        Some("jpeg") | Some("jpg") => true,
+        Some(_) => return (StatusCode::BAD_REQUEST, "format must be png, jpeg, webp or avif").into_response(),
+    };
+    let request = match Request::from_params(&params) {
+        Ok(request) => request,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    let Request { layer, params, time, view } = request;
+    Span::current().record("sat", layer.sat.as_str());
+    let timestamp = match frame_timestamp(&layer, &params, time).await {
+        Ok(timestamp) => timestamp,
+        Err(response) => return response,
+    };
+
+    let Ok(_slot) = STITCH_SLOT.acquire().await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed").into_response();
+    };
+    let reprojection = match reproject(&layer, &timestamp, view, MAX_SOURCE_TILES).await {
+        Ok(reprojection) => reprojection,
+        Err(response) => return response,
+    };
+    let missing = reprojection.missing;
+    let image = reprojection.image;
+    let encode = move || {
+        if !jpeg {
+            return Ok(reproject::png(image));
+        }
+        let mut out = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(image).to_rgb8())
+            .write_to(&mut out, ImageOutputFormat::Jpeg(JPEG_QUALITY))
+            .map(|_| out.into_inner())
+    };
+    let image = match tokio::task::spawn_blocking(encode).await {
+        Ok(Ok(image)) => image,
+        _ => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image").into_response(),
+    };
+    info!(sat = layer.sat, timestamp, width = view.width, height = view.height, missing, bytes = image.len(), "Cropped frame");
+
+    let cache_control = if missing == 0 && time.is_some() {
+        format!("public, max-age={}, immutable", CONFIG.tile_max_age)
+    } else {
+        "no-cache".to_string()
+    };
+    let content_type = if jpeg { "image/jpeg" } else { "image/png" };
+    let response = (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CACHE_CONTROL, cache_control),
+            (MISSING_HEADER, missing.to_string()),
+        ],
+        image,
+    )
+        .into_response();
+    let response = if offline::enabled() { offline::mark(response) } else { response };
+    layer.mark(response)
+}
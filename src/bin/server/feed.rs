@@ -0,0 +1,142 @@
+use std::fmt::Write;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tracing::{info, Span};
+use crate::cache::DEFAULT_SECTOR;
+use crate::config::CONFIG;
+use crate::dates::{iso, parse_time};
+use crate::frames::{timestamps_in, Layer};
+use crate::wmts::{base_url, escape};
+use crate::{auth, offline, satellites, slider_latest, Params};
+
+const DEFAULT_LIMIT: usize = 24;
+const MAX_LIMIT: usize = 100;
+// Width of the preview in each entry
+const PREVIEW_SIZE: u32 = 512;
+// Feed readers poll on their own schedule; this just keeps a burst of
+// them from each listing the frames
+const MAX_AGE: u64 = 60;
+
+// Where links in the feed point: `public_url`, or the server as the feed
+// reader reached it
+fn feed_base(headers: &HeaderMap) -> String {
+    let base = CONFIG.public_url.clone().unwrap_or_else(|| format!("{}{}", base_url(headers), CONFIG.base_path));
+    base.trim_end_matches('/').to_string()
+}
+
+// The query naming `layer`, with the token the feed was asked for with so
+// links work behind authentication
+fn layer_query(layer: &Layer, token: Option<&String>) -> String {
+    let mut query = format!("sat={}&sector={}&product={}", layer.sat, layer.sector, layer.product);
+    if let Some(token) = token {
+        query.push_str(&format!("&token={}", urlencoding::encode(token)));
+    }
+    query
+}
+
+fn atom(base: &str, layer: &Layer, title: &str, timestamps: &[u64], token: Option<&String>) -> String {
+    let id = format!("urn:peepsat:{}:{}:{}", layer.sat, layer.sector, layer.product);
+    let updated = timestamps.first().and_then(|t| parse_time(&t.to_string())).map(iso).unwrap_or_default();
+    let query = layer_query(layer, token);
+    let mut xml = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <id>{}</id>
+  <title>{}</title>
+  <updated>{}</updated>
+  <link rel="self" type="application/atom+xml" href="{}"/>
+  <link rel="alternate" type="text/html" href="{}"/>
+  <author><name>peepsat</name></author>
+  <generator>peepsat</generator>
+"#,
+        id,
+        escape(title),
+        updated,
+        escape(&format!("{}/feed.atom?{}", base, query)),
+        escape(&format!("{}/?{}", base, query)),
+    );
+    for timestamp in timestamps {
+        let text = timestamp.to_string();
+        let Some(time) = parse_time(&text).map(iso) else {
+            continue;
+        };
+        let viewer = format!("{}/?{}&t={}", base, query, text);
+        let preview = format!("{}/api/preview?{}&t={}&size={}", base, query, text, PREVIEW_SIZE);
+        let content = format!(r#"<p><a href="{}"><img src="{}" alt="{} at {}"/></a></p>"#, escape(&viewer), escape(&preview), escape(title), time);
+        let _ = write!(
+            xml,
+            r#"  <entry>
+    <id>{}:{}</id>
+    <title>{} at {}</title>
+    <updated>{}</updated>
+    <link rel="alternate" type="text/html" href="{}"/>
+    <link rel="enclosure" type="image/png" href="{}"/>
+    <content type="html">{}</content>
+  </entry>
+"#,
+            id,
+            text,
+            escape(title),
+            time,
+            time,
+            escape(&viewer),
+            escape(&preview),
+            escape(&content),
+        );
+    }
+    xml.push_str("</feed>\n");
+    xml
+}
+
+/// `GET /feed.atom?sat=19&product=band_13`: an Atom feed of a layer's most
+/// recent frames, newest first, each with a preview image and a link to
+/// the viewer at that frame, for feed readers. `limit` is how many (24 by
+/// default, at most 100); `sector`, `product` and `cdn` work as for
+/// `/slider-tile`. Links are made from `public_url` if it's set.
+pub async fn handle_feed(Query(params): Query<Params>, headers: HeaderMap) -> Response {
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let Some(satellite) = satellites::find(&layer.sat) else {
+        return (StatusCode::NOT_FOUND, format!("Unknown satellite {}", layer.sat)).into_response();
+    };
+    let limit = match params.get("limit").map(|l| l.parse::<usize>()) {
+        None => DEFAULT_LIMIT,
+        Some(Ok(limit)) if (1..=MAX_LIMIT).contains(&limit) => limit,
+        Some(_) => return (StatusCode::BAD_REQUEST, format!("limit must be from 1 to {}", MAX_LIMIT)).into_response(),
+    };
+    Span::current().record("sat", layer.sat.as_str());
+
+    let latest = slider_latest(layer.sat.clone(), layer.cdn.clone(), &params).await;
+    if !latest.status().is_success() {
+        return layer.mark(latest);
+    }
+    let from_cache = latest.headers().contains_key(offline::HEADER);
+    let Some(mut timestamps) = timestamps_in(latest).await else {
+        return layer.mark((StatusCode::BAD_GATEWAY, "Failed").into_response());
+    };
+    timestamps.sort_unstable_by(|a, b| b.cmp(a));
+    timestamps.dedup();
+    timestamps.truncate(limit);
+
+    let mut title = format!("{} {}", satellite.name, satellites::product_name(&layer.product));
+    if layer.sector != DEFAULT_SECTOR {
+        title.push_str(&format!(" ({})", layer.sector.replace('_', " ")));
+    }
+    let body = atom(&feed_base(&headers), &layer, &title, &timestamps, params.get("token"));
+    info!(sat = layer.sat, product = layer.product, frames = timestamps.len(), "Feed");
+
+    // Behind credentials, shared caches mustn't hand it to anyone else
+    let cache_control = format!("{}, max-age={}", if auth::enabled() { "private" } else { "public" }, MAX_AGE);
+    let response = (
+        [
+            (header::CONTENT_TYPE, "application/atom+xml; charset=utf-8".to_string()),
+            (header::CACHE_CONTROL, cache_control),
+        ],
+        body,
+    )
+        .into_response();
+    let response = if from_cache { offline::mark(response) } else { response };
+    layer.mark(response)
+}
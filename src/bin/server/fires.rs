@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use axum::body::Bytes;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use image::{ImageOutputFormat, Rgba, RgbaImage};
+use serde_json::json;
+use tracing::{debug, info, warn, Span};
+use crate::cache::TileFormat;
+use crate::config::CONFIG;
+use crate::dates::{self, days_from_civil, parse_time};
+use crate::upstream::{self, HTTP_CLIENT};
+use crate::{bad_gateway, geos, offline, satellites, tile_response, Params};
+
+// FIRMS adds passes to its 24-hour files as they're processed
+const REFRESH: Duration = Duration::from_secs(30 * 60);
+// The 24-hour files run to a few tens of MB in a busy fire season
+const MAX_CSV_BYTES: usize = 64 * 1024 * 1024;
+// Detections older than this before a frame aren't drawn over it
+const WINDOW: i64 = 24 * 3600;
+
+/// A FIRMS active-fire product: its name in requests and its global
+/// 24-hour CSV file.
+struct Source {
+    id: &'static str,
+    path: &'static str,
+}
+
+const SOURCES: &[Source] = &[
+    Source { id: "viirs_noaa20", path: "/data/active_fire/noaa-20-viirs-c2/csv/J1_VIIRS_C2_Global_24h.csv" },
+    Source { id: "viirs_snpp", path: "/data/active_fire/suomi-npp-viirs-c2/csv/SUOMI_VIIRS_C2_Global_24h.csv" },
+    Source { id: "modis", path: "/data/active_fire/modis-c6.1/csv/MODIS_C6_1_Global_24h.csv" },
+];
+
+/// One hotspot detection.
+struct Fire {
+    lat: f64,
+    lon: f64,
+    /// Overpass time, seconds since the epoch
+    time: i64,
+    /// Fire radiative power, MW
+    frp: f64,
+    /// "l", "n", "h" for VIIRS; 0-100 for MODIS
+    confidence: String,
+}
+
+// A source's detections and when they were fetched
+type Detections = (Instant, Arc<Vec<Fire>>);
+
+lazy_static::lazy_static! {
+    static ref FIRES: Mutex<HashMap<&'static str, Detections>> = Mutex::new(HashMap::new());
+}
+
+// Detections from a FIRMS CSV file, by column name since VIIRS and MODIS
+// order theirs differently
+fn parse_csv(text: &str) -> Vec<Fire> {
+    let mut lines = text.lines();
+    let header: Vec<&str> = lines.next().unwrap_or_default().split(',').map(str::trim).collect();
+    let column = |name: &str| header.iter().position(|h| *h == name);
+    let (Some(lat), Some(lon), Some(date), Some(time)) = (column("latitude"), column("longitude"), column("acq_date"), column("acq_time")) else {
+        return Vec::new();
+    };
+    let (frp, confidence) = (column("frp"), column("confidence"));
+    lines
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+            let mut ymd = fields.get(date)?.split('-').map(|v| v.parse::<i64>());
+            let days = days_from_civil(ymd.next()?.ok()?, ymd.next()?.ok()?, ymd.next()?.ok()?);
+            // HHMM, sometimes without its leading zeros
+            let hhmm: i64 = fields.get(time)?.parse().ok()?;
+            Some(Fire {
+                lat: fields.get(lat)?.parse().ok()?,
+                lon: fields.get(lon)?.parse().ok()?,
+                time: days * 86400 + hhmm / 100 * 3600 + hhmm % 100 * 60,
+                frp: frp.and_then(|i| fields.get(i)?.parse().ok()).unwrap_or(0.0),
+                confidence: confidence.and_then(|i| fields.get(i)).unwrap_or(&"").to_string(),
+            })
+        })
+        .collect()
+}
+
+// A source's detections over the past day, fetched again once they're
+// `REFRESH` old. If FIRMS can't be reached, the last ones keep being used.
+async fn fires(source: &'static Source) -> Result<Arc<Vec<Fire>>, &'static str> {
+    let cached = FIRES.lock().unwrap().get(source.id).cloned();
+    if let Some((fetched, fires)) = &cached {
+        if fetched.elapsed() < REFRESH {
+            return Ok(fires.clone());
+        }
+    }
+    let url = format!("{}{}", CONFIG.firms_url, source.path);
+    debug!(url, "Fetching fire detections");
+    let fetched = match upstream::get(&HTTP_CLIENT, &[url]).await {
+        Ok(r) if r.status().is_success() => upstream::read_limited(r, MAX_CSV_BYTES).await.map_err(|e| e.to_string()),
+        Ok(r) => Err(format!("status {}", r.status().as_u16())),
+        Err(e) => Err(e.to_string()),
+    };
+    let body = match fetched {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(source = source.id, error = %e, "Fire detections unavailable");
+            return cached.map(|(_, fires)| fires).ok_or("Fire detections unavailable");
+        }
+    };
+    let fires = Arc::new(tokio::task::spawn_blocking(move || parse_csv(&String::from_utf8_lossy(&body))).await.map_err(|_| "Failed")?);
+    info!(source = source.id, fires = fires.len(), "Updated fire detections");
+    FIRES.lock().unwrap().insert(source.id, (Instant::now(), fires.clone()));
+    Ok(fires)
+}
+
+// The `source` a request names, VIIRS on NOAA-20 by default
+fn source(params: &Params) -> Option<&'static Source> {
+    match params.get("source") {
+        Some(id) => SOURCES.iter().find(|s| s.id == id),
+        None => SOURCES.first(),
+    }
+}
+
+// The detections window ending at frame time `t`, or the whole file;
+// `None` if `t` isn't a time
+fn window(params: &Params) -> Option<(i64, i64)> {
+    match params.get("t") {
+        Some(t) => parse_time(t).map(|end| (end - WINDOW, end)),
+        None => Some((i64::MIN, i64::MAX)),
+    }
+}
+
+/// `GET /api/fires`: FIRMS hotspots from the past 24 hours as GeoJSON
+/// points. `source` is `viirs_noaa20` (the default), `viirs_snpp` or
+/// `modis`; `sat` keeps those on that satellite's disk and `t` those
+/// detected in the day before a frame.
+pub async fn handle_fires(Query(params): Query<Params>) -> Response {
+    let Some(source) = source(&params) else {
+        return (StatusCode::BAD_REQUEST, "Unknown source").into_response();
+    };
+    let satellite = match params.get("sat") {
+        Some(sat) => match satellites::find(sat) {
+            Some(satellite) => Some(satellite),
+            None => return (StatusCode::BAD_REQUEST, "Unknown satellite").into_response(),
+        },
+        None => None,
+    };
+    let Some((start, end)) = window(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid t").into_response();
+    };
+    if offline::enabled() {
+        return offline::unavailable();
+    }
+    let fires = match fires(source).await {
+        Ok(fires) => fires,
+        Err(message) => return bad_gateway(message),
+    };
+    let features: Vec<_> = fires
+        .iter()
+        .filter(|f| (start..=end).contains(&f.time))
+        .filter(|f| satellite.is_none_or(|s| geos::pixel(s, 0, f.lat, f.lon).is_some()))
+        .map(|f| {
+            json!({
+                "type": "Feature",
+                "geometry": { "type": "Point", "coordinates": [f.lon, f.lat] },
+                "properties": { "time": dates::iso(f.time), "frp_mw": f.frp, "confidence": f.confidence },
+            })
+        })
+        .collect();
+    (
+        [(header::CONTENT_TYPE, "application/geo+json"), (header::CACHE_CONTROL, "no-cache")],
+        json!({ "type": "FeatureCollection", "features": features }).to_string(),
+    )
+        .into_response()
+}
+
+// Marker radius in pixels, larger for hotter fires and deeper zooms
+fn radius(frp: f64, zoom: u32) -> f64 {
+    1.5 + zoom as f64 * 0.5 + (frp.max(0.0) + 1.0).log10()
+}
+
+/// One transparent tile of hotspot markers in a satellite's full-disk grid,
+/// with the same `sat`, `x` (row), `y` (column) and `z` as `/slider-tile`,
+/// and `t` and `source` as for `/api/fires`.
+pub async fn handle_fire_tile(Query(params): Query<Params>, headers: HeaderMap) -> Response {
+    let sat = params.get("sat").cloned().unwrap_or_else(|| CONFIG.default_satellite.clone());
+    let Some(satellite) = satellites::find(&sat) else {
+        return (StatusCode::BAD_REQUEST, "Unknown satellite").into_response();
+    };
+    let Some(source) = source(&params) else {
+        return (StatusCode::BAD_REQUEST, "Unknown source").into_response();
+    };
+    let zoom: u32 = params.get("z").and_then(|s| s.parse().ok()).unwrap_or(0).min(satellite.max_zoom);
+    let x: u32 = params.get("x").and_then(|s| s.parse().ok()).unwrap_or(0);
+    let y: u32 = params.get("y").and_then(|s| s.parse().ok()).unwrap_or(0);
+    if x >= 1 << zoom || y >= 1 << zoom {
+        return (StatusCode::BAD_REQUEST, "Tile out of range").into_response();
+    }
+    let Some((start, end)) = window(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid t").into_response();
+    };
+    let span = Span::current();
+    span.record("sat", sat.as_str());
+    span.record("x", x);
+    span.record("y", y);
+    span.record("z", zoom);
+    if offline::enabled() {
+        return offline::unavailable();
+    }
+
+    let fires = match fires(source).await {
+        Ok(fires) => fires,
+        Err(message) => return bad_gateway(message),
+    };
+    let tile_size = satellite.tile_size;
+    let render = move || {
+        let mut image = RgbaImage::new(tile_size, tile_size);
+        let (top, left) = ((x * tile_size) as f64, (y * tile_size) as f64);
+        for fire in fires.iter().filter(|f| (start..=end).contains(&f.time)) {
+            let Some((row, column)) = geos::pixel(satellite, zoom, fire.lat, fire.lon) else {
+                continue;
+            };
+            let (cy, cx, r) = (row - top, column - left, radius(fire.frp, zoom));
+            if cy < -r - 1.0 || cx < -r - 1.0 || cy > tile_size as f64 + r || cx > tile_size as f64 + r {
+                continue;
+            }
+            // An orange dot with a dark rim, to stand out on smoke and cloud
+            let (y0, y1) = ((cy - r - 1.0).max(0.0) as u32, ((cy + r + 1.0).ceil() as u32).min(tile_size));
+            let (x0, x1) = ((cx - r - 1.0).max(0.0) as u32, ((cx + r + 1.0).ceil() as u32).min(tile_size));
+            for py in y0..y1 {
+                for px in x0..x1 {
+                    let distance = (px as f64 + 0.5 - cx).hypot(py as f64 + 0.5 - cy);
+                    if distance <= r {
+                        image.put_pixel(px, py, Rgba([255, 96, 0, 235]));
+                    } else if distance <= r + 1.0 && image.get_pixel(px, py)[3] == 0 {
+                        image.put_pixel(px, py, Rgba([60, 0, 0, 200]));
+                    }
+                }
+            }
+        }
+        let mut png = Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgba8(image).write_to(&mut png, ImageOutputFormat::Png).expect("PNG encoding to memory");
+        png.into_inner()
+    };
+    let png = match tokio::task::spawn_blocking(render).await {
+        Ok(png) => Bytes::from(png),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to render tile").into_response(),
+    };
+    // New passes keep arriving, so the browser revalidates by ETag
+    tile_response(png, TileFormat::Png, "MISS", "0", &headers)
+}
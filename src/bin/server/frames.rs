@@ -0,0 +1,459 @@
+use std::collections::HashSet;
+use axum::body::{to_bytes, Bytes};
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::future::join_all;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{debug, warn, Span};
+use crate::cache;
+use crate::config::CONFIG;
+use crate::satellites::{Satellite, Status, SATELLITES};
+use crate::prefetch::{collect_timestamps, days_between};
+use crate::upstream::{self, HTTP_CLIENT};
+use crate::{
+    bad_gateway, dates, eumetsat, get_cdn_url, get_layer, is_nict_cdn, json_cache, mark_source, noaa, offline, satellite_id,
+    satellites, slider_dates, slider_latest, source_name, Params, NICT_GRID, NICT_PRODUCT,
+};
+
+const DEFAULT_COUNT: usize = 24;
+// SLIDER's latest_times.json rarely lists more
+const MAX_COUNT: usize = 200;
+// Frame lists are a few KB, a day's listing a little more
+const MAX_LIST_BYTES: usize = 1024 * 1024;
+// Scans either side of a time to look through for its nearest frame, so a
+// few missed ones don't leave nothing to snap to
+const NEAREST_SCANS: i64 = 6;
+// How far from the reference time other satellites' frames may be, by
+// default and at most
+const DEFAULT_SYNC_TOLERANCE: i64 = 15 * 60;
+const MAX_SYNC_TOLERANCE: i64 = 6 * 3600;
+
+#[derive(Deserialize)]
+struct AvailableDates {
+    dates_int: Vec<u32>,
+}
+
+/// The satellite, upstream, sector and product a request is about.
+pub struct Layer {
+    pub sat: String,
+    pub cdn: String,
+    pub sector: String,
+    pub product: String,
+}
+
+impl Layer {
+    /// From the `sat`, `cdn`, `sector` and `product` (or `band`) query
+    /// parameters; `None` if the satellite lacks the sector or product.
+    pub fn from_params(params: &Params) -> Option<Layer> {
+        let sat = params.get("sat").cloned().unwrap_or_else(|| CONFIG.default_satellite.clone());
+        let cdn = get_cdn_url(&sat, params);
+        let (sector, product) = get_layer(&sat, &cdn, params)?;
+        Some(Layer { sat, cdn, sector, product })
+    }
+
+    /// Deepest zoom level the layer's tiles go to, and their size.
+    pub fn tile_grid(&self) -> (u32, u32) {
+        if self.product == NICT_PRODUCT {
+            return NICT_GRID;
+        }
+        let (max_zoom, tile_size) = satellites::sector_grid(&self.sat, &self.sector).unwrap_or((4, 678));
+        (max_zoom.saturating_sub(satellites::product_zoom_reduction(&self.sat, &self.product)), tile_size)
+    }
+
+    // The zoom asked for with `z` (the deepest by default) and that zoom
+    // level's grid
+    fn grid(&self, params: &Params) -> (u32, Value) {
+        let (max_zoom, tile_size) = self.tile_grid();
+        let zoom = params.get("z").and_then(|z| z.parse().ok()).unwrap_or(max_zoom).min(max_zoom);
+        Span::current().record("z", zoom);
+        let grid = json!({
+            "zoom": zoom,
+            "max_zoom": max_zoom,
+            "tiles_per_side": 1u32 << zoom,
+            "tile_size": tile_size,
+            "tiles": 1u64 << (2 * zoom),
+        });
+        (zoom, grid)
+    }
+
+    // One frame as the manifests list it: its timestamp, the date its
+    // tiles are filed under, and how many of them are cached at `zoom`
+    fn frame(&self, timestamp: u64, zoom: u32) -> Value {
+        let text = timestamp.to_string();
+        let cached = cache::cached_tiles(&self.sat, &self.sector, &self.product, &text, zoom);
+        let tiles = 1u64 << (2 * zoom);
+        json!({
+            "timestamp": timestamp,
+            "date": text.get(..8).and_then(|d| d.parse::<u32>().ok()),
+            "time": dates::parse_time(&text).map(dates::iso),
+            "cached_tiles": cached,
+            "cache": if cached == tiles { "full" } else if cached > 0 { "partial" } else { "none" },
+        })
+    }
+
+    // `body` with the layer's sat, sector and product added, and the
+    // headers of the frame lists it was made from
+    fn respond(&self, mut body: Value, from_cache: bool) -> Response {
+        body["sat"] = json!(self.sat);
+        body["sector"] = json!(self.sector);
+        body["product"] = json!(self.product);
+        let response = Json(body).into_response();
+        let response = if from_cache { offline::mark(response) } else { response };
+        self.mark(response)
+    }
+
+    /// Tag `response` with the layer's source and satellite status.
+    pub fn mark(&self, response: Response) -> Response {
+        satellites::mark_status(&self.sat, mark_source(&self.cdn, response))
+    }
+}
+
+/// The timestamps in a frame list response; `None` if it isn't one.
+pub async fn timestamps_in(response: Response) -> Option<Vec<u64>> {
+    if !response.status().is_success() {
+        return None;
+    }
+    let body = to_bytes(response.into_body(), MAX_LIST_BYTES).await.ok()?;
+    let json: Value = serde_json::from_slice(&body).ok()?;
+    let mut timestamps = Vec::new();
+    collect_timestamps(&json, &mut timestamps);
+    Some(timestamps)
+}
+
+// The days in an available_dates.json response
+async fn dates_in(response: Response) -> Option<HashSet<u32>> {
+    if !response.status().is_success() {
+        return None;
+    }
+    let body = to_bytes(response.into_body(), MAX_LIST_BYTES).await.ok()?;
+    serde_json::from_slice::<AvailableDates>(&body).ok().map(|d| d.dates_int.into_iter().collect())
+}
+
+/// `GET /api/frames?sat=19&count=24&z=2`: the newest `count` frames,
+/// oldest first, each with its date (the `d` of `/slider-tile`) and how
+/// many of its tiles at zoom `z` are cached, along with that zoom's grid.
+/// Takes the same `sector`, `product` and `cdn` as `/slider-latest`, and
+/// carries the same headers, so an animation can be planned in one request.
+pub async fn handle_frames(Query(params): Query<Params>) -> Response {
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let count = params.get("count").and_then(|c| c.parse().ok()).unwrap_or(DEFAULT_COUNT).clamp(1, MAX_COUNT);
+    let (zoom, grid) = layer.grid(&params);
+
+    let latest = slider_latest(layer.sat.clone(), layer.cdn.clone(), &params).await;
+    if !latest.status().is_success() {
+        return layer.mark(latest);
+    }
+    let from_cache = latest.headers().contains_key(offline::HEADER);
+    let Some(mut timestamps) = timestamps_in(latest).await else {
+        return bad_gateway("Invalid frame list");
+    };
+    // The newest `count`, played oldest first
+    timestamps.sort_unstable_by(|a, b| b.cmp(a));
+    timestamps.dedup();
+    timestamps.truncate(count);
+    timestamps.reverse();
+
+    let frames: Vec<Value> = timestamps.iter().map(|&t| layer.frame(t, zoom)).collect();
+    layer.respond(json!({ "grid": grid, "frames": frames }), from_cache)
+}
+
+/// Seconds since the epoch of the start and end of what an ISO 8601 time
+/// or YYYYMMDD[HH[MM[SS]]] timestamp names, in UTC: "2024-09-26" covers
+/// the whole day and "2024-09-26T00:00Z" that minute.
+pub fn time_bounds(text: &str) -> Option<(i64, i64)> {
+    let digits: String = text.chars().filter(char::is_ascii_digit).collect();
+    let span = match digits.len() {
+        8 => 86400,
+        10 => 3600,
+        12 => 60,
+        14 => 1,
+        _ => return None,
+    };
+    let start = dates::parse_time(&format!("{:0<14}", digits))?;
+    Some((start, start + span - 1))
+}
+
+/// Seconds in a step such as "30m", "2h", "90s" or plain seconds.
+pub fn parse_step(text: &str) -> Option<i64> {
+    let (number, unit) = match text.strip_suffix(['s', 'm', 'h']) {
+        Some(number) => (number, &text[number.len()..]),
+        None => (text, "s"),
+    };
+    let seconds = number.parse::<i64>().ok()? * if unit == "h" { 3600 } else if unit == "m" { 60 } else { 1 };
+    (seconds > 0).then_some(seconds)
+}
+
+/// The YYYYMMDD day of a time.
+pub fn day(seconds: i64) -> u32 {
+    dates::timestamp(seconds)[..8].parse().unwrap_or(0)
+}
+
+async fn fetch_day(targets: Vec<String>) -> Result<Bytes, &'static str> {
+    debug!(url = %targets[0], "Fetching day listing");
+    match upstream::get(&HTTP_CLIENT, &targets).await {
+        Ok(r) if r.status().is_success() => r.bytes().await.map_err(|_| "Failed"),
+        Ok(r) => {
+            warn!(status = r.status().as_u16(), "Slider day listing unavailable");
+            Err("Failed")
+        }
+        Err(e) => {
+            warn!(error = %e, "Slider day listing failed");
+            Err("Failed")
+        }
+    }
+}
+
+// SLIDER's frames of one day (YYYYMMDD), kept like the other frame lists
+async fn day_frames(layer: &Layer, day: u32) -> Option<Vec<u64>> {
+    let product = satellites::listed_product(&layer.product);
+    let path = format!("/data/json/{}/{}/{}/{}_by_hour_max.json", satellite_id(&layer.sat), layer.sector, product, day);
+    let targets = upstream::with_fallbacks(&layer.cdn, &path);
+    let key = format!("day {} {} {} {} {}", layer.cdn, layer.sat, layer.sector, product, day);
+    timestamps_in(json_cache::get(key, || fetch_day(targets)).await).await
+}
+
+/// The frames of `layer` from `from` to `to` (seconds since the epoch,
+/// spanning at most a week), oldest first, and whether they were listed
+/// from the cache alone; or, if no list could be had, the response to
+/// pass on. SLIDER's available dates say which days to look at and those
+/// days' listings what's in them. The latest frames are always included,
+/// since the day listings can lag them by a few minutes and are all there
+/// is offline or from NICT, EUMETView and NOAA's archive.
+pub async fn frames_between(layer: &Layer, params: &Params, from: i64, to: i64) -> Result<(Vec<u64>, bool), Response> {
+    let latest = slider_latest(layer.sat.clone(), layer.cdn.clone(), params).await;
+    let from_cache = latest.headers().contains_key(offline::HEADER);
+    let latest_status = latest.status();
+    let mut timestamps = timestamps_in(latest).await;
+    if !offline::enabled() && !is_nict_cdn(&layer.cdn) && !eumetsat::is_source(&layer.cdn) && !noaa::is_source(&layer.cdn) {
+        // If the dates can't be had, every day is asked about
+        let available = dates_in(slider_dates(layer.sat.clone(), layer.cdn.clone(), params).await).await;
+        let days = days_between(day(from), day(to)).unwrap_or_default();
+        let wanted: Vec<u32> = days.into_iter().filter(|d| available.as_ref().is_none_or(|a| a.contains(d))).collect();
+        let listed = join_all(wanted.iter().map(|&d| day_frames(layer, d))).await;
+        for list in listed.into_iter().flatten() {
+            timestamps.get_or_insert_with(Vec::new).extend(list);
+        }
+    }
+    let Some(mut timestamps) = timestamps else {
+        let status = if latest_status.is_success() { StatusCode::BAD_GATEWAY } else { latest_status };
+        return Err(layer.mark((status, "Failed").into_response()));
+    };
+
+    let (first, last) = (dates::timestamp(from).parse::<u64>().unwrap_or(0), dates::timestamp(to).parse::<u64>().unwrap_or(0));
+    timestamps.retain(|t| (first..=last).contains(t));
+    timestamps.sort_unstable();
+    timestamps.dedup();
+    Ok((timestamps, from_cache))
+}
+
+/// Keep only the first of `timestamps` (oldest first) in each `step`
+/// seconds after `from`.
+pub fn thin(timestamps: &mut Vec<u64>, from: i64, step: i64) {
+    let mut last_bucket = None;
+    timestamps.retain(|t| {
+        let bucket = dates::parse_time(&t.to_string()).map(|seconds| (seconds - from) / step);
+        bucket != std::mem::replace(&mut last_bucket, bucket)
+    });
+}
+
+/// `GET /api/frames/range?sat=19&from=2024-09-26T00:00Z&to=2024-09-27T00:00Z&step=30m`:
+/// the frames upstream has between `from` and `to` (at most a week apart),
+/// found from SLIDER's available dates and each of those days' listings,
+/// in the same form as `/api/frames`. With `step`, only the first frame of
+/// each step after `from` is kept. Offline, and for NICT and EUMETView,
+/// it's whatever their frame lists still reach.
+pub async fn handle_frame_range(Query(params): Query<Params>) -> Response {
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let (Some((from, _)), Some((_, to))) =
+        (params.get("from").and_then(|f| time_bounds(f)), params.get("to").and_then(|t| time_bounds(t)))
+    else {
+        return (StatusCode::BAD_REQUEST, "from and to must be times, e.g. 2024-09-26T00:00Z").into_response();
+    };
+    if from > to {
+        return (StatusCode::BAD_REQUEST, "from must not be after to").into_response();
+    }
+    let step = match params.get("step") {
+        Some(step) => match parse_step(step) {
+            Some(seconds) => Some(seconds),
+            None => return (StatusCode::BAD_REQUEST, "Invalid step").into_response(),
+        },
+        None => None,
+    };
+    if days_between(day(from), day(to)).is_none() {
+        return (StatusCode::BAD_REQUEST, "time range longer than a week").into_response();
+    }
+    let (zoom, grid) = layer.grid(&params);
+
+    let (mut timestamps, from_cache) = match frames_between(&layer, &params, from, to).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    if let Some(step) = step {
+        thin(&mut timestamps, from, step);
+    }
+
+    let frames: Vec<Value> = timestamps.iter().map(|&t| layer.frame(t, zoom)).collect();
+    let body = json!({
+        "from": dates::iso(from),
+        "to": dates::iso(to),
+        "step_seconds": step,
+        "grid": grid,
+        "frames": frames,
+    });
+    layer.respond(body, from_cache)
+}
+
+// Seconds since the epoch of a frame's timestamp
+fn seconds(timestamp: u64) -> i64 {
+    dates::parse_time(&timestamp.to_string()).unwrap_or(0)
+}
+
+// The last of `timestamps` (oldest first) at or before `t` and the first after it
+fn around(timestamps: &[u64], t: i64) -> (Option<u64>, Option<u64>) {
+    let before = timestamps.iter().rev().find(|&&f| seconds(f) <= t).copied();
+    let after = timestamps.iter().find(|&&f| seconds(f) > t).copied();
+    (before, after)
+}
+
+// Whichever of `before` and `after` is closer to `t`, the earlier on a tie
+fn nearest(before: Option<u64>, after: Option<u64>, t: i64) -> Option<u64> {
+    match (before, after) {
+        (Some(b), Some(a)) => Some(if t - seconds(b) <= seconds(a) - t { b } else { a }),
+        (b, a) => b.or(a),
+    }
+}
+
+/// `GET /api/frames/nearest?sat=himawari&t=2024-07-04T05:12Z`: the last
+/// frame at or before `t` and the first after it, looking up to six scans
+/// either way by the sector's cadence, and whichever is `nearest`, in the
+/// form of `/api/frames`. 404 if there's neither.
+pub async fn handle_nearest_frame(Query(params): Query<Params>) -> Response {
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let Some((t, _)) = params.get("t").and_then(|t| time_bounds(t)) else {
+        return (StatusCode::BAD_REQUEST, "t must be a time, e.g. 2024-07-04T05:12Z").into_response();
+    };
+    let cadence = satellites::sector_cadence(&layer.sat, &layer.sector).unwrap_or(600) as i64;
+    let window = cadence * NEAREST_SCANS;
+    let (zoom, grid) = layer.grid(&params);
+
+    let (timestamps, from_cache) = match frames_between(&layer, &params, t - window, t + window).await {
+        Ok(found) => found,
+        Err(response) => return response,
+    };
+    let (before, after) = around(&timestamps, t);
+    let Some(nearest) = nearest(before, after, t) else {
+        let message = format!("No frame within {} minutes of {}", window / 60, dates::iso(t));
+        return layer.mark((StatusCode::NOT_FOUND, message).into_response());
+    };
+
+    let body = json!({
+        "t": dates::iso(t),
+        "cadence_seconds": cadence,
+        "before": before.map(|f| layer.frame(f, zoom)),
+        "after": after.map(|f| layer.frame(f, zoom)),
+        "nearest": layer.frame(nearest, zoom),
+        "offset_seconds": seconds(nearest) - t,
+        "grid": grid,
+    });
+    layer.respond(body, from_cache)
+}
+
+/// `layer`'s frame nearest `t` within `tolerance` seconds either way, if
+/// there is one, and whether the frame lists were the cache's alone.
+pub async fn nearest_within(layer: &Layer, params: &Params, t: i64, tolerance: i64) -> Result<(Option<u64>, bool), Response> {
+    let (timestamps, from_cache) = frames_between(layer, params, t - tolerance, t + tolerance).await?;
+    let (before, after) = around(&timestamps, t);
+    Ok((nearest(before, after, t), from_cache))
+}
+
+/// `params` for `satellite` instead: its own sector and product where it
+/// lacks the ones asked for.
+pub fn params_for(satellite: &Satellite, params: &Params) -> Params {
+    let mut params = params.clone();
+    params.insert("sat".to_string(), satellite.key.to_string());
+    if params.get("sector").is_some_and(|s| satellites::sector_grid(satellite.key, s).is_none()) {
+        params.remove("sector");
+    }
+    let band = params.get("band").map(|b| format!("band_{:0>2}", b));
+    if band.as_ref().or(params.get("product")).is_some_and(|p| !satellites::has_product(satellite.key, p)) {
+        params.remove("band");
+        params.remove("product");
+    }
+    params
+}
+
+/// `GET /api/frames/sync?t=2024-09-26T18:00Z&tolerance=15m&sats=19,18,himawari`:
+/// for each satellite (every operational one by default), its frame nearest
+/// `t` if one is within `tolerance` (15 minutes by default, at most 6 hours),
+/// so views of several satellites show the same moment. `sector` and
+/// `product` apply where a satellite has them; the others get their own.
+pub async fn handle_frame_sync(Query(params): Query<Params>) -> Response {
+    let Some((t, _)) = params.get("t").and_then(|t| time_bounds(t)) else {
+        return (StatusCode::BAD_REQUEST, "t must be a time, e.g. 2024-09-26T18:00Z").into_response();
+    };
+    let tolerance = match params.get("tolerance") {
+        Some(tolerance) => match parse_step(tolerance).filter(|&s| s <= MAX_SYNC_TOLERANCE) {
+            Some(seconds) => seconds,
+            None => return (StatusCode::BAD_REQUEST, "Invalid tolerance").into_response(),
+        },
+        None => DEFAULT_SYNC_TOLERANCE,
+    };
+    let chosen: Vec<&Satellite> = match params.get("sats") {
+        Some(sats) => {
+            let mut chosen = Vec::new();
+            for sat in sats.split(',').filter(|s| !s.is_empty()) {
+                match satellites::find(sat) {
+                    Some(satellite) => chosen.push(satellite),
+                    None => return (StatusCode::BAD_REQUEST, "Unknown satellite").into_response(),
+                }
+            }
+            chosen
+        }
+        None => SATELLITES.iter().filter(|s| s.status == Status::Operational).collect(),
+    };
+
+    let matches = join_all(chosen.iter().map(|satellite| async {
+        let params = params_for(satellite, &params);
+        let layer = Layer::from_params(&params)?;
+        let (zoom, _) = layer.grid(&params);
+        let (frame, from_cache) = nearest_within(&layer, &params, t, tolerance).await.ok()?;
+        let entry = json!({
+            "sat": satellite.key,
+            "name": satellite.name,
+            "sector": layer.sector,
+            "product": layer.product,
+            "source": source_name(&layer.cdn),
+            "frame": frame.map(|f| layer.frame(f, zoom)),
+            "offset_seconds": frame.map(|f| seconds(f) - t),
+        });
+        Some((entry, from_cache))
+    }))
+    .await;
+
+    let from_cache = matches.iter().flatten().any(|(_, from_cache)| *from_cache);
+    // Satellites whose frame lists couldn't be had are listed without a frame
+    let entries: Vec<Value> = chosen
+        .iter()
+        .zip(matches)
+        .map(|(satellite, found)| match found {
+            Some((entry, _)) => entry,
+            None => json!({ "sat": satellite.key, "name": satellite.name, "frame": null, "offset_seconds": null }),
+        })
+        .collect();
+    let response = Json(json!({
+        "t": dates::iso(t),
+        "tolerance_seconds": tolerance,
+        "satellites": entries,
+    }))
+    .into_response();
+    if from_cache { offline::mark(response) } else { response }
+}
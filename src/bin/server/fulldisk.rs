@@ -0,0 +1,278 @@
+use std::io::Cursor;
+use axum::body::Bytes;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, HeaderName, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::future::join_all;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use tokio::sync::Semaphore;
+use tracing::{info, warn, Span};
+use crate::cache::{cache_key, get_cached_tile, get_negative, put_cached_tile, TileFormat};
+use crate::config::CONFIG;
+use crate::dates::parse_time;
+use crate::frames::{timestamps_in, Layer};
+use crate::reproject::frame_timestamp;
+use crate::satellites::DAY_NIGHT_PRODUCT;
+use crate::upstream::{Priority, HTTP_CLIENT, NICT_CLIENT};
+use crate::{
+    bad_gateway, cached_tile_response, coalesce, daynight, eumetsat, fetch_tile_coalesced, is_nict_cdn, noaa, offline, slider_latest,
+    slider_tile_targets, tile_response, Params, Tile,
+};
+
+/// Widest image stitched: zoom 3 of every full disk, about 90 MB as RGB.
+pub const MAX_SIDE: u32 = 8192;
+// Tiles of one image downloaded at once
+const CONCURRENCY: usize = 8;
+pub const JPEG_QUALITY: u8 = 90;
+// Previews are made from zoom 0 or 1, whichever is first at least as big,
+// and cached as "preview_{sector}/{product}/{sat}_{timestamp}_{zoom}_{size}_0"
+const PREVIEW_MAX_ZOOM: u32 = 1;
+const DEFAULT_PREVIEW_SIZE: u32 = 256;
+const MAX_PREVIEW_SIZE: u32 = 512;
+
+/// Set to how many tiles couldn't be had and were left black.
+pub const MISSING_HEADER: HeaderName = HeaderName::from_static("x-peepsat-missing-tiles");
+
+lazy_static::lazy_static! {
+    // Stitched images are large, so they're made one at a time
+    pub static ref STITCH_SLOT: Semaphore = Semaphore::new(1);
+    static ref DOWNLOADS: Semaphore = Semaphore::new(CONCURRENCY);
+}
+
+/// A tile from the cache, or else from upstream (caching it); `None` if
+/// neither has it. Day/night tiles are blended from the tiles of the
+/// products they're made of.
+pub async fn tile_bytes(cdn: &str, tile: Tile<'_>, date: &str) -> Option<Bytes> {
+    if tile.product != DAY_NIGHT_PRODUCT {
+        return source_tile_bytes(cdn, tile, date).await;
+    }
+    let key = tile.key();
+    if let Some((data, _)) = get_cached_tile(&key).await {
+        return Some(data);
+    }
+    coalesce(&key, daynight::render(cdn, &tile, date, &key)).await.ok().map(|(_, data)| data)
+}
+
+/// A tile SLIDER (or whichever upstream `cdn` is) serves, from the cache or
+/// else upstream, caching it; `None` if neither has it.
+pub async fn source_tile_bytes(cdn: &str, tile: Tile<'_>, date: &str) -> Option<Bytes> {
+    let key = tile.key();
+    if let Some((data, _)) = get_cached_tile(&key).await {
+        return Some(data);
+    }
+    if offline::enabled() || get_negative(&key).is_some() {
+        return None;
+    }
+    let _permit = DOWNLOADS.acquire().await.ok()?;
+    let result = if eumetsat::is_source(cdn) {
+        coalesce(&key, eumetsat::fetch_tile(&tile, &key, Priority::Interactive)).await
+    } else if noaa::is_source(cdn) {
+        coalesce(&key, noaa::fetch_tile(&tile, &key)).await
+    } else {
+        let targets = slider_tile_targets(cdn, &tile, date);
+        let client = if is_nict_cdn(cdn) { &*NICT_CLIENT } else { &*HTTP_CLIENT };
+        fetch_tile_coalesced(client, &targets, &key, Priority::Interactive).await
+    };
+    match result {
+        Ok((status, bytes)) if status.is_success() && !bytes.is_empty() => Some(bytes),
+        _ => None,
+    }
+}
+
+/// The tiles of a frame's full disk at `zoom`, row by row as SLIDER
+/// numbers them (x the row, y the column), from the cache or upstream;
+/// `None` for those that couldn't be had.
+pub async fn disk_tiles(layer: &Layer, timestamp: &str, zoom: u32) -> Vec<Option<Bytes>> {
+    let date = &timestamp[..8];
+    let tiles_per_side = 1u32 << zoom;
+    join_all((0..tiles_per_side * tiles_per_side).map(|i| {
+        let tile = Tile {
+            sat: &layer.sat,
+            sector: &layer.sector,
+            product: &layer.product,
+            timestamp,
+            zoom,
+            x: i / tiles_per_side,
+            y: i % tiles_per_side,
+        };
+        tile_bytes(&layer.cdn, tile, date)
+    }))
+    .await
+}
+
+/// `disk_tiles` laid out on one image `side` pixels across, those missing
+/// or undecodable left black.
+pub fn assemble(tiles: &[Option<Bytes>], side: u32, tile_size: u32) -> RgbImage {
+    let tiles_per_side = side / tile_size;
+    let mut image = RgbImage::new(side, side);
+    for (i, data) in tiles.iter().enumerate() {
+        let Some(data) = data else {
+            continue;
+        };
+        let (x, y) = (i as u32 / tiles_per_side, i as u32 % tiles_per_side);
+        match image::load_from_memory(data) {
+            Ok(tile) => image::imageops::replace(&mut image, &tile.to_rgb8(), (y * tile_size) as i64, (x * tile_size) as i64),
+            Err(e) => warn!(x, y, error = %e, "Undecodable tile left out of full disk"),
+        }
+    }
+    image
+}
+
+// The tiles stitched and encoded
+fn stitch(tiles: Vec<Option<Bytes>>, side: u32, tile_size: u32, jpeg: bool) -> Result<Vec<u8>, String> {
+    let image = assemble(&tiles, side, tile_size);
+    let format = if jpeg { ImageOutputFormat::Jpeg(JPEG_QUALITY) } else { ImageOutputFormat::Png };
+    let mut out = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(image).write_to(&mut out, format).map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
+
+/// `GET /api/fulldisk?sat=19&t=20241016120000&z=3&format=png`: every tile
+/// of a frame at zoom `z`, from the cache or upstream, stitched into one
+/// image. `t` defaults to the newest frame and `z` to the deepest zoom
+/// that fits in 8192 pixels across; `format` is `png`, `jpeg`, `webp` or
+/// `avif`. Takes the same `sector`, `product` and `cdn` as `/slider-tile`. Tiles
+/// that can't be had are left black and counted in
+/// `X-Peepsat-Missing-Tiles`.
+pub async fn handle_fulldisk(Query(params): Query<Params>) -> Response {
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let jpeg = match params.get("format").map(String::as_str) {
+        // WebP and AVIF are the PNG re-encoded by transcode::layer
+        None | Some("png") | Some("webp") | Some("avif") => false,
+        Some("jpeg") | Some("jpg") => true,
+        Some(_) => return (StatusCode::BAD_REQUEST, "format must be png, jpeg, webp or avif").into_response(),
+    };
+    let (max_zoom, tile_size) = layer.tile_grid();
+    let deepest = (0..=max_zoom).rev().find(|&z| tile_size << z <= MAX_SIDE).unwrap_or(0);
+    let zoom = params.get("z").and_then(|z| z.parse().ok()).unwrap_or(deepest).min(deepest);
+    let span = Span::current();
+    span.record("sat", layer.sat.as_str());
+    span.record("z", zoom);
+
+    let timestamp = match params.get("t") {
+        Some(t) if t.len() == 14 && t.bytes().all(|b| b.is_ascii_digit()) => t.clone(),
+        Some(_) => return (StatusCode::BAD_REQUEST, "t must be a frame timestamp, e.g. 20241016120000").into_response(),
+        None => {
+            let latest = slider_latest(layer.sat.clone(), layer.cdn.clone(), &params).await;
+            if !latest.status().is_success() {
+                return layer.mark(latest);
+            }
+            match timestamps_in(latest).await.and_then(|t| t.into_iter().max()) {
+                Some(t) => t.to_string(),
+                None => return bad_gateway("No frames listed"),
+            }
+        }
+    };
+    let Ok(_slot) = STITCH_SLOT.acquire().await else {
+        return bad_gateway("Failed");
+    };
+    let tiles = disk_tiles(&layer, &timestamp, zoom).await;
+    let missing = tiles.iter().filter(|t| t.is_none()).count();
+    if missing == tiles.len() {
+        return if offline::enabled() { offline::unavailable() } else { bad_gateway("No tiles for that frame") };
+    }
+
+    let side = tile_size << zoom;
+    let image = match tokio::task::spawn_blocking(move || stitch(tiles, side, tile_size, jpeg)).await {
+        Ok(Ok(image)) => image,
+        Ok(Err(e)) => {
+            warn!(error = %e, "Full disk encoding failed");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image").into_response();
+        }
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image").into_response(),
+    };
+    info!(sat = layer.sat, timestamp, zoom, side, missing, bytes = image.len(), "Stitched full disk");
+
+    // Complete frames never change, like their tiles
+    let cache_control = if missing == 0 && params.contains_key("t") {
+        format!("public, max-age={}, immutable", CONFIG.tile_max_age)
+    } else {
+        "no-cache".to_string()
+    };
+    let content_type = if jpeg { "image/jpeg" } else { "image/png" };
+    let response = (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CACHE_CONTROL, cache_control),
+            (MISSING_HEADER, missing.to_string()),
+        ],
+        image,
+    )
+        .into_response();
+    let response = if offline::enabled() { offline::mark(response) } else { response };
+    layer.mark(response)
+}
+
+// The tiles shrunk to `size` pixels across, as PNG
+fn shrink(tiles: Vec<Option<Bytes>>, side: u32, tile_size: u32, size: u32) -> Vec<u8> {
+    let disk = assemble(&tiles, side, tile_size);
+    let image = imageops::resize(&disk, size, size, FilterType::Triangle);
+    let mut out = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(image).write_to(&mut out, ImageOutputFormat::Png).expect("PNG encoding to memory");
+    out.into_inner()
+}
+
+/// `GET /api/preview?sat=19&t=20241016120000&size=256`: a frame's full
+/// disk `size` pixels across (256 by default, at most 512) from its zoom 0
+/// or 1 tiles, for thumbnails. `t` defaults to the newest frame; takes the
+/// same `sector`, `product` and `cdn` as `/slider-tile`. Complete previews
+/// are cached like tiles.
+pub async fn handle_preview(Query(params): Query<Params>, headers: HeaderMap) -> Response {
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let size = match params.get("size").map(|s| s.parse::<u32>()) {
+        None => DEFAULT_PREVIEW_SIZE,
+        Some(Ok(size)) if (16..=MAX_PREVIEW_SIZE).contains(&size) => size,
+        Some(_) => return (StatusCode::BAD_REQUEST, format!("size must be from 16 to {}", MAX_PREVIEW_SIZE)).into_response(),
+    };
+    let (max_zoom, tile_size) = layer.tile_grid();
+    let zoom = (0..=max_zoom.min(PREVIEW_MAX_ZOOM)).find(|&z| tile_size << z >= size).unwrap_or(max_zoom.min(PREVIEW_MAX_ZOOM));
+    let span = Span::current();
+    span.record("sat", layer.sat.as_str());
+    span.record("z", zoom);
+
+    let time = match params.get("t") {
+        Some(t) => match parse_time(t) {
+            Some(time) => Some(time),
+            None => return (StatusCode::BAD_REQUEST, "t must be a frame timestamp, e.g. 20241016120000").into_response(),
+        },
+        None => None,
+    };
+    let timestamp = match frame_timestamp(&layer, &params, time).await {
+        Ok(timestamp) => timestamp,
+        Err(response) => return response,
+    };
+    // The newest frame moves on, so only fixed frames are kept by the browser
+    let response_timestamp = if time.is_none() { "0" } else { timestamp.as_str() };
+
+    let key = cache_key(&layer.sat, &format!("preview_{}", layer.sector), &layer.product, &timestamp, zoom, size, 0);
+    if let Some((data, format)) = get_cached_tile(&key).await {
+        span.record("cache", "HIT");
+        let response = cached_tile_response(data, format, response_timestamp, &headers).await;
+        let response = if offline::enabled() { offline::mark(response) } else { response };
+        return layer.mark(response);
+    }
+    span.record("cache", "MISS");
+
+    let tiles = disk_tiles(&layer, &timestamp, zoom).await;
+    let missing = tiles.iter().filter(|t| t.is_none()).count();
+    if missing == tiles.len() {
+        return if offline::enabled() { offline::unavailable() } else { bad_gateway("No tiles for that frame") };
+    }
+    let Ok(png) = tokio::task::spawn_blocking(move || shrink(tiles, tile_size << zoom, tile_size, size)).await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image").into_response();
+    };
+    let png = Bytes::from(png);
+    // Previews with holes are made again once the rest of the frame turns up
+    if missing == 0 {
+        put_cached_tile(&key, &png).await;
+    }
+    let mut response = tile_response(png, TileFormat::Png, "MISS", if missing == 0 { response_timestamp } else { "0" }, &headers);
+    response.headers_mut().insert(MISSING_HEADER, missing.into());
+    let response = if offline::enabled() { offline::mark(response) } else { response };
+    layer.mark(response)
+}
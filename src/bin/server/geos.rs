@@ -0,0 +1,122 @@
+use std::io::Cursor;
+use image::{ImageOutputFormat, Rgb, RgbImage};
+use crate::satellites::Satellite;
+use crate::Tile;
+
+// Earth and orbit per the CGMS geostationary projection, km
+const ORBIT_RADIUS: f64 = 42164.0;
+const EQUATOR_RADIUS: f64 = 6378.169;
+const POLAR_RADIUS: f64 = 6356.5838;
+
+/// Latitude and longitude seen at scan angles `x` (east) and `y` (north),
+/// in radians, from a satellite over `sub_lon`; `None` off the Earth's disk.
+fn geos_to_lat_lon(x: f64, y: f64, sub_lon: f64) -> Option<(f64, f64)> {
+    let flattening = (EQUATOR_RADIUS / POLAR_RADIUS).powi(2);
+    let (cos_x, cos_y, sin_y) = (x.cos(), y.cos(), y.sin());
+    let a = cos_y * cos_y + flattening * sin_y * sin_y;
+    let b = ORBIT_RADIUS * cos_x * cos_y;
+    let discriminant = b * b - a * (ORBIT_RADIUS * ORBIT_RADIUS - EQUATOR_RADIUS * EQUATOR_RADIUS);
+    if discriminant < 0.0 {
+        return None;
+    }
+    let sn = (b - discriminant.sqrt()) / a;
+    let s1 = ORBIT_RADIUS - sn * cos_x * cos_y;
+    let s2 = sn * x.sin() * cos_y;
+    let s3 = sn * sin_y;
+    let lat = (flattening * s3 / s1.hypot(s2)).atan().to_degrees();
+    let lon = (s2 / s1).atan().to_degrees() + sub_lon;
+    Some((lat, (lon + 540.0).rem_euclid(360.0) - 180.0))
+}
+
+/// Scan angles `x` (east) and `y` (north), in radians, at which a
+/// satellite over `sub_lon` sees `lat`, `lon`; `None` if it's over the limb.
+fn lat_lon_to_geos(lat: f64, lon: f64, sub_lon: f64) -> Option<(f64, f64)> {
+    let eccentricity = 1.0 - (POLAR_RADIUS / EQUATOR_RADIUS).powi(2);
+    let flattening = (EQUATOR_RADIUS / POLAR_RADIUS).powi(2);
+    let geocentric = (lat.to_radians().tan() / flattening).atan();
+    let radius = POLAR_RADIUS / (1.0 - eccentricity * geocentric.cos().powi(2)).sqrt();
+    let delta = (lon - sub_lon).to_radians();
+    let p1 = radius * geocentric.cos() * delta.cos();
+    let p2 = radius * geocentric.cos() * delta.sin();
+    let p3 = radius * geocentric.sin();
+    // Facing away from the satellite
+    if ORBIT_RADIUS * p1 <= p1 * p1 + p2 * p2 + flattening * p3 * p3 {
+        return None;
+    }
+    let d1 = ORBIT_RADIUS - p1;
+    Some(((p2 / d1).atan(), (p3 / (d1 * d1 + p2 * p2 + p3 * p3).sqrt()).asin()))
+}
+
+/// Half the scan angle a full disk spans, degrees. SLIDER's full-disk
+/// tiles cover the imager's whole frame, a little beyond the Earth's limb.
+pub fn scan_half_angle(satellite: &Satellite) -> f64 {
+    match satellite.key {
+        "19" | "18" | "16" | "ewsg1" | "ewsg2" => 8.7,
+        "himawari" | "gk2a" => 8.8,
+        // SEVIRI and FCI, and close enough for the rest
+        _ => 8.915,
+    }
+}
+
+/// How far the Earth reaches across `satellite`'s full-disk image, east
+/// to west and north to south, as fractions of the image's width.
+pub fn limb(satellite: &Satellite) -> (f64, f64) {
+    let half_angle = scan_half_angle(satellite).to_radians();
+    let reach = |radius: f64| (radius / ORBIT_RADIUS).asin() / half_angle;
+    (reach(EQUATOR_RADIUS), reach(POLAR_RADIUS))
+}
+
+/// Latitude and longitude of every pixel of a full-disk tile of
+/// `satellite`, row by row. SLIDER names tiles row first, so `tile.x` is
+/// the row and `tile.y` the column.
+pub fn tile_coordinates(tile: &Tile, satellite: &Satellite) -> Vec<Option<(f64, f64)>> {
+    let (tile_size, sub_lon) = (satellite.tile_size, satellite.longitude);
+    let full = (tile_size << tile.zoom) as f64;
+    let step = (2.0 * scan_half_angle(satellite)).to_radians() / full;
+    let mut coordinates = Vec::with_capacity((tile_size * tile_size) as usize);
+    for py in 0..tile_size {
+        let row = (tile.x * tile_size + py) as f64 + 0.5;
+        for px in 0..tile_size {
+            let column = (tile.y * tile_size + px) as f64 + 0.5;
+            coordinates.push(geos_to_lat_lon((column - full / 2.0) * step, (full / 2.0 - row) * step, sub_lon));
+        }
+    }
+    coordinates
+}
+
+/// Where `lat`, `lon` falls in `satellite`'s full disk at `zoom`, as
+/// fractional (row, column) pixels across all its tiles; `None` if it
+/// can't be seen.
+pub fn pixel(satellite: &Satellite, zoom: u32, lat: f64, lon: f64) -> Option<(f64, f64)> {
+    let full = (satellite.tile_size << zoom) as f64;
+    let step = (2.0 * scan_half_angle(satellite)).to_radians() / full;
+    let (x, y) = lat_lon_to_geos(lat, lon, satellite.longitude)?;
+    Some((full / 2.0 - y / step, full / 2.0 + x / step))
+}
+
+/// The (south, west, north, east) box around a tile's coordinates.
+pub fn bounds(coordinates: &[Option<(f64, f64)>]) -> (f64, f64, f64, f64) {
+    let mut bbox = (90.0f64, 180.0f64, -90.0f64, -180.0f64);
+    for &(lat, lon) in coordinates.iter().flatten() {
+        bbox = (bbox.0.min(lat), bbox.1.min(lon), bbox.2.max(lat), bbox.3.max(lon));
+    }
+    bbox
+}
+
+/// Resample an EPSG:4326 image covering `bbox` (south, west, north, east)
+/// onto a tile's pixels as PNG; space is black, as in SLIDER's tiles.
+pub fn render(map: &RgbImage, bbox: (f64, f64, f64, f64), coordinates: &[Option<(f64, f64)>], tile_size: u32) -> Vec<u8> {
+    let (south, west, north, east) = bbox;
+    let (width, height) = (map.width() as f64, map.height() as f64);
+    let tile = RgbImage::from_fn(tile_size, tile_size, |px, py| {
+        let Some((lat, lon)) = coordinates[(py * tile_size + px) as usize] else {
+            return Rgb([0, 0, 0]);
+        };
+        let u = ((lon - west) / (east - west).max(f64::EPSILON) * width) as u32;
+        let v = ((north - lat) / (north - south).max(f64::EPSILON) * height) as u32;
+        *map.get_pixel(u.min(map.width() - 1), v.min(map.height() - 1))
+    });
+    let mut out = Cursor::new(Vec::new());
+    image::DynamicImage::ImageRgb8(tile).write_to(&mut out, ImageOutputFormat::Png).expect("PNG encoding to memory");
+    out.into_inner()
+}
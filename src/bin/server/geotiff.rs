@@ -0,0 +1,180 @@
+use std::io::Write;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+use crate::reproject::{mercator_y, Projection};
+
+// Tiles of the image and its overviews, which stop once they fit in one
+const TILE_SIDE: u32 = 256;
+// WGS 84's semi-major axis, which Web Mercator's metres are measured on
+const EARTH_RADIUS: f64 = 6378137.0;
+
+// TIFF field types
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const DOUBLE: u16 = 12;
+
+// One field of an image file directory: tag, type, count and its value's
+// little-endian bytes
+struct Field(u16, u16, u32, Vec<u8>);
+
+fn shorts(tag: u16, values: &[u16]) -> Field {
+    Field(tag, SHORT, values.len() as u32, values.iter().flat_map(|v| v.to_le_bytes()).collect())
+}
+
+fn longs(tag: u16, values: &[u32]) -> Field {
+    Field(tag, LONG, values.len() as u32, values.iter().flat_map(|v| v.to_le_bytes()).collect())
+}
+
+fn doubles(tag: u16, values: &[f64]) -> Field {
+    Field(tag, DOUBLE, values.len() as u32, values.iter().flat_map(|v| v.to_le_bytes()).collect())
+}
+
+// An image cut into deflated tiles, padded out to whole tiles at the
+// right and bottom edges
+fn tiles(image: &RgbaImage) -> Vec<Vec<u8>> {
+    let (across, down) = (image.width().div_ceil(TILE_SIDE), image.height().div_ceil(TILE_SIDE));
+    let mut tiles = Vec::with_capacity((across * down) as usize);
+    for row in 0..down {
+        for column in 0..across {
+            let mut tile = RgbaImage::new(TILE_SIDE, TILE_SIDE);
+            let view = imageops::crop_imm(image, column * TILE_SIDE, row * TILE_SIDE, TILE_SIDE, TILE_SIDE);
+            imageops::replace(&mut tile, &*view, 0, 0);
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(tile.as_raw()).expect("deflating to memory");
+            tiles.push(encoder.finish().expect("deflating to memory"));
+        }
+    }
+    tiles
+}
+
+// The fields GeoTIFF adds to say where the image is: its pixel size and
+// top-left corner in the CRS, and the CRS itself
+fn georeferencing(projection: Projection, bbox: (f64, f64, f64, f64), width: u32, height: u32) -> Vec<Field> {
+    let (west, south, east, north) = bbox;
+    let (left, bottom, right, top, keys) = match projection {
+        // GTModelType geographic, GeographicType EPSG:4326
+        Projection::Geographic => (west, south, east, north, [1024, 0, 1, 2, 1025, 0, 1, 1, 2048, 0, 1, 4326]),
+        // GTModelType projected, ProjectedCSType EPSG:3857
+        Projection::Mercator => {
+            let metres = |degrees: f64| degrees.to_radians() * EARTH_RADIUS;
+            let (bottom, top) = (mercator_y(south) * EARTH_RADIUS, mercator_y(north) * EARTH_RADIUS);
+            (metres(west), bottom, metres(east), top, [1024, 0, 1, 1, 1025, 0, 1, 1, 3072, 0, 1, 3857])
+        }
+    };
+    // Key directory version 1.1.0 with three keys, raster type being
+    // pixel-is-area
+    let mut directory = vec![1, 1, 0, 3];
+    directory.extend_from_slice(&keys);
+    vec![
+        doubles(33550, &[(right - left) / width as f64, (top - bottom) / height as f64, 0.0]),
+        doubles(33922, &[0.0, 0.0, 0.0, left, top, 0.0]),
+        shorts(34735, &directory),
+    ]
+}
+
+/// `image`, covering `bbox` (west, south, east, north, in degrees) in
+/// `projection`, as a Cloud-Optimized GeoTIFF: deflated 256-pixel RGBA
+/// tiles, halved overviews down to a single tile, and every directory at
+/// the start of the file ahead of the tile data, smallest overview first.
+pub fn cloud_optimized(image: RgbaImage, projection: Projection, bbox: (f64, f64, f64, f64)) -> Vec<u8> {
+    let (width, height) = image.dimensions();
+    let mut levels = vec![image];
+    loop {
+        let last = levels.last().expect("the full image");
+        if last.width() <= TILE_SIDE && last.height() <= TILE_SIDE {
+            break;
+        }
+        let half = imageops::resize(last, last.width().div_ceil(2), last.height().div_ceil(2), FilterType::Triangle);
+        levels.push(half);
+    }
+
+    // Each level's directory, tile offsets to be filled in
+    let mut directories = Vec::new();
+    let mut data = Vec::new();
+    for (i, level) in levels.iter().enumerate() {
+        let tiles = tiles(level);
+        let counts: Vec<u32> = tiles.iter().map(|t| t.len() as u32).collect();
+        let mut fields = vec![
+            // An overview of the first image, or that image
+            longs(254, &[(i > 0) as u32]),
+            longs(256, &[level.width()]),
+            longs(257, &[level.height()]),
+            shorts(258, &[8, 8, 8, 8]),
+            // Deflate
+            shorts(259, &[8]),
+            // RGB
+            shorts(262, &[2]),
+            shorts(277, &[4]),
+            // Chunky
+            shorts(284, &[1]),
+            longs(322, &[TILE_SIDE]),
+            longs(323, &[TILE_SIDE]),
+            longs(324, &vec![0; tiles.len()]),
+            longs(325, &counts),
+            // The fourth sample is unassociated alpha
+            shorts(338, &[2]),
+        ];
+        if i == 0 {
+            fields.extend(georeferencing(projection, bbox, width, height));
+        }
+        directories.push(fields);
+        data.push(tiles);
+    }
+
+    // Directories take 2 bytes, 12 a field and 4 for the next one's offset,
+    // plus their values that don't fit in 4 bytes, kept at even offsets
+    let directory_size = |fields: &[Field]| {
+        6 + 12 * fields.len() + fields.iter().map(|f| if f.3.len() > 4 { f.3.len().next_multiple_of(2) } else { 0 }).sum::<usize>()
+    };
+    let mut offset = 8 + directories.iter().map(|d| directory_size(d)).sum::<usize>();
+    // Tile data goes smallest overview first, so a reader wanting only
+    // an overview reads one short stretch
+    for (fields, tiles) in directories.iter_mut().zip(&data).rev() {
+        let mut offsets = Vec::with_capacity(tiles.len());
+        for tile in tiles {
+            offsets.push(offset as u32);
+            offset += tile.len();
+        }
+        let slot = fields.iter_mut().find(|f| f.0 == 324).expect("tile offsets field");
+        *slot = longs(324, &offsets);
+    }
+
+    let mut out = Vec::with_capacity(offset);
+    // Little-endian classic TIFF, the first directory right after
+    out.extend_from_slice(b"II");
+    out.extend_from_slice(&42u16.to_le_bytes());
+    out.extend_from_slice(&8u32.to_le_bytes());
+    let count = directories.len();
+    for (i, fields) in directories.iter().enumerate() {
+        let start = out.len();
+        let mut values_at = start + 6 + 12 * fields.len();
+        let mut values = Vec::new();
+        out.extend_from_slice(&(fields.len() as u16).to_le_bytes());
+        for Field(tag, kind, count, bytes) in fields {
+            out.extend_from_slice(&tag.to_le_bytes());
+            out.extend_from_slice(&kind.to_le_bytes());
+            out.extend_from_slice(&count.to_le_bytes());
+            if bytes.len() <= 4 {
+                let mut inline = [0u8; 4];
+                inline[..bytes.len()].copy_from_slice(bytes);
+                out.extend_from_slice(&inline);
+            } else {
+                out.extend_from_slice(&(values_at as u32).to_le_bytes());
+                values.extend_from_slice(bytes);
+                values.resize(values.len().next_multiple_of(2), 0);
+                values_at = start + 6 + 12 * fields.len() + values.len();
+            }
+        }
+        let next = if i + 1 < count { start + directory_size(fields) } else { 0 };
+        out.extend_from_slice(&(next as u32).to_le_bytes());
+        out.extend_from_slice(&values);
+    }
+    for tiles in data.iter().rev() {
+        for tile in tiles {
+            out.extend_from_slice(tile);
+        }
+    }
+    out
+}
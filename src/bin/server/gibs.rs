@@ -0,0 +1,166 @@
+use axum::body::Bytes;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tracing::{debug, warn, Span};
+use crate::cache::{cache_key, get_cached_tile, get_negative, put_cached_tile, put_negative, TileFormat};
+use crate::config::CONFIG;
+use crate::dates::{self, civil_from_days, days_from_civil};
+use crate::upstream::{self, Priority, HTTP_CLIENT};
+use crate::{bad_gateway, cached_tile_response, coalesce, metrics, offline, tile_response, transcode, Params, UpstreamTile, MAX_TILE_BYTES};
+
+// GIBS tiles are cached as "epsg3857/{layer}/gibs_{YYYYMMDD}_{z}_{x}_{y}",
+// apart from every satellite's
+const CACHE_SECTOR: &str = "epsg3857";
+const CACHE_SAT: &str = "gibs";
+
+/// A GIBS layer served by `/gibs-tile`, from the Web Mercator tile set.
+struct Layer {
+    /// Name used in requests and cache keys
+    id: &'static str,
+    /// GIBS layer identifier
+    identifier: &'static str,
+    matrix_set: &'static str,
+    max_zoom: u32,
+    /// Extension of the files GIBS serves, "jpg" or "png"
+    format: &'static str,
+    /// First day with imagery, YYYYMMDD
+    first_date: u32,
+    /// The one date of a layer that never changes
+    fixed_date: Option<u32>,
+}
+
+const LAYERS: &[Layer] = &[
+    Layer { id: "viirs_snpp", identifier: "VIIRS_SNPP_CorrectedReflectance_TrueColor", matrix_set: "GoogleMapsCompatible_Level9", max_zoom: 9, format: "jpg", first_date: 20151124, fixed_date: None },
+    Layer { id: "viirs_noaa20", identifier: "VIIRS_NOAA20_CorrectedReflectance_TrueColor", matrix_set: "GoogleMapsCompatible_Level9", max_zoom: 9, format: "jpg", first_date: 20180101, fixed_date: None },
+    Layer { id: "modis_terra", identifier: "MODIS_Terra_CorrectedReflectance_TrueColor", matrix_set: "GoogleMapsCompatible_Level9", max_zoom: 9, format: "jpg", first_date: 20000224, fixed_date: None },
+    Layer { id: "modis_aqua", identifier: "MODIS_Aqua_CorrectedReflectance_TrueColor", matrix_set: "GoogleMapsCompatible_Level9", max_zoom: 9, format: "jpg", first_date: 20020703, fixed_date: None },
+    Layer { id: "black_marble", identifier: "VIIRS_Black_Marble", matrix_set: "GoogleMapsCompatible_Level8", max_zoom: 8, format: "png", first_date: 20160101, fixed_date: Some(20160101) },
+];
+
+// Today's UTC date as YYYYMMDD, `offset` days away
+fn utc_date(offset: i64) -> u32 {
+    let (year, month, day) = civil_from_days(dates::now() / 86400 + offset);
+    (year * 10000 + month * 100 + day) as u32
+}
+
+// YYYYMMDD from "YYYY-MM-DD" or "YYYYMMDD", if it's a real date
+fn parse_date(text: &str) -> Option<u32> {
+    let digits: String = text.chars().filter(char::is_ascii_digit).collect();
+    if digits.len() != 8 || digits.len() + 2 < text.len() {
+        return None;
+    }
+    let date: u32 = digits.parse().ok()?;
+    let (year, month, day) = ((date / 10000) as i64, (date / 100 % 100) as i64, (date % 100) as i64);
+    // Out-of-range days roll over into the next month
+    (civil_from_days(days_from_civil(year, month, day)) == (year, month, day)).then_some(date)
+}
+
+/// Proxy one GIBS tile: `/gibs-tile?layer=viirs_snpp&date=2024-06-01&z=3&x=2&y=1`,
+/// x and y counting columns and rows of the Web Mercator grid. Daily layers
+/// default to yesterday, the newest complete day; today's tiles fill in as
+/// passes arrive, so they're neither cached here nor by the browser.
+pub async fn handle_gibs_tile(Query(params): Query<Params>, headers: HeaderMap) -> Response {
+    let Some(layer) = params.get("layer").and_then(|id| LAYERS.iter().find(|l| l.id == id)) else {
+        return (StatusCode::BAD_REQUEST, "Unknown layer").into_response();
+    };
+    let zoom: u32 = params.get("z").and_then(|s| s.parse().ok()).unwrap_or(0);
+    let x: u32 = params.get("x").and_then(|s| s.parse().ok()).unwrap_or(0);
+    let y: u32 = params.get("y").and_then(|s| s.parse().ok()).unwrap_or(0);
+    if zoom > layer.max_zoom || x >= 1 << zoom || y >= 1 << zoom {
+        return (StatusCode::BAD_REQUEST, "Tile out of range").into_response();
+    }
+    let today = utc_date(0);
+    let date = match (layer.fixed_date, params.get("date")) {
+        (Some(date), _) => date,
+        (None, Some(text)) => match parse_date(text) {
+            Some(date) if (layer.first_date..=today).contains(&date) => date,
+            _ => return (StatusCode::BAD_REQUEST, "Invalid date").into_response(),
+        },
+        (None, None) => utc_date(-1),
+    };
+    let complete = date < today;
+
+    let span = Span::current();
+    span.record("sat", layer.id);
+    span.record("x", x);
+    span.record("y", y);
+    span.record("z", zoom);
+
+    let timestamp = date.to_string();
+    // "0" keeps the browser from holding on to tiles that are still changing
+    let browser_timestamp = if complete { timestamp.as_str() } else { "0" };
+    let key = cache_key(CACHE_SAT, CACHE_SECTOR, layer.id, &timestamp, zoom, x, y);
+    if let Some((data, format)) = get_cached_tile(&key).await {
+        span.record("cache", "HIT");
+        let response = cached_tile_response(data, format, browser_timestamp, &headers).await;
+        return if offline::enabled() { offline::mark(response) } else { response };
+    }
+    if offline::enabled() {
+        span.record("cache", "MISS");
+        return offline::unavailable();
+    }
+    if let Some(status) = get_negative(&key) {
+        span.record("cache", "NEGATIVE");
+        metrics::NEGATIVE_CACHE_HITS.inc();
+        return (status, [(header::HeaderName::from_static("x-cache"), "NEGATIVE"), (header::CACHE_CONTROL, "no-cache")]).into_response();
+    }
+
+    span.record("cache", "MISS");
+    match coalesce(&key, fetch_tile(layer, date, zoom, x, y, &key, complete)).await {
+        Ok((status, bytes)) if status.is_success() => tile_response(bytes, TileFormat::Png, "MISS", browser_timestamp, &headers),
+        Ok((status, bytes)) => (status, bytes).into_response(),
+        Err(()) => bad_gateway("Failed"),
+    }
+}
+
+// Download a tile from GIBS as PNG, caching it if the day is complete
+async fn fetch_tile(layer: &Layer, date: u32, zoom: u32, x: u32, y: u32, key: &str, cache: bool) -> UpstreamTile {
+    let _slot = upstream::acquire(Priority::Interactive).await;
+    // WMTS REST order is TileMatrix/TileRow/TileCol
+    let url = format!(
+        "{}/wmts/epsg3857/best/{}/default/{:04}-{:02}-{:02}/{}/{}/{}/{}.{}",
+        CONFIG.gibs_url, layer.identifier, date / 10000, date / 100 % 100, date % 100, layer.matrix_set, zoom, y, x, layer.format,
+    );
+    debug!(key, url, "Fetching GIBS tile");
+    let response = match upstream::get(&HTTP_CLIENT, &[url]).await {
+        Ok(r) => r,
+        Err(e) => {
+            warn!(key, error = %e, "GIBS tile fetch failed");
+            return Err(());
+        }
+    };
+    let status = response.status();
+    let body = match upstream::read_limited(response, MAX_TILE_BYTES).await {
+        Ok(body) => body,
+        Err(e) => {
+            warn!(key, error = %e, "GIBS tile download failed");
+            return Err(());
+        }
+    };
+    Span::current().record("upstream_status", status.as_u16());
+    if !status.is_success() {
+        if status == StatusCode::NOT_FOUND {
+            put_negative(key, status);
+        }
+        return Ok((status, body));
+    }
+
+    // The true-colour layers are JPEG; the cache and the viewer deal in PNG
+    let png = if layer.format == "png" {
+        body
+    } else {
+        match tokio::task::spawn_blocking(move || transcode::to_png(&body)).await {
+            Ok(Ok(png)) => Bytes::from(png),
+            Ok(Err(e)) => {
+                warn!(key, error = %e, "GIBS returned no image");
+                return Err(());
+            }
+            Err(_) => return Err(()),
+        }
+    };
+    if cache {
+        put_cached_tile(key, &png).await;
+    }
+    Ok((StatusCode::OK, png))
+}
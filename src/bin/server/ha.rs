@@ -0,0 +1,105 @@
+use axum::extract::{Path, Query};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tracing::{debug, Span};
+use crate::dates::{http_date, parse_time};
+use crate::frames::Layer;
+use crate::mjpeg::{self, frame_jpeg};
+use crate::reproject::frame_timestamp;
+use crate::{auth, bad_gateway, etag_matches, offline, satellites, tile_etag, Params};
+
+// Named sizes, so camera configs needn't spell out pixels
+const SIZES: [(&str, u32); 3] = [("small", 512), ("medium", 1024), ("large", 2048)];
+const DEFAULT_SIZE: u32 = 1024;
+// How long a still may be shown before it's asked for again. New frames
+// come every few minutes at best, so this is about how late one can be.
+const STILL_MAX_AGE: u64 = 30;
+
+// The request with satellite `sat` from the path, which must be one
+fn camera_params(sat: &str, mut params: Params) -> Result<Params, String> {
+    if satellites::find(sat).is_none() {
+        return Err(format!("Unknown satellite {}", sat));
+    }
+    params.insert("sat".to_string(), sat.to_string());
+    Ok(params)
+}
+
+// The width and height `size` asks for, one of the named sizes. Arbitrary
+// ones would each get a JPEG of their own in the shared cache.
+fn image_size(params: &Params) -> Result<(u32, u32), String> {
+    let Some(size) = params.get("size") else {
+        return Ok((DEFAULT_SIZE, DEFAULT_SIZE));
+    };
+    SIZES
+        .iter()
+        .find(|(name, _)| name == size)
+        .map(|&(_, side)| (side, side))
+        .ok_or_else(|| "size must be small, medium or large".to_string())
+}
+
+/// `GET /ha/camera/{sat}/latest.jpg?size=medium`: the newest full disk as
+/// a JPEG, at a stable URL for Home Assistant's generic camera and the
+/// like. `size` is `small` (512x512), `medium` (1024x1024, the default)
+/// or `large` (2048x2048), the disk fitted inside it on black; `sector`
+/// and `product` work as for `/slider-tile`. It may be kept for 30
+/// seconds, and then revalidated by its ETag; `Last-Modified` is the
+/// frame's time. Images are shared with `/stream.mjpeg`.
+pub async fn handle_still(Path(sat): Path<String>, Query(params): Query<Params>, headers: HeaderMap) -> Response {
+    let params = match camera_params(&sat, params) {
+        Ok(params) => params,
+        Err(message) => return (StatusCode::NOT_FOUND, message).into_response(),
+    };
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let (width, height) = match image_size(&params) {
+        Ok(size) => size,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    Span::current().record("sat", layer.sat.as_str());
+
+    let timestamp = match frame_timestamp(&layer, &params, None).await {
+        Ok(timestamp) => timestamp,
+        Err(response) => return response,
+    };
+    let Some(jpeg) = frame_jpeg(&layer, &timestamp, width, height).await else {
+        return if offline::enabled() { offline::unavailable() } else { bad_gateway("No tiles for the newest frame") };
+    };
+    debug!(sat = layer.sat, timestamp, width, height, "Camera still");
+
+    let etag = tile_etag(&jpeg);
+    // Behind credentials, shared caches mustn't hand it to anyone else
+    let cache_control = format!("{}, max-age={}", if auth::enabled() { "private" } else { "public" }, STILL_MAX_AGE);
+    let not_modified = etag_matches(&headers, &etag);
+    let response_headers = [
+        (header::CONTENT_TYPE, "image/jpeg".to_string()),
+        (header::CACHE_CONTROL, cache_control),
+        (header::ETAG, etag),
+    ];
+    let mut response = if not_modified {
+        (StatusCode::NOT_MODIFIED, response_headers).into_response()
+    } else {
+        (response_headers, jpeg).into_response()
+    };
+    if let Some(Ok(modified)) = parse_time(&timestamp).map(|time| HeaderValue::from_str(&http_date(time))) {
+        response.headers_mut().insert(header::LAST_MODIFIED, modified);
+    }
+    let response = if offline::enabled() { offline::mark(response) } else { response };
+    layer.mark(response)
+}
+
+/// `GET /ha/camera/{sat}/stream.mjpeg?size=medium`: `/stream.mjpeg` for
+/// satellite `sat`, sized as for `latest.jpg`, to pair with it in Home
+/// Assistant's MJPEG camera.
+pub async fn handle_stream(Path(sat): Path<String>, Query(params): Query<Params>) -> Response {
+    let mut params = match camera_params(&sat, params) {
+        Ok(params) => params,
+        Err(message) => return (StatusCode::NOT_FOUND, message).into_response(),
+    };
+    let (width, height) = match image_size(&params) {
+        Ok(size) => size,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    params.insert("res".to_string(), format!("{}x{}", width, height));
+    mjpeg::handle_stream(Query(params)).await
+}
@@ -0,0 +1,41 @@
+use axum::Json;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use serde_json::json;
+use tracing::warn;
+use crate::SLIDER_BASE_URL;
+use crate::cache::CACHE_DIR;
+
+// Write and remove a probe file rather than trusting permission bits
+async fn cache_writable() -> bool {
+    let probe = CACHE_DIR.join(".healthz");
+    let ok = tokio::fs::write(&probe, b"ok").await.is_ok();
+    let _ = tokio::fs::remove_file(&probe).await;
+    ok
+}
+
+async fn upstream_resolves() -> bool {
+    let Some(host) = reqwest::Url::parse(SLIDER_BASE_URL).ok().and_then(|u| u.host_str().map(str::to_string)) else {
+        return false;
+    };
+    let result = tokio::net::lookup_host(format!("{}:443", host)).await;
+    match result {
+        Ok(mut addrs) => addrs.next().is_some(),
+        Err(e) => {
+            warn!(host, error = %e, "Upstream DNS lookup failed");
+            false
+        }
+    }
+}
+
+/// Liveness/readiness probe for systemd and container healthchecks.
+pub async fn handle_healthz() -> Response {
+    let (cache_ok, dns_ok) = tokio::join!(cache_writable(), upstream_resolves());
+    let status = if cache_ok && dns_ok { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let body = json!({
+        "status": if status.is_success() { "ok" } else { "degraded" },
+        "cache_writable": cache_ok,
+        "upstream_dns": dns_ok,
+    });
+    (status, Json(body)).into_response()
+}
@@ -0,0 +1,78 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use axum::body::Bytes;
+use crate::config::CONFIG;
+use crate::metrics;
+
+/// Recently served tile bytes, kept in memory in front of the disk cache
+/// so an animation looping over the same frames never touches disk.
+struct HotCache {
+    // Bytes plus the tick of the last access
+    entries: HashMap<String, (Bytes, u64)>,
+    bytes: u64,
+    tick: u64,
+}
+
+lazy_static::lazy_static! {
+    static ref HOT_CACHE: Mutex<HotCache> = Mutex::new(HotCache {
+        entries: HashMap::new(),
+        bytes: 0,
+        tick: 0,
+    });
+}
+
+fn capacity() -> u64 {
+    CONFIG.memory_cache_mb * 1024 * 1024
+}
+
+pub fn get(key: &str) -> Option<Bytes> {
+    let mut cache = HOT_CACHE.lock().unwrap();
+    cache.tick += 1;
+    let tick = cache.tick;
+    let (data, last_used) = cache.entries.get_mut(key)?;
+    *last_used = tick;
+    Some(data.clone())
+}
+
+pub fn insert(key: &str, data: Bytes) {
+    let capacity = capacity();
+    // Tiles bigger than a quarter of the budget would just churn it
+    if data.len() as u64 > capacity / 4 {
+        return;
+    }
+    let mut cache = HOT_CACHE.lock().unwrap();
+    cache.tick += 1;
+    let tick = cache.tick;
+    let len = data.len() as u64;
+    if let Some((old, _)) = cache.entries.insert(key.to_string(), (data, tick)) {
+        cache.bytes -= old.len() as u64;
+    }
+    cache.bytes += len;
+
+    // A few thousand tiles at most, so a linear scan for the oldest is fine
+    while cache.bytes > capacity {
+        let Some(oldest) = cache.entries.iter().min_by_key(|(_, (_, t))| *t).map(|(k, _)| k.clone()) else {
+            break;
+        };
+        if let Some((data, _)) = cache.entries.remove(&oldest) {
+            cache.bytes -= data.len() as u64;
+        }
+    }
+    metrics::HOT_CACHE_BYTES.set(cache.bytes as i64);
+}
+
+pub fn remove(key: &str) {
+    let mut cache = HOT_CACHE.lock().unwrap();
+    if let Some((data, _)) = cache.entries.remove(key) {
+        cache.bytes -= data.len() as u64;
+        metrics::HOT_CACHE_BYTES.set(cache.bytes as i64);
+    }
+}
+
+/// Drop entries whose key fails `keep`, e.g. after disk eviction or a purge.
+pub fn retain(keep: impl Fn(&str) -> bool) {
+    let mut cache = HOT_CACHE.lock().unwrap();
+    cache.entries.retain(|key, _| keep(key));
+    cache.bytes = cache.entries.values().map(|(data, _)| data.len() as u64).sum();
+    metrics::HOT_CACHE_BYTES.set(cache.bytes as i64);
+}
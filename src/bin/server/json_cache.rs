@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use axum::body::Bytes;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tracing::debug;
+use crate::config::CONFIG;
+
+struct Entry {
+    body: Bytes,
+    fetched: Instant,
+    refreshing: bool,
+}
+
+lazy_static::lazy_static! {
+    // Frame and date lists by upstream, satellite, sector and product
+    static ref JSON_CACHE: Mutex<HashMap<String, Entry>> = Mutex::new(HashMap::new());
+}
+
+// The cdn parameter is part of the key, so clients can add entries at will
+const MAX_ENTRIES: usize = 1000;
+
+fn store(key: &str, body: Bytes) {
+    let mut cache = JSON_CACHE.lock().unwrap();
+    if cache.len() >= MAX_ENTRIES {
+        let max_stale = Duration::from_secs(CONFIG.json_cache_ttl + CONFIG.json_max_stale);
+        cache.retain(|_, e| e.fetched.elapsed() < max_stale);
+        if cache.len() >= MAX_ENTRIES {
+            cache.clear();
+        }
+    }
+    cache.insert(key.to_string(), Entry { body, fetched: Instant::now(), refreshing: false });
+}
+
+fn respond(body: Bytes, age: Duration, cache_status: &'static str) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, "application/json".to_string()),
+            (header::AGE, age.as_secs().to_string()),
+            (header::HeaderName::from_static("x-cache"), cache_status.to_string()),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Serve the JSON cached under `key`. Fresh copies (younger than
+/// `json_cache_ttl`) go out as they are; stale ones up to `json_max_stale`
+/// go out immediately while `fetch` refreshes them in the background.
+/// Anything older, or missing, waits for `fetch`.
+pub async fn get<F, Fut>(key: String, fetch: F) -> Response
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<Bytes, &'static str>> + Send + 'static,
+{
+    let ttl = Duration::from_secs(CONFIG.json_cache_ttl);
+    let max_stale = ttl + Duration::from_secs(CONFIG.json_max_stale);
+    let cached = JSON_CACHE.lock().unwrap().get_mut(&key).and_then(|entry| {
+        let age = entry.fetched.elapsed();
+        if age >= max_stale {
+            return None;
+        }
+        // Only the first request to see it stale starts a refresh
+        let refresh = age >= ttl && !std::mem::replace(&mut entry.refreshing, true);
+        Some((entry.body.clone(), age, refresh))
+    });
+
+    match cached {
+        Some((body, age, _)) if age < ttl => respond(body, age, "HIT"),
+        Some((body, age, refresh)) => {
+            if refresh {
+                debug!(key, "Refreshing stale JSON in the background");
+                let fetch = fetch();
+                tokio::spawn(async move {
+                    match fetch.await {
+                        Ok(body) => store(&key, body),
+                        // Leave it to the next request to try again
+                        Err(_) => {
+                            if let Some(entry) = JSON_CACHE.lock().unwrap().get_mut(&key) {
+                                entry.refreshing = false;
+                            }
+                        }
+                    }
+                });
+            }
+            respond(body, age, "STALE")
+        }
+        None => match fetch().await {
+            Ok(body) => {
+                if CONFIG.json_cache_ttl > 0 {
+                    store(&key, body.clone());
+                }
+                respond(body, Duration::ZERO, "MISS")
+            }
+            Err(message) => (StatusCode::BAD_GATEWAY, message).into_response(),
+        },
+    }
+}
@@ -0,0 +1,77 @@
+use std::io;
+use std::net::TcpListener;
+use std::os::fd::{FromRawFd, IntoRawFd, RawFd};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use tracing::info;
+use crate::config::CONFIG;
+
+// First descriptor passed by systemd (SD_LISTEN_FDS_START)
+const LISTEN_FDS_START: RawFd = 3;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The path is set when we created the socket file and should remove it
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+/// The socket to serve on: one inherited through systemd socket activation,
+/// otherwise `unix:<path>` or the configured TCP address. Call it before
+/// any threads are started, as it changes the environment.
+pub fn open() -> io::Result<Listener> {
+    let listener = match inherited_fd() {
+        Some(fd) => {
+            info!(fd, "Using socket from systemd");
+            from_fd(fd)
+        }
+        None => match CONFIG.unix_socket() {
+            Some(path) => {
+                // A socket file left behind by an unclean exit blocks bind()
+                if std::fs::symlink_metadata(&path).is_ok_and(|m| is_socket(&m)) {
+                    std::fs::remove_file(&path)?;
+                }
+                Listener::Unix(UnixListener::bind(&path)?, Some(path))
+            }
+            None => Listener::Tcp(TcpListener::bind(CONFIG.listen_addr())?),
+        },
+    };
+    match &listener {
+        Listener::Tcp(l) => l.set_nonblocking(true)?,
+        Listener::Unix(l, _) => l.set_nonblocking(true)?,
+    }
+    Ok(listener)
+}
+
+fn is_socket(meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    meta.file_type().is_socket()
+}
+
+/// The activation protocol: LISTEN_PID names us and LISTEN_FDS counts the
+/// sockets starting at fd 3. Only the first one is used.
+fn inherited_fd() -> Option<RawFd> {
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let count: u32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if pid != std::process::id() || count == 0 {
+        return None;
+    }
+    // Anything we spawn must not mistake the sockets for its own. Nothing
+    // else is running yet to be reading the environment meanwhile.
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    Some(LISTEN_FDS_START)
+}
+
+fn from_fd(fd: RawFd) -> Listener {
+    // SAFETY: systemd hands us ownership of this descriptor, and nothing
+    // else in the process has touched it
+    let unix = unsafe { UnixListener::from_raw_fd(fd) };
+    // local_addr() fails with InvalidInput when the socket isn't AF_UNIX
+    if unix.local_addr().is_ok() {
+        return Listener::Unix(unix, None);
+    }
+    let fd = unix.into_raw_fd();
+    // SAFETY: as above; ownership moves from the UnixListener we just released
+    Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) })
+}
@@ -0,0 +1,49 @@
+use std::time::Instant;
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::response::Response;
+use tracing::{field, info, info_span, Instrument};
+use tracing_subscriber::EnvFilter;
+use crate::config::CONFIG;
+
+/// Install the global subscriber. `RUST_LOG` wins over `--log-level` so
+/// individual modules can still be turned up while debugging.
+pub fn init() {
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("server={},tower_http=warn", CONFIG.log_level)));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter).with_target(false);
+    if CONFIG.log_format == "json" {
+        builder.json().flatten_event(true).with_current_span(true).with_span_list(false).init();
+    } else {
+        builder.init();
+    }
+}
+
+/// Wraps each request in a span that handlers fill in (satellite, tile
+/// coordinates, cache status, upstream status) and logs one access line
+/// when the response is ready.
+pub async fn access_log(request: Request, next: Next) -> Response {
+    let span = info_span!(
+        "request",
+        method = %request.method(),
+        path = %request.uri().path(),
+        sat = field::Empty,
+        x = field::Empty,
+        y = field::Empty,
+        z = field::Empty,
+        cache = field::Empty,
+        upstream_status = field::Empty,
+    );
+    let start = Instant::now();
+    async move {
+        let response = next.run(request).await;
+        info!(
+            status = response.status().as_u16(),
+            duration_ms = start.elapsed().as_millis() as u64,
+            "request completed"
+        );
+        response
+    }
+    .instrument(span)
+    .await
+}
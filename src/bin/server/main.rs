@@ -0,0 +1,928 @@
+use std::fs;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use axum::Router;
+use axum::body::Bytes;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::middleware;
+use axum::routing::{get, post, put};
+use tokio::sync::OnceCell;
+use tower_http::compression::predicate::{DefaultPredicate, NotForContentType, Predicate};
+use tower_http::compression::CompressionLayer;
+use tracing::{debug, error, info, warn, Span};
+
+mod admin;
+mod alerts;
+mod archive;
+mod auth;
+mod aurora;
+mod basemap;
+mod cache;
+mod catalog;
+mod compression;
+mod config;
+mod cors;
+mod dates;
+mod daynight;
+mod disk;
+mod eumetsat;
+mod events;
+mod eviction;
+mod export;
+mod feed;
+mod fires;
+mod frames;
+mod fulldisk;
+mod geos;
+mod geotiff;
+mod gibs;
+mod ha;
+mod health;
+mod hot_cache;
+mod json_cache;
+mod listen;
+mod logging;
+mod metrics;
+mod mjpeg;
+mod mosaic;
+mod mqtt;
+mod noaa;
+mod offline;
+mod ogcapi;
+mod palette;
+mod prefetch;
+mod probe;
+mod products;
+mod radar;
+mod reproject;
+mod ratelimit;
+mod redis;
+mod s3;
+mod satellites;
+mod schedule;
+mod static_files;
+mod stats;
+mod storms;
+mod timelapse;
+mod tls;
+mod transcode;
+mod upsample;
+mod upstream;
+mod wallpaper;
+mod webhooks;
+mod wmts;
+mod ws;
+mod xyz;
+mod zip;
+
+use cache::{cache_key, TileFormat, DEFAULT_SECTOR, get_cached_tile, get_negative, put_cached_tile, put_negative, CACHE_DIR, CACHE_INDEX};
+use config::CONFIG;
+use upstream::{Priority, HTTP_CLIENT, NICT_CLIENT};
+
+const SLIDER_BASE_URL: &str = "https://rammb-slider.cira.colostate.edu";
+const NICT_BASE_URL: &str = "https://himawari8-dl.nict.go.jp";
+// NICT's true-colour Himawari tiles are a different grid from SLIDER's, so
+// they're cached as a product of their own
+const NICT_PRODUCT: &str = "nict";
+// That grid: 550-pixel tiles, 16 by 16 at the deepest
+const NICT_GRID: (u32, u32) = (4, 550);
+// Likewise tiles rendered from EUMETView
+const EUMETSAT_PRODUCT: &str = "eumetsat";
+// Named in SOURCE_HEADER; its tiles are the SLIDER grid's and products
+const NOAA_SOURCE: &str = "noaa";
+
+// Upstream tiles are a few hundred KB at most; anything far bigger is not a tile
+const MAX_TILE_BYTES: usize = 16 * 1024 * 1024;
+
+type Params = HashMap<String, String>;
+type UpstreamTile = Result<(StatusCode, Bytes), ()>;
+
+lazy_static::lazy_static! {
+    // Upstream tile fetches in progress, keyed by cache key
+    static ref IN_FLIGHT: Mutex<HashMap<String, Arc<OnceCell<UpstreamTile>>>> = Mutex::new(HashMap::new());
+}
+
+// Unknown satellites fall back to GOES-19, as they always have
+fn satellite_id(sat: &str) -> &'static str {
+    satellites::find(sat).map_or("goes-19", |s| s.slider_id)
+}
+
+fn satellite_max_zoom(sat: &str) -> u32 {
+    satellites::find(sat).map_or(4, |s| s.max_zoom)
+}
+
+fn get_cdn_url(sat: &str, params: &Params) -> String {
+    let cdn = params.get("cdn").cloned().unwrap_or_else(|| SLIDER_BASE_URL.to_string());
+    // A satellite configured for NICT uses it in place of SLIDER, but a
+    // request naming some other mirror still gets that mirror
+    let is_slider = cdn == SLIDER_BASE_URL || CONFIG.fallback_cdns.contains(&cdn);
+    match CONFIG.satellite_sources.get(sat).map(String::as_str) {
+        Some("nict") if is_slider => NICT_BASE_URL.to_string(),
+        Some("eumetsat") if is_slider => CONFIG.eumetsat_view_url.clone(),
+        Some("noaa") if is_slider => CONFIG.noaa_goes_url.clone(),
+        _ => cdn,
+    }
+}
+
+// Set on frame lists that come from somewhere other than the CDN the
+// frontend asked for, so it can lay out that source's tiles
+const SOURCE_HEADER: header::HeaderName = header::HeaderName::from_static("x-peepsat-source");
+
+fn is_nict_cdn(cdn: &str) -> bool {
+    cdn.contains("himawari8") && cdn.contains("nict.go.jp")
+}
+
+// Sector and product (or band) from the query, defaulting to full-disk GeoColor.
+// They end up in upstream URLs and cache paths, so only plain names pass.
+fn get_layer(sat: &str, cdn: &str, params: &Params) -> Option<(String, String)> {
+    let valid = |name: &String| {
+        !name.is_empty() && name.len() <= 64 && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_')
+    };
+    let sector = params.get("sector").map_or(Some(DEFAULT_SECTOR.to_string()), |s| valid(s).then(|| s.clone()))?;
+    // band=13 is short for product=band_13
+    let band = params.get("band").map(|b| format!("band_{:0>2}", b));
+    let product = match band.as_ref().or(params.get("product")) {
+        Some(p) => valid(p).then(|| p.clone())?,
+        None => satellites::default_product(sat).to_string(),
+    };
+    // NICT and EUMETView only give us the full disk
+    if is_nict_cdn(cdn) {
+        return (sector == DEFAULT_SECTOR).then(|| (sector, NICT_PRODUCT.to_string()));
+    }
+    if eumetsat::is_source(cdn) {
+        return (sector == DEFAULT_SECTOR).then(|| (sector, EUMETSAT_PRODUCT.to_string()));
+    }
+    // NOAA's archive has the full disk's bands, and no GeoColor to default to
+    if noaa::is_source(cdn) {
+        let product = if band.is_none() && !params.contains_key("product") { noaa::DEFAULT_PRODUCT.to_string() } else { product };
+        return (sector == DEFAULT_SECTOR && noaa::has_product(&product)).then_some((sector, product));
+    }
+    let available = satellites::sector_grid(sat, &sector).is_some() && satellites::has_product(sat, &product);
+    // The day/night blend's sun angles are worked out on the full-disk grid
+    let blendable = product != satellites::DAY_NIGHT_PRODUCT || sector == DEFAULT_SECTOR;
+    (available && blendable).then_some((sector, product))
+}
+
+fn bad_gateway(message: &'static str) -> Response {
+    (StatusCode::BAD_GATEWAY, message).into_response()
+}
+
+// FNV-1a over the tile bytes; only needs to be stable, not cryptographic
+fn tile_etag(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("\"{:016x}-{:x}\"", hash, data.len())
+}
+
+// Whether the request's If-None-Match takes `etag`
+fn etag_matches(headers: &HeaderMap, etag: &str) -> bool {
+    headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v == "*" || v.split(',').any(|tag| tag.trim() == etag))
+}
+
+fn tile_response(
+    data: Bytes,
+    format: TileFormat,
+    cache_status: &'static str,
+    timestamp: &str,
+    headers: &HeaderMap,
+) -> Response {
+    let etag = tile_etag(&data);
+    // Tiles for a fixed timestamp never change, so the browser can keep them
+    let cache_control = if timestamp != "0" {
+        format!("public, max-age={}, immutable", CONFIG.tile_max_age)
+    } else {
+        "no-cache".to_string()
+    };
+
+    let not_modified = etag_matches(headers, &etag);
+
+    let response_headers = [
+        (header::CONTENT_TYPE, format.content_type().to_string()),
+        (header::HeaderName::from_static("x-cache"), cache_status.to_string()),
+        (header::CACHE_CONTROL, cache_control),
+        (header::ETAG, etag),
+        // The same URL can be PNG or WebP depending on Accept
+        (header::VARY, "Accept".to_string()),
+    ];
+    if not_modified {
+        (StatusCode::NOT_MODIFIED, response_headers).into_response()
+    } else {
+        (response_headers, data).into_response()
+    }
+}
+
+// Tiles cached as WebP go out as-is to clients that take WebP and are
+// converted back to PNG for the rest
+async fn cached_tile_response(data: Bytes, format: TileFormat, timestamp: &str, headers: &HeaderMap) -> Response {
+    if format == TileFormat::Png || transcode::accepts(headers, "image/webp") {
+        return tile_response(data, format, "HIT", timestamp, headers);
+    }
+    match tokio::task::spawn_blocking(move || transcode::to_png(&data)).await {
+        Ok(Ok(png)) => tile_response(Bytes::from(png), TileFormat::Png, "HIT", timestamp, headers),
+        _ => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to decode cached tile").into_response(),
+    }
+}
+
+async fn handle_slider_latest(Query(params): Query<Params>) -> Response {
+    let sat = params.get("sat").cloned().unwrap_or_else(|| CONFIG.default_satellite.clone());
+    let cdn = get_cdn_url(&sat, &params);
+    let response = slider_latest(sat.clone(), cdn.clone(), &params).await;
+    satellites::mark_status(&sat, mark_source(&cdn, response))
+}
+
+// What `cdn` is called in SOURCE_HEADER, unless it's SLIDER
+fn source_name(cdn: &str) -> Option<&'static str> {
+    if is_nict_cdn(cdn) {
+        Some(NICT_PRODUCT)
+    } else if eumetsat::is_source(cdn) {
+        Some(EUMETSAT_PRODUCT)
+    } else if noaa::is_source(cdn) {
+        Some(NOAA_SOURCE)
+    } else {
+        None
+    }
+}
+
+// Tag a frame list with where it came from, unless that's SLIDER
+fn mark_source(cdn: &str, mut response: Response) -> Response {
+    if let Some(source) = source_name(cdn) {
+        response.headers_mut().insert(SOURCE_HEADER, header::HeaderValue::from_static(source));
+    }
+    response
+}
+
+async fn slider_latest(sat: String, cdn: String, params: &Params) -> Response {
+    Span::current().record("sat", sat.as_str());
+    let Some((sector, product)) = get_layer(&sat, &cdn, params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let product = satellites::listed_product(&product).to_string();
+    if offline::enabled() {
+        return offline::latest(&sat, &sector, &product).unwrap_or_else(offline::unavailable);
+    }
+    let key = format!("latest {} {} {} {}", cdn, sat, sector, product);
+    let response = json_cache::get(key, || fetch_latest(cdn, sat.clone(), sector.clone(), product.clone())).await;
+    // Upstream is down: carry on with the frames we have
+    if response.status() == StatusCode::BAD_GATEWAY {
+        if let Some(cached) = offline::latest(&sat, &sector, &product) {
+            warn!(sat, "Upstream unavailable, listing cached frames");
+            return cached;
+        }
+    }
+    response
+}
+
+async fn fetch_latest(cdn: String, sat: String, sector: String, product: String) -> Result<Bytes, &'static str> {
+    if eumetsat::is_source(&cdn) {
+        return eumetsat::latest(&sat).await;
+    }
+    if noaa::is_source(&cdn) {
+        return noaa::latest(&sat, &product).await;
+    }
+    // NICT Himawari uses different API
+    if is_nict_cdn(&cdn) {
+        let target = "https://himawari8.nict.go.jp/img/D531106/latest.json";
+        debug!(url = target, "Fetching NICT latest");
+        return match upstream::get(&NICT_CLIENT, &[target.to_string()]).await {
+            Ok(r) => {
+                // NICT returns {"date":"2025-12-26 18:30:00","file":"..."}
+                // Convert to SLIDER format {"timestamps_int":[...], ...}
+                if let Ok(text) = r.text().await {
+                    if let Some(date_str) = text.split("\"date\":\"").nth(1).and_then(|s| s.split('"').next()) {
+                        // Parse "2025-12-26 18:30:00" to timestamp format
+                        let parts: Vec<&str> = date_str.split(&['-', ' ', ':'][..]).collect();
+                        if parts.len() >= 5 {
+                            let ts = format!("{}{}{}{}{}", parts[0], parts[1], parts[2], parts[3], parts[4]);
+                            let ts_int: i64 = ts.parse().unwrap_or(0);
+                            let date_int: i64 = format!("{}{}{}", parts[0], parts[1], parts[2]).parse().unwrap_or(0);
+                            let json = format!(r#"{{"timestamps_int":[{}],"dates_int":[{}]}}"#, ts_int, date_int);
+                            return Ok(Bytes::from(json));
+                        }
+                    }
+                }
+                Err("Failed to parse NICT response")
+            }
+            Err(e) => {
+                warn!(error = %e, "NICT latest failed");
+                Err("Failed")
+            }
+        };
+    }
+
+    let path = format!("/data/json/{}/{}/{}/latest_times.json", satellite_id(&sat), sector, product);
+    let targets = upstream::with_fallbacks(&cdn, &path);
+
+    debug!(url = %targets[0], "Fetching latest times");
+    match upstream::get(&HTTP_CLIENT, &targets).await {
+        Ok(r) if r.status().is_success() => r.bytes().await.map_err(|_| "Failed"),
+        Ok(r) => {
+            warn!(status = r.status().as_u16(), "Slider latest unavailable");
+            Err("Failed")
+        }
+        Err(e) => {
+            warn!(error = %e, "Slider latest failed");
+            Err("Failed")
+        }
+    }
+}
+
+async fn handle_slider_dates(Query(params): Query<Params>) -> Response {
+    let sat = params.get("sat").cloned().unwrap_or_else(|| CONFIG.default_satellite.clone());
+    let cdn = get_cdn_url(&sat, &params);
+    slider_dates(sat, cdn, &params).await
+}
+
+async fn slider_dates(sat: String, cdn: String, params: &Params) -> Response {
+    Span::current().record("sat", sat.as_str());
+    let Some((sector, product)) = get_layer(&sat, &cdn, params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let product = satellites::listed_product(&product).to_string();
+    if offline::enabled() {
+        return offline::dates(&sat, &sector, &product).unwrap_or_else(offline::unavailable);
+    }
+    let key = format!("dates {} {} {} {}", cdn, sat, sector, product);
+    let response = json_cache::get(key, || fetch_dates(cdn, sat.clone(), sector.clone(), product.clone())).await;
+    if response.status() == StatusCode::BAD_GATEWAY {
+        if let Some(cached) = offline::dates(&sat, &sector, &product) {
+            return cached;
+        }
+    }
+    response
+}
+
+async fn fetch_dates(cdn: String, sat: String, sector: String, product: String) -> Result<Bytes, &'static str> {
+    if eumetsat::is_source(&cdn) {
+        return eumetsat::dates(&sat).await;
+    }
+    if noaa::is_source(&cdn) {
+        return noaa::dates(&sat, &product).await;
+    }
+    // NICT doesn't have a dates endpoint, use same as latest
+    if is_nict_cdn(&cdn) {
+        let target = "https://himawari8.nict.go.jp/img/D531106/latest.json";
+        debug!(url = target, "Fetching NICT dates (from latest)");
+        return match upstream::get(&NICT_CLIENT, &[target.to_string()]).await {
+            Ok(r) => {
+                if let Ok(text) = r.text().await {
+                    if let Some(date_str) = text.split("\"date\":\"").nth(1).and_then(|s| s.split('"').next()) {
+                        let parts: Vec<&str> = date_str.split(&['-', ' ', ':'][..]).collect();
+                        if parts.len() >= 3 {
+                            let date_int: i64 = format!("{}{}{}", parts[0], parts[1], parts[2]).parse().unwrap_or(0);
+                            let json = format!(r#"{{"dates_int":[{}]}}"#, date_int);
+                            return Ok(Bytes::from(json));
+                        }
+                    }
+                }
+                Err("Failed")
+            }
+            Err(e) => {
+                warn!(error = %e, "NICT dates failed");
+                Err("Failed")
+            }
+        };
+    }
+
+    let path = format!("/data/json/{}/{}/{}/available_dates.json", satellite_id(&sat), sector, product);
+    let targets = upstream::with_fallbacks(&cdn, &path);
+
+    debug!(url = %targets[0], "Fetching available dates");
+    match upstream::get(&HTTP_CLIENT, &targets).await {
+        Ok(r) if r.status().is_success() => r.bytes().await.map_err(|_| "Failed"),
+        Ok(r) => {
+            warn!(status = r.status().as_u16(), "Slider dates unavailable");
+            Err("Failed")
+        }
+        Err(e) => {
+            warn!(error = %e, "Slider dates failed");
+            Err("Failed")
+        }
+    }
+}
+
+async fn handle_slider_tile(Query(params): Query<Params>, headers: HeaderMap) -> Response {
+    let fill = match params.get("fill").map(String::as_str) {
+        None => false,
+        Some("parent") => true,
+        Some(_) => return (StatusCode::BAD_REQUEST, "fill must be parent").into_response(),
+    };
+    let Some(name) = params.get("palette") else {
+        return filled_tile(&params, &headers, fill).await;
+    };
+    let sat = params.get("sat").cloned().unwrap_or_else(|| CONFIG.default_satellite.clone());
+    let cdn = get_cdn_url(&sat, &params);
+    let Some((_, product)) = get_layer(&sat, &cdn, &params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    if !satellites::is_ir_band(&sat, &product) {
+        return (StatusCode::BAD_REQUEST, "palette only applies to IR bands").into_response();
+    }
+    let Some(palette) = palette::find(name) else {
+        return (StatusCode::BAD_REQUEST, "Unknown palette").into_response();
+    };
+    // The grey tile is had as PNG and recoloured, so the client's ETag is
+    // checked against the recoloured one's
+    let mut plain = headers.clone();
+    plain.remove(header::IF_NONE_MATCH);
+    plain.remove(header::ACCEPT);
+    palette::apply(filled_tile(&params, &plain, fill).await, palette, &headers).await
+}
+
+// The tile, or with `fill` a stand-in scaled up from a shallower zoom if
+// it isn't found
+async fn filled_tile(params: &Params, headers: &HeaderMap, fill: bool) -> Response {
+    let response = slider_tile(params, headers).await;
+    if !fill || response.status() != StatusCode::NOT_FOUND {
+        return response;
+    }
+    upsample::from_parent(params, headers).await.unwrap_or(response)
+}
+
+async fn slider_tile(params: &Params, headers: &HeaderMap) -> Response {
+    // Parse: /slider-tile?sat=19&t=20231026153000&x=7&y=8&z=4&cdn=...
+    let sat = params.get("sat").cloned().unwrap_or_else(|| CONFIG.default_satellite.clone());
+    let timestamp = params.get("t").cloned().unwrap_or_else(|| "0".to_string());
+    let x: u32 = params.get("x").and_then(|s| s.parse().ok()).unwrap_or(0);
+    let y: u32 = params.get("y").and_then(|s| s.parse().ok()).unwrap_or(0);
+    let date = params.get("d").cloned().unwrap_or_default(); // YYYYMMDD format
+    let zoom: u32 = params.get("z").and_then(|s| s.parse().ok()).unwrap_or(4);
+    let cdn = get_cdn_url(&sat, params);
+    let Some((sector, product)) = get_layer(&sat, &cdn, params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+
+    // Clamp zoom to the sector's valid range (e.g. 0-4 for GOES full disk, 0-1 for mesoscale)
+    let max_zoom = satellites::sector_grid(&sat, &sector).map_or(4, |(max_zoom, _)| max_zoom);
+    let max_zoom = max_zoom.saturating_sub(satellites::product_zoom_reduction(&sat, &product));
+    let zoom = zoom.min(max_zoom);
+
+    let span = Span::current();
+    span.record("sat", sat.as_str());
+    span.record("x", x);
+    span.record("y", y);
+    span.record("z", zoom);
+
+    // Check cache first
+    let tile = Tile { sat: &sat, sector: &sector, product: &product, timestamp: &timestamp, zoom, x, y };
+    let key = tile.key();
+    if let Some((data, format)) = get_cached_tile(&key).await {
+        span.record("cache", "HIT");
+        let response = cached_tile_response(data, format, &timestamp, headers).await;
+        return if offline::enabled() { offline::mark(response) } else { response };
+    }
+    // Blended from cached or fetched GeoColor and IR tiles, offline too
+    if product == satellites::DAY_NIGHT_PRODUCT {
+        span.record("cache", "MISS");
+        return match coalesce(&key, daynight::render(&cdn, &tile, &date, &key)).await {
+            Ok((_, bytes)) => tile_response(bytes, TileFormat::Png, "MISS", &timestamp, headers),
+            Err(()) if offline::enabled() => offline::unavailable(),
+            Err(()) => bad_gateway("No GeoColor or IR tile to blend"),
+        };
+    }
+    if offline::enabled() {
+        span.record("cache", "MISS");
+        return offline::unavailable();
+    }
+    if let Some(status) = get_negative(&key) {
+        span.record("cache", "NEGATIVE");
+        metrics::NEGATIVE_CACHE_HITS.inc();
+        return (
+            status,
+            [
+                (header::HeaderName::from_static("x-cache"), "NEGATIVE"),
+                (header::CACHE_CONTROL, "no-cache"),
+            ],
+        )
+            .into_response();
+    }
+
+    span.record("cache", "MISS");
+    let result = if eumetsat::is_source(&cdn) {
+        coalesce(&key, eumetsat::fetch_tile(&tile, &key, Priority::Interactive)).await
+    } else if noaa::is_source(&cdn) {
+        coalesce(&key, noaa::fetch_tile(&tile, &key)).await
+    } else {
+        let targets = slider_tile_targets(&cdn, &tile, &date);
+        let client = if is_nict_cdn(&cdn) { &*NICT_CLIENT } else { &*HTTP_CLIENT };
+        fetch_tile_coalesced(client, &targets, &key, Priority::Interactive).await
+    };
+    match result {
+        Ok((status, bytes)) if status.is_success() && !bytes.is_empty() => {
+            tile_response(bytes, TileFormat::Png, "MISS", &timestamp, headers)
+        }
+        Ok((status, bytes)) if !upstream::is_retryable(status) => (status, bytes).into_response(),
+        result => match stale_tile_response(&key, headers).await {
+            Some(response) => response,
+            None => match result {
+                Ok((status, bytes)) => (status, bytes).into_response(),
+                Err(()) => bad_gateway("Failed"),
+            },
+        },
+    }
+}
+
+// Upstream is failing: rather than a hole in the image, send the same tile
+// from the nearest earlier cached frame, marked with the frame it's from
+async fn stale_tile_response(key: &str, headers: &HeaderMap) -> Option<Response> {
+    let stale_key = cache::nearest_earlier(key)?;
+    let (data, format) = get_cached_tile(&stale_key).await?;
+    let (_, stale_timestamp) = cache::key_parts(&stale_key);
+    warn!(key, stale = stale_key, "Upstream failed, serving stale tile");
+    metrics::STALE_TILES_SERVED.inc();
+    Span::current().record("cache", "STALE");
+    // Timestamp "0" keeps the browser from caching it as the real frame
+    let mut response = cached_tile_response(data, format, "0", headers).await;
+    if let Ok(value) = header::HeaderValue::from_str(stale_timestamp) {
+        response.headers_mut().insert(header::HeaderName::from_static("x-peepsat-stale"), value);
+    }
+    response.headers_mut().insert(header::HeaderName::from_static("x-cache"), header::HeaderValue::from_static("STALE"));
+    Some(response)
+}
+
+// One tile of one frame
+struct Tile<'a> {
+    sat: &'a str,
+    sector: &'a str,
+    product: &'a str,
+    timestamp: &'a str,
+    zoom: u32,
+    x: u32,
+    y: u32,
+}
+
+impl Tile<'_> {
+    fn key(&self) -> String {
+        cache_key(self.sat, self.sector, self.product, self.timestamp, self.zoom, self.x, self.y)
+    }
+}
+
+// Upstream URLs for one tile, primary CDN first
+fn slider_tile_targets(cdn: &str, tile: &Tile, date: &str) -> Vec<String> {
+    let Tile { sat, sector, product, timestamp, zoom, x, y } = *tile;
+    // Parse date into year/month/day
+    let (year, month, day) = if date.len() == 8 {
+        let y: u32 = date[0..4].parse().unwrap_or(2024);
+        let m: u32 = date[4..6].parse().unwrap_or(1);
+        let d: u32 = date[6..8].parse().unwrap_or(1);
+        (y, m, d)
+    } else {
+        (2024, 1, 1)
+    };
+
+    // NICT uses different URL format
+    if is_nict_cdn(cdn) {
+        // NICT zoom: 1d=1x1, 2d=2x2, 4d=4x4, 8d=8x8, 16d=16x16
+        // SLIDER zoom 0=1x1, 1=2x2, 2=4x4, 3=8x8, 4=16x16
+        let nict_zoom = 1u32 << zoom; // 2^zoom
+        // Timestamp format: YYYYMMDDHHMM00 -> we need HHMM
+        let hour = if timestamp.len() >= 10 { &timestamp[8..10] } else { "00" };
+        let min = if timestamp.len() >= 12 { &timestamp[10..12] } else { "00" };
+        vec![format!(
+            "https://himawari8-dl.nict.go.jp/himawari8/img/D531106/{}d/550/{:04}/{:02}/{:02}/{}{}00_{}_{}.png",
+            nict_zoom, year, month, day, hour, min, y, x
+        )]
+    } else {
+        // URL format from satpaper: {base}/data/imagery/{year}/{month}/{day}/{sat_id}---{sector}/{product}/{timestamp}/{zoom}/{x:03}_{y:03}.png
+        let path = format!(
+            "/data/imagery/{:04}/{:02}/{:02}/{}---{}/{}/{}/{:02}/{:03}_{:03}.png",
+            year, month, day, satellite_id(sat), sector, product, timestamp, zoom, x, y
+        );
+        upstream::with_fallbacks(cdn, &path)
+    }
+}
+
+// Download a tile and cache it on success. Result is cloneable so it can be
+// handed to every request waiting on the same in-flight fetch.
+async fn fetch_tile(client: &reqwest::Client, targets: &[String], key: &str, priority: Priority) -> UpstreamTile {
+    let _slot = upstream::acquire(priority).await;
+    debug!(key, url = %targets[0], "Fetching tile");
+    match upstream::get(client, targets).await {
+        Ok(r) => {
+            let status = r.status();
+            // Read whole rather than streamed: the tile is hashed for its
+            // ETag, cached and shared with every coalesced request
+            let bytes = match upstream::read_limited(r, MAX_TILE_BYTES).await {
+                Ok(bytes) => bytes,
+                Err(e) => {
+                    warn!(key, error = %e, "Tile download failed");
+                    return Err(());
+                }
+            };
+            Span::current().record("upstream_status", status.as_u16());
+            debug!(key, status = status.as_u16(), len = bytes.len(), "Tile fetched");
+
+            if status.is_success() && !bytes.is_empty() {
+                // Cache the tile
+                put_cached_tile(key, &bytes).await;
+            } else if status == StatusCode::NOT_FOUND || status.is_success() {
+                put_negative(key, status);
+            }
+            Ok((status, bytes))
+        }
+        Err(e) => {
+            warn!(key, error = %e, "Tile fetch failed");
+            Err(())
+        }
+    }
+}
+
+async fn fetch_tile_coalesced(client: &reqwest::Client, targets: &[String], key: &str, priority: Priority) -> UpstreamTile {
+    coalesce(key, fetch_tile(client, targets, key, priority)).await
+}
+
+// Concurrent requests for the same uncached tile share one upstream download,
+// queued at the priority of whichever asked first
+async fn coalesce(key: &str, fetch: impl Future<Output = UpstreamTile>) -> UpstreamTile {
+    let cell = {
+        let mut in_flight = IN_FLIGHT.lock().unwrap();
+        if let Some(cell) = in_flight.get(key) {
+            debug!(key, "Joining in-flight fetch");
+            cell.clone()
+        } else {
+            let cell = Arc::new(OnceCell::new());
+            in_flight.insert(key.to_string(), cell.clone());
+            cell
+        }
+    };
+
+    // Boxed, or the handlers awaiting this overflow a worker's stack in
+    // debug builds
+    let result = cell.get_or_init(|| Box::pin(fetch_shared(key, fetch))).await.clone();
+
+    let mut in_flight = IN_FLIGHT.lock().unwrap();
+    if in_flight.get(key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+        in_flight.remove(key);
+    }
+    result
+}
+
+// Servers sharing a bucket and Redis do the same between them: one takes
+// a lock and downloads the tile, and the others wait for it to be stored
+async fn fetch_shared(key: &str, fetch: impl Future<Output = UpstreamTile>) -> UpstreamTile {
+    if !redis::shares_cache() {
+        return fetch.await;
+    }
+    // Long enough for every attempt to time out
+    let ttl = CONFIG.upstream_timeout() * (CONFIG.upstream_retries + 1);
+    let lock = format!("lock:{}", key);
+    match redis::set_once(&lock, ttl).await {
+        Ok(true) => {
+            let result = fetch.await;
+            redis::release(&lock).await;
+            result
+        }
+        Ok(false) => {
+            debug!(key, "Waiting on another server's fetch");
+            redis::wait_released(&lock, ttl).await;
+            // Anything but a stored tile (a 404, say) is asked for again
+            match get_cached_tile(key).await {
+                Some((data, TileFormat::Png)) => Ok((StatusCode::OK, data)),
+                Some((data, TileFormat::Webp)) => match tokio::task::spawn_blocking(move || transcode::to_png(&data)).await {
+                    Ok(Ok(png)) => Ok((StatusCode::OK, Bytes::from(png))),
+                    _ => fetch.await,
+                },
+                None => fetch.await,
+            }
+        }
+        Err(e) => {
+            debug!(key, error = %e, "Redis unavailable, fetching alone");
+            fetch.await
+        }
+    }
+}
+
+async fn handle_goes_proxy(Query(params): Query<Params>) -> Response {
+    // Parse query string for timestamp, satellite, and resolution parameters
+    let timestamp = params.get("t");
+    let satellite = params.get("sat").map(String::as_str).unwrap_or("18");
+    let resolution = params.get("res").map(String::as_str).unwrap_or("5424x5424");
+    Span::current().record("sat", satellite);
+    if offline::enabled() {
+        return offline::unavailable();
+    }
+
+    let target = if let Some(ts) = timestamp {
+        // Format: YYYYDDDHHMM -> https://cdn.star.nesdis.noaa.gov/GOES{sat}/ABI/FD/GEOCOLOR/YYYYDDDHHMM_GOES{sat}-ABI-FD-GEOCOLOR-{res}.jpg
+        format!("https://cdn.star.nesdis.noaa.gov/GOES{}/ABI/FD/GEOCOLOR/{}_GOES{}-ABI-FD-GEOCOLOR-{}.jpg", satellite, ts, satellite, resolution)
+    } else {
+        format!("https://cdn.star.nesdis.noaa.gov/GOES{}/ABI/FD/GEOCOLOR/latest.jpg", satellite)
+    };
+
+    debug!(url = %target, "Fetching GOES image");
+    match upstream::get(&HTTP_CLIENT, &[target]).await {
+        Ok(r) => {
+            let status = r.status();
+            Span::current().record("upstream_status", status.as_u16());
+            debug!(status = status.as_u16(), len = ?r.content_length(), "GOES proxy streaming");
+            // Full-disk JPEGs run to tens of MB, so pass them through
+            // chunk by chunk rather than holding the whole image
+            let mut headers = HeaderMap::new();
+            if status.is_success() {
+                headers.insert(header::CONTENT_TYPE, header::HeaderValue::from_static("image/jpeg"));
+            }
+            if let Some(len) = r.content_length() {
+                headers.insert(header::CONTENT_LENGTH, len.into());
+            }
+            (status, headers, axum::body::Body::from_stream(r.bytes_stream())).into_response()
+        }
+        Err(e) => {
+            warn!(error = %e, "GOES proxy failed");
+            bad_gateway("Failed to fetch GOES image")
+        }
+    }
+}
+
+fn main() {
+    lazy_static::initialize(&CONFIG);
+    logging::init();
+    // Before the runtime starts any threads: taking over systemd's socket
+    // clears LISTEN_* from the environment, which they'd be reading
+    let listener = match listen::open() {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!(error = %e, "Failed to open listening socket");
+            std::process::exit(1);
+        }
+    };
+    tokio::runtime::Runtime::new().expect("failed to start the runtime").block_on(serve(listener));
+}
+
+async fn serve(listener: listen::Listener) {
+    if let Some(path) = &CONFIG.source {
+        info!(path = ?path, "Loaded config");
+    }
+    if auth::enabled() {
+        info!("Proxy endpoints require authentication");
+    }
+    cache::init_cache_index();
+    disk::check();
+    cache::spawn_index_flusher();
+    disk::spawn_watchdog();
+    schedule::spawn_scheduler();
+    events::spawn_watcher();
+    redis::spawn_subscriber();
+    mqtt::spawn_publisher();
+    webhooks::spawn_senders();
+    alerts::spawn_checker();
+
+    let proxy = Router::new()
+        .route("/goes-proxy", get(handle_goes_proxy))
+        .route("/slider-latest", get(handle_slider_latest))
+        .route("/slider-dates", get(handle_slider_dates))
+        .route("/slider-tile", get(handle_slider_tile))
+        .route("/slider-products", get(products::handle_slider_products))
+        .route("/gibs-tile", get(gibs::handle_gibs_tile))
+        .route("/base-tile", get(basemap::handle_base_tile))
+        .route("/radar-times", get(radar::handle_radar_times))
+        .route("/radar-tile", get(radar::handle_radar_tile))
+        .route("/aurora-tile", get(aurora::handle_aurora_tile))
+        .route("/fire-tile", get(fires::handle_fire_tile))
+        .route("/api/catalog", get(catalog::handle_catalog))
+        .route("/api/palettes", get(palette::handle_palettes))
+        .route("/api/storms", get(storms::handle_storms))
+        .route("/api/fires", get(fires::handle_fires))
+        .route("/api/frames", get(frames::handle_frames))
+        .route("/api/frames/range", get(frames::handle_frame_range))
+        .route("/api/frames/nearest", get(frames::handle_nearest_frame))
+        .route("/api/frames/sync", get(frames::handle_frame_sync))
+        .route("/api/events", get(events::handle_events))
+        .route("/ws", get(ws::handle_ws))
+        .route("/api/fulldisk", get(fulldisk::handle_fulldisk))
+        .route("/api/preview", get(fulldisk::handle_preview))
+        .route("/api/probe", get(probe::handle_probe))
+        .route("/api/stats", get(stats::handle_stats))
+        .route("/api/wallpaper", get(wallpaper::handle_wallpaper))
+        .route("/api/mosaic", get(mosaic::handle_mosaic))
+        .route("/api/export/geotiff", get(export::handle_geotiff))
+        .route("/api/export/kmz", get(export::handle_kmz))
+        .route("/api/crop", get(export::handle_crop))
+        .route("/api/timelapse", get(timelapse::handle_timelapse).post(timelapse::handle_timelapse_job))
+        .route("/api/timelapse/daily", get(timelapse::handle_daily))
+        .route("/api/timelapse/{id}", get(timelapse::handle_timelapse_status))
+        .route("/api/timelapse/{id}/video", get(timelapse::handle_timelapse_video))
+        .route("/stream.mjpeg", get(mjpeg::handle_stream))
+        .route("/feed.atom", get(feed::handle_feed))
+        .route("/ha/camera/{sat}/latest.jpg", get(ha::handle_still))
+        .route("/ha/camera/{sat}/stream.mjpeg", get(ha::handle_stream))
+        .route("/xyz/{sat}/{t}/{z}/{x}/{y}", get(xyz::handle_xyz_tile))
+        .route("/wmts", get(wmts::handle_wmts))
+        .route("/ogcapi", get(ogcapi::handle_landing))
+        .route("/ogcapi/conformance", get(ogcapi::handle_conformance))
+        .route("/ogcapi/tileMatrixSets", get(ogcapi::handle_tile_matrix_sets))
+        .route("/ogcapi/tileMatrixSets/{id}", get(ogcapi::handle_tile_matrix_set))
+        .route("/ogcapi/collections", get(ogcapi::handle_collections))
+        .route("/ogcapi/collections/{id}", get(ogcapi::handle_collection))
+        .route("/ogcapi/collections/{id}/map/tiles", get(ogcapi::handle_tilesets))
+        .route("/ogcapi/collections/{id}/map/tiles/{tms}", get(ogcapi::handle_tileset))
+        .route("/ogcapi/collections/{id}/map/tiles/{tms}/{z}/{row}/{col}", get(ogcapi::handle_tile))
+        .route_layer(middleware::from_fn(transcode::layer))
+        .route_layer(middleware::from_fn(auth::require_auth))
+        .route_layer(middleware::from_fn(ratelimit::limit));
+
+    let admin = Router::new()
+        .route("/api/cache/stats", get(admin::handle_stats))
+        .route("/api/cache/list", get(admin::handle_list))
+        .route("/api/cache/purge", post(admin::handle_purge))
+        .route("/api/cache/export", get(admin::handle_export))
+        .route("/api/cache/import", post(admin::handle_import))
+        .route("/api/prefetch", get(admin::handle_prefetch_jobs).post(admin::handle_prefetch))
+        .route("/api/prefetch/{id}", get(admin::handle_prefetch_status))
+        .route("/api/schedule", get(admin::handle_schedule))
+        .route("/api/schedule/{name}/run", post(admin::handle_run_job))
+        .route("/api/alerts/test", post(admin::handle_test_alert))
+        .route("/api/palettes/{name}", put(palette::handle_put_palette).delete(palette::handle_delete_palette))
+        .route_layer(middleware::from_fn(auth::require_auth));
+
+    let app = Router::new()
+        .merge(proxy)
+        .merge(admin)
+        .route("/metrics", get(metrics::handle_metrics))
+        .route("/healthz", get(health::handle_healthz))
+        // Static files negotiate their own (cached) compression, and the
+        // MJPEG stream would be held back by the compressor's buffering
+        .route_layer(CompressionLayer::new().compress_when(DefaultPredicate::new().and(NotForContentType::const_new("multipart/"))))
+        .fallback(static_files::handle_static)
+        .layer(cors::layer())
+        .layer(middleware::from_fn(metrics::track_requests))
+        .layer(middleware::from_fn(logging::access_log));
+
+    // Behind a reverse proxy at e.g. /peepsat/, everything lives under the
+    // prefix; the frontend uses relative URLs so it follows along
+    let app = if CONFIG.base_path.is_empty() {
+        app
+    } else {
+        info!(base_path = %CONFIG.base_path, "Serving under base path");
+        Router::new().nest_service(&CONFIG.base_path, app)
+    };
+
+    info!(cache_dir = ?*CACHE_DIR, "Cache directory");
+
+    let mut socket_file = None;
+    match listener {
+        listen::Listener::Tcp(listener) if CONFIG.tls_enabled() => {
+            tls::serve(app, listener, shutdown_signal()).await;
+        }
+        listen::Listener::Tcp(listener) => {
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            info!("Server running on http://{}", listener.local_addr().unwrap());
+            axum::serve(listener, app.into_make_service_with_connect_info::<std::net::SocketAddr>())
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .unwrap();
+        }
+        listen::Listener::Unix(listener, path) => {
+            if CONFIG.tls_enabled() {
+                error!("TLS is not supported on Unix sockets; terminate TLS in the reverse proxy");
+                std::process::exit(1);
+            }
+            let listener = tokio::net::UnixListener::from_std(listener).unwrap();
+            info!(addr = ?listener.local_addr().ok(), "Server running on Unix socket");
+            socket_file = path;
+            axum::serve(listener, app)
+                .with_graceful_shutdown(shutdown_signal())
+                .await
+                .unwrap();
+        }
+    }
+    if let Some(path) = socket_file {
+        let _ = fs::remove_file(path);
+    }
+
+    cache::flush_index().await;
+    if let Ok(index) = CACHE_INDEX.lock() {
+        let total: u64 = index.values().map(|e| e.size).sum();
+        info!(entries = index.len(), bytes = total, "Shut down cleanly");
+    }
+}
+
+// Resolves on SIGINT or SIGTERM; axum then stops accepting connections and
+// waits for in-flight requests to finish.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c().await.expect("failed to install SIGINT handler");
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutdown signal received, finishing in-flight requests");
+}
@@ -0,0 +1,97 @@
+use std::time::Instant;
+use axum::body::HttpBody;
+use axum::extract::{MatchedPath, Request};
+use axum::http::header;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tracing::warn;
+use prometheus::{
+    register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge,
+    Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, TextEncoder,
+};
+
+lazy_static::lazy_static! {
+    pub static ref CACHE_HITS: IntCounter =
+        register_int_counter!("peepsat_cache_hits_total", "Tile requests served from the disk cache").unwrap();
+    pub static ref CACHE_MISSES: IntCounter =
+        register_int_counter!("peepsat_cache_misses_total", "Tile requests that had to go upstream").unwrap();
+    pub static ref NEGATIVE_CACHE_HITS: IntCounter =
+        register_int_counter!("peepsat_negative_cache_hits_total", "Tile requests answered from the negative cache").unwrap();
+    pub static ref STALE_TILES_SERVED: IntCounter =
+        register_int_counter!("peepsat_stale_tiles_served_total", "Earlier frames' tiles sent because upstream failed").unwrap();
+    pub static ref CACHE_CORRUPT: IntCounter =
+        register_int_counter!("peepsat_cache_corrupt_total", "Cached tiles discarded as corrupt").unwrap();
+    pub static ref HOT_CACHE_HITS: IntCounter =
+        register_int_counter!("peepsat_hot_cache_hits_total", "Cache hits served from memory").unwrap();
+    pub static ref HOT_CACHE_BYTES: IntGauge =
+        register_int_gauge!("peepsat_hot_cache_bytes", "Tile bytes held in the in-memory cache").unwrap();
+    pub static ref PREFETCHED_TILES: IntCounter =
+        register_int_counter!("peepsat_prefetched_tiles_total", "Tiles downloaded by the background prefetcher").unwrap();
+    pub static ref CACHE_EVICTIONS: IntCounter =
+        register_int_counter!("peepsat_cache_evictions_total", "Tiles evicted from the disk cache").unwrap();
+    pub static ref CACHE_SIZE_BYTES: IntGauge =
+        register_int_gauge!("peepsat_cache_size_bytes", "Total size of cached tiles").unwrap();
+    pub static ref CACHE_LIMIT_BYTES: IntGauge =
+        register_int_gauge!("peepsat_cache_limit_bytes", "Cache size limit in effect after free-space checks").unwrap();
+    pub static ref CACHE_ENTRIES: IntGauge =
+        register_int_gauge!("peepsat_cache_entries", "Number of cached tiles").unwrap();
+    pub static ref REQUESTS: IntCounterVec =
+        register_int_counter_vec!("peepsat_requests_total", "HTTP requests handled", &["route", "status"]).unwrap();
+    pub static ref REQUESTS_IN_FLIGHT: IntGauge =
+        register_int_gauge!("peepsat_requests_in_flight", "HTTP requests currently being handled").unwrap();
+    pub static ref BYTES_SERVED: IntCounterVec =
+        register_int_counter_vec!("peepsat_bytes_served_total", "Response body bytes sent", &["route"]).unwrap();
+    pub static ref UPSTREAM_LATENCY: Histogram = register_histogram!(
+        "peepsat_upstream_request_duration_seconds",
+        "Time to first byte for upstream requests",
+        vec![0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0]
+    )
+    .unwrap();
+    pub static ref UPSTREAM_QUEUED: IntGauge =
+        register_int_gauge!("peepsat_upstream_queued", "Tile fetches waiting for an upstream connection").unwrap();
+    pub static ref UPSTREAM_REQUESTS: IntCounterVec =
+        register_int_counter_vec!("peepsat_upstream_requests_total", "Upstream requests by outcome", &["status"]).unwrap();
+}
+
+/// Tracks in-flight requests, per-route counts, and response bytes.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|p| p.as_str().to_string())
+        .unwrap_or_else(|| "static".to_string());
+
+    REQUESTS_IN_FLIGHT.inc();
+    let response = next.run(request).await;
+    REQUESTS_IN_FLIGHT.dec();
+
+    REQUESTS.with_label_values(&[&route, response.status().as_str()]).inc();
+    // Streamed bodies have no exact size hint but set Content-Length themselves
+    let len = response.body().size_hint().exact().or_else(|| {
+        response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+    });
+    if let Some(len) = len {
+        BYTES_SERVED.with_label_values(&[&route]).inc_by(len);
+    }
+    response
+}
+
+/// Record the outcome of one upstream attempt started at `start`.
+pub fn observe_upstream(start: Instant, status: Option<reqwest::StatusCode>) {
+    UPSTREAM_LATENCY.observe(start.elapsed().as_secs_f64());
+    let label = status.map(|s| s.as_u16().to_string()).unwrap_or_else(|| "error".to_string());
+    UPSTREAM_REQUESTS.with_label_values(&[&label]).inc();
+}
+
+pub async fn handle_metrics() -> Response {
+    let mut buffer = Vec::new();
+    let encoder = TextEncoder::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        warn!(error = %e, "Metrics encode failed");
+    }
+    ([(header::CONTENT_TYPE, encoder.format_type().to_string())], buffer).into_response()
+}
@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::io::Cursor;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use axum::body::{Body, Bytes};
+use axum::extract::Query;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::stream;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageOutputFormat, RgbImage};
+use tracing::{debug, info, Span};
+use crate::frames::Layer;
+use crate::fulldisk::{assemble, disk_tiles, JPEG_QUALITY, STITCH_SLOT};
+use crate::reproject::frame_timestamp;
+use crate::{bad_gateway, offline, Params};
+
+const BOUNDARY: &str = "peepsat-frame";
+const DEFAULT_SIDE: u32 = 1024;
+const MAX_SIDE: u32 = 4096;
+// How often the newest frame is looked for, and how long an unchanged one
+// goes before it's sent again so idle connections aren't dropped
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+const RESEND_INTERVAL: Duration = Duration::from_secs(120);
+// JPEGs kept, each up to a few MB, whatever sizes clients ask for
+const MAX_JPEGS: usize = 32;
+
+// A JPEG made for a layer and size, and when it was last wanted
+struct Made {
+    layer: String,
+    timestamp: String,
+    jpeg: Bytes,
+    used: Instant,
+}
+
+lazy_static::lazy_static! {
+    // The newest JPEG made for each layer and size, shared by everyone
+    // watching it
+    static ref LATEST: Mutex<HashMap<String, Made>> = Mutex::new(HashMap::new());
+}
+
+// One stream's layer, image size and what it sent last
+struct Feed {
+    layer: Layer,
+    params: Params,
+    width: u32,
+    height: u32,
+    timestamp: String,
+    sent_at: Instant,
+}
+
+/// WIDTHxHEIGHT, each from 16 to 4096.
+pub fn parse_res(text: &str) -> Option<(u32, u32)> {
+    let (width, height) = text.split_once('x')?;
+    let (width, height) = (width.parse().ok()?, height.parse().ok()?);
+    let valid = |side: u32| (16..=MAX_SIDE).contains(&side);
+    (valid(width) && valid(height)).then_some((width, height))
+}
+
+// The full disk scaled to fit `width` by `height` and centred on black
+fn compose(tiles: &[Option<Bytes>], side: u32, tile_size: u32, width: u32, height: u32) -> Result<Vec<u8>, String> {
+    let disk = assemble(tiles, side, tile_size);
+    let fit = width.min(height);
+    let disk = imageops::resize(&disk, fit, fit, FilterType::Triangle);
+    let mut image = RgbImage::new(width, height);
+    imageops::replace(&mut image, &disk, ((width - fit) / 2) as i64, ((height - fit) / 2) as i64);
+    let mut out = Cursor::new(Vec::new());
+    DynamicImage::ImageRgb8(image).write_to(&mut out, ImageOutputFormat::Jpeg(JPEG_QUALITY)).map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
+
+// The JPEG already made of frame `timestamp` for `key`, if there is one
+fn made(key: &str, timestamp: &str) -> Option<Bytes> {
+    let mut latest = LATEST.lock().unwrap();
+    let made = latest.get_mut(key).filter(|made| made.timestamp == timestamp)?;
+    made.used = Instant::now();
+    Some(made.jpeg.clone())
+}
+
+fn store(layer: String, key: String, timestamp: &str, jpeg: Bytes) {
+    let mut latest = LATEST.lock().unwrap();
+    // Only the newest frame of each layer is kept; sizes of it still on an
+    // older one won't be asked for that again
+    if latest.values().any(|made| made.layer == layer && made.timestamp.as_str() > timestamp) {
+        return;
+    }
+    latest.retain(|_, made| made.layer != layer || made.timestamp.as_str() >= timestamp);
+    if !latest.contains_key(&key) && latest.len() >= MAX_JPEGS {
+        let least_used = latest.iter().min_by_key(|(_, made)| made.used).map(|(key, _)| key.clone());
+        if let Some(least_used) = least_used {
+            latest.remove(&least_used);
+        }
+    }
+    latest.insert(key, Made { layer, timestamp: timestamp.to_string(), jpeg, used: Instant::now() });
+}
+
+/// Frame `timestamp` of `layer` as a `width` by `height` JPEG, the disk
+/// fitted inside it on black, made once however many streams and stills
+/// show it; `None` if none of its tiles could be had.
+pub async fn frame_jpeg(layer: &Layer, timestamp: &str, width: u32, height: u32) -> Option<Bytes> {
+    let Layer { sat, sector, product, .. } = layer;
+    let layer_key = format!("{} {} {}", sat, sector, product);
+    let key = format!("{} {}x{}", layer_key, width, height);
+    if let Some(jpeg) = made(&key, timestamp) {
+        return Some(jpeg);
+    }
+
+    let (max_zoom, tile_size) = layer.tile_grid();
+    let fit = width.min(height);
+    let zoom = (0..=max_zoom).find(|&z| tile_size << z >= fit).unwrap_or(max_zoom);
+    let _slot = STITCH_SLOT.acquire().await.ok()?;
+    // Someone else may have made it while we waited for the slot
+    if let Some(jpeg) = made(&key, timestamp) {
+        return Some(jpeg);
+    }
+    let tiles = disk_tiles(layer, timestamp, zoom).await;
+    if tiles.iter().all(Option::is_none) {
+        return None;
+    }
+    let jpeg = tokio::task::spawn_blocking(move || compose(&tiles, tile_size << zoom, tile_size, width, height)).await.ok()?.ok()?;
+    let jpeg = Bytes::from(jpeg);
+    store(layer_key, key, timestamp, jpeg.clone());
+    Some(jpeg)
+}
+
+// One part of the multipart stream
+fn part(jpeg: &Bytes) -> Bytes {
+    let head = format!("--{}\r\nContent-Type: image/jpeg\r\nContent-Length: {}\r\n\r\n", BOUNDARY, jpeg.len());
+    let mut part = Vec::with_capacity(head.len() + jpeg.len() + 2);
+    part.extend_from_slice(head.as_bytes());
+    part.extend_from_slice(jpeg);
+    part.extend_from_slice(b"\r\n");
+    Bytes::from(part)
+}
+
+// Wait for the next part to send: a new frame as soon as one is listed,
+// or the current one again after RESEND_INTERVAL
+async fn next_part(mut feed: Feed) -> Option<(Result<Bytes, Infallible>, Feed)> {
+    loop {
+        tokio::time::sleep(POLL_INTERVAL).await;
+        let timestamp = match frame_timestamp(&feed.layer, &feed.params, None).await {
+            Ok(timestamp) => timestamp,
+            Err(response) => {
+                debug!(sat = feed.layer.sat, status = response.status().as_u16(), "MJPEG stream: no frame list");
+                continue;
+            }
+        };
+        if timestamp == feed.timestamp && feed.sent_at.elapsed() < RESEND_INTERVAL {
+            continue;
+        }
+        // A new frame whose tiles aren't up yet waits for the next poll
+        let Some(jpeg) = frame_jpeg(&feed.layer, &timestamp, feed.width, feed.height).await else {
+            continue;
+        };
+        if timestamp != feed.timestamp {
+            info!(sat = feed.layer.sat, timestamp, "MJPEG stream: new frame");
+        }
+        feed.timestamp = timestamp;
+        feed.sent_at = Instant::now();
+        return Some((Ok(part(&jpeg)), feed));
+    }
+}
+
+/// `GET /stream.mjpeg?sat=19&res=1808x1808`: the newest full disk as a
+/// `multipart/x-mixed-replace` MJPEG stream, a new JPEG following whenever
+/// a new frame is listed, for players and dashboards that show a camera
+/// feed. `res` is the image size (1024x1024 by default, at most 4096 a
+/// side), the disk being fitted inside it on black; `sector`, `product`
+/// and `cdn` work as for `/slider-tile`.
+pub async fn handle_stream(Query(params): Query<Params>) -> Response {
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let (width, height) = match params.get("res") {
+        None => (DEFAULT_SIDE, DEFAULT_SIDE),
+        Some(res) => match parse_res(res) {
+            Some(size) => size,
+            None => return (StatusCode::BAD_REQUEST, "res must be WIDTHxHEIGHT, e.g. 1808x1808, at most 4096 a side").into_response(),
+        },
+    };
+    Span::current().record("sat", layer.sat.as_str());
+
+    // The first frame is made before answering, so failures get a status
+    let timestamp = match frame_timestamp(&layer, &params, None).await {
+        Ok(timestamp) => timestamp,
+        Err(response) => return response,
+    };
+    let feed = Feed { layer, params, width, height, timestamp: String::new(), sent_at: Instant::now() };
+    let Some(jpeg) = frame_jpeg(&feed.layer, &timestamp, feed.width, feed.height).await else {
+        return if offline::enabled() { offline::unavailable() } else { bad_gateway("No tiles for the newest frame") };
+    };
+    info!(sat = feed.layer.sat, timestamp, width, height, "MJPEG stream opened");
+    let feed = Feed { timestamp, ..feed };
+
+    let first = stream::once(async move { Ok::<_, Infallible>(part(&jpeg)) });
+    let body = Body::from_stream(futures_util::StreamExt::chain(first, stream::unfold(feed, next_part)));
+    (
+        [
+            (header::CONTENT_TYPE, format!("multipart/x-mixed-replace; boundary={}", BOUNDARY)),
+            (header::CACHE_CONTROL, "no-cache, no-store".to_string()),
+        ],
+        body,
+    )
+        .into_response()
+}
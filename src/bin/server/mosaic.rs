@@ -0,0 +1,267 @@
+use std::io::Cursor;
+use axum::body::Bytes;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::future::join_all;
+use image::{DynamicImage, ImageOutputFormat, Rgba, RgbaImage};
+use tracing::{debug, info, warn, Span};
+use crate::cache::{cache_key, get_cached_tile, put_cached_tile, TileFormat, DEFAULT_PRODUCT, DEFAULT_SECTOR};
+use crate::config::CONFIG;
+use crate::frames::{nearest_within, params_for, time_bounds, Layer};
+use crate::fulldisk::{JPEG_QUALITY, MISSING_HEADER};
+use crate::reproject::{self, frame_timestamp, reproject, Projection, View};
+use crate::satellites::{self, Satellite};
+use crate::{bad_gateway, cached_tile_response, dates, offline, tile_response, xyz, Params};
+
+/// What the mosaic goes by in `/xyz` URLs, in place of a satellite.
+pub const SAT: &str = "mosaic";
+/// Set to the frames a mosaic was made from, as `sat:timestamp` pairs.
+pub const FRAMES_HEADER: HeaderName = HeaderName::from_static("x-peepsat-frames");
+// Mosaic tiles of the default satellites' GeoColor are cached as
+// "xyz/geocolor/mosaic_{timestamp}_{z}_{x}_{y}", the timestamp being the
+// time asked for
+const CACHE_SECTOR: &str = "xyz";
+// GOES-West, GOES-East, Meteosat and Himawari, round the equator
+const DEFAULT_SATS: &[&str] = &["18", "19", "meteosat12", "himawari"];
+const MAX_SATS: usize = 6;
+// A satellite's frame further than this from the time asked for is left out
+const TOLERANCE: i64 = 30 * 60;
+// How steeply a satellite's share of a pixel falls off away from the point
+// under it; neighbours' seams feather over about ten degrees of longitude
+const FEATHER: i32 = 12;
+const DEFAULT_WIDTH: u32 = 2048;
+const MAX_WIDTH: u32 = 8192;
+// Full-disk tiles resampled from each satellite for a whole mosaic, and
+// for one tile of it
+const MAX_IMAGE_SOURCES: usize = 64;
+const MAX_TILE_SOURCES: usize = 16;
+
+// One satellite's frame in the mosaic
+struct Source {
+    layer: Layer,
+    timestamp: String,
+    longitude: f64,
+}
+
+// A mosaic and what went into it
+struct Mosaic {
+    image: RgbaImage,
+    frames: String,
+    // Satellites asked for with no frame, or none of its tiles, to add
+    absent: usize,
+    // Full-disk tiles that couldn't be had
+    missing: usize,
+}
+
+// The satellites asked for with `sats`, or DEFAULT_SATS
+fn chosen(params: &Params) -> Result<Vec<&'static Satellite>, String> {
+    let Some(sats) = params.get("sats") else {
+        return Ok(DEFAULT_SATS.iter().filter_map(|sat| satellites::find(sat)).collect());
+    };
+    let chosen: Vec<&Satellite> = sats
+        .split(',')
+        .filter(|s| !s.is_empty())
+        .map(|sat| satellites::find(sat).ok_or_else(|| format!("Unknown satellite {}", sat)))
+        .collect::<Result<_, _>>()?;
+    if chosen.is_empty() || chosen.len() > MAX_SATS {
+        return Err(format!("sats must name 1 to {} satellites", MAX_SATS));
+    }
+    Ok(chosen)
+}
+
+// Each satellite's full-disk frame nearest `time`, within TOLERANCE, or
+// its newest if that's `None`; those without one are left out
+async fn sources(chosen: &[&'static Satellite], params: &Params, time: Option<i64>) -> Vec<Source> {
+    let found = join_all(chosen.iter().map(|satellite| async move {
+        let mut params = params.clone();
+        params.insert("sector".to_string(), DEFAULT_SECTOR.to_string());
+        let params = params_for(satellite, &params);
+        let layer = Layer::from_params(&params)?;
+        let timestamp = match time {
+            Some(t) => nearest_within(&layer, &params, t, TOLERANCE).await.ok()?.0?.to_string(),
+            None => frame_timestamp(&layer, &params, None).await.ok()?,
+        };
+        Some(Source { layer, timestamp, longitude: satellite.longitude })
+    }))
+    .await;
+    found.into_iter().flatten().collect()
+}
+
+// The reprojected frames blended: each pixel a mix of the satellites that
+// see it, weighted toward the one it's nearest the middle of the disk of
+fn blend(layers: &[(f64, RgbaImage)], view: View) -> RgbaImage {
+    RgbaImage::from_fn(view.width, view.height, |px, py| {
+        let (lat, lon) = view.lat_lon(px, py);
+        let cos_lat = lat.to_radians().cos();
+        let (mut sum, mut total) = ([0.0f64; 3], 0.0f64);
+        for (longitude, image) in layers {
+            let [r, g, b, a] = image.get_pixel(px, py).0;
+            if a == 0 {
+                continue;
+            }
+            // The cosine of the angle from the point under the satellite
+            let weight = (cos_lat * (lon - longitude).to_radians().cos()).max(0.0).powi(FEATHER).max(f64::MIN_POSITIVE);
+            for (channel, value) in sum.iter_mut().zip([r, g, b]) {
+                *channel += value as f64 * weight;
+            }
+            total += weight;
+        }
+        if total == 0.0 {
+            return Rgba([0, 0, 0, 0]);
+        }
+        let [r, g, b] = sum.map(|channel| (channel / total).round() as u8);
+        Rgba([r, g, b, 255])
+    })
+}
+
+// The mosaic of the frames of `chosen` nearest `time` (or their newest)
+// on `view`, from at most `max_sources` full-disk tiles of each
+async fn mosaic(chosen: &[&'static Satellite], params: &Params, time: Option<i64>, view: View, max_sources: usize) -> Result<Mosaic, Response> {
+    let sources = sources(chosen, params, time).await;
+    let reprojections = join_all(sources.iter().map(|s| reproject(&s.layer, &s.timestamp, view, max_sources))).await;
+    let (mut layers, mut frames, mut missing) = (Vec::new(), Vec::new(), 0);
+    for (source, reprojection) in sources.iter().zip(reprojections) {
+        match reprojection {
+            Ok(reprojection) => {
+                missing += reprojection.missing;
+                frames.push(format!("{}:{}", source.layer.sat, source.timestamp));
+                layers.push((source.longitude, reprojection.image));
+            }
+            Err(response) => debug!(sat = source.layer.sat, status = response.status().as_u16(), "Satellite left out of mosaic"),
+        }
+    }
+    if layers.is_empty() {
+        return Err(if offline::enabled() { offline::unavailable() } else { bad_gateway("No frames for the mosaic") });
+    }
+    let absent = chosen.len() - layers.len();
+    let image = match tokio::task::spawn_blocking(move || blend(&layers, view)).await {
+        Ok(image) => image,
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to render mosaic").into_response()),
+    };
+    Ok(Mosaic { image, frames: frames.join(","), absent, missing })
+}
+
+// Whether a mosaic for `time` is final: every satellite and tile in it,
+// and far enough back that no nearer frame will be listed
+fn is_final(mosaic: &Mosaic, time: Option<i64>) -> bool {
+    mosaic.absent == 0 && mosaic.missing == 0 && time.is_some_and(|t| t + TOLERANCE < dates::now())
+}
+
+// The mosaic's headers on a response
+fn mark(mut response: Response, frames: &str, missing: usize) -> Response {
+    if let Ok(value) = HeaderValue::from_str(frames) {
+        response.headers_mut().insert(FRAMES_HEADER, value);
+    }
+    response.headers_mut().insert(MISSING_HEADER, HeaderValue::from(missing));
+    if offline::enabled() { offline::mark(response) } else { response }
+}
+
+/// `GET /api/mosaic?t=2024-10-16T12:00Z&width=4096`: GOES-18 and -19,
+/// Meteosat-12 and Himawari's frames nearest `t` (their newest without it)
+/// reprojected onto one equirectangular map of the world, `width` pixels
+/// across (2048 by default, at most 8192) and half that high. Where
+/// satellites overlap the seam is feathered, each pixel weighted toward
+/// the satellite it's nearest the middle of the disk of. `sats` picks other
+/// satellites, `product` applies where a satellite has it, and `format` is
+/// `png`, `jpeg`, `webp` or `avif`. Satellites with no frame within 30 minutes of
+/// `t` are left out.
+pub async fn handle_mosaic(Query(params): Query<Params>) -> Response {
+    let chosen = match chosen(&params) {
+        Ok(chosen) => chosen,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    let time = match params.get("t") {
+        Some(t) => match time_bounds(t) {
+            Some((start, _)) => Some(start),
+            None => return (StatusCode::BAD_REQUEST, "t must be a time, e.g. 2024-10-16T12:00Z").into_response(),
+        },
+        None => None,
+    };
+    let width = match params.get("width").map(|w| w.parse::<u32>()) {
+        None => DEFAULT_WIDTH,
+        Some(Ok(width)) if (256..=MAX_WIDTH).contains(&width) => width,
+        Some(_) => return (StatusCode::BAD_REQUEST, format!("width must be from 256 to {}", MAX_WIDTH)).into_response(),
+    };
+    let jpeg = match params.get("format").map(String::as_str) {
+        // WebP and AVIF are the PNG re-encoded by transcode::layer
+        None | Some("png") | Some("webp") | Some("avif") => false,
+        Some("jpeg") | Some("jpg") => true,
+        Some(_) => return (StatusCode::BAD_REQUEST, "format must be png, jpeg, webp or avif").into_response(),
+    };
+
+    let view = View { projection: Projection::Geographic, bbox: (-180.0, -90.0, 180.0, 90.0), width, height: width / 2 };
+    let mosaic = match mosaic(&chosen, &params, time, view, MAX_IMAGE_SOURCES).await {
+        Ok(mosaic) => mosaic,
+        Err(response) => return response,
+    };
+    let cache_control = if is_final(&mosaic, time) {
+        format!("public, max-age={}, immutable", CONFIG.tile_max_age)
+    } else {
+        "no-cache".to_string()
+    };
+    let Mosaic { image, frames, missing, .. } = mosaic;
+    let encode = move || {
+        let (image, format) = if jpeg {
+            (DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(image).to_rgb8()), ImageOutputFormat::Jpeg(JPEG_QUALITY))
+        } else {
+            (DynamicImage::ImageRgba8(image), ImageOutputFormat::Png)
+        };
+        let mut out = Cursor::new(Vec::new());
+        image.write_to(&mut out, format).map(|_| out.into_inner()).map_err(|e| e.to_string())
+    };
+    let image = match tokio::task::spawn_blocking(encode).await {
+        Ok(Ok(image)) => Bytes::from(image),
+        Ok(Err(e)) => {
+            warn!(error = %e, "Mosaic encoding failed");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image").into_response();
+        }
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image").into_response(),
+    };
+    info!(frames, width, missing, bytes = image.len(), "Made mosaic");
+    let content_type = if jpeg { "image/jpeg" } else { "image/png" };
+    let response = ([(header::CONTENT_TYPE, content_type.to_string()), (header::CACHE_CONTROL, cache_control)], image).into_response();
+    mark(response, &frames, missing)
+}
+
+/// Web Mercator tile `zoom`/`x`/`y` of the mosaic at `time` (the newest
+/// frames if that's `None`), for `/xyz/mosaic/...`. Takes `sats` and
+/// `product` as `/api/mosaic` does; tiles of the default satellites'
+/// GeoColor are cached once final.
+pub async fn tile(params: &Params, time: Option<i64>, zoom: u32, x: u32, y: u32, headers: &HeaderMap) -> Response {
+    let chosen = match chosen(params) {
+        Ok(chosen) => chosen,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    let span = Span::current();
+    span.record("sat", SAT);
+    span.record("x", x);
+    span.record("y", y);
+    span.record("z", zoom);
+
+    // Only the default mosaic is cached, from SLIDER's GeoColor
+    let cacheable = !["sats", "product", "band", "cdn"].iter().any(|p| params.contains_key(*p));
+    let key = time.filter(|_| cacheable).map(|t| cache_key(SAT, CACHE_SECTOR, DEFAULT_PRODUCT, &dates::timestamp(t), zoom, x, y));
+    let response_timestamp = time.map_or_else(|| "0".to_string(), dates::timestamp);
+    if let Some(key) = &key {
+        if let Some((data, format)) = get_cached_tile(key).await {
+            span.record("cache", "HIT");
+            let response = cached_tile_response(data, format, &response_timestamp, headers).await;
+            return if offline::enabled() { offline::mark(response) } else { response };
+        }
+    }
+    span.record("cache", "MISS");
+
+    let mosaic = match mosaic(&chosen, params, time, xyz::tile_view(zoom, x, y), MAX_TILE_SOURCES).await {
+        Ok(mosaic) => mosaic,
+        Err(response) => return response,
+    };
+    let done = is_final(&mosaic, time);
+    let Mosaic { image, frames, missing, .. } = mosaic;
+    let png = Bytes::from(reproject::png(image));
+    if let Some(key) = key.filter(|_| done) {
+        put_cached_tile(&key, &png).await;
+    }
+    let response = tile_response(png, TileFormat::Png, "MISS", if done { &response_timestamp } else { "0" }, headers);
+    mark(response, &frames, missing)
+}
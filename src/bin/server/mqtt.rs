@@ -0,0 +1,186 @@
+use std::io;
+use std::time::Duration;
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, info, warn};
+use crate::config::CONFIG;
+use crate::events::{self, with_links, NewFrame, Subscription};
+use crate::frames::Layer;
+use crate::Params;
+
+// Just enough of MQTT 3.1.1 to publish: CONNECT, PUBLISH at QoS 0 and
+// keep-alive pings, over plain TCP
+const PROTOCOL_LEVEL: u8 = 4;
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+const DEFAULT_PORT: u16 = 1883;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+// Packet types, in the fixed header's high nibble
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+// QoS 0, retained, so a client subscribing later gets the newest frame
+const PUBLISH_RETAINED: u8 = 0x31;
+const PINGREQ: u8 = 0xc0;
+// Broker replies are tiny; anything bigger is taken as a broken connection
+const MAX_PACKET: usize = 64 * 1024;
+
+fn put_string(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(&(text.len() as u16).to_be_bytes());
+    out.extend_from_slice(text.as_bytes());
+}
+
+// A packet: its fixed header (`kind` and the remaining length, 7 bits a
+// byte, low first) and then `body`
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    let mut length = body.len();
+    loop {
+        let byte = (length % 128) as u8;
+        length /= 128;
+        out.push(if length > 0 { byte | 0x80 } else { byte });
+        if length == 0 {
+            break;
+        }
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+fn connect_packet(client_id: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    put_string(&mut body, "MQTT");
+    body.push(PROTOCOL_LEVEL);
+    // Clean session, and the credentials that follow; a password is only
+    // allowed after a username
+    let username = CONFIG.mqtt_username.as_deref();
+    let password = CONFIG.mqtt_password.as_deref().filter(|_| username.is_some());
+    let mut flags = 0x02;
+    if username.is_some() {
+        flags |= 0x80;
+    }
+    if password.is_some() {
+        flags |= 0x40;
+    }
+    body.push(flags);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    put_string(&mut body, client_id);
+    for credential in [username, password].into_iter().flatten() {
+        put_string(&mut body, credential);
+    }
+    packet(CONNECT, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(2 + topic.len() + payload.len());
+    put_string(&mut body, topic);
+    body.extend_from_slice(payload);
+    packet(PUBLISH_RETAINED, &body)
+}
+
+// One packet from the broker: its fixed header's first byte and its body
+async fn read_packet(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<(u8, Vec<u8>)> {
+    let kind = reader.read_u8().await?;
+    let mut length = 0usize;
+    for shift in (0..28).step_by(7) {
+        let byte = reader.read_u8().await?;
+        length |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    if length > MAX_PACKET {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "packet too large"));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).await?;
+    Ok((kind, body))
+}
+
+fn topic(frame: &NewFrame) -> String {
+    let sat = frame.data.get("sat").and_then(Value::as_str).unwrap_or_default();
+    format!("{}/{}", CONFIG.mqtt_topic.trim_end_matches('/'), sat)
+}
+
+// The PUBLISH announcing `frame` on its satellite's topic
+fn frame_packet(frame: &NewFrame) -> Vec<u8> {
+    publish_packet(&topic(frame), with_links(&frame.data).to_string().as_bytes())
+}
+
+// Connect to the broker and publish new frames until the connection drops
+async fn session(
+    broker: &str,
+    layers: &[(Layer, Params)],
+    subscription: &Subscription,
+    frames: &mut broadcast::Receiver<NewFrame>,
+) -> io::Result<()> {
+    let address = if broker.contains(':') { broker.to_string() } else { format!("{}:{}", broker, DEFAULT_PORT) };
+    let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "timed out");
+    let mut stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&address)).await.map_err(|_| timed_out())??;
+    stream.write_all(&connect_packet(&format!("peepsat-{:08x}", fastrand::u32(..)))).await?;
+    let (kind, body) = tokio::time::timeout(CONNECT_TIMEOUT, read_packet(&mut stream)).await.map_err(|_| timed_out())??;
+    if kind & 0xf0 != CONNACK || body.get(1) != Some(&0) {
+        let message = format!("broker refused the connection (code {})", body.get(1).copied().unwrap_or_default());
+        return Err(io::Error::new(io::ErrorKind::ConnectionRefused, message));
+    }
+    info!(broker, "Connected to MQTT broker");
+
+    let (mut reader, mut writer) = stream.into_split();
+    // Pings are answered, and nothing else is expected; this ends when
+    // the broker hangs up
+    let mut closed = tokio::spawn(async move { while read_packet(&mut reader).await.is_ok() {} });
+    for frame in events::newest(layers).await {
+        writer.write_all(&frame_packet(&frame)).await?;
+    }
+    let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + KEEP_ALIVE / 2, KEEP_ALIVE / 2);
+    loop {
+        tokio::select! {
+            frame = frames.recv() => match frame {
+                Ok(frame) if frame.claimed && subscription.wants(&frame) => {
+                    writer.write_all(&frame_packet(&frame)).await?;
+                    debug!(topic = topic(&frame), timestamp = frame.timestamp, "Published new frame");
+                }
+                Ok(_) | Err(RecvError::Lagged(_)) => {}
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = ping.tick() => writer.write_all(&packet(PINGREQ, &[])).await?,
+            _ = &mut closed => return Err(io::Error::new(io::ErrorKind::ConnectionReset, "broker closed the connection")),
+        }
+    }
+}
+
+/// With `mqtt_broker` set, publish each new frame of `mqtt_satellites` to
+/// "{mqtt_topic}/{sat}" as JSON (as `/api/events` describes it, plus
+/// `preview_url` and `image_url`), retained, and each satellite's newest
+/// frame on connecting. Reconnects whenever the connection drops.
+pub fn spawn_publisher() {
+    let Some(broker) = CONFIG.mqtt_broker.clone() else {
+        return;
+    };
+    let sats = events::announced_satellites(&CONFIG.mqtt_satellites);
+    let params = Params::from([("sats".to_string(), sats.join(","))]);
+    let layers = match events::layers(&params) {
+        Ok(layers) => layers,
+        Err(message) => {
+            warn!(error = message, "MQTT: not publishing");
+            return;
+        }
+    };
+    info!(broker, ?sats, "Publishing new frames over MQTT");
+    tokio::spawn(async move {
+        let mut frames = events::new_frames();
+        // Followed for as long as the server runs
+        let Some(subscription) = Subscription::new(&layers) else {
+            warn!("MQTT: too many layers being followed");
+            return;
+        };
+        loop {
+            match session(&broker, &layers, &subscription, &mut frames).await {
+                Ok(()) => return,
+                Err(e) => warn!(broker, error = %e, "MQTT connection failed; retrying in {}s", RETRY_DELAY.as_secs()),
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+        }
+    });
+}
@@ -0,0 +1,365 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use axum::body::Bytes;
+use axum::http::StatusCode;
+use image::{DynamicImage, GrayImage, ImageOutputFormat, Luma};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{OnceCell, Semaphore};
+use tracing::{debug, info, warn};
+use crate::cache::CACHE_DIR;
+use crate::config::CONFIG;
+use crate::dates::{civil_from_days, days_from_civil, now, parse_time, timestamp};
+use crate::upstream::{self, HTTP_CLIENT};
+use crate::{cache, s3, satellites, Tile, UpstreamTile};
+
+// Frames listed by slider-latest, searched for this far back
+const SEARCH_HOURS: i64 = 6;
+// Full-disk files run to a few hundred MB for the 0.5 km band
+const MAX_FILE_BYTES: usize = 1024 * 1024 * 1024;
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(600);
+// Files kept on disk to cut tiles from; a frame's tiles are mostly asked
+// for together, so a few bands of the newest frames will do
+const MAX_FILES: usize = 8;
+// Archive downloads at once. They take their own slots rather than the
+// upstream ones, which they'd hold for minutes while viewers waited.
+const MAX_DOWNLOADS: usize = 2;
+// Brightness temperatures shown from white to black, K, as SLIDER's IR
+// bands are
+const COLDEST: f32 = 180.0;
+const WARMEST: f32 = 330.0;
+// Reflectances are brightened by this gamma, or the disk is mostly dark
+const GAMMA: f32 = 2.2;
+// The ABI's reflective bands; the rest are infrared
+const REFLECTIVE_BANDS: u32 = 6;
+const ABI_BANDS: u32 = 16;
+
+/// The band the source serves when a request doesn't name a product, as
+/// there's no GeoColor in the archive.
+pub const DEFAULT_PRODUCT: &str = "band_13";
+
+/// NOAA's Open Data bucket for each GOES-R satellite.
+const BUCKETS: &[(&str, &str)] = &[("16", "noaa-goes16"), ("18", "noaa-goes18"), ("19", "noaa-goes19")];
+
+/// Whether `satellite_sources` can send `sat` to NOAA's archive.
+pub fn supports(sat: &str) -> bool {
+    BUCKETS.iter().any(|(key, _)| *key == sat)
+}
+
+/// Whether `cdn` is NOAA's archive, i.e. a satellite configured for it.
+pub fn is_source(cdn: &str) -> bool {
+    cdn == CONFIG.noaa_goes_url
+}
+
+/// Whether the archive has `product`: one of the ABI's bands.
+pub fn has_product(product: &str) -> bool {
+    satellites::band_number(product).is_some_and(|band| (1..=ABI_BANDS).contains(&band))
+}
+
+/// The ABI's bands, which are all the archive is rendered from.
+pub fn products() -> Vec<String> {
+    (1..=ABI_BANDS).map(|band| format!("band_{:02}", band)).collect()
+}
+
+// The data set files are listed under: radiances (L1b) or the Cloud and
+// Moisture Imagery made from them (L2), full disk
+fn data_set() -> &'static str {
+    if CONFIG.noaa_goes_level == "l2" {
+        "ABI-L2-CMIPF"
+    } else {
+        "ABI-L1b-RadF"
+    }
+}
+
+fn bucket(sat: &str) -> Option<&'static str> {
+    BUCKETS.iter().find(|(key, _)| *key == sat).map(|(_, bucket)| *bucket)
+}
+
+// Scan start of a file named like
+// OR_ABI-L1b-RadF-M6C13_G19_s20242901200205_e..., as seconds since the epoch
+fn scan_start(name: &str) -> Option<i64> {
+    let start = name.split("_s").nth(1)?.get(..13)?;
+    let field = |range: std::ops::Range<usize>| start.get(range)?.parse::<i64>().ok();
+    let days = days_from_civil(field(0..4)?, 1, 1) + field(4..7)? - 1;
+    Some(days * 86400 + field(7..9)? * 3600 + field(9..11)? * 60 + field(11..13)?)
+}
+
+// The files of `band` from the hour starting at `hour` (seconds since the
+// epoch), with their scan starts
+async fn hour_files(bucket: &str, band: u32, hour: i64) -> Result<Vec<(i64, String)>, &'static str> {
+    let days = hour.div_euclid(86400);
+    let (year, _, _) = civil_from_days(days);
+    let day_of_year = days - days_from_civil(year, 1, 1) + 1;
+    let prefix = format!("{}/{}/{:03}/{:02}/", data_set(), year, day_of_year, hour.rem_euclid(86400) / 3600);
+    // An hour of one data set is well under a page of 1000 keys
+    let url = format!("{}/{}?list-type=2&prefix={}", CONFIG.noaa_goes_url, bucket, urlencoding::encode(&prefix));
+    debug!(url, "Listing NOAA archive");
+    let response = match upstream::get(&HTTP_CLIENT, &[url]).await {
+        Ok(r) if r.status().is_success() => r,
+        Ok(r) => {
+            warn!(bucket, status = r.status().as_u16(), "NOAA archive listing refused");
+            return Err("Failed");
+        }
+        Err(e) => {
+            warn!(bucket, error = %e, "NOAA archive listing failed");
+            return Err("Failed");
+        }
+    };
+    let xml = response.text().await.map_err(|_| "Failed")?;
+    let channel = format!("C{:02}_G", band);
+    Ok(s3::elements(&xml, "Key")
+        .into_iter()
+        .filter(|key| key.contains(&channel))
+        .filter_map(|key| Some((scan_start(&key)?, key)))
+        .collect())
+}
+
+// Scan starts of `band`'s frames in the last SEARCH_HOURS, newest first
+async fn frame_times(sat: &str, product: &str) -> Result<Vec<i64>, &'static str> {
+    let bucket = bucket(sat).ok_or("Not a GOES-R satellite")?;
+    let band = satellites::band_number(product).ok_or("Not an ABI band")?;
+    let this_hour = now() - now().rem_euclid(3600);
+    let hours = (0..SEARCH_HOURS).map(|back| hour_files(bucket, band, this_hour - back * 3600));
+    let mut times = Vec::new();
+    for listed in futures_util::future::join_all(hours).await {
+        // The current hour may not have been started on yet
+        times.extend(listed.unwrap_or_default().into_iter().map(|(time, _)| time));
+    }
+    if times.is_empty() {
+        return Err("Failed");
+    }
+    times.sort_unstable_by(|a, b| b.cmp(a));
+    times.dedup();
+    Ok(times)
+}
+
+/// `latest_times.json` for `product` of `sat`, as SLIDER would send it.
+pub async fn latest(sat: &str, product: &str) -> Result<Bytes, &'static str> {
+    let times: Vec<String> = frame_times(sat, product).await?.into_iter().map(timestamp).collect();
+    Ok(Bytes::from(format!(r#"{{"timestamps_int":[{}]}}"#, times.join(","))))
+}
+
+/// `available_dates.json` for `product` of `sat`, covering the days of
+/// the frames found.
+pub async fn dates(sat: &str, product: &str) -> Result<Bytes, &'static str> {
+    let mut dates: Vec<String> = frame_times(sat, product).await?.into_iter().map(|t| timestamp(t)[..8].to_string()).collect();
+    dates.dedup();
+    Ok(Bytes::from(format!(r#"{{"dates_int":[{}]}}"#, dates.join(","))))
+}
+
+type Download = Arc<OnceCell<Option<PathBuf>>>;
+
+lazy_static::lazy_static! {
+    // Files downloaded or being downloaded, by name, oldest first
+    static ref FILES: Mutex<VecDeque<(String, Download)>> = Mutex::new(VecDeque::new());
+    // Left over from the last run, which nothing refers to any more
+    static ref CLEARED: OnceCell<()> = OnceCell::new();
+    static ref DOWNLOAD_SLOTS: Semaphore = Semaphore::new(MAX_DOWNLOADS);
+}
+
+fn files_dir() -> PathBuf {
+    CACHE_DIR.join("noaa")
+}
+
+// The file `key` of `bucket` on disk, downloaded once however many tiles
+// are cut from it. The oldest file goes when there are more than MAX_FILES.
+async fn download(bucket: &str, key: &str) -> Option<PathBuf> {
+    CLEARED
+        .get_or_init(|| async {
+            let _ = tokio::fs::remove_dir_all(files_dir()).await;
+        })
+        .await;
+    let name = key.rsplit('/').next().unwrap_or(key).to_string();
+    let cell = {
+        let mut files = FILES.lock().unwrap();
+        match files.iter().find(|(file, _)| *file == name) {
+            Some((_, cell)) => cell.clone(),
+            None => {
+                let cell = Download::default();
+                files.push_back((name.clone(), cell.clone()));
+                // Not one still downloading, which would be left behind
+                let done = files.iter().position(|(_, cell)| cell.initialized());
+                if let (true, Some(oldest)) = (files.len() > MAX_FILES, done) {
+                    if let Some((old, _)) = files.remove(oldest) {
+                        // Tiles being cut from it keep it open until they're done
+                        let _ = std::fs::remove_file(files_dir().join(old));
+                    }
+                }
+                cell
+            }
+        }
+    };
+    let path = cell.get_or_init(|| fetch_file(bucket, key, files_dir().join(&name))).await.clone();
+    if path.is_none() {
+        // Let the next request try again
+        FILES.lock().unwrap().retain(|(_, c)| !Arc::ptr_eq(c, &cell));
+    }
+    path
+}
+
+async fn fetch_file(bucket: &str, key: &str, path: PathBuf) -> Option<PathBuf> {
+    let url = format!("{}/{}/{}", CONFIG.noaa_goes_url, bucket, key);
+    info!(url, "Downloading from NOAA archive");
+    let _slot = DOWNLOAD_SLOTS.acquire().await.ok()?;
+    // Far longer than a tile is allowed
+    let mut response = match HTTP_CLIENT.get(&url).timeout(DOWNLOAD_TIMEOUT).send().await {
+        Ok(r) if r.status().is_success() => r,
+        Ok(r) => {
+            warn!(url, status = r.status().as_u16(), "NOAA archive download refused");
+            return None;
+        }
+        Err(e) => {
+            warn!(url, error = %e, "NOAA archive download failed");
+            return None;
+        }
+    };
+    let partial = path.with_extension("part");
+    let written = async {
+        tokio::fs::create_dir_all(files_dir()).await.map_err(|e| e.to_string())?;
+        let mut file = tokio::fs::File::create(&partial).await.map_err(|e| e.to_string())?;
+        let mut size = 0;
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            size += chunk.len();
+            if size > MAX_FILE_BYTES {
+                return Err(format!("larger than {} bytes", MAX_FILE_BYTES));
+            }
+            file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+        }
+        file.flush().await.map_err(|e| e.to_string())?;
+        tokio::fs::rename(&partial, &path).await.map_err(|e| e.to_string())
+    };
+    match written.await {
+        Ok(()) => Some(path),
+        Err(e) => {
+            warn!(url, error = %e, "NOAA archive download failed");
+            let _ = tokio::fs::remove_file(&partial).await;
+            None
+        }
+    }
+}
+
+/// Cut one tile of `tile.product` at `tile.timestamp` from the archive's
+/// NetCDF file and cache it. The ABI's fixed grid is the one SLIDER tiles,
+/// so each band's full resolution fills its deepest zoom level.
+pub async fn fetch_tile(tile: &Tile<'_>, key: &str) -> UpstreamTile {
+    let (Some(bucket), Some(band), Some(satellite)) = (bucket(tile.sat), satellites::band_number(tile.product), satellites::find(tile.sat)) else {
+        return Ok((StatusCode::NOT_FOUND, Bytes::new()));
+    };
+    let Some(time) = parse_time(tile.timestamp) else {
+        return Ok((StatusCode::BAD_REQUEST, Bytes::from_static(b"Invalid timestamp")));
+    };
+    let Ok(listed) = hour_files(bucket, band, time - time.rem_euclid(3600)).await else {
+        return Err(());
+    };
+    let Some((_, file)) = listed.into_iter().find(|(start, _)| *start == time) else {
+        return Ok((StatusCode::NOT_FOUND, Bytes::new()));
+    };
+    let Some(path) = download(bucket, &file).await else {
+        return Err(());
+    };
+    let (zoom, row, column, tile_size) = (tile.zoom, tile.x, tile.y, satellite.tile_size);
+    let rendered = tokio::task::spawn_blocking(move || render(&path, band, zoom, row, column, tile_size)).await;
+    let png = match rendered {
+        Ok(Ok(png)) => Bytes::from(png),
+        Ok(Err(e)) => {
+            warn!(key, file, error = %e, "Couldn't read NOAA archive file");
+            return Err(());
+        }
+        Err(_) => return Err(()),
+    };
+    cache::put_cached_tile(key, &png).await;
+    Ok((StatusCode::OK, png))
+}
+
+// Grey level of a reflectance factor or brightness temperature (K) of `band`
+fn grey(band: u32, value: f32) -> u8 {
+    let level = if band <= REFLECTIVE_BANDS {
+        value.clamp(0.0, 1.0).powf(1.0 / GAMMA)
+    } else {
+        ((WARMEST - value) / (WARMEST - COLDEST)).clamp(0.0, 1.0)
+    };
+    (level * 255.0).round() as u8
+}
+
+// Tile (`row`, `column`) at `zoom` of the file at `path` as a grey PNG;
+// space is black
+fn render(path: &Path, band: u32, zoom: u32, row: u32, column: u32, tile_size: u32) -> Result<Vec<u8>, String> {
+    let window = read(path, band, zoom, row, column, tile_size)?;
+    let mut image = GrayImage::new(tile_size, tile_size);
+    for (i, value) in window.values.iter().enumerate() {
+        if let Some(value) = value {
+            image.put_pixel((i % window.width) as u32, (i / window.width) as u32, Luma([grey(band, *value)]));
+        }
+    }
+    let mut png = Cursor::new(Vec::new());
+    DynamicImage::ImageLuma8(image).write_to(&mut png, ImageOutputFormat::Png).map_err(|e| e.to_string())?;
+    Ok(png.into_inner())
+}
+
+// Part of a tile's pixels, from its top left corner, row by row:
+// reflectance factors or brightness temperatures, `None` off the disk
+struct Window {
+    width: usize,
+    values: Vec<Option<f32>>,
+}
+
+// A tile's pixels from the file at `path`. Coarser zooms take every nth
+// pixel of the band's full resolution.
+#[cfg(feature = "netcdf")]
+fn read(path: &Path, band: u32, zoom: u32, row: u32, column: u32, tile_size: u32) -> Result<Window, String> {
+    let file = netcdf::open(path).map_err(|e| e.to_string())?;
+    let level_two = file.variable("CMI").is_some();
+    let variable = file.variable(if level_two { "CMI" } else { "Rad" }).ok_or("No radiance or CMI variable")?;
+    let [rows, columns] = variable.dimensions() else {
+        return Err("Not a 2D image".to_string());
+    };
+    let (rows, columns) = (rows.len(), columns.len());
+    let attribute = |name: &str| variable.attribute_value(name)?.ok();
+    let number = |name: &str| attribute(name).and_then(|value| f32::try_from(value).ok());
+    let (scale, offset) = (number("scale_factor").unwrap_or(1.0), number("add_offset").unwrap_or(0.0));
+    let fill = attribute("_FillValue").and_then(|value| i16::try_from(value).ok());
+    // Stored as shorts, to be read as unsigned
+    let unsigned = matches!(attribute("_Unsigned"), Some(netcdf::AttributeValue::Str(s)) if s == "true");
+    let scalar = |name: &str| -> Result<f32, String> {
+        let variable = file.variable(name).ok_or_else(|| format!("No {}", name))?;
+        variable.get_value::<f32, _>(()).map_err(|e| e.to_string())
+    };
+    // L2 has done the calibration; from L1b radiances it's reflectance
+    // factor by kappa0, or brightness temperature by the inverse Planck
+    // function with the band's coefficients
+    let calibrate: Box<dyn Fn(f32) -> f32> = if level_two {
+        Box::new(|value| value)
+    } else if band <= REFLECTIVE_BANDS {
+        let kappa = scalar("kappa0")?;
+        Box::new(move |radiance| radiance * kappa)
+    } else {
+        let (fk1, fk2, bc1, bc2) = (scalar("planck_fk1")?, scalar("planck_fk2")?, scalar("planck_bc1")?, scalar("planck_bc2")?);
+        Box::new(move |radiance| if radiance > 0.0 { (fk2 / (fk1 / radiance + 1.0).ln() - bc1) / bc2 } else { WARMEST })
+    };
+
+    let tile_size = tile_size as usize;
+    let stride = (rows.min(columns) / (tile_size << zoom)).max(1);
+    let (first_row, first_column) = (row as usize * tile_size * stride, column as usize * tile_size * stride);
+    let count = |first: usize, total: usize| total.saturating_sub(first).div_ceil(stride).min(tile_size);
+    let (height, width) = (count(first_row, rows), count(first_column, columns));
+    if height == 0 || width == 0 {
+        return Ok(Window { width: tile_size, values: Vec::new() });
+    }
+    let extents = ([first_row, first_column], [height, width], [stride as isize, stride as isize]);
+    let raw = variable.get_values::<i16, _>(extents).map_err(|e| e.to_string())?;
+    let values = raw
+        .into_iter()
+        .map(|raw| {
+            let value = if unsigned { raw as u16 as f32 } else { raw as f32 };
+            (Some(raw) != fill).then(|| calibrate(value * scale + offset))
+        })
+        .collect();
+    Ok(Window { width, values })
+}
+
+#[cfg(not(feature = "netcdf"))]
+fn read(_path: &Path, _band: u32, _zoom: u32, _row: u32, _column: u32, _tile_size: u32) -> Result<Window, String> {
+    Err("built without NetCDF support".to_string())
+}
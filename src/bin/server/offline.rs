@@ -0,0 +1,53 @@
+use axum::http::{header, HeaderName, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use crate::cache;
+use crate::config::CONFIG;
+
+/// Set on everything answered from the cache alone, so the frontend can
+/// show that it's looking at old imagery.
+pub const HEADER: HeaderName = HeaderName::from_static("x-peepsat-offline");
+
+/// Whether upstream is never contacted (`--offline`).
+pub fn enabled() -> bool {
+    CONFIG.offline
+}
+
+pub fn mark(mut response: Response) -> Response {
+    response.headers_mut().insert(HEADER, HeaderValue::from_static("1"));
+    response
+}
+
+fn json(body: String) -> Response {
+    mark(([(header::CONTENT_TYPE, "application/json"), (header::CACHE_CONTROL, "no-cache")], body).into_response())
+}
+
+/// `latest_times.json` as SLIDER would send it, listing the cached frames.
+/// `None` if nothing of that satellite and product is cached.
+pub fn latest(sat: &str, sector: &str, product: &str) -> Option<Response> {
+    let frames = cache::cached_frames(sat, sector, product);
+    if frames.is_empty() {
+        return None;
+    }
+    Some(json(format!(r#"{{"timestamps_int":[{}]}}"#, frames.join(","))))
+}
+
+/// `available_dates.json` for the cached frames.
+pub fn dates(sat: &str, sector: &str, product: &str) -> Option<Response> {
+    let mut dates: Vec<&str> = Vec::new();
+    let frames = cache::cached_frames(sat, sector, product);
+    for frame in &frames {
+        let date = frame.get(..8).unwrap_or(frame);
+        if dates.last() != Some(&date) {
+            dates.push(date);
+        }
+    }
+    if dates.is_empty() {
+        return None;
+    }
+    Some(json(format!(r#"{{"dates_int":[{}]}}"#, dates.join(","))))
+}
+
+/// What to send when offline and nothing suitable is cached.
+pub fn unavailable() -> Response {
+    mark((StatusCode::NOT_FOUND, [(header::CACHE_CONTROL, "no-cache")], "Not cached (offline)").into_response())
+}
@@ -0,0 +1,255 @@
+use axum::extract::{Path, Query};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::future::join_all;
+use serde_json::{json, Value};
+use crate::dates::{iso, parse_time};
+use crate::satellites::{self, Satellite, SATELLITES};
+use crate::wmts::{base_url, find_layer, frame_times, layer_id, DISK_REACH, TILE_MATRIX_SET, WORLD_EDGE, ZOOM_0_SCALE};
+use crate::xyz::{reprojected_tile, MAX_ZOOM};
+use crate::Params;
+
+// Everything is under this prefix, the landing page being the prefix itself
+const ROOT: &str = "/ogcapi";
+// Metres per pixel of WebMercatorQuad's zoom 0 at the equator
+const ZOOM_0_CELL_SIZE: f64 = 156543.03392804097;
+
+const CONFORMANCE: &[&str] = &[
+    "http://www.opengis.net/spec/ogcapi-common-1/1.0/conf/core",
+    "http://www.opengis.net/spec/ogcapi-common-1/1.0/conf/landing-page",
+    "http://www.opengis.net/spec/ogcapi-common-1/1.0/conf/json",
+    "http://www.opengis.net/spec/ogcapi-common-2/1.0/conf/collections",
+    "http://www.opengis.net/spec/ogcapi-tiles-1/1.0/conf/core",
+    "http://www.opengis.net/spec/ogcapi-tiles-1/1.0/conf/tileset",
+    "http://www.opengis.net/spec/ogcapi-tiles-1/1.0/conf/tilesets-list",
+    "http://www.opengis.net/spec/ogcapi-tiles-1/1.0/conf/geodata-tilesets",
+    "http://www.opengis.net/spec/ogcapi-tiles-1/1.0/conf/datetime",
+    "http://www.opengis.net/spec/ogcapi-tiles-1/1.0/conf/png",
+    "http://www.opengis.net/spec/tms/2.0/conf/tilematrixset",
+    "http://www.opengis.net/spec/tms/2.0/conf/json-tilematrixset",
+];
+
+fn link(href: String, rel: &str, kind: &str, title: &str) -> Value {
+    json!({ "href": href, "rel": rel, "type": kind, "title": title })
+}
+
+fn not_found(what: &str) -> Response {
+    (StatusCode::NOT_FOUND, format!("Unknown {}", what)).into_response()
+}
+
+// The definition of WebMercatorQuad, as OGC's Two Dimensional Tile Matrix
+// Set standard (2.0) writes it
+fn tile_matrix_set(base: &str) -> Value {
+    let matrices: Vec<Value> = (0..=MAX_ZOOM)
+        .map(|zoom| {
+            json!({
+                "id": zoom.to_string(),
+                "scaleDenominator": ZOOM_0_SCALE / (1u64 << zoom) as f64,
+                "cellSize": ZOOM_0_CELL_SIZE / (1u64 << zoom) as f64,
+                "cornerOfOrigin": "topLeft",
+                "pointOfOrigin": [-WORLD_EDGE, WORLD_EDGE],
+                "tileWidth": 256,
+                "tileHeight": 256,
+                "matrixWidth": 1u32 << zoom,
+                "matrixHeight": 1u32 << zoom,
+            })
+        })
+        .collect();
+    json!({
+        "id": TILE_MATRIX_SET,
+        "title": "Google Maps Compatible for the World",
+        "uri": format!("http://www.opengis.net/def/tilematrixset/OGC/1.0/{}", TILE_MATRIX_SET),
+        "crs": "http://www.opengis.net/def/crs/EPSG/0/3857",
+        "orderedAxes": ["E", "N"],
+        "wellKnownScaleSet": "http://www.opengis.net/def/wkss/OGC/1.0/GoogleMapsCompatible",
+        "tileMatrices": matrices,
+        "links": [link(format!("{}{}/tileMatrixSets/{}", base, ROOT, TILE_MATRIX_SET), "self", "application/json", TILE_MATRIX_SET)],
+    })
+}
+
+// One satellite's product as a collection, with the frame times it has
+fn collection(base: &str, satellite: &Satellite, product: &str, times: &[i64]) -> Value {
+    let id = layer_id(satellite, product);
+    let href = format!("{}{}/collections/{}", base, ROOT, id);
+    let west = ((satellite.longitude - DISK_REACH).max(-180.0) * 10.0).round() / 10.0;
+    let east = ((satellite.longitude + DISK_REACH).min(180.0) * 10.0).round() / 10.0;
+    let interval = match (times.last(), times.first()) {
+        (Some(&oldest), Some(&newest)) => json!([[iso(oldest), iso(newest)]]),
+        _ => json!([[null, null]]),
+    };
+    json!({
+        "id": id,
+        "title": format!("{} {}", satellite.name, satellites::product_name(product)),
+        "description": format!("{}'s full disk over {}, reprojected to Web Mercator", satellite.name, satellite.region),
+        "dataType": "map",
+        "extent": {
+            "spatial": { "bbox": [[west, -DISK_REACH, east, DISK_REACH]], "crs": "http://www.opengis.net/def/crs/OGC/1.3/CRS84" },
+            "temporal": { "interval": interval, "trs": "http://www.opengis.net/def/uom/ISO-8601/0/Gregorian" },
+        },
+        "links": [
+            link(href.clone(), "self", "application/json", "This collection"),
+            link(format!("{}/map/tiles", href), "http://www.opengis.net/def/rel/ogc/1.0/tilesets-map", "application/json", "Map tilesets"),
+        ],
+    })
+}
+
+/// `GET /ogcapi`: the OGC API landing page, linking to the rest.
+pub async fn handle_landing(headers: HeaderMap) -> Json<Value> {
+    let root = format!("{}{}", base_url(&headers), ROOT);
+    Json(json!({
+        "title": "peepsat",
+        "description": "Geostationary satellite imagery reprojected to Web Mercator tiles",
+        "links": [
+            link(root.clone(), "self", "application/json", "This document"),
+            link(format!("{}/conformance", root), "conformance", "application/json", "Conformance classes"),
+            link(format!("{}/collections", root), "data", "application/json", "Satellites and products"),
+            link(format!("{}/tileMatrixSets", root), "http://www.opengis.net/def/rel/ogc/1.0/tiling-schemes", "application/json", "Tile matrix sets"),
+        ],
+    }))
+}
+
+/// `GET /ogcapi/conformance`: the conformance classes implemented.
+pub async fn handle_conformance() -> Json<Value> {
+    Json(json!({ "conformsTo": CONFORMANCE }))
+}
+
+/// `GET /ogcapi/tileMatrixSets`: the tile matrix sets offered, which is
+/// only WebMercatorQuad.
+pub async fn handle_tile_matrix_sets(headers: HeaderMap) -> Json<Value> {
+    let href = format!("{}{}/tileMatrixSets/{}", base_url(&headers), ROOT, TILE_MATRIX_SET);
+    Json(json!({
+        "tileMatrixSets": [{
+            "id": TILE_MATRIX_SET,
+            "title": "Google Maps Compatible for the World",
+            "links": [link(href, "self", "application/json", TILE_MATRIX_SET)],
+        }],
+    }))
+}
+
+/// `GET /ogcapi/tileMatrixSets/{id}`: a tile matrix set's definition.
+pub async fn handle_tile_matrix_set(Path(id): Path<String>, headers: HeaderMap) -> Response {
+    if id != TILE_MATRIX_SET {
+        return not_found("tile matrix set");
+    }
+    Json(tile_matrix_set(&base_url(&headers))).into_response()
+}
+
+/// `GET /ogcapi/collections`: every satellite's products, each a
+/// collection with the span of its recent frames.
+pub async fn handle_collections(headers: HeaderMap) -> Json<Value> {
+    let base = base_url(&headers);
+    let times = join_all(SATELLITES.iter().map(frame_times)).await;
+    let collections: Vec<Value> = SATELLITES
+        .iter()
+        .zip(&times)
+        .flat_map(|(satellite, times)| {
+            satellites::products(satellite).into_iter().map(|product| collection(&base, satellite, &product, times)).collect::<Vec<_>>()
+        })
+        .collect();
+    Json(json!({
+        "collections": collections,
+        "links": [link(format!("{}{}/collections", base, ROOT), "self", "application/json", "This document")],
+    }))
+}
+
+/// `GET /ogcapi/collections/{id}`: one collection, `{sat}_{product}` as
+/// in WMTS.
+pub async fn handle_collection(Path(id): Path<String>, headers: HeaderMap) -> Response {
+    let Some((layer, _)) = find_layer(&id) else {
+        return not_found("collection");
+    };
+    let Some(satellite) = satellites::find(&layer.sat) else {
+        return not_found("collection");
+    };
+    let times = frame_times(satellite).await;
+    Json(collection(&base_url(&headers), satellite, &layer.product, &times)).into_response()
+}
+
+/// `GET /ogcapi/collections/{id}/map/tiles`: a collection's tilesets.
+pub async fn handle_tilesets(Path(id): Path<String>, headers: HeaderMap) -> Response {
+    if find_layer(&id).is_none() {
+        return not_found("collection");
+    }
+    let base = base_url(&headers);
+    let href = format!("{}{}/collections/{}/map/tiles/{}", base, ROOT, id, TILE_MATRIX_SET);
+    Json(json!({
+        "tilesets": [{
+            "title": id,
+            "dataType": "map",
+            "crs": "http://www.opengis.net/def/crs/EPSG/0/3857",
+            "tileMatrixSetURI": format!("http://www.opengis.net/def/tilematrixset/OGC/1.0/{}", TILE_MATRIX_SET),
+            "links": [link(href, "self", "application/json", TILE_MATRIX_SET)],
+        }],
+    }))
+    .into_response()
+}
+
+/// `GET /ogcapi/collections/{id}/map/tiles/{tms}`: a tileset's metadata
+/// and tile URL template.
+pub async fn handle_tileset(Path((id, tms)): Path<(String, String)>, headers: HeaderMap) -> Response {
+    if find_layer(&id).is_none() {
+        return not_found("collection");
+    }
+    if tms != TILE_MATRIX_SET {
+        return not_found("tile matrix set");
+    }
+    let base = base_url(&headers);
+    let href = format!("{}{}/collections/{}/map/tiles/{}", base, ROOT, id, TILE_MATRIX_SET);
+    let limits: Vec<Value> = (0..=MAX_ZOOM)
+        .map(|zoom| {
+            let last = (1u32 << zoom) - 1;
+            json!({ "tileMatrix": zoom.to_string(), "minTileRow": 0, "maxTileRow": last, "minTileCol": 0, "maxTileCol": last })
+        })
+        .collect();
+    Json(json!({
+        "title": id,
+        "dataType": "map",
+        "crs": "http://www.opengis.net/def/crs/EPSG/0/3857",
+        "tileMatrixSetURI": format!("http://www.opengis.net/def/tilematrixset/OGC/1.0/{}", TILE_MATRIX_SET),
+        "tileMatrixSetLimits": limits,
+        "links": [
+            link(href.clone(), "self", "application/json", "This tileset"),
+            link(
+                format!("{}{}/tileMatrixSets/{}", base, ROOT, TILE_MATRIX_SET),
+                "http://www.opengis.net/def/rel/ogc/1.0/tiling-scheme",
+                "application/json",
+                TILE_MATRIX_SET,
+            ),
+            json!({
+                "href": format!("{}/{{tileMatrix}}/{{tileRow}}/{{tileCol}}", href),
+                "rel": "item",
+                "type": "image/png",
+                "title": "Tiles; add datetime for a frame other than the newest",
+                "templated": true,
+            }),
+        ],
+    }))
+    .into_response()
+}
+
+/// `GET /ogcapi/collections/{id}/map/tiles/{tms}/{z}/{row}/{col}`: one
+/// tile, from the newest frame unless `datetime` names another.
+pub async fn handle_tile(
+    Path((id, tms, zoom, y, x)): Path<(String, String, u32, u32, u32)>,
+    Query(params): Query<Params>,
+    headers: HeaderMap,
+) -> Response {
+    let Some((layer, layer_params)) = find_layer(&id) else {
+        return not_found("collection");
+    };
+    if tms != TILE_MATRIX_SET {
+        return not_found("tile matrix set");
+    }
+    if zoom > MAX_ZOOM || x >= 1 << zoom || y >= 1 << zoom {
+        return (StatusCode::NOT_FOUND, "Tile out of range").into_response();
+    }
+    let time = match params.get("datetime").map(String::as_str) {
+        None | Some("") | Some("now") => None,
+        Some(text) => match parse_time(text) {
+            Some(time) => Some(time),
+            None => return (StatusCode::BAD_REQUEST, "datetime must be an ISO 8601 time").into_response(),
+        },
+    };
+    reprojected_tile(&layer, &layer_params, time, zoom, x, y, &headers).await
+}
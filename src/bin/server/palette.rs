@@ -0,0 +1,301 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::Path;
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage, Rgba, RgbaImage};
+use serde_json::json;
+use tracing::{info, warn};
+use crate::cache::CACHE_DIR;
+use crate::{etag_matches, tile_etag, MAX_TILE_BYTES};
+
+// SLIDER's IR tiles are grey, coldest brightest, taken here as linear in
+// brightness temperature from WARMEST (black) to COLDEST (white), in °C
+const WARMEST: f64 = 57.0;
+const COLDEST: f64 = -110.0;
+// Uploaded colour tables are small; a row per degree over the whole range
+// is under 3 KB
+const MAX_CSV_BYTES: usize = 64 * 1024;
+const MAX_STOPS: usize = 512;
+
+// Colour tables as stops of temperature (°C) and colour, warmest first,
+// linear in between. Two stops at one temperature make a step.
+type Stop = (f64, [u8; 3]);
+
+const GRAYSCALE: &[Stop] = &[(WARMEST, [0, 0, 0]), (COLDEST, [255, 255, 255])];
+
+// Dvorak's BD curve for tropical cyclone intensity: a grey ramp for warm
+// surfaces and low cloud, then flat greys, black and white in the bands of
+// cloud-top temperature his technique reads
+const BD: &[Stop] = &[
+    (WARMEST, [0, 0, 0]),
+    (9.0, [110, 110, 110]),
+    (-30.5, [200, 200, 200]),
+    (-30.5, [60, 60, 60]),
+    (-41.5, [60, 60, 60]),
+    (-41.5, [120, 120, 120]),
+    (-53.5, [120, 120, 120]),
+    (-53.5, [180, 180, 180]),
+    (-63.5, [180, 180, 180]),
+    (-63.5, [0, 0, 0]),
+    (-69.5, [0, 0, 0]),
+    (-69.5, [255, 255, 255]),
+    (-75.5, [255, 255, 255]),
+    (-75.5, [140, 140, 140]),
+    (-80.5, [140, 140, 140]),
+    (-80.5, [90, 90, 90]),
+    (COLDEST, [90, 90, 90]),
+];
+
+// Grey for the surface and warm cloud, then blue through red to white
+// for ever colder tops
+const RAINBOW: &[Stop] = &[
+    (WARMEST, [0, 0, 0]),
+    (-20.0, [190, 190, 190]),
+    (-20.0, [0, 60, 220]),
+    (-35.0, [0, 190, 255]),
+    (-45.0, [0, 200, 60]),
+    (-55.0, [255, 240, 0]),
+    (-65.0, [255, 120, 0]),
+    (-72.0, [220, 0, 0]),
+    (-80.0, [200, 0, 200]),
+    (-90.0, [255, 255, 255]),
+    (COLDEST, [255, 255, 255]),
+];
+
+const BUILT_IN: &[(&str, &[Stop])] = &[("grayscale", GRAYSCALE), ("bd", BD), ("dvorak", BD), ("rainbow", RAINBOW)];
+
+lazy_static::lazy_static! {
+    // Uploaded colour tables, by name, as they're used
+    static ref CUSTOM: Mutex<HashMap<String, Arc<Palette>>> = Mutex::new(HashMap::new());
+}
+
+/// A colour for each grey level of an IR tile.
+pub struct Palette([[u8; 3]; 256]);
+
+impl Palette {
+    fn from_stops(stops: &[Stop]) -> Palette {
+        let mut colours = [[0; 3]; 256];
+        for (value, colour) in colours.iter_mut().enumerate() {
+            let temperature = brightness_temperature(value as u8);
+            *colour = colour_at(stops, temperature);
+        }
+        Palette(colours)
+    }
+}
+
+/// Brightness temperature, °C, of a grey level of an IR tile, as the
+/// palettes take it.
+pub fn brightness_temperature(value: u8) -> f64 {
+    WARMEST + (COLDEST - WARMEST) * value as f64 / 255.0
+}
+
+// The colour `stops` give `temperature`; the end ones beyond them
+fn colour_at(stops: &[Stop], temperature: f64) -> [u8; 3] {
+    let (Some(first), Some(last)) = (stops.first(), stops.last()) else {
+        return [0; 3];
+    };
+    if temperature >= first.0 {
+        return first.1;
+    }
+    for pair in stops.windows(2) {
+        let ((warm, from), (cold, to)) = (pair[0], pair[1]);
+        if temperature <= warm && temperature >= cold && warm > cold {
+            let f = (warm - temperature) / (warm - cold);
+            return [0, 1, 2].map(|i| (from[i] as f64 + (to[i] as f64 - from[i] as f64) * f).round() as u8);
+        }
+    }
+    last.1
+}
+
+// Uploaded names: short, lower case and not a built-in's
+fn valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name.bytes().all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'_' || b == b'-')
+        && !BUILT_IN.iter().any(|(id, _)| *id == name)
+}
+
+fn palette_dir() -> PathBuf {
+    CACHE_DIR.join("palettes")
+}
+
+fn palette_path(name: &str) -> PathBuf {
+    palette_dir().join(format!("{}.csv", name))
+}
+
+// Rows of "temperature,r,g,b", temperature in °C; blank lines, # comments
+// and a header row are skipped. Sorted warmest first, rows at the same
+// temperature keeping their order so they make a step.
+fn parse_csv(text: &str) -> Result<Vec<Stop>, String> {
+    let mut stops = Vec::new();
+    let mut header = false;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(',').map(str::trim).collect();
+        let Ok(temperature) = fields[0].parse::<f64>() else {
+            if stops.is_empty() && !header {
+                header = true;
+                continue;
+            }
+            return Err(format!("line {}: temperature must be a number (°C)", i + 1));
+        };
+        if fields.len() != 4 || !(-150.0..=100.0).contains(&temperature) {
+            return Err(format!("line {}: must be temperature,r,g,b with the temperature from -150 to 100 °C", i + 1));
+        }
+        let channel = |f: &str| f.parse::<u8>().map_err(|_| format!("line {}: r, g and b must be from 0 to 255", i + 1));
+        stops.push((temperature, [channel(fields[1])?, channel(fields[2])?, channel(fields[3])?]));
+        if stops.len() > MAX_STOPS {
+            return Err(format!("at most {} rows", MAX_STOPS));
+        }
+    }
+    if stops.len() < 2 {
+        return Err("needs at least two rows of temperature,r,g,b".to_string());
+    }
+    stops.sort_by(|a, b| b.0.total_cmp(&a.0));
+    Ok(stops)
+}
+
+/// The built-in or uploaded colour table called `name`, if there is one.
+pub fn find(name: &str) -> Option<Arc<Palette>> {
+    if let Some((_, stops)) = BUILT_IN.iter().find(|(id, _)| *id == name) {
+        return Some(Arc::new(Palette::from_stops(stops)));
+    }
+    if !valid_name(name) {
+        return None;
+    }
+    if let Some(palette) = CUSTOM.lock().unwrap().get(name) {
+        return Some(palette.clone());
+    }
+    let text = fs::read_to_string(palette_path(name)).ok()?;
+    let stops = match parse_csv(&text) {
+        Ok(stops) => stops,
+        Err(e) => {
+            warn!(name, error = %e, "Stored palette is invalid");
+            return None;
+        }
+    };
+    let palette = Arc::new(Palette::from_stops(&stops));
+    CUSTOM.lock().unwrap().insert(name.to_string(), palette.clone());
+    Some(palette)
+}
+
+// The grey IR tile in the palette's colours, as a PNG, keeping any alpha
+fn recolour(tile: &[u8], palette: &Palette) -> Result<Vec<u8>, String> {
+    let image = image::load_from_memory(tile).map_err(|e| e.to_string())?;
+    let coloured = if image.color().has_alpha() {
+        let grey = image.to_luma_alpha8();
+        DynamicImage::ImageRgba8(RgbaImage::from_fn(grey.width(), grey.height(), |x, y| {
+            let [value, alpha] = grey.get_pixel(x, y).0;
+            let [r, g, b] = palette.0[value as usize];
+            Rgba([r, g, b, alpha])
+        }))
+    } else {
+        let grey = image.to_luma8();
+        DynamicImage::ImageRgb8(RgbImage::from_fn(grey.width(), grey.height(), |x, y| Rgb(palette.0[grey.get_pixel(x, y).0[0] as usize])))
+    };
+    let mut out = Cursor::new(Vec::new());
+    coloured.write_to(&mut out, ImageOutputFormat::Png).map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
+
+/// A tile response with the tile recoloured through `palette`, its ETag
+/// the recoloured tile's, answering `If-None-Match` in `headers`. Anything
+/// but a tile passes through.
+pub async fn apply(response: Response, palette: Arc<Palette>, headers: &HeaderMap) -> Response {
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    let Ok(tile) = to_bytes(body, MAX_TILE_BYTES).await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read tile").into_response();
+    };
+    let png = match tokio::task::spawn_blocking(move || recolour(&tile, &palette)).await {
+        Ok(Ok(png)) => png,
+        Ok(Err(e)) => {
+            warn!(error = %e, "Recolouring tile failed");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to recolour tile").into_response();
+        }
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to recolour tile").into_response(),
+    };
+    let etag = tile_etag(&png);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+    if let Ok(value) = HeaderValue::from_str(&etag) {
+        parts.headers.insert(header::ETAG, value);
+    }
+    if etag_matches(headers, &etag) {
+        parts.status = StatusCode::NOT_MODIFIED;
+        return Response::from_parts(parts, Body::empty());
+    }
+    Response::from_parts(parts, Body::from(png))
+}
+
+/// `GET /api/palettes`: the colour tables `palette=` takes for IR bands,
+/// built in and uploaded.
+pub async fn handle_palettes() -> Response {
+    let mut custom: Vec<String> = fs::read_dir(palette_dir())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .filter_map(|e| e.file_name().to_string_lossy().strip_suffix(".csv").map(str::to_string))
+        .filter(|name| valid_name(name))
+        .collect();
+    custom.sort();
+    let built_in: Vec<&str> = BUILT_IN.iter().map(|(id, _)| *id).collect();
+    Json(json!({ "built_in": built_in, "custom": custom })).into_response()
+}
+
+/// `PUT /api/palettes/{name}` with a CSV colour table as the body, rows of
+/// `temperature,r,g,b` with the temperature in °C, interpolated between.
+/// Replaces any table of that name.
+pub async fn handle_put_palette(Path(name): Path<String>, body: Bytes) -> Response {
+    if !valid_name(&name) {
+        return (StatusCode::BAD_REQUEST, "name must be up to 32 of a-z, 0-9, _ and -, and not a built-in's").into_response();
+    }
+    if body.len() > MAX_CSV_BYTES {
+        return (StatusCode::PAYLOAD_TOO_LARGE, "Colour table too large").into_response();
+    }
+    let Ok(text) = std::str::from_utf8(&body) else {
+        return (StatusCode::BAD_REQUEST, "Colour table must be UTF-8 CSV").into_response();
+    };
+    let stops = match parse_csv(text) {
+        Ok(stops) => stops,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    let saved = fs::create_dir_all(palette_dir()).and_then(|_| fs::write(palette_path(&name), text));
+    if let Err(e) = saved {
+        warn!(name, error = %e, "Failed to save palette");
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to save colour table").into_response();
+    }
+    CUSTOM.lock().unwrap().insert(name.clone(), Arc::new(Palette::from_stops(&stops)));
+    info!(name, stops = stops.len(), "Saved palette");
+    Json(json!({ "name": name, "stops": stops.len() })).into_response()
+}
+
+/// `DELETE /api/palettes/{name}`: remove an uploaded colour table.
+pub async fn handle_delete_palette(Path(name): Path<String>) -> Response {
+    if !valid_name(&name) {
+        return (StatusCode::BAD_REQUEST, "Not an uploaded colour table").into_response();
+    }
+    CUSTOM.lock().unwrap().remove(&name);
+    match fs::remove_file(palette_path(&name)) {
+        Ok(()) => {
+            info!(name, "Deleted palette");
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (StatusCode::NOT_FOUND, "No such colour table").into_response(),
+        Err(e) => {
+            warn!(name, error = %e, "Failed to delete palette");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Failed to delete colour table").into_response()
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
+use tokio::sync::{broadcast, Semaphore};
+use tokio::task::JoinSet;
+use tracing::{debug, info, warn};
+use crate::cache::{get_negative, is_cached, DEFAULT_SECTOR};
+use crate::upstream::{self, Priority, HTTP_CLIENT};
+use crate::{fetch_tile_coalesced, metrics, satellites, satellite_id, satellite_max_zoom, slider_tile_targets, Tile, SLIDER_BASE_URL};
+
+// Upstream downloads in flight at once while prefetching; low enough that
+// interactive requests still get through promptly
+const CONCURRENCY: usize = 4;
+
+// Longest time window a single prefetch job may cover
+const MAX_JOB_DAYS: usize = 7;
+// Finished jobs kept around for the status endpoint
+const MAX_JOBS_KEPT: usize = 50;
+// A running job's progress is announced every this many tiles, as well as
+// whenever its state changes
+const PROGRESS_STEP: u64 = 50;
+const PROGRESS_CHANNEL_SIZE: usize = 64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+/// A prefetch job as reported by the status endpoint.
+#[derive(Clone, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub sat: String,
+    pub from: String,
+    pub to: String,
+    pub zoom: u32,
+    pub state: JobState,
+    pub frames: usize,
+    pub tiles_total: u64,
+    /// Tiles dealt with so far, whether fetched, skipped or failed
+    pub tiles_done: u64,
+    pub tiles_fetched: u64,
+    pub tiles_failed: u64,
+    pub error: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref JOBS: Mutex<BTreeMap<u64, Job>> = Mutex::new(BTreeMap::new());
+    // Jobs run one at a time, in the order they were submitted
+    static ref JOB_SLOT: Semaphore = Semaphore::new(1);
+    static ref PROGRESS: broadcast::Sender<Job> = broadcast::channel(PROGRESS_CHANNEL_SIZE).0;
+}
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Timestamps listed in one of SLIDER's JSON files for `sat`. The files
+/// nest them differently, so every 14-digit number is taken.
+async fn fetch_timestamps(sat: &str, file: &str) -> Option<Vec<u64>> {
+    let path = format!("/data/json/{}/full_disk/{}/{}", satellite_id(sat), satellites::default_product(sat), file);
+    let targets = upstream::with_fallbacks(SLIDER_BASE_URL, &path);
+    let response = match upstream::get(&HTTP_CLIENT, &targets).await {
+        Ok(r) if r.status().is_success() => r,
+        Ok(r) => {
+            warn!(sat, file, status = r.status().as_u16(), "Prefetch: timestamp list unavailable");
+            return None;
+        }
+        Err(e) => {
+            warn!(sat, file, error = %e, "Prefetch: timestamp list failed");
+            return None;
+        }
+    };
+    let json: serde_json::Value = serde_json::from_slice(&response.bytes().await.ok()?).ok()?;
+    let mut timestamps = Vec::new();
+    collect_timestamps(&json, &mut timestamps);
+    Some(timestamps)
+}
+
+/// Every 14-digit number anywhere in `value`, as SLIDER's timestamps are.
+pub fn collect_timestamps(value: &serde_json::Value, out: &mut Vec<u64>) {
+    match value {
+        serde_json::Value::Number(n) => {
+            if let Some(t) = n.as_u64().filter(|t| (10_000_000_000_000..100_000_000_000_000).contains(t)) {
+                out.push(t);
+            }
+        }
+        serde_json::Value::Array(items) => items.iter().for_each(|v| collect_timestamps(v, out)),
+        serde_json::Value::Object(map) => map.values().for_each(|v| collect_timestamps(v, out)),
+        _ => {}
+    }
+}
+
+/// The newest timestamp SLIDER lists for `sat`.
+async fn latest_timestamp(sat: &str) -> Option<String> {
+    fetch_timestamps(sat, "latest_times.json").await?.into_iter().max().map(|t| t.to_string())
+}
+
+// YYYYMMDD of the day after `date`
+fn next_day(date: u32) -> u32 {
+    let (year, month, day) = (date / 10000, date / 100 % 100, date % 100);
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if day < days_in_month {
+        date + 1
+    } else if month < 12 {
+        year * 10000 + (month + 1) * 100 + 1
+    } else {
+        (year + 1) * 10000 + 101
+    }
+}
+
+/// The days from `from` through `to` (both YYYYMMDD), or `None` if that's
+/// more than `MAX_JOB_DAYS`.
+pub fn days_between(from: u32, to: u32) -> Option<Vec<u32>> {
+    let mut days = vec![from];
+    while *days.last()? < to {
+        if days.len() == MAX_JOB_DAYS {
+            return None;
+        }
+        days.push(next_day(*days.last()?));
+    }
+    Some(days)
+}
+
+/// Frames of `sat` with from <= timestamp <= to, oldest first.
+async fn frames_between(sat: &str, from: &str, to: &str) -> Vec<String> {
+    let (Ok(first), Ok(last)) = (from[..8].parse(), to[..8].parse()) else {
+        return Vec::new();
+    };
+    let mut timestamps = Vec::new();
+    for day in days_between(first, last).unwrap_or_default() {
+        timestamps.extend(fetch_timestamps(sat, &format!("{}_by_hour_max.json", day)).await.unwrap_or_default());
+    }
+    // The day listings can lag the latest frames by a few minutes
+    timestamps.extend(fetch_timestamps(sat, "latest_times.json").await.unwrap_or_default());
+
+    let mut frames: Vec<String> = timestamps
+        .into_iter()
+        .map(|t| t.to_string())
+        .filter(|t| t.as_str() >= from && t.as_str() <= to)
+        .collect();
+    frames.sort();
+    frames.dedup();
+    frames
+}
+
+fn update_job(id: u64, f: impl FnOnce(&mut Job)) {
+    if let Some(job) = JOBS.lock().unwrap().get_mut(&id) {
+        let state = job.state;
+        f(job);
+        if job.state != state || (job.tiles_done > 0 && job.tiles_done % PROGRESS_STEP == 0) {
+            // Nobody listening is fine
+            let _ = PROGRESS.send(job.clone());
+        }
+    }
+}
+
+/// Jobs as they progress, for `/ws`.
+pub fn progress() -> broadcast::Receiver<Job> {
+    PROGRESS.subscribe()
+}
+
+/// Queue a job downloading every tile down to `zoom` of every `sat` frame
+/// between the 14-digit timestamps `from` and `to`. Returns its id.
+pub fn enqueue(sat: String, from: String, to: String, zoom: u32) -> u64 {
+    let id = NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut jobs = JOBS.lock().unwrap();
+        while jobs.len() >= MAX_JOBS_KEPT {
+            let finished = jobs.iter().find(|(_, j)| matches!(j.state, JobState::Done | JobState::Failed));
+            match finished.map(|(id, _)| *id) {
+                Some(old) => jobs.remove(&old),
+                None => break,
+            };
+        }
+        jobs.insert(id, Job {
+            id,
+            sat: sat.clone(),
+            from: from.clone(),
+            to: to.clone(),
+            zoom,
+            state: JobState::Queued,
+            frames: 0,
+            tiles_total: 0,
+            tiles_done: 0,
+            tiles_fetched: 0,
+            tiles_failed: 0,
+            error: None,
+        });
+    }
+    info!(id, sat, from, to, zoom, "Prefetch job queued");
+
+    tokio::spawn(async move {
+        let _slot = JOB_SLOT.acquire().await.unwrap();
+        update_job(id, |job| job.state = JobState::Running);
+        let frames = frames_between(&sat, &from, &to).await;
+        if frames.is_empty() {
+            warn!(id, sat, "Prefetch job found no frames");
+            update_job(id, |job| {
+                job.state = JobState::Failed;
+                job.error = Some("no frames in that time window".to_string());
+            });
+            return;
+        }
+        let per_frame = tiles_per_frame(&sat, zoom);
+        update_job(id, |job| {
+            job.frames = frames.len();
+            job.tiles_total = per_frame * frames.len() as u64;
+        });
+
+        let mut fetched = 0;
+        for timestamp in &frames {
+            fetched += fetch_frame(&sat, timestamp, zoom, |outcome| {
+                update_job(id, |job| {
+                    job.tiles_done += 1;
+                    match outcome {
+                        TileOutcome::Fetched => job.tiles_fetched += 1,
+                        TileOutcome::Failed => job.tiles_failed += 1,
+                        TileOutcome::Skipped => {}
+                    }
+                })
+            })
+            .await;
+        }
+        update_job(id, |job| job.state = JobState::Done);
+        info!(id, sat, frames = frames.len(), fetched, "Prefetch job finished");
+    });
+    id
+}
+
+/// One job's progress, if it's still remembered.
+pub fn job(id: u64) -> Option<Job> {
+    JOBS.lock().unwrap().get(&id).cloned()
+}
+
+/// All remembered jobs, oldest first.
+pub fn jobs() -> Vec<Job> {
+    JOBS.lock().unwrap().values().cloned().collect()
+}
+
+/// What happened to one tile of a prefetched frame.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TileOutcome {
+    /// Already cached, or known to be missing upstream
+    Skipped,
+    Fetched,
+    Failed,
+}
+
+/// Tiles in a frame down to `max_zoom`: 1 + 4 + 16 + ...
+pub fn tiles_per_frame(sat: &str, max_zoom: u32) -> u64 {
+    (0..=max_zoom.min(satellite_max_zoom(sat))).map(|z| 1u64 << (2 * z)).sum()
+}
+
+/// Download every tile of one frame that isn't cached yet, up to
+/// `max_zoom`, reporting each tile to `on_tile` as it completes. Returns
+/// how many tiles were fetched.
+pub async fn fetch_frame(sat: &str, timestamp: &str, max_zoom: u32, mut on_tile: impl FnMut(TileOutcome)) -> usize {
+    let date = timestamp.get(..8).unwrap_or_default().to_string();
+    let limit = Arc::new(Semaphore::new(CONCURRENCY));
+    let mut tasks = JoinSet::new();
+    let mut fetched = 0;
+    for zoom in 0..=max_zoom.min(satellite_max_zoom(sat)) {
+        let tiles = 1u32 << zoom;
+        for y in 0..tiles {
+            for x in 0..tiles {
+                let tile = Tile { sat, sector: DEFAULT_SECTOR, product: satellites::default_product(sat), timestamp, zoom, x, y };
+                let key = tile.key();
+                if is_cached(&key) || get_negative(&key).is_some() {
+                    on_tile(TileOutcome::Skipped);
+                    continue;
+                }
+                let targets = slider_tile_targets(SLIDER_BASE_URL, &tile, &date);
+                let permit = limit.clone().acquire_owned().await.unwrap();
+                tasks.spawn(async move {
+                    let result = fetch_tile_coalesced(&HTTP_CLIENT, &targets, &key, Priority::Background).await;
+                    drop(permit);
+                    matches!(result, Ok((status, _)) if status.is_success())
+                });
+                // Report downloads that finished while we waited for a permit
+                while let Some(result) = tasks.try_join_next() {
+                    let outcome = if result.unwrap_or(false) { TileOutcome::Fetched } else { TileOutcome::Failed };
+                    fetched += (outcome == TileOutcome::Fetched) as usize;
+                    on_tile(outcome);
+                }
+            }
+        }
+    }
+    while let Some(result) = tasks.join_next().await {
+        let outcome = if result.unwrap_or(false) { TileOutcome::Fetched } else { TileOutcome::Failed };
+        fetched += (outcome == TileOutcome::Fetched) as usize;
+        on_tile(outcome);
+    }
+    metrics::PREFETCHED_TILES.inc_by(fetched as u64);
+    fetched
+}
+
+/// Pull the newest frame of `sat` into the cache down to `max_zoom`,
+/// returning its timestamp and how many tiles were fetched; `None` if its
+/// frame list can't be had. Tiles already cached are skipped, so an
+/// unchanged frame costs one request.
+pub async fn prefetch_latest(sat: &str, max_zoom: u32) -> Option<(String, usize)> {
+    let timestamp = latest_timestamp(sat).await?;
+    match fetch_frame(sat, &timestamp, max_zoom, |_| {}).await {
+        0 => {
+            debug!(sat, timestamp, "Prefetch: nothing new");
+            Some((timestamp, 0))
+        }
+        fetched => {
+            info!(sat, timestamp, fetched, "Prefetched latest frame");
+            Some((timestamp, fetched))
+        }
+    }
+}
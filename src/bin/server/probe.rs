@@ -0,0 +1,111 @@
+use axum::extract::Query;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde_json::json;
+use tracing::Span;
+use crate::cache::DEFAULT_SECTOR;
+use crate::config::CONFIG;
+use crate::dates::{iso, parse_time};
+use crate::frames::Layer;
+use crate::fulldisk::tile_bytes;
+use crate::palette::brightness_temperature;
+use crate::reproject::frame_timestamp;
+use crate::{bad_gateway, geos, offline, satellites, Params, Tile};
+
+const KELVIN: f64 = 273.15;
+
+// A coordinate parameter within ±`limit` degrees
+fn degrees(params: &Params, name: &str, limit: f64) -> Result<f64, String> {
+    match params.get(name).and_then(|v| v.parse::<f64>().ok()) {
+        Some(value) if (-limit..=limit).contains(&value) => Ok(value),
+        _ => Err(format!("{} must be degrees from -{} to {}", name, limit, limit)),
+    }
+}
+
+/// `GET /api/probe?sat=19&t=20241016120000&lat=25.7&lon=-80.2&band=13`:
+/// what one band of a frame (the newest without `t`) shows at a point:
+/// brightness temperature for the IR bands and reflectance for the others.
+/// They're read back from SLIDER's grey tiles at the band's deepest zoom,
+/// not from calibrated radiances, so they're only as good as the tiles'
+/// scaling: IR as `palette` takes it, reflectance as the grey level over
+/// 255. Full disk only; takes the same `cdn` as `/slider-tile`.
+pub async fn handle_probe(Query(mut params): Query<Params>) -> Response {
+    params.insert("sector".to_string(), DEFAULT_SECTOR.to_string());
+    if !params.contains_key("band") && !params.contains_key("product") {
+        return (StatusCode::BAD_REQUEST, "band is required, e.g. band=13").into_response();
+    }
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Unknown satellite or band").into_response();
+    };
+    let (Some(satellite), Some(band)) = (satellites::find(&layer.sat), satellites::band_number(&layer.product)) else {
+        return (StatusCode::BAD_REQUEST, "band must be one of the satellite's bands").into_response();
+    };
+    let (lat, lon) = match degrees(&params, "lat", 90.0).and_then(|lat| Ok((lat, degrees(&params, "lon", 180.0)?))) {
+        Ok(point) => point,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    let time = match params.get("t") {
+        Some(t) => match parse_time(t) {
+            Some(time) => Some(time),
+            None => return (StatusCode::BAD_REQUEST, "t must be a frame timestamp, e.g. 20241016120000").into_response(),
+        },
+        None => None,
+    };
+    Span::current().record("sat", layer.sat.as_str());
+
+    let (zoom, tile_size) = layer.tile_grid();
+    let Some((row, column)) = geos::pixel(satellite, zoom, lat, lon) else {
+        return (StatusCode::BAD_REQUEST, "That point can't be seen from the satellite").into_response();
+    };
+    let (row, column) = (row as u32, column as u32);
+    let timestamp = match frame_timestamp(&layer, &params, time).await {
+        Ok(timestamp) => timestamp,
+        Err(response) => return response,
+    };
+    let tile = Tile {
+        sat: &layer.sat,
+        sector: &layer.sector,
+        product: &layer.product,
+        timestamp: &timestamp,
+        zoom,
+        x: row / tile_size,
+        y: column / tile_size,
+    };
+    let Some(data) = tile_bytes(&layer.cdn, tile, &timestamp[..8]).await else {
+        return if offline::enabled() { offline::unavailable() } else { bad_gateway("No tile for that point") };
+    };
+    let value = match image::load_from_memory(&data) {
+        Ok(image) => image.to_luma8().get_pixel_checked(column % tile_size, row % tile_size).map(|p| p.0[0]),
+        Err(_) => None,
+    };
+    let Some(value) = value else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read tile").into_response();
+    };
+
+    let mut body = json!({
+        "sat": layer.sat,
+        "band": band,
+        "timestamp": timestamp.parse::<u64>().ok(),
+        "time": parse_time(&timestamp).map(iso),
+        "lat": lat,
+        "lon": lon,
+        "zoom": zoom,
+        "value": value,
+    });
+    if satellites::is_ir_band(&layer.sat, &layer.product) {
+        let celsius = brightness_temperature(value);
+        body["brightness_temperature_c"] = json!((celsius * 10.0).round() / 10.0);
+        body["brightness_temperature_k"] = json!(((celsius + KELVIN) * 10.0).round() / 10.0);
+    } else {
+        body["reflectance"] = json!((value as f64 / 255.0 * 1000.0).round() / 1000.0);
+    }
+    let cache_control = if time.is_some() {
+        format!("public, max-age={}, immutable", CONFIG.tile_max_age)
+    } else {
+        "no-cache".to_string()
+    };
+    let response = ([(header::CACHE_CONTROL, cache_control)], Json(body)).into_response();
+    let response = if offline::enabled() { offline::mark(response) } else { response };
+    layer.mark(response)
+}
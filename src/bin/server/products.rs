@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use axum::extract::Query;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::future::join_all;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{debug, info};
+use crate::cache::{self, DEFAULT_PRODUCT, DEFAULT_SECTOR};
+use crate::config::CONFIG;
+use crate::satellites::{self, Satellite, COMPOSITES};
+use crate::upstream::{self, HTTP_CLIENT};
+use crate::{bad_gateway, catalog, eumetsat, get_cdn_url, is_nict_cdn, noaa, offline, Params};
+
+// SLIDER adds and retires products rarely
+const LIST_TTL: Duration = Duration::from_secs(3600);
+// The cdn parameter is part of the key, so clients can add entries at will
+const MAX_LISTS: usize = 100;
+
+#[derive(Deserialize)]
+struct LatestTimes {
+    timestamps_int: Vec<i64>,
+}
+
+lazy_static::lazy_static! {
+    // Product lists by upstream, satellite and sector, and when they were made
+    static ref LISTS: Mutex<HashMap<String, (Instant, Value)>> = Mutex::new(HashMap::new());
+}
+
+// Products worth asking SLIDER about: the registry's for imagers it knows,
+// otherwise every name SLIDER uses for any imager
+fn candidates(satellite: &Satellite) -> Vec<String> {
+    if satellite.imager.is_some() || !satellite.products.is_empty() {
+        return satellites::products(satellite);
+    }
+    let mut products = vec![DEFAULT_PRODUCT.to_string()];
+    products.extend(COMPOSITES.iter().map(|(id, _)| id.to_string()));
+    products.extend((1..=16).map(|band| format!("band_{:02}", band)));
+    products
+}
+
+// Whether SLIDER has frames of `product`: `Some(true)` if its frame list
+// has any, `Some(false)` if not, `None` if SLIDER couldn't be asked
+async fn has_frames(cdn: &str, satellite: &Satellite, sector: &str, product: &str) -> Option<bool> {
+    let product = satellites::listed_product(product);
+    let path = format!("/data/json/{}/{}/{}/latest_times.json", satellite.slider_id, sector, product);
+    let response = upstream::get(&HTTP_CLIENT, &upstream::with_fallbacks(cdn, &path)).await.ok()?;
+    if !response.status().is_success() {
+        return Some(false);
+    }
+    let body = response.bytes().await.ok()?;
+    Some(serde_json::from_slice::<LatestTimes>(&body).is_ok_and(|l| !l.timestamps_int.is_empty()))
+}
+
+/// `GET /slider-products?sat=19&sector=full_disk`: the products SLIDER
+/// actually has frames of for a satellite and sector, described as in
+/// `/api/catalog`, the default first. SLIDER keeps no index of them, so
+/// each candidate's frame list is checked; the answer is kept for an hour.
+/// Offline, it's the products with cached frames.
+pub async fn handle_slider_products(Query(params): Query<Params>) -> Response {
+    let sat = params.get("sat").cloned().unwrap_or_else(|| CONFIG.default_satellite.clone());
+    let Some(satellite) = satellites::find(&sat) else {
+        return (StatusCode::BAD_REQUEST, "Unknown satellite").into_response();
+    };
+    let sector = params.get("sector").cloned().unwrap_or_else(|| DEFAULT_SECTOR.to_string());
+    if satellites::sector_grid(&sat, &sector).is_none() {
+        return (StatusCode::BAD_REQUEST, "Unknown sector").into_response();
+    }
+    let cdn = get_cdn_url(&sat, &params);
+    let list = |products: Vec<String>| {
+        let products: Vec<Value> = products.iter().map(|p| catalog::product(satellite, p)).collect();
+        json!({ "sat": sat, "sector": sector, "products": products })
+    };
+
+    // NICT and EUMETView have the one full-disk product
+    if is_nict_cdn(&cdn) || eumetsat::is_source(&cdn) {
+        return Json(list(vec![satellites::default_product(&sat).to_string()])).into_response();
+    }
+    if noaa::is_source(&cdn) {
+        return Json(list(noaa::products())).into_response();
+    }
+    if offline::enabled() {
+        let cached = candidates(satellite).into_iter().filter(|p| !cache::cached_frames(&sat, &sector, p).is_empty()).collect();
+        return offline::mark(Json(list(cached)).into_response());
+    }
+
+    let key = format!("{} {} {}", cdn, sat, sector);
+    if let Some((made, list)) = LISTS.lock().unwrap().get(&key) {
+        if made.elapsed() < LIST_TTL {
+            return Json(list.clone()).into_response();
+        }
+    }
+    let candidates = candidates(satellite);
+    debug!(sat, sector, candidates = candidates.len(), "Checking SLIDER products");
+    let found = join_all(candidates.iter().map(|p| has_frames(&cdn, satellite, &sector, p))).await;
+    if found.iter().all(Option::is_none) {
+        return bad_gateway("Failed");
+    }
+    // Products SLIDER couldn't be asked about are left out, and asked about again next time
+    let complete = found.iter().all(Option::is_some);
+    let available: Vec<String> = candidates.into_iter().zip(found).filter(|(_, f)| *f == Some(true)).map(|(p, _)| p).collect();
+    info!(sat, sector, products = available.len(), "Listed SLIDER products");
+    let list = list(available);
+    if !complete {
+        return Json(list).into_response();
+    }
+    let mut lists = LISTS.lock().unwrap();
+    if lists.len() >= MAX_LISTS {
+        lists.retain(|_, (made, _)| made.elapsed() < LIST_TTL);
+        if lists.len() >= MAX_LISTS {
+            lists.clear();
+        }
+    }
+    lists.insert(key, (Instant::now(), list.clone()));
+    Json(list).into_response()
+}
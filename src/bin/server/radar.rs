@@ -0,0 +1,261 @@
+use std::f64::consts::PI;
+use std::io::Cursor;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use axum::body::Bytes;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::future::join_all;
+use image::{ImageOutputFormat, Rgba, RgbaImage};
+use serde::Deserialize;
+use tracing::{debug, warn, Span};
+use crate::cache::{cache_key, get_cached_tile, put_cached_tile, TileFormat};
+use crate::config::CONFIG;
+use crate::dates::{parse_time, timestamp};
+use crate::upstream::{self, Priority, HTTP_CLIENT};
+use crate::{bad_gateway, cached_tile_response, coalesce, geos, offline, satellites, tile_response, Params, Tile, UpstreamTile, MAX_TILE_BYTES};
+
+// Radar tiles are cached as "radar/rainviewer/{sat}_{radar time}_{z}_{x}_{y}"
+const CACHE_SECTOR: &str = "radar";
+const CACHE_PRODUCT: &str = "rainviewer";
+
+// How far a radar frame may be from the satellite frame it's drawn over
+const FRAME_TOLERANCE: i64 = 10 * 60;
+// How long RainViewer's frame list is reused
+const LIST_TTL: Duration = Duration::from_secs(60);
+// RainViewer's deepest zoom, and the most of its tiles stitched for one of ours
+const MAX_SOURCE_ZOOM: u32 = 7;
+const MAX_SOURCE_TILES: usize = 16;
+const SOURCE_TILE_SIZE: u32 = 256;
+// Web Mercator stops short of the poles
+const MAX_LATITUDE: f64 = 85.0511;
+
+#[derive(Deserialize)]
+struct WeatherMaps {
+    host: String,
+    radar: RadarFrames,
+}
+
+#[derive(Deserialize)]
+struct RadarFrames {
+    past: Vec<RadarFrame>,
+}
+
+#[derive(Deserialize, Clone)]
+struct RadarFrame {
+    /// Seconds since the epoch
+    time: i64,
+    path: String,
+}
+
+lazy_static::lazy_static! {
+    // RainViewer's tile host and past radar frames, and when they were fetched
+    static ref FRAMES: Mutex<Option<(Instant, String, Vec<RadarFrame>)>> = Mutex::new(None);
+}
+
+// The radar frames RainViewer has now (about the past two hours) and the
+// host their tiles are on
+async fn frames() -> Result<(String, Vec<RadarFrame>), &'static str> {
+    if let Some((fetched, host, frames)) = FRAMES.lock().unwrap().clone() {
+        if fetched.elapsed() < LIST_TTL {
+            return Ok((host, frames));
+        }
+    }
+    let url = format!("{}/public/weather-maps.json", CONFIG.rainviewer_url);
+    debug!(url, "Fetching radar frames");
+    let body = match upstream::get(&HTTP_CLIENT, &[url]).await {
+        Ok(r) if r.status().is_success() => r.bytes().await.map_err(|_| "Failed")?,
+        Ok(r) => {
+            warn!(status = r.status().as_u16(), "Radar frame list unavailable");
+            return Err("Failed");
+        }
+        Err(e) => {
+            warn!(error = %e, "Radar frame list failed");
+            return Err("Failed");
+        }
+    };
+    let maps: WeatherMaps = serde_json::from_slice(&body).map_err(|_| "Unexpected radar frame list")?;
+    *FRAMES.lock().unwrap() = Some((Instant::now(), maps.host.clone(), maps.radar.past.clone()));
+    Ok((maps.host, maps.radar.past))
+}
+
+/// `/radar-times`: the radar frames available, as `{"timestamps_int": [...]}`
+/// like `/slider-latest`.
+pub async fn handle_radar_times() -> Response {
+    if offline::enabled() {
+        return offline::unavailable();
+    }
+    match frames().await {
+        Ok((_, frames)) => {
+            let times: Vec<String> = frames.iter().map(|f| timestamp(f.time)).collect();
+            (
+                [(header::CONTENT_TYPE, "application/json"), (header::CACHE_CONTROL, "no-cache")],
+                format!(r#"{{"timestamps_int":[{}]}}"#, times.join(",")),
+            )
+                .into_response()
+        }
+        Err(message) => bad_gateway(message),
+    }
+}
+
+/// One radar tile in a satellite's full-disk grid, with the same `sat`, `x`
+/// (row), `y` (column) and `z` as `/slider-tile`: RainViewer's mosaic
+/// nearest the satellite frame time `t`, transparent where there's no rain.
+pub async fn handle_radar_tile(Query(params): Query<Params>, headers: HeaderMap) -> Response {
+    let sat = params.get("sat").cloned().unwrap_or_else(|| CONFIG.default_satellite.clone());
+    let Some(satellite) = satellites::find(&sat) else {
+        return (StatusCode::BAD_REQUEST, "Unknown satellite").into_response();
+    };
+    let zoom: u32 = params.get("z").and_then(|s| s.parse().ok()).unwrap_or(0).min(satellite.max_zoom);
+    let x: u32 = params.get("x").and_then(|s| s.parse().ok()).unwrap_or(0);
+    let y: u32 = params.get("y").and_then(|s| s.parse().ok()).unwrap_or(0);
+    if x >= 1 << zoom || y >= 1 << zoom {
+        return (StatusCode::BAD_REQUEST, "Tile out of range").into_response();
+    }
+    let Some(time) = params.get("t").and_then(|t| parse_time(t)) else {
+        return (StatusCode::BAD_REQUEST, "Invalid timestamp").into_response();
+    };
+
+    let span = Span::current();
+    span.record("sat", sat.as_str());
+    span.record("x", x);
+    span.record("y", y);
+    span.record("z", zoom);
+
+    // Offline, any cached radar frame close enough to `t` will do
+    if offline::enabled() {
+        for key in crate::cache::cached_frames(&sat, CACHE_SECTOR, CACHE_PRODUCT)
+            .into_iter()
+            .filter(|frame| parse_time(frame).is_some_and(|t| (t - time).abs() <= FRAME_TOLERANCE))
+            .map(|frame| cache_key(&sat, CACHE_SECTOR, CACHE_PRODUCT, &frame, zoom, x, y))
+        {
+            if let Some((data, format)) = get_cached_tile(&key).await {
+                span.record("cache", "HIT");
+                return offline::mark(cached_tile_response(data, format, "0", &headers).await);
+            }
+        }
+        return offline::unavailable();
+    }
+
+    let (host, frames) = match frames().await {
+        Ok(list) => list,
+        Err(message) => return bad_gateway(message),
+    };
+    let Some(frame) = frames.into_iter().filter(|f| (f.time - time).abs() <= FRAME_TOLERANCE).min_by_key(|f| (f.time - time).abs()) else {
+        return (StatusCode::NOT_FOUND, "No radar frame near that time").into_response();
+    };
+    let frame_timestamp = timestamp(frame.time);
+    let key = cache_key(&sat, CACHE_SECTOR, CACHE_PRODUCT, &frame_timestamp, zoom, x, y);
+    if let Some((data, format)) = get_cached_tile(&key).await {
+        span.record("cache", "HIT");
+        return cached_tile_response(data, format, &frame_timestamp, &headers).await;
+    }
+
+    span.record("cache", "MISS");
+    let tile = Tile { sat: &sat, sector: CACHE_SECTOR, product: CACHE_PRODUCT, timestamp: &frame_timestamp, zoom, x, y };
+    match coalesce(&key, fetch_tile(&host, &frame, &tile, &key)).await {
+        Ok((status, bytes)) if status.is_success() => tile_response(bytes, TileFormat::Png, "MISS", &frame_timestamp, &headers),
+        Ok((status, bytes)) => (status, bytes).into_response(),
+        Err(()) => bad_gateway("Failed"),
+    }
+}
+
+// Web Mercator pixel coordinates of a point at `zoom`
+fn mercator(lat: f64, lon: f64, zoom: u32) -> (f64, f64) {
+    let world = (SOURCE_TILE_SIZE << zoom) as f64;
+    let lat = lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians();
+    let x = (lon + 180.0) / 360.0 * world;
+    let y = (1.0 - (lat.tan() + 1.0 / lat.cos()).ln() / PI) / 2.0 * world;
+    (x, y)
+}
+
+// Stitch the RainViewer tiles under a tile of ours and resample them onto
+// its pixels, then cache it
+async fn fetch_tile(host: &str, frame: &RadarFrame, tile: &Tile<'_>, key: &str) -> UpstreamTile {
+    let Some(satellite) = satellites::find(tile.sat) else {
+        return Ok((StatusCode::NOT_FOUND, Bytes::new()));
+    };
+    let tile_size = satellite.tile_size;
+    let coordinates = geos::tile_coordinates(tile, satellite);
+    let (south, west, north, east) = geos::bounds(&coordinates);
+
+    // About as many source pixels across as ours, within the tile budget
+    let width = (east - west).max(0.01);
+    let mut zoom = ((360.0 / width * tile_size as f64 / SOURCE_TILE_SIZE as f64).log2().ceil().max(0.0) as u32).min(MAX_SOURCE_ZOOM);
+    let range = |zoom: u32| {
+        let last = (1u32 << zoom) - 1;
+        let (x0, y0) = mercator(north, west, zoom);
+        let (x1, y1) = mercator(south, east, zoom);
+        let cell = |v: f64| ((v / SOURCE_TILE_SIZE as f64) as u32).min(last);
+        (cell(x0), cell(y0), cell(x1), cell(y1))
+    };
+    let mut cells = range(zoom);
+    while zoom > 0 && ((cells.2 - cells.0 + 1) * (cells.3 - cells.1 + 1)) as usize > MAX_SOURCE_TILES {
+        zoom -= 1;
+        cells = range(zoom);
+    }
+    let (cx0, cy0, cx1, cy1) = cells;
+
+    let sources: Vec<(u32, u32)> = if coordinates.iter().all(Option::is_none) {
+        Vec::new()
+    } else {
+        (cy0..=cy1).flat_map(|cy| (cx0..=cx1).map(move |cx| (cx, cy))).collect()
+    };
+    let slot = upstream::acquire(Priority::Interactive).await;
+    let downloads = sources.iter().map(|&(cx, cy)| {
+        // Colour scheme 2 (Universal Blue), smoothed, with snow
+        let url = format!("{}{}/{}/{}/{}/{}/2/1_1.png", host, frame.path, SOURCE_TILE_SIZE, zoom, cx, cy);
+        async move {
+            debug!(key, url, "Fetching radar tile");
+            let response = upstream::get(&HTTP_CLIENT, &[url]).await.map_err(|e| e.to_string())?;
+            // Outside radar coverage
+            if response.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            if !response.status().is_success() {
+                return Err(format!("status {}", response.status().as_u16()));
+            }
+            let body = upstream::read_limited(response, MAX_TILE_BYTES).await?;
+            image::load_from_memory(&body).map(|image| Some(image.to_rgba8())).map_err(|e| e.to_string())
+        }
+    });
+    let mut mosaic = RgbaImage::new((cx1 - cx0 + 1) * SOURCE_TILE_SIZE, (cy1 - cy0 + 1) * SOURCE_TILE_SIZE);
+    for (&(cx, cy), result) in sources.iter().zip(join_all(downloads).await) {
+        match result {
+            Ok(Some(image)) => {
+                image::imageops::replace(&mut mosaic, &image, ((cx - cx0) * SOURCE_TILE_SIZE) as i64, ((cy - cy0) * SOURCE_TILE_SIZE) as i64)
+            }
+            Ok(None) => {}
+            Err(e) => {
+                warn!(key, error = %e, "Radar tile download failed");
+                return Err(());
+            }
+        }
+    }
+    drop(slot);
+
+    let origin = ((cx0 * SOURCE_TILE_SIZE) as f64, (cy0 * SOURCE_TILE_SIZE) as f64);
+    let render = move || {
+        let out = RgbaImage::from_fn(tile_size, tile_size, |px, py| {
+            let Some((lat, lon)) = coordinates[(py * tile_size + px) as usize] else {
+                return Rgba([0, 0, 0, 0]);
+            };
+            let (mx, my) = mercator(lat, lon, zoom);
+            let (u, v) = ((mx - origin.0) as i64, (my - origin.1) as i64);
+            if u < 0 || v < 0 || u >= mosaic.width() as i64 || v >= mosaic.height() as i64 {
+                return Rgba([0, 0, 0, 0]);
+            }
+            *mosaic.get_pixel(u as u32, v as u32)
+        });
+        let mut png = Cursor::new(Vec::new());
+        image::DynamicImage::ImageRgba8(out).write_to(&mut png, ImageOutputFormat::Png).expect("PNG encoding to memory");
+        png.into_inner()
+    };
+    let png = match tokio::task::spawn_blocking(render).await {
+        Ok(png) => Bytes::from(png),
+        Err(_) => return Err(()),
+    };
+    put_cached_tile(key, &png).await;
+    Ok((StatusCode::OK, png))
+}
@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
+use std::time::Instant;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use tracing::warn;
+use crate::config::CONFIG;
+
+// Past this many tracked clients, idle (full) buckets are dropped
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+lazy_static::lazy_static! {
+    static ref BUCKETS: Mutex<HashMap<IpAddr, Bucket>> = Mutex::new(HashMap::new());
+}
+
+fn enabled() -> bool {
+    CONFIG.rate_limit_per_sec > 0.0
+}
+
+/// The client's address. Behind a reverse proxy (`trust_forwarded_for`)
+/// that is the last X-Forwarded-For entry, the one the proxy itself added.
+fn client_ip(headers: &HeaderMap, peer: Option<IpAddr>) -> Option<IpAddr> {
+    if CONFIG.trust_forwarded_for {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .next_back()
+            .and_then(|ip| ip.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    peer
+}
+
+/// Take a token for `ip`, or return how many seconds until one is available.
+fn take_token(ip: IpAddr) -> Result<(), u64> {
+    let rate = CONFIG.rate_limit_per_sec;
+    let burst = CONFIG.rate_limit_burst.max(1) as f64;
+    let now = Instant::now();
+
+    let mut buckets = BUCKETS.lock().unwrap();
+    if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(&ip) {
+        buckets.retain(|_, b| b.tokens + now.duration_since(b.updated).as_secs_f64() * rate < burst);
+    }
+    let bucket = buckets.entry(ip).or_insert(Bucket { tokens: burst, updated: now });
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+    bucket.updated = now;
+
+    if bucket.tokens >= 1.0 {
+        bucket.tokens -= 1.0;
+        Ok(())
+    } else {
+        Err(((1.0 - bucket.tokens) / rate).ceil() as u64)
+    }
+}
+
+/// Token-bucket limit per client on the proxy endpoints, so one client
+/// can't make us hammer the upstream CDNs.
+pub async fn limit(request: Request, next: Next) -> Response {
+    if !enabled() {
+        return next.run(request).await;
+    }
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    // Unix socket peers have no IP; without X-Forwarded-For they share a bucket
+    let ip = client_ip(request.headers(), peer).unwrap_or(IpAddr::from([0, 0, 0, 0]));
+
+    match take_token(ip) {
+        Ok(()) => next.run(request).await,
+        Err(retry_after) => {
+            warn!(%ip, "Rate limited client");
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, retry_after.max(1).to_string())],
+                "429 Too Many Requests",
+            )
+                .into_response()
+        }
+    }
+}
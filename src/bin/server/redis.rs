@@ -0,0 +1,327 @@
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader, BufStream};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, Mutex};
+use tracing::{debug, info, warn};
+use crate::config::CONFIG;
+use crate::{cache, events, s3};
+
+// Just enough of RESP2 for commands whose replies don't nest arrays, and
+// for pub/sub, over plain TCP
+const DEFAULT_PORT: u16 = 6379;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const RETRY_DELAY: Duration = Duration::from_secs(30);
+// Connections commands take turns on, so one slow reply doesn't hold up
+// every other
+const POOL_SIZE: usize = 4;
+// The subscriber pings this often, and gives up after two unanswered
+const PING_INTERVAL: Duration = Duration::from_secs(60);
+// A whole shared index comes back in one reply; anything bigger is taken
+// as a broken connection
+const MAX_REPLY: usize = 256 * 1024 * 1024;
+const MAX_LINE: u64 = 64 * 1024;
+// How often a server waiting on another's lock looks to see it released
+const LOCK_POLL: Duration = Duration::from_millis(200);
+// Deletes a lock only if it's still this server's
+const RELEASE_SCRIPT: &str = "if redis.call('get', KEYS[1]) == ARGV[1] then return redis.call('del', KEYS[1]) else return 0 end";
+
+/// Channel servers announce new frames on.
+pub const FRAMES_CHANNEL: &str = "frames";
+/// Channel servers sharing a bucket announce their index changes on.
+pub const INDEX_CHANNEL: &str = "index-updates";
+
+/// A reply from Redis.
+pub enum Reply {
+    Nil,
+    /// A simple string: OK, PONG and the like
+    Status,
+    Integer(i64),
+    Data(Vec<u8>),
+    /// Of anything but arrays
+    Array(Vec<Reply>),
+    Error(String),
+}
+
+lazy_static::lazy_static! {
+    /// Tells this server's locks and messages from those of the others
+    pub static ref INSTANCE: String = format!("{:016x}", fastrand::u64(..));
+    static ref POOL: Vec<Mutex<Option<BufStream<TcpStream>>>> = (0..POOL_SIZE).map(|_| Mutex::new(None)).collect();
+}
+
+static NEXT_CONNECTION: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether servers coordinate through `redis_url`.
+pub fn enabled() -> bool {
+    CONFIG.redis_url.is_some()
+}
+
+/// Whether servers also share the cache index and tile downloads, which
+/// they can only when they share the tiles, in a bucket.
+pub fn shares_cache() -> bool {
+    enabled() && s3::enabled()
+}
+
+/// `name` with `redis_prefix`.
+pub fn key(name: &str) -> String {
+    format!("{}{}", CONFIG.redis_prefix, name)
+}
+
+fn encode(args: &[&[u8]]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        out.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        out.extend_from_slice(arg);
+        out.extend_from_slice(b"\r\n");
+    }
+    out
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<String> {
+    let mut line = Vec::new();
+    (&mut *reader).take(MAX_LINE).read_until(b'\n', &mut line).await?;
+    if !line.ends_with(b"\r\n") {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed mid-reply"));
+    }
+    line.truncate(line.len() - 2);
+    String::from_utf8(line).map_err(|_| invalid("reply header isn't text"))
+}
+
+// Anything but an array, whose header `line` has been read
+async fn read_scalar(reader: &mut (impl AsyncBufRead + Unpin), line: &str) -> io::Result<Reply> {
+    let kind = line.chars().next().unwrap_or_default();
+    let rest = &line[kind.len_utf8().min(line.len())..];
+    match kind {
+        '+' => Ok(Reply::Status),
+        '-' => Ok(Reply::Error(rest.to_string())),
+        ':' => rest.parse().map(Reply::Integer).map_err(|_| invalid("bad integer")),
+        '$' => {
+            let len: i64 = rest.parse().map_err(|_| invalid("bad length"))?;
+            if len < 0 {
+                return Ok(Reply::Nil);
+            }
+            if len as usize > MAX_REPLY {
+                return Err(invalid("reply too large"));
+            }
+            // The data and its CRLF
+            let mut data = vec![0; len as usize + 2];
+            reader.read_exact(&mut data).await?;
+            data.truncate(len as usize);
+            Ok(Reply::Data(data))
+        }
+        _ => Err(invalid(format!("unexpected reply {:?}", line))),
+    }
+}
+
+async fn read_reply(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Reply> {
+    let line = read_line(reader).await?;
+    let Some(count) = line.strip_prefix('*') else {
+        return read_scalar(reader, &line).await;
+    };
+    let count: i64 = count.parse().map_err(|_| invalid("bad array length"))?;
+    if count < 0 {
+        return Ok(Reply::Nil);
+    }
+    let mut items = Vec::new();
+    for _ in 0..count {
+        let line = read_line(reader).await?;
+        if line.starts_with('*') {
+            return Err(invalid("nested arrays aren't supported"));
+        }
+        items.push(read_scalar(reader, &line).await?);
+    }
+    Ok(Reply::Array(items))
+}
+
+async fn roundtrip(stream: &mut BufStream<TcpStream>, args: &[&[u8]]) -> io::Result<Reply> {
+    stream.write_all(&encode(args)).await?;
+    stream.flush().await?;
+    read_reply(stream).await
+}
+
+// Connect, authenticate and pick the database `redis_url` names
+async fn connect() -> io::Result<BufStream<TcpStream>> {
+    let url = reqwest::Url::parse(CONFIG.redis_url.as_deref().unwrap_or_default()).map_err(|e| invalid(e.to_string()))?;
+    let address = format!("{}:{}", url.host_str().unwrap_or_default(), url.port().unwrap_or(DEFAULT_PORT));
+    let timed_out = || io::Error::new(io::ErrorKind::TimedOut, "timed out");
+    let stream = tokio::time::timeout(CONNECT_TIMEOUT, TcpStream::connect(&address)).await.map_err(|_| timed_out())??;
+    let mut stream = BufStream::new(stream);
+
+    let decode = |text: &str| urlencoding::decode(text).map(|t| t.into_owned()).unwrap_or_else(|_| text.to_string());
+    let password = CONFIG.redis_password.clone().or_else(|| url.password().map(decode));
+    let user = decode(url.username());
+    let db = url.path().trim_start_matches('/');
+    let mut setup: Vec<Vec<&[u8]>> = Vec::new();
+    match &password {
+        Some(password) if user.is_empty() => setup.push(vec![b"AUTH", password.as_bytes()]),
+        Some(password) => setup.push(vec![b"AUTH", user.as_bytes(), password.as_bytes()]),
+        None => {}
+    }
+    if !db.is_empty() {
+        setup.push(vec![b"SELECT", db.as_bytes()]);
+    }
+    for args in setup {
+        let reply = tokio::time::timeout(CONNECT_TIMEOUT, roundtrip(&mut stream, &args)).await.map_err(|_| timed_out())??;
+        if let Reply::Error(message) = reply {
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, message));
+        }
+    }
+    Ok(stream)
+}
+
+/// Run a command on one of the pooled connections, connecting it first if
+/// need be. Error replies are returned as `Err`.
+pub async fn command(args: &[&[u8]]) -> Result<Reply, String> {
+    let slot = &POOL[NEXT_CONNECTION.fetch_add(1, Ordering::Relaxed) % POOL_SIZE];
+    let mut connection = slot.lock().await;
+    let mut stream = match connection.take() {
+        Some(stream) => stream,
+        None => connect().await.map_err(|e| e.to_string())?,
+    };
+    // A connection that failed or timed out mid-reply isn't put back
+    let reply = match tokio::time::timeout(CONFIG.upstream_timeout(), roundtrip(&mut stream, args)).await {
+        Ok(Ok(reply)) => reply,
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err("timed out".to_string()),
+    };
+    *connection = Some(stream);
+    match reply {
+        Reply::Error(message) => Err(message),
+        reply => Ok(reply),
+    }
+}
+
+/// Set `name` to this server's id unless it's already set, to expire
+/// after `ttl`. Whether it was set: a lock taken, or a claim made first.
+pub async fn set_once(name: &str, ttl: Duration) -> Result<bool, String> {
+    let millis = ttl.as_millis().max(1).to_string();
+    let reply = command(&[b"SET", key(name).as_bytes(), INSTANCE.as_bytes(), b"NX", b"PX", millis.as_bytes()]).await?;
+    Ok(matches!(reply, Reply::Status))
+}
+
+/// Release the lock `name` if this server still holds it.
+pub async fn release(name: &str) {
+    if let Err(e) = command(&[b"EVAL", RELEASE_SCRIPT.as_bytes(), b"1", key(name).as_bytes(), INSTANCE.as_bytes()]).await {
+        warn!(name, error = %e, "Failed to release Redis lock");
+    }
+}
+
+/// Wait until the lock `name` is released or expires, or `limit` passes.
+pub async fn wait_released(name: &str, limit: Duration) {
+    let deadline = Instant::now() + limit;
+    let key = key(name);
+    while Instant::now() < deadline {
+        match command(&[b"EXISTS", key.as_bytes()]).await {
+            Ok(Reply::Integer(1)) => tokio::time::sleep(LOCK_POLL).await,
+            _ => return,
+        }
+    }
+}
+
+/// Send `payload` to the servers subscribed to `channel`, this one included.
+pub async fn publish(channel: &str, payload: &[u8]) -> Result<(), String> {
+    command(&[b"PUBLISH", key(channel).as_bytes(), payload]).await.map(|_| ())
+}
+
+// Hand a message to whatever follows its channel
+fn dispatch(reply: Reply) {
+    let Reply::Array(items) = reply else {
+        return;
+    };
+    let mut items = items.into_iter();
+    let (Some(Reply::Data(kind)), Some(Reply::Data(channel)), Some(Reply::Data(payload))) = (items.next(), items.next(), items.next()) else {
+        return;
+    };
+    if kind != b"message" {
+        return;
+    }
+    if channel == key(FRAMES_CHANNEL).as_bytes() {
+        events::remote_frame(&payload);
+    } else if channel == key(INDEX_CHANNEL).as_bytes() {
+        cache::apply_shared_update(&payload);
+    }
+}
+
+// Subscribe, and pass on messages until the connection drops
+async fn listen() -> io::Result<()> {
+    let mut channels = vec![key(FRAMES_CHANNEL)];
+    if shares_cache() {
+        channels.push(key(INDEX_CHANNEL));
+    }
+    let (reader, mut writer) = connect().await?.into_inner().into_split();
+    let mut args: Vec<&[u8]> = vec![b"SUBSCRIBE"];
+    args.extend(channels.iter().map(|c| c.as_bytes()));
+    writer.write_all(&encode(&args)).await?;
+
+    // Read in a task of its own, so that pings can go out meanwhile
+    let (sender, mut replies) = mpsc::channel(256);
+    let reading = tokio::spawn(async move {
+        let mut reader = BufReader::new(reader);
+        while let Ok(reply) = read_reply(&mut reader).await {
+            if sender.send(reply).await.is_err() {
+                return;
+            }
+        }
+    });
+    let result = async {
+        // One confirmation per channel
+        for _ in &channels {
+            match replies.recv().await {
+                Some(Reply::Error(message)) => return Err(io::Error::new(io::ErrorKind::PermissionDenied, message)),
+                Some(_) => {}
+                None => return Err(io::Error::new(io::ErrorKind::ConnectionReset, "Redis closed the connection")),
+            }
+        }
+        info!(channels = channels.len(), "Subscribed to Redis");
+        // Anything missed while not subscribed is made up for
+        if shares_cache() {
+            cache::load_shared_index().await;
+        }
+        let mut ping = tokio::time::interval_at(tokio::time::Instant::now() + PING_INTERVAL, PING_INTERVAL);
+        let mut unanswered = 0;
+        loop {
+            tokio::select! {
+                reply = replies.recv() => match reply {
+                    Some(reply) => {
+                        unanswered = 0;
+                        dispatch(reply);
+                    }
+                    None => return Err(io::Error::new(io::ErrorKind::ConnectionReset, "Redis closed the connection")),
+                },
+                _ = ping.tick() => {
+                    if unanswered >= 2 {
+                        return Err(io::Error::new(io::ErrorKind::TimedOut, "Redis stopped answering"));
+                    }
+                    unanswered += 1;
+                    writer.write_all(&encode(&[b"PING"])).await?;
+                }
+            }
+        }
+    }
+    .await;
+    reading.abort();
+    result
+}
+
+/// With `redis_url` set, follow what the other servers announce through
+/// it, resubscribing whenever the connection drops.
+pub fn spawn_subscriber() {
+    if !enabled() {
+        return;
+    }
+    info!(prefix = CONFIG.redis_prefix, shared_cache = shares_cache(), instance = *INSTANCE, "Coordinating through Redis");
+    tokio::spawn(async {
+        loop {
+            if let Err(e) = listen().await {
+                warn!(error = %e, "Redis subscription failed; retrying in {}s", RETRY_DELAY.as_secs());
+            }
+            tokio::time::sleep(RETRY_DELAY).await;
+            debug!("Resubscribing to Redis");
+        }
+    });
+}
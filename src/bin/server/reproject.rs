@@ -0,0 +1,194 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::Cursor;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use futures_util::future::join_all;
+use image::{ImageOutputFormat, Rgba, RgbImage, RgbaImage};
+use tracing::{debug, warn};
+use crate::dates::timestamp;
+use crate::frames::{timestamps_in, Layer};
+use crate::fulldisk::tile_bytes;
+use crate::{bad_gateway, geos, offline, satellites, slider_latest, Params, Tile};
+
+/// Web Mercator stops short of the poles.
+pub const MAX_LATITUDE: f64 = 85.0511;
+// Views up to this wide are checked pixel by pixel for which full-disk
+// tiles they need, and bigger ones every SAMPLE_STEP pixels, far fewer than
+// a tile's width
+const EXACT_SIDE: u32 = 512;
+const SAMPLE_STEP: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Projection {
+    /// Plate carrée, EPSG:4326
+    Geographic,
+    /// Web Mercator, EPSG:3857
+    Mercator,
+}
+
+/// An image to resample a frame onto: its projection, its (west, south,
+/// east, north) bounds in degrees and its size in pixels.
+#[derive(Clone, Copy, Debug)]
+pub struct View {
+    pub projection: Projection,
+    pub bbox: (f64, f64, f64, f64),
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Web Mercator's y, in radians of longitude, at `lat`.
+pub fn mercator_y(lat: f64) -> f64 {
+    lat.clamp(-MAX_LATITUDE, MAX_LATITUDE).to_radians().tan().asinh()
+}
+
+/// The latitude at Web Mercator's `y`.
+pub fn mercator_latitude(y: f64) -> f64 {
+    y.sinh().atan().to_degrees()
+}
+
+impl View {
+    /// Latitude and longitude of the centre of pixel (`px`, `py`).
+    pub fn lat_lon(&self, px: u32, py: u32) -> (f64, f64) {
+        let (west, south, east, north) = self.bbox;
+        let fx = (px as f64 + 0.5) / self.width as f64;
+        let fy = (py as f64 + 0.5) / self.height as f64;
+        let lat = match self.projection {
+            Projection::Geographic => north - (north - south) * fy,
+            Projection::Mercator => {
+                let top = mercator_y(north);
+                mercator_latitude(top - (top - mercator_y(south)) * fy)
+            }
+        };
+        (lat, west + (east - west) * fx)
+    }
+}
+
+/// A frame resampled onto a view, transparent where the satellite can't
+/// see, with how many full-disk tiles it took and how many of those
+/// couldn't be had (and are transparent too).
+pub struct Reprojection {
+    pub image: RgbaImage,
+    pub sources: usize,
+    pub missing: usize,
+}
+
+/// The frame `time` of `layer`, or its newest if that's `None`, as a
+/// timestamp. `params` are the query parameters the layer came from.
+pub async fn frame_timestamp(layer: &Layer, params: &Params, time: Option<i64>) -> Result<String, Response> {
+    if let Some(time) = time {
+        return Ok(timestamp(time));
+    }
+    let latest = slider_latest(layer.sat.clone(), layer.cdn.clone(), params).await;
+    if !latest.status().is_success() {
+        return Err(layer.mark(latest));
+    }
+    match timestamps_in(latest).await.and_then(|t| t.into_iter().max()) {
+        Some(t) => Ok(t.to_string()),
+        None => Err(bad_gateway("No frames listed")),
+    }
+}
+
+// Resample full-disk tiles, keyed by (row, column), onto the view; `locate`
+// gives where a point falls in the full disk as fractions of its width
+fn render(
+    view: View,
+    locate: impl Fn(f64, f64) -> Option<(f64, f64)>,
+    tiles: &HashMap<(u32, u32), RgbImage>,
+    tile_size: u32,
+    side: f64,
+) -> RgbaImage {
+    RgbaImage::from_fn(view.width, view.height, |px, py| {
+        let (lat, lon) = view.lat_lon(px, py);
+        let Some((row, column)) = locate(lat, lon) else {
+            return Rgba([0, 0, 0, 0]);
+        };
+        let (row, column) = ((row * side) as u32, (column * side) as u32);
+        let Some(tile) = tiles.get(&(row / tile_size, column / tile_size)) else {
+            return Rgba([0, 0, 0, 0]);
+        };
+        let u = (column % tile_size).min(tile.width() - 1);
+        let v = (row % tile_size).min(tile.height() - 1);
+        let [r, g, b] = tile.get_pixel(u, v).0;
+        Rgba([r, g, b, 255])
+    })
+}
+
+/// Frame `timestamp` of `layer`'s full disk resampled onto `view` from
+/// the tiles under it, from the cache or upstream, at about the view's
+/// resolution but from no more than `max_sources` tiles.
+pub async fn reproject(layer: &Layer, timestamp: &str, view: View, max_sources: usize) -> Result<Reprojection, Response> {
+    let Some(satellite) = satellites::find(&layer.sat) else {
+        return Err((StatusCode::BAD_REQUEST, "Unknown satellite").into_response());
+    };
+    let (max_zoom, tile_size) = layer.tile_grid();
+    let native_size = satellite.tile_size as f64;
+    let locate = move |lat: f64, lon: f64| {
+        let (row, column) = geos::pixel(satellite, 0, lat, lon)?;
+        Some((row / native_size, column / native_size)).filter(|&(r, c)| (0.0..1.0).contains(&r) && (0.0..1.0).contains(&c))
+    };
+
+    // Which parts of the full disk the view shows
+    let step = if view.width.max(view.height) <= EXACT_SIDE { 1 } else { SAMPLE_STEP };
+    let steps = move |length: u32| (0..length).step_by(step).chain(std::iter::once(length - 1));
+    let samples: Vec<(f64, f64)> = steps(view.height)
+        .flat_map(|py| steps(view.width).map(move |px| view.lat_lon(px, py)))
+        .filter_map(|(lat, lon)| locate(lat, lon))
+        .collect();
+
+    // About as many full-disk pixels across as the view's, within the budget
+    let (mut low, mut high) = ((1.0f64, 1.0f64), (0.0f64, 0.0f64));
+    for &(r, c) in &samples {
+        low = (low.0.min(r), low.1.min(c));
+        high = (high.0.max(r), high.1.max(c));
+    }
+    let extent = (high.0 - low.0).max(high.1 - low.1);
+    let pixels = view.width.max(view.height) as f64;
+    let mut zoom = (0..=max_zoom).find(|&z| (tile_size << z) as f64 * extent >= pixels).unwrap_or(max_zoom);
+    let cells = |z: u32| -> BTreeSet<(u32, u32)> {
+        let side = (tile_size << z) as f64;
+        let last = (1u32 << z) - 1;
+        let cell = |f: f64| ((f * side) as u32 / tile_size).min(last);
+        samples.iter().map(|&(r, c)| (cell(r), cell(c))).collect()
+    };
+    let mut sources = cells(zoom);
+    while zoom > 0 && sources.len() > max_sources {
+        zoom -= 1;
+        sources = cells(zoom);
+    }
+
+    let date = &timestamp[..8];
+    let downloads = sources.iter().map(|&(row, column)| {
+        let tile = Tile { sat: &layer.sat, sector: &layer.sector, product: &layer.product, timestamp, zoom, x: row, y: column };
+        tile_bytes(&layer.cdn, tile, date)
+    });
+    let mut tiles = HashMap::new();
+    for (&cell, data) in sources.iter().zip(join_all(downloads).await) {
+        let Some(data) = data else {
+            continue;
+        };
+        match image::load_from_memory(&data) {
+            Ok(image) => {
+                tiles.insert(cell, image.to_rgb8());
+            }
+            Err(e) => warn!(sat = layer.sat, timestamp, row = cell.0, column = cell.1, error = %e, "Undecodable tile left out of reprojection"),
+        }
+    }
+    if tiles.is_empty() && !sources.is_empty() {
+        return Err(if offline::enabled() { offline::unavailable() } else { bad_gateway("No tiles for that frame") });
+    }
+    let (count, missing) = (sources.len(), sources.len() - tiles.len());
+    debug!(sat = layer.sat, timestamp, zoom, sources = count, missing, width = view.width, height = view.height, "Reprojecting");
+
+    let side = (tile_size << zoom) as f64;
+    match tokio::task::spawn_blocking(move || render(view, locate, &tiles, tile_size, side)).await {
+        Ok(image) => Ok(Reprojection { image, sources: count, missing }),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to render image").into_response()),
+    }
+}
+
+/// An image as PNG.
+pub fn png(image: RgbaImage) -> Vec<u8> {
+    let mut png = Cursor::new(Vec::new());
+    image::DynamicImage::ImageRgba8(image).write_to(&mut png, ImageOutputFormat::Png).expect("PNG encoding to memory");
+    png.into_inner()
+}
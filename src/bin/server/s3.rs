@@ -0,0 +1,165 @@
+use std::time::{Duration, SystemTime};
+use axum::body::Bytes;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use crate::config::CONFIG;
+use crate::dates::{now, parse_time, timestamp};
+use crate::upstream::HTTP_CLIENT;
+
+// Longest error body kept for a log line
+const MAX_ERROR_CHARS: usize = 200;
+
+/// An object in the bucket, as listed.
+pub struct Object {
+    /// Its name below `s3_prefix`
+    pub name: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// Whether the tile cache lives in an S3 bucket rather than `cache_dir`.
+pub fn enabled() -> bool {
+    CONFIG.cache_backend == "s3"
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// "/{bucket}" and, for an object, "/{name}" with each segment encoded as
+// SigV4 wants it
+fn object_path(name: Option<&str>) -> String {
+    let bucket = CONFIG.s3_bucket.as_deref().unwrap_or_default();
+    match name {
+        Some(name) => {
+            let full = format!("{}{}", CONFIG.s3_prefix, name);
+            let encoded: Vec<String> = full.split('/').map(|segment| urlencoding::encode(segment).into_owned()).collect();
+            format!("/{}/{}", bucket, encoded.join("/"))
+        }
+        None => format!("/{}", bucket),
+    }
+}
+
+// A request signed with AWS Signature Version 4, path-style, so it works
+// with MinIO and other S3-compatible stores as well as AWS
+fn signed(method: reqwest::Method, path: &str, query: &[(&str, String)], body: Bytes) -> Result<reqwest::RequestBuilder, String> {
+    let endpoint = CONFIG.s3_endpoint.as_deref().unwrap_or_default().trim_end_matches('/');
+    let mut query: Vec<(String, String)> = query.iter().map(|(k, v)| (urlencoding::encode(k).into_owned(), urlencoding::encode(v).into_owned())).collect();
+    query.sort();
+    let query = query.iter().map(|(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&");
+    let url = reqwest::Url::parse(&format!("{}{}{}{}", endpoint, path, if query.is_empty() { "" } else { "?" }, query)).map_err(|e| e.to_string())?;
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_string(),
+        (None, _) => return Err("s3_endpoint has no host".to_string()),
+    };
+
+    let amz_date = {
+        let t = timestamp(now());
+        format!("{}T{}Z", &t[..8], &t[8..])
+    };
+    let date = &amz_date[..8];
+    let payload_hash = hex(&Sha256::digest(&body));
+    let canonical = format!(
+        "{}\n{}\n{}\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+        method, path, query, host, payload_hash, amz_date, payload_hash
+    );
+    let scope = format!("{}/{}/s3/aws4_request", date, CONFIG.s3_region);
+    let to_sign = format!("AWS4-HMAC-SHA256\n{}\n{}\n{}", amz_date, scope, hex(&Sha256::digest(canonical.as_bytes())));
+    let secret = CONFIG.s3_secret_key.as_deref().unwrap_or_default();
+    let key = ["s3", "aws4_request"].iter().fold(
+        hmac(&hmac(format!("AWS4{}", secret).as_bytes(), date), &CONFIG.s3_region),
+        |key, part| hmac(&key, part),
+    );
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+        CONFIG.s3_access_key.as_deref().unwrap_or_default(),
+        scope,
+        hex(&hmac(&key, &to_sign))
+    );
+    Ok(HTTP_CLIENT
+        .request(method, url)
+        .header("x-amz-date", amz_date)
+        .header("x-amz-content-sha256", payload_hash)
+        .header(reqwest::header::AUTHORIZATION, authorization)
+        .timeout(CONFIG.upstream_timeout())
+        .body(body))
+}
+
+async fn failure(response: reqwest::Response) -> String {
+    let status = response.status().as_u16();
+    let body = response.text().await.unwrap_or_default();
+    format!("status {}: {}", status, body.chars().take(MAX_ERROR_CHARS).collect::<String>())
+}
+
+/// The object `name`, or `None` if there's no such object.
+pub async fn get(name: &str) -> Result<Option<Bytes>, String> {
+    let response = signed(reqwest::Method::GET, &object_path(Some(name)), &[], Bytes::new())?.send().await.map_err(|e| e.to_string())?;
+    match response.status() {
+        status if status.is_success() => response.bytes().await.map(Some).map_err(|e| e.to_string()),
+        reqwest::StatusCode::NOT_FOUND => Ok(None),
+        _ => Err(failure(response).await),
+    }
+}
+
+pub async fn put(name: &str, data: Bytes) -> Result<(), String> {
+    let response = signed(reqwest::Method::PUT, &object_path(Some(name)), &[], data)?.send().await.map_err(|e| e.to_string())?;
+    if response.status().is_success() { Ok(()) } else { Err(failure(response).await) }
+}
+
+/// Delete the object `name`; deleting one that's already gone succeeds.
+pub async fn delete(name: &str) -> Result<(), String> {
+    let response = signed(reqwest::Method::DELETE, &object_path(Some(name)), &[], Bytes::new())?.send().await.map_err(|e| e.to_string())?;
+    if response.status().is_success() || response.status() == reqwest::StatusCode::NOT_FOUND {
+        Ok(())
+    } else {
+        Err(failure(response).await)
+    }
+}
+
+/// The text of each <tag> element in `xml`, entities decoded.
+pub fn elements(xml: &str, tag: &str) -> Vec<String> {
+    let (open, close) = (format!("<{}>", tag), format!("</{}>", tag));
+    xml.split(open.as_str())
+        .skip(1)
+        .filter_map(|rest| rest.split(close.as_str()).next())
+        .map(|text| text.replace("&lt;", "<").replace("&gt;", ">").replace("&quot;", "\"").replace("&apos;", "'").replace("&amp;", "&"))
+        .collect()
+}
+
+/// Every object below `s3_prefix`, a page of up to 1000 at a time.
+pub async fn list() -> Result<Vec<Object>, String> {
+    let mut objects = Vec::new();
+    let mut token: Option<String> = None;
+    loop {
+        let mut query = vec![("list-type", "2".to_string()), ("prefix", CONFIG.s3_prefix.clone())];
+        if let Some(token) = &token {
+            query.push(("continuation-token", token.clone()));
+        }
+        let response = signed(reqwest::Method::GET, &object_path(None), &query, Bytes::new())?.send().await.map_err(|e| e.to_string())?;
+        if !response.status().is_success() {
+            return Err(failure(response).await);
+        }
+        let xml = response.text().await.map_err(|e| e.to_string())?;
+        for contents in elements(&xml, "Contents") {
+            let field = |tag: &str| elements(&contents, tag).into_iter().next().unwrap_or_default();
+            let Some(name) = field("Key").strip_prefix(CONFIG.s3_prefix.as_str()).map(str::to_string) else {
+                continue;
+            };
+            let modified = parse_time(&field("LastModified")).map_or(SystemTime::UNIX_EPOCH, |seconds| {
+                SystemTime::UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64)
+            });
+            objects.push(Object { name, size: field("Size").parse().unwrap_or(0), modified });
+        }
+        token = elements(&xml, "NextContinuationToken").into_iter().next();
+        if elements(&xml, "IsTruncated").first().map(String::as_str) != Some("true") || token.is_none() {
+            return Ok(objects);
+        }
+    }
+}
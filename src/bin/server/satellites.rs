@@ -0,0 +1,313 @@
+use axum::http::{HeaderName, HeaderValue};
+use axum::response::Response;
+use tracing::debug;
+use crate::cache::{DEFAULT_PRODUCT, DEFAULT_SECTOR};
+
+/// Whether a satellite is currently the one imaging its position.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Status {
+    Operational,
+    /// Parked in orbit as a spare; SLIDER may still carry its archive or
+    /// occasional imagery
+    Standby,
+    /// Still served so existing links and caches keep working, but replaced
+    /// by a newer satellite at the same position
+    Deprecated,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Operational => "operational",
+            Status::Standby => "standby",
+            Status::Deprecated => "deprecated",
+        }
+    }
+}
+
+/// Set on frame lists of satellites that aren't operational, so the
+/// frontend can say why the newest frame may be old.
+pub const STATUS_HEADER: HeaderName = HeaderName::from_static("x-peepsat-satellite-status");
+
+/// A satellite SLIDER serves full-disk imagery for.
+pub struct Satellite {
+    /// Value of the `sat` query parameter
+    pub key: &'static str,
+    /// Name in SLIDER URLs
+    pub slider_id: &'static str,
+    pub name: &'static str,
+    /// Area it looks over, for listings
+    pub region: &'static str,
+    /// Sub-satellite longitude, degrees east
+    pub longitude: f64,
+    /// Deepest tile zoom level; zoom z is a 2^z by 2^z grid
+    pub max_zoom: u32,
+    /// Tile width and height in pixels
+    pub tile_size: u32,
+    /// Seconds between full-disk images
+    pub cadence: u32,
+    pub status: Status,
+    /// Products SLIDER has for it, the first being the default; empty for
+    /// the usual full set with GeoColor as the default
+    pub products: &'static [&'static str],
+    /// Sectors besides the full disk, which is described by the fields above
+    pub sectors: &'static [Sector],
+    /// Its imager's bands, when SLIDER has them individually
+    pub imager: Option<&'static Imager>,
+    /// Carries a GLM lightning mapper, as the GOES-R series does
+    pub glm: bool,
+    /// NESDIS STAR publishes its full-disk GeoColor as whole images, which
+    /// /goes-proxy serves
+    pub star_images: bool,
+}
+
+/// GLM flash extent density, a transparent overlay on a 2 km grid, so one
+/// zoom level short like the 2 km bands.
+pub const LIGHTNING_PRODUCT: &str = "glm_flash_extent_density";
+
+/// Hourly sea surface temperature on a 2 km grid, coloured by temperature
+/// and left clear under cloud.
+pub const SST_PRODUCT: &str = "sea_surface_temperature";
+
+/// GeoColor where the sun is up and colourised band 13 IR where it isn't,
+/// blended by the server from those two products' tiles, so it has no
+/// frame lists or tiles of its own upstream. Full disk only, and one zoom
+/// level short like band 13.
+pub const DAY_NIGHT_PRODUCT: &str = "day_night";
+/// The IR band blended into the night side of `DAY_NIGHT_PRODUCT`.
+pub const DAY_NIGHT_IR_BAND: u32 = 13;
+
+/// An imager's spectral bands, which SLIDER names "band_01" onwards.
+pub struct Imager {
+    pub bands: u32,
+    /// Bands at 1 km or finer; the rest are 2 km and stop one zoom level
+    /// short of the sector's deepest
+    pub fine_bands: &'static [u32],
+    /// Has NOAA's L2 sea surface temperature, which SLIDER carries for the ABI
+    pub sst: bool,
+}
+
+/// SLIDER's RGB composites of the ABI, AHI and AMI bands, by product id
+/// and name. All mix in 2 km bands, so they stop one zoom level short like
+/// those bands do.
+pub const COMPOSITES: &[(&str, &str)] = &[
+    ("airmass", "Air Mass"),
+    ("dust", "Dust"),
+    ("day_cloud_phase_distinction", "Day Cloud Phase"),
+    ("fire_temperature", "Fire Temperature"),
+    // Fog and low cloud at night, when GeoColor falls back to city lights
+    ("nighttime_microphysics", "Night Microphysics"),
+];
+
+// Bands 7 onward of the ABI, AHI and AMI are infrared, as are the GOES
+// imager's channels 2 onward
+const FIRST_IR_BAND: u32 = 7;
+const GOES_IMAGER_FIRST_IR_BAND: u32 = 2;
+
+const ABI: Imager = Imager { bands: 16, fine_bands: &[1, 2, 3, 5], sst: true };
+const AHI: Imager = Imager { bands: 16, fine_bands: &[1, 2, 3, 4], sst: false };
+const AMI: Imager = Imager { bands: 16, fine_bands: &[1, 2, 3, 4], sst: false };
+
+/// A region SLIDER images separately from the full disk, with its own
+/// tile grid.
+pub struct Sector {
+    /// Value of the `sector` query parameter, as in SLIDER URLs
+    pub id: &'static str,
+    pub max_zoom: u32,
+    pub tile_size: u32,
+    /// Seconds between images
+    pub cadence: u32,
+}
+
+const NO_SECTORS: &[Sector] = &[];
+// The ABI's CONUS/PACUS scan every 5 minutes and the two movable
+// mesoscale boxes every minute
+const GOES_SECTORS: &[Sector] = &[
+    Sector { id: "conus", max_zoom: 3, tile_size: 625, cadence: 300 },
+    Sector { id: "mesoscale_01", max_zoom: 1, tile_size: 500, cadence: 60 },
+    Sector { id: "mesoscale_02", max_zoom: 1, tile_size: 500, cadence: 60 },
+];
+// AHI's Japan area every 2.5 minutes and its target area every 30 seconds
+const HIMAWARI_SECTORS: &[Sector] = &[
+    Sector { id: "japan", max_zoom: 2, tile_size: 750, cadence: 150 },
+    Sector { id: "mesoscale_01", max_zoom: 1, tile_size: 500, cadence: 30 },
+];
+
+const ALL_PRODUCTS: &[&str] = &[];
+// The GOES-13/15 imager's five channels; no true-colour composite
+const GOES_IMAGER_PRODUCTS: &[&str] = &["band_01", "band_02", "band_03", "band_04", "band_06"];
+
+pub const SATELLITES: &[Satellite] = &[
+    Satellite { key: "19", slider_id: "goes-19", name: "GOES-19", region: "East/Atlantic", longitude: -75.2, max_zoom: 4, tile_size: 678, cadence: 600, status: Status::Operational, products: ALL_PRODUCTS, sectors: GOES_SECTORS, imager: Some(&ABI), glm: true, star_images: true },
+    Satellite { key: "18", slider_id: "goes-18", name: "GOES-18", region: "West/Pacific", longitude: -137.0, max_zoom: 4, tile_size: 678, cadence: 600, status: Status::Operational, products: ALL_PRODUCTS, sectors: GOES_SECTORS, imager: Some(&ABI), glm: true, star_images: true },
+    // GOES-East until April 2025, now the on-orbit spare
+    Satellite { key: "16", slider_id: "goes-16", name: "GOES-16", region: "Americas", longitude: -104.7, max_zoom: 4, tile_size: 678, cadence: 600, status: Status::Standby, products: ALL_PRODUCTS, sectors: GOES_SECTORS, imager: Some(&ABI), glm: true, star_images: false },
+    Satellite { key: "himawari", slider_id: "himawari", name: "Himawari", region: "Asia/Pacific", longitude: 140.7, max_zoom: 4, tile_size: 688, cadence: 600, status: Status::Operational, products: ALL_PRODUCTS, sectors: HIMAWARI_SECTORS, imager: Some(&AHI), glm: false, star_images: false },
+    // KMA's AMI imager, alongside Himawari over the western Pacific
+    Satellite { key: "gk2a", slider_id: "gk2a", name: "GK-2A", region: "Asia/Pacific", longitude: 128.2, max_zoom: 4, tile_size: 688, cadence: 600, status: Status::Operational, products: ALL_PRODUCTS, sectors: NO_SECTORS, imager: Some(&AMI), glm: false, star_images: false },
+    // MTG-I1's FCI images at twice the resolution of the SEVIRI Meteosats
+    Satellite { key: "meteosat12", slider_id: "meteosat-12", name: "Meteosat-12", region: "Africa/Europe", longitude: 0.0, max_zoom: 4, tile_size: 696, cadence: 600, status: Status::Operational, products: ALL_PRODUCTS, sectors: NO_SECTORS, imager: None, glm: false, star_images: false },
+    // CMA's AGRI at 105°E; a full disk every 15 minutes rather than 10, which
+    // needs nothing special since frames come from SLIDER's listings
+    Satellite { key: "fy4b", slider_id: "fy4b", name: "FY-4B", region: "China/Indian Ocean", longitude: 105.0, max_zoom: 4, tile_size: 687, cadence: 900, status: Status::Operational, products: ALL_PRODUCTS, sectors: NO_SECTORS, imager: None, glm: false, star_images: false },
+    // Roshydromet's MSU-GS imagers, 76°E and 14.5°W
+    Satellite { key: "elektro2", slider_id: "elektro-l2", name: "Elektro-L N2", region: "Indian Ocean", longitude: 76.0, max_zoom: 3, tile_size: 464, cadence: 1800, status: Status::Operational, products: ALL_PRODUCTS, sectors: NO_SECTORS, imager: None, glm: false, star_images: false },
+    Satellite { key: "elektro3", slider_id: "elektro-l3", name: "Elektro-L N3", region: "Atlantic", longitude: -14.5, max_zoom: 3, tile_size: 464, cadence: 1800, status: Status::Operational, products: ALL_PRODUCTS, sectors: NO_SECTORS, imager: None, glm: false, star_images: false },
+    // Repurposed GOES-13 and GOES-15 filling the Indian Ocean gap for the
+    // US Space Force; G1 was retired once G2 took over at 61.5°E
+    Satellite { key: "ewsg1", slider_id: "ews-g1", name: "EWS-G1", region: "Indian Ocean", longitude: 61.5, max_zoom: 3, tile_size: 678, cadence: 1800, status: Status::Deprecated, products: GOES_IMAGER_PRODUCTS, sectors: NO_SECTORS, imager: None, glm: false, star_images: false },
+    Satellite { key: "ewsg2", slider_id: "ews-g2", name: "EWS-G2", region: "Indian Ocean", longitude: 61.5, max_zoom: 3, tile_size: 678, cadence: 1800, status: Status::Operational, products: GOES_IMAGER_PRODUCTS, sectors: NO_SECTORS, imager: None, glm: false, star_images: false },
+    Satellite { key: "meteosat9", slider_id: "meteosat-9", name: "Meteosat-9", region: "Indian Ocean", longitude: 45.5, max_zoom: 3, tile_size: 464, cadence: 900, status: Status::Deprecated, products: ALL_PRODUCTS, sectors: NO_SECTORS, imager: None, glm: false, star_images: false },
+    Satellite { key: "meteosat10", slider_id: "meteosat-0deg", name: "Meteosat-10", region: "Africa/Europe", longitude: 0.0, max_zoom: 3, tile_size: 464, cadence: 900, status: Status::Deprecated, products: ALL_PRODUCTS, sectors: NO_SECTORS, imager: None, glm: false, star_images: false },
+];
+
+pub fn find(key: &str) -> Option<&'static Satellite> {
+    SATELLITES.iter().find(|s| s.key == key)
+}
+
+/// The product served for `sat` when a request doesn't name one.
+pub fn default_product(sat: &str) -> &'static str {
+    find(sat).and_then(|s| s.products.first().copied()).unwrap_or(DEFAULT_PRODUCT)
+}
+
+/// The band number of a "band_NN" product.
+pub fn band_number(product: &str) -> Option<u32> {
+    product.strip_prefix("band_")?.parse().ok()
+}
+
+/// Whether SLIDER has `product` for `sat`. Satellites outside the registry
+/// are left for upstream to judge.
+pub fn has_product(sat: &str, product: &str) -> bool {
+    let Some(satellite) = find(sat) else {
+        return true;
+    };
+    if !satellite.products.is_empty() {
+        return satellite.products.contains(&product);
+    }
+    if product == LIGHTNING_PRODUCT {
+        return satellite.glm;
+    }
+    let Some(imager) = satellite.imager else {
+        return true;
+    };
+    if product == SST_PRODUCT {
+        return imager.sst;
+    }
+    if product == DAY_NIGHT_PRODUCT {
+        return imager.bands >= DAY_NIGHT_IR_BAND;
+    }
+    match band_number(product) {
+        Some(band) => (1..=imager.bands).contains(&band),
+        None => product == DEFAULT_PRODUCT || COMPOSITES.iter().any(|(id, _)| *id == product),
+    }
+}
+
+/// Whether `product` is one of `sat`'s infrared bands, which SLIDER serves
+/// as grey brightness temperatures, coldest brightest.
+pub fn is_ir_band(sat: &str, product: &str) -> bool {
+    let (Some(satellite), Some(band)) = (find(sat), band_number(product)) else {
+        return false;
+    };
+    if satellite.products == GOES_IMAGER_PRODUCTS {
+        return band >= GOES_IMAGER_FIRST_IR_BAND && satellite.products.contains(&product);
+    }
+    satellite.imager.is_some_and(|imager| (FIRST_IR_BAND..=imager.bands).contains(&band))
+}
+
+/// Every product SLIDER has for `satellite`, the default first. Those
+/// outside an imager's known set list only the default, although
+/// upstream may have more.
+pub fn products(satellite: &Satellite) -> Vec<String> {
+    if !satellite.products.is_empty() {
+        return satellite.products.iter().map(|p| p.to_string()).collect();
+    }
+    let mut products = vec![DEFAULT_PRODUCT.to_string()];
+    if let Some(imager) = satellite.imager {
+        products.extend(COMPOSITES.iter().map(|(id, _)| id.to_string()));
+        if imager.bands >= DAY_NIGHT_IR_BAND {
+            products.push(DAY_NIGHT_PRODUCT.to_string());
+        }
+        if imager.sst {
+            products.push(SST_PRODUCT.to_string());
+        }
+        products.extend((1..=imager.bands).map(|band| format!("band_{:02}", band)));
+    }
+    if satellite.glm {
+        products.push(LIGHTNING_PRODUCT.to_string());
+    }
+    products
+}
+
+/// A product's name for people, e.g. "Band 13" or "Air Mass".
+pub fn product_name(product: &str) -> String {
+    if let Some(band) = band_number(product) {
+        return format!("Band {}", band);
+    }
+    match product {
+        DEFAULT_PRODUCT => "GeoColor".to_string(),
+        SST_PRODUCT => "Sea Surface Temperature".to_string(),
+        LIGHTNING_PRODUCT => "Lightning".to_string(),
+        DAY_NIGHT_PRODUCT => "Day/Night Blend".to_string(),
+        _ => COMPOSITES.iter().find(|(id, _)| *id == product).map_or(product, |(_, name)| name).to_string(),
+    }
+}
+
+/// The product whose frame lists stand for `product`'s: GeoColor's for
+/// the day/night blend, which SLIDER doesn't list, otherwise its own.
+pub fn listed_product(product: &str) -> &str {
+    if product == DAY_NIGHT_PRODUCT {
+        DEFAULT_PRODUCT
+    } else {
+        product
+    }
+}
+
+/// Zoom levels `product` of `sat` has fewer than the sector's grid: one
+/// for the imager's 2 km bands, the composites, lightning, SST and the
+/// day/night blend, none otherwise.
+pub fn product_zoom_reduction(sat: &str, product: &str) -> u32 {
+    if product == LIGHTNING_PRODUCT || product == SST_PRODUCT || product == DAY_NIGHT_PRODUCT {
+        return 1;
+    }
+    let Some(imager) = find(sat).and_then(|s| s.imager) else {
+        return 0;
+    };
+    match band_number(product) {
+        Some(band) => !imager.fine_bands.contains(&band) as u32,
+        None => COMPOSITES.iter().any(|(id, _)| *id == product) as u32,
+    }
+}
+
+/// Deepest zoom level and tile size of `sector` of `sat`; `None` if
+/// SLIDER doesn't image that sector. Satellites outside the registry are
+/// left for upstream to judge, with GOES full-disk tiles.
+pub fn sector_grid(sat: &str, sector: &str) -> Option<(u32, u32)> {
+    let Some(satellite) = find(sat) else {
+        return Some((4, 678));
+    };
+    if sector == DEFAULT_SECTOR {
+        return Some((satellite.max_zoom, satellite.tile_size));
+    }
+    satellite.sectors.iter().find(|s| s.id == sector).map(|s| (s.max_zoom, s.tile_size))
+}
+
+/// Seconds between images of `sector` of `sat`, GOES's ten minutes for
+/// satellites outside the registry.
+pub fn sector_cadence(sat: &str, sector: &str) -> Option<u32> {
+    let Some(satellite) = find(sat) else {
+        return Some(600);
+    };
+    if sector == DEFAULT_SECTOR {
+        return Some(satellite.cadence);
+    }
+    satellite.sectors.iter().find(|s| s.id == sector).map(|s| s.cadence)
+}
+
+/// Tag `response` with the status of `sat` unless it's operational.
+pub fn mark_status(sat: &str, mut response: Response) -> Response {
+    if let Some(s) = find(sat).filter(|s| s.status != Status::Operational) {
+        debug!(sat = s.name, status = s.status.as_str(), "Satellite is not operational");
+        response.headers_mut().insert(STATUS_HEADER, HeaderValue::from_static(s.status.as_str()));
+    }
+    response
+}
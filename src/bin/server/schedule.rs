@@ -0,0 +1,288 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use serde::Serialize;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+use crate::config::{ScheduledJob, CONFIG};
+use crate::dates::{iso, now, timestamp};
+use crate::events::announced_satellites;
+use crate::frames::{day, parse_step, Layer};
+use crate::reproject::frame_timestamp;
+use crate::{cache, prefetch, timelapse, Params};
+
+const DEFAULT_KEEP_DAYS: u32 = 7;
+
+/// A scheduled job as the admin API reports it.
+#[derive(Clone, Serialize)]
+pub struct Status {
+    pub name: String,
+    pub task: String,
+    /// "every 5m" or "daily at 00:30 UTC"
+    pub schedule: String,
+    pub running: bool,
+    pub next_run: Option<String>,
+    pub last_run: Option<String>,
+    pub last_duration_ms: Option<u64>,
+    /// What the last run did, or why it failed
+    pub last_result: Option<String>,
+    pub last_ok: Option<bool>,
+    pub runs: u64,
+}
+
+struct Entry {
+    status: Status,
+    trigger: Arc<Notify>,
+}
+
+lazy_static::lazy_static! {
+    static ref JOBS: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+}
+
+#[derive(Clone, Copy)]
+enum When {
+    /// Seconds between runs, the first at startup
+    Every(i64),
+    /// Seconds after midnight UTC
+    Daily(i64),
+}
+
+impl When {
+    fn of(job: &ScheduledJob) -> Option<When> {
+        match (&job.every, &job.at) {
+            (Some(every), None) => parse_step(every).map(When::Every),
+            (None, Some(at)) => parse_at(at).map(When::Daily),
+            _ => None,
+        }
+    }
+
+    // When the next run after one at `time` is due
+    fn after(self, time: i64) -> i64 {
+        match self {
+            When::Every(seconds) => time + seconds,
+            When::Daily(offset) => {
+                let today = time - time.rem_euclid(86400) + offset;
+                if today > time { today } else { today + 86400 }
+            }
+        }
+    }
+
+    fn describe(self, job: &ScheduledJob) -> String {
+        match self {
+            When::Every(_) => format!("every {}", job.every.as_deref().unwrap_or_default()),
+            When::Daily(_) => format!("daily at {} UTC", job.at.as_deref().unwrap_or_default()),
+        }
+    }
+}
+
+/// Seconds after midnight of a "HH:MM" time of day.
+pub fn parse_at(text: &str) -> Option<i64> {
+    let (hours, minutes) = text.split_once(':')?;
+    let (hours, minutes) = (hours.parse::<i64>().ok()?, minutes.parse::<i64>().ok()?);
+    ((0..24).contains(&hours) && (0..60).contains(&minutes)).then_some(hours * 3600 + minutes * 60)
+}
+
+// The configured jobs, with `prefetch_satellites` as a built-in "prefetch"
+// job unless one of them has that name
+fn configured() -> Vec<ScheduledJob> {
+    let mut jobs = CONFIG.schedule.clone();
+    if !CONFIG.prefetch_satellites.is_empty() && !CONFIG.offline && !jobs.iter().any(|job| job.name == "prefetch") {
+        jobs.push(ScheduledJob {
+            name: "prefetch".to_string(),
+            task: "prefetch".to_string(),
+            every: Some(format!("{}s", CONFIG.prefetch_interval.max(1))),
+            satellites: CONFIG.prefetch_satellites.clone(),
+            zoom: Some(CONFIG.prefetch_max_zoom),
+            ..ScheduledJob::default()
+        });
+    }
+    jobs
+}
+
+fn update(index: usize, change: impl FnOnce(&mut Status)) {
+    if let Some(entry) = JOBS.lock().unwrap().get_mut(index) {
+        change(&mut entry.status);
+    }
+}
+
+// Look up the newest frame of each satellite, which refreshes the frame
+// lists everything else is served from
+async fn poll(sats: &[String]) -> Result<String, String> {
+    let mut found = Vec::new();
+    let mut failed = Vec::new();
+    for sat in sats {
+        let params = Params::from([("sat".to_string(), sat.clone())]);
+        let Some(layer) = Layer::from_params(&params) else {
+            failed.push(format!("{}: unknown", sat));
+            continue;
+        };
+        match frame_timestamp(&layer, &params, None).await {
+            Ok(timestamp) => found.push(format!("{} {}", sat, timestamp)),
+            Err(response) => failed.push(format!("{}: {}", sat, response.status())),
+        }
+    }
+    if failed.is_empty() { Ok(found.join(", ")) } else { Err(failed.join(", ")) }
+}
+
+async fn prefetch(job: &ScheduledJob, sats: &[String]) -> Result<String, String> {
+    if CONFIG.offline {
+        return Err("offline".to_string());
+    }
+    let zoom = job.zoom.unwrap_or(CONFIG.prefetch_max_zoom);
+    let mut fetched = Vec::new();
+    let mut failed = Vec::new();
+    for sat in sats {
+        match prefetch::prefetch_latest(sat, zoom).await {
+            Some((timestamp, tiles)) => fetched.push(format!("{} {}: {} tiles", sat, timestamp, tiles)),
+            None => failed.push(format!("{}: no frame list", sat)),
+        }
+    }
+    if failed.is_empty() { Ok(fetched.join(", ")) } else { Err(failed.join(", ")) }
+}
+
+// The previous UTC day's timelapse of each satellite
+async fn daily_timelapse(job: &ScheduledJob, sats: &[String]) -> Result<String, String> {
+    let date = day(now() - 86400);
+    let mut made = Vec::new();
+    let mut failed = Vec::new();
+    for sat in sats {
+        let mut params = Params::from([("sat".to_string(), sat.clone())]);
+        let settings = [
+            ("product", job.product.clone()),
+            ("format", job.format.clone()),
+            ("size", job.size.map(|s| s.to_string())),
+            ("fps", job.fps.map(|f| f.to_string())),
+            ("step", job.step.clone()),
+        ];
+        params.extend(settings.into_iter().filter_map(|(name, value)| Some((name.to_string(), value?))));
+        match timelapse::make_daily(&params, date).await {
+            Ok(frames) => made.push(format!("{} {}: {} frames", sat, date, frames)),
+            Err(message) => failed.push(format!("{} {}: {}", sat, date, message)),
+        }
+    }
+    if failed.is_empty() { Ok(made.join(", ")) } else { Err(failed.join(", ")) }
+}
+
+// Drop tiles and daily timelapses older than `keep_days`, then let the
+// eviction policy expire what it would
+async fn prune(job: &ScheduledJob) -> Result<String, String> {
+    let cutoff = now() - job.keep_days.unwrap_or(DEFAULT_KEEP_DAYS) as i64 * 86400;
+    let sats = job.satellites.clone();
+    // Deleting thousands of files shouldn't stall a runtime worker
+    let pruned = tokio::task::spawn_blocking(move || {
+        let before = timestamp(cutoff);
+        let (mut tiles, mut bytes) = (0, 0);
+        if sats.is_empty() {
+            (tiles, bytes) = cache::purge_before(None, &before);
+        }
+        for sat in &sats {
+            let (t, b) = cache::purge_before(Some(sat), &before);
+            tiles += t;
+            bytes += b;
+        }
+        let timelapses = timelapse::prune_daily(day(cutoff));
+        cache::sweep();
+        (tiles, bytes, timelapses)
+    });
+    let (tiles, bytes, timelapses) = pruned.await.map_err(|e| e.to_string())?;
+    Ok(format!("{} tiles ({} bytes) and {} timelapses before {}", tiles, bytes, timelapses, iso(cutoff)))
+}
+
+async fn run(job: &ScheduledJob) -> Result<String, String> {
+    match job.task.as_str() {
+        "poll" => poll(&announced_satellites(&job.satellites)).await,
+        "prefetch" => prefetch(job, &announced_satellites(&job.satellites)).await,
+        "timelapse" => daily_timelapse(job, &announced_satellites(&job.satellites)).await,
+        "prune" => prune(job).await,
+        task => Err(format!("unknown task {}", task)),
+    }
+}
+
+/// Run each job of `[[schedule]]`, and `prefetch_satellites` as the
+/// built-in "prefetch" job, on its schedule: jobs with `every` first at
+/// startup, jobs with `at` at the next such time. Each runs one at a time,
+/// and `trigger` runs one now.
+pub fn spawn_scheduler() {
+    for job in configured() {
+        let Some(when) = When::of(&job) else {
+            continue;
+        };
+        let trigger = Arc::new(Notify::new());
+        let index = {
+            let mut jobs = JOBS.lock().unwrap();
+            jobs.push(Entry {
+                status: Status {
+                    name: job.name.clone(),
+                    task: job.task.clone(),
+                    schedule: when.describe(&job),
+                    running: false,
+                    next_run: None,
+                    last_run: None,
+                    last_duration_ms: None,
+                    last_result: None,
+                    last_ok: None,
+                    runs: 0,
+                },
+                trigger: trigger.clone(),
+            });
+            jobs.len() - 1
+        };
+        info!(name = job.name, task = job.task, schedule = when.describe(&job), "Scheduled job");
+        tokio::spawn(async move {
+            let mut next = match when {
+                When::Every(_) => now(),
+                When::Daily(_) => when.after(now()),
+            };
+            loop {
+                update(index, |status| status.next_run = Some(iso(next)));
+                let wait = Duration::from_secs((next - now()).max(0) as u64);
+                tokio::select! {
+                    _ = tokio::time::sleep(wait) => {}
+                    _ = trigger.notified() => {}
+                }
+                let started_at = now();
+                update(index, |status| {
+                    status.running = true;
+                    status.next_run = None;
+                    status.last_run = Some(iso(started_at));
+                });
+                let started = Instant::now();
+                let result = run(&job).await;
+                let elapsed = started.elapsed();
+                match &result {
+                    Ok(summary) => info!(name = job.name, elapsed_ms = elapsed.as_millis() as u64, summary, "Scheduled job done"),
+                    Err(message) => warn!(name = job.name, elapsed_ms = elapsed.as_millis() as u64, error = message, "Scheduled job failed"),
+                }
+                update(index, |status| {
+                    status.running = false;
+                    status.last_duration_ms = Some(elapsed.as_millis() as u64);
+                    status.last_ok = Some(result.is_ok());
+                    status.last_result = Some(result.unwrap_or_else(|message| message));
+                    status.runs += 1;
+                });
+                next = when.after(now());
+            }
+        });
+    }
+}
+
+/// Every scheduled job, in the order they were configured.
+pub fn jobs() -> Vec<Status> {
+    JOBS.lock().unwrap().iter().map(|entry| entry.status.clone()).collect()
+}
+
+/// Why a job couldn't be triggered.
+pub enum TriggerError {
+    NoSuchJob,
+    Running,
+}
+
+/// Run the job `name` now rather than when it's next due.
+pub fn trigger(name: &str) -> Result<(), TriggerError> {
+    let jobs = JOBS.lock().unwrap();
+    let entry = jobs.iter().find(|entry| entry.status.name == name).ok_or(TriggerError::NoSuchJob)?;
+    if entry.status.running {
+        return Err(TriggerError::Running);
+    }
+    entry.trigger.notify_one();
+    Ok(())
+}
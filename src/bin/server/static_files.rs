@@ -0,0 +1,227 @@
+use std::io::SeekFrom;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use axum::body::{Body, Bytes};
+use axum::extract::OriginalUri;
+use axum::http::{header, HeaderMap, Method, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use rust_embed::RustEmbed;
+use tokio_util::io::ReaderStream;
+use tracing::warn;
+use crate::compression;
+use crate::config::CONFIG;
+
+// The frontend ships inside the binary so the server runs from any
+// directory; `--static-dir` serves from disk instead during development.
+const INDEX_HTML: &[u8] = include_bytes!("../../../index.html");
+
+/// wasm-pack output (JS glue and the .wasm blob), if it was built.
+#[derive(RustEmbed)]
+#[folder = "$CARGO_MANIFEST_DIR/pkg"]
+#[prefix = "pkg/"]
+#[allow_missing = true]
+struct WasmPkg;
+
+lazy_static::lazy_static! {
+    static ref STATIC_ROOT: Option<PathBuf> = CONFIG.static_dir.as_ref().map(|dir| {
+        dir.canonicalize().unwrap_or_else(|e| {
+            eprintln!("Static directory {:?} is not usable: {}", dir, e);
+            std::process::exit(1);
+        })
+    });
+}
+
+enum Asset {
+    Embedded(Bytes),
+    File(tokio::fs::File),
+}
+
+fn find_embedded(path: &str) -> Option<(Bytes, SystemTime)> {
+    if path == "index.html" {
+        return Some((Bytes::from_static(INDEX_HTML), SystemTime::UNIX_EPOCH));
+    }
+    let file = WasmPkg::get(path)?;
+    let modified = file
+        .metadata
+        .last_modified()
+        .map_or(SystemTime::UNIX_EPOCH, |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs));
+    Some((Bytes::from(file.data.into_owned()), modified))
+}
+
+/// Open `path` under `root`, refusing anything that would escape it
+/// (`..` components, absolute paths, or symlinks pointing outside).
+async fn open_in_root(root: &Path, path: &str) -> Option<(tokio::fs::File, PathBuf)> {
+    if Path::new(path).components().any(|c| !matches!(c, Component::Normal(_))) {
+        warn!(path, "Rejected static path outside the static root");
+        return None;
+    }
+    let full_path = tokio::fs::canonicalize(root.join(path)).await.ok()?;
+    if !full_path.starts_with(root) {
+        warn!(path, "Rejected static path outside the static root");
+        return None;
+    }
+    let file = tokio::fs::File::open(&full_path).await.ok()?;
+    Some((file, full_path))
+}
+
+/// Parse a single `bytes=` range against a file of `len` bytes into an
+/// inclusive (start, end) pair. `None` means "serve the whole file" (no or
+/// multi-range header); `Some(Err(()))` means the range can't be satisfied.
+fn parse_range(headers: &HeaderMap, len: u64) -> Option<Result<(u64, u64), ()>> {
+    let spec = headers.get(header::RANGE)?.to_str().ok()?.strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let range = match (start.trim(), end.trim()) {
+        // Suffix range: last N bytes
+        ("", suffix) => {
+            let n: u64 = suffix.parse().ok()?;
+            if n == 0 || len == 0 {
+                return Some(Err(()));
+            }
+            (len.saturating_sub(n), len - 1)
+        }
+        (start, "") => (start.parse().ok()?, len.saturating_sub(1)),
+        (start, end) => {
+            let start: u64 = start.parse().ok()?;
+            let end: u64 = end.parse().ok()?;
+            (start, end.min(len.saturating_sub(1)))
+        }
+    };
+    if range.0 >= len || range.0 > range.1 {
+        return Some(Err(()));
+    }
+    Some(Ok(range))
+}
+
+/// Content-Type for a static file, with an explicit charset on text types
+/// so browsers don't have to sniff.
+fn content_type(path: &str) -> String {
+    let mime = match Path::new(path).extension().and_then(|e| e.to_str()) {
+        // Source maps aren't in mime_guess's table
+        Some("map") => mime_guess::mime::APPLICATION_JSON,
+        _ => mime_guess::from_path(path).first_or_octet_stream(),
+    };
+    let textual = mime.type_() == mime_guess::mime::TEXT
+        || matches!(mime.subtype().as_str(), "javascript" | "json" | "xml")
+        || mime.suffix().is_some_and(|s| s == mime_guess::mime::XML || s == mime_guess::mime::JSON);
+    if textual && mime.get_param(mime_guess::mime::CHARSET).is_none() {
+        format!("{}; charset=utf-8", mime)
+    } else {
+        mime.to_string()
+    }
+}
+
+pub async fn handle_static(
+    method: Method,
+    uri: Uri,
+    OriginalUri(original_uri): OriginalUri,
+    headers: HeaderMap,
+) -> Response {
+    if method != Method::GET && method != Method::HEAD {
+        return (StatusCode::METHOD_NOT_ALLOWED, [(header::ALLOW, "GET, HEAD")]).into_response();
+    }
+
+    // Under a base path "/peepsat" arrives here as "/", but the frontend's
+    // relative URLs only resolve against "/peepsat/"
+    if uri.path() == "/" && !original_uri.path().ends_with('/') {
+        let location = match original_uri.query() {
+            Some(query) => format!("{}/?{}", original_uri.path(), query),
+            None => format!("{}/", original_uri.path()),
+        };
+        return Redirect::permanent(&location).into_response();
+    }
+
+    let path = match uri.path() {
+        "/" => "index.html",
+        p => &p[1..],
+    };
+
+    let content_type = content_type(path);
+    let content_type = content_type.as_str();
+
+    let not_found = || (StatusCode::NOT_FOUND, "404 Not Found").into_response();
+    let (asset, len, modified, cache_path) = match &*STATIC_ROOT {
+        Some(root) => {
+            let Some((file, full_path)) = open_in_root(root, path).await else {
+                return not_found();
+            };
+            match file.metadata().await {
+                Ok(meta) if meta.is_file() => {
+                    let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                    (Asset::File(file), meta.len(), modified, full_path)
+                }
+                _ => return not_found(),
+            }
+        }
+        None => {
+            let Some((data, modified)) = find_embedded(path) else {
+                return not_found();
+            };
+            let len = data.len() as u64;
+            (Asset::Embedded(data), len, modified, Path::new("embedded").join(path))
+        }
+    };
+
+    // Whole-file requests for text assets get a cached compressed copy;
+    // ranges always address the identity encoding
+    if !headers.contains_key(header::RANGE) && compression::is_compressible(content_type) {
+        if let Some(encoding) = compression::negotiate(&headers) {
+            let embedded = match &asset {
+                Asset::Embedded(data) => Some(data.clone()),
+                Asset::File(_) => None,
+            };
+            if let Some(data) = compression::precompressed(&cache_path, modified, encoding, embedded).await {
+                let response = [
+                    (header::CONTENT_TYPE, content_type.to_string()),
+                    (header::CONTENT_ENCODING, encoding.header_value().to_string()),
+                    (header::VARY, "Accept-Encoding".to_string()),
+                    (header::CONTENT_LENGTH, data.len().to_string()),
+                ];
+                return if method == Method::HEAD {
+                    response.into_response()
+                } else {
+                    (response, data).into_response()
+                };
+            }
+        }
+    }
+
+    let (status, start, end) = match parse_range(&headers, len) {
+        None => (StatusCode::OK, 0, len.saturating_sub(1)),
+        Some(Ok((start, end))) => (StatusCode::PARTIAL_CONTENT, start, end),
+        Some(Err(())) => {
+            return (
+                StatusCode::RANGE_NOT_SATISFIABLE,
+                [(header::CONTENT_RANGE, format!("bytes */{}", len))],
+            )
+                .into_response();
+        }
+    };
+    let body_len = if len == 0 { 0 } else { end - start + 1 };
+
+    let mut response = Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .header(header::VARY, "Accept-Encoding")
+        .header(header::CONTENT_LENGTH, body_len);
+    if status == StatusCode::PARTIAL_CONTENT {
+        response = response.header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, len));
+    }
+
+    // HEAD gets the same headers without opening a body stream
+    let body = match asset {
+        _ if method == Method::HEAD => Body::empty(),
+        Asset::Embedded(data) => Body::from(data.slice(start as usize..(start + body_len) as usize)),
+        Asset::File(mut file) => {
+            if start > 0 && file.seek(SeekFrom::Start(start)).await.is_err() {
+                return (StatusCode::INTERNAL_SERVER_ERROR, "Seek failed").into_response();
+            }
+            Body::from_stream(ReaderStream::new(file.take(body_len)))
+        }
+    };
+    response.body(body).unwrap()
+}
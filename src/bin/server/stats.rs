@@ -0,0 +1,267 @@
+use std::path::PathBuf;
+use axum::extract::Query;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::future::join_all;
+use image::RgbImage;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tracing::{debug, info, Span};
+use crate::cache::{cache_key, is_valid_key, CACHE_DIR, DEFAULT_SECTOR};
+use crate::config::CONFIG;
+use crate::dates::{iso, parse_time};
+use crate::frames::{day, frames_between, parse_step, thin, time_bounds, Layer};
+use crate::fulldisk::{assemble, disk_tiles};
+use crate::palette::brightness_temperature;
+use crate::prefetch::days_between;
+use crate::reproject::frame_timestamp;
+use crate::{bad_gateway, geos, offline, satellites, Params};
+
+// Stats are taken from the zoom 0 tiles: a whole disk is a few hundred
+// thousand pixels, plenty for a histogram
+const ZOOM: u32 = 0;
+const LEVELS: usize = 256;
+const DEFAULT_BINS: usize = 64;
+// The most frames in one series
+const MAX_FRAMES: usize = 144;
+// IR window bands, where cloud tops are colder than the ground under them
+const WINDOW_BANDS: std::ops::RangeInclusive<u32> = 13..=15;
+// Colder than this in a window band counts as cloud
+const CLOUD_TEMPERATURE: f64 = -20.0;
+// Brighter than this, and greyer than CLOUD_SATURATION, counts as cloud
+// in colour and visible images
+const CLOUD_LUMINANCE: f64 = 120.0;
+const CLOUD_SATURATION: f64 = 0.25;
+
+// One frame's numbers as cached, kept at full resolution so any `bins`
+// can be made from them
+#[derive(Serialize, Deserialize)]
+struct FrameStats {
+    pixels: u64,
+    mean: f64,
+    histogram: Vec<u64>,
+    cloud_fraction: Option<f64>,
+}
+
+// How a pixel is judged to be cloud, if the product allows it at all
+#[derive(Clone, Copy)]
+enum CloudTest {
+    Cold,
+    BrightAndGrey,
+}
+
+fn cloud_test(layer: &Layer) -> Option<CloudTest> {
+    if !satellites::is_ir_band(&layer.sat, &layer.product) {
+        return Some(CloudTest::BrightAndGrey);
+    }
+    let band = satellites::band_number(&layer.product)?;
+    WINDOW_BANDS.contains(&band).then_some(CloudTest::Cold)
+}
+
+// Stats are cached as "stats/{sat}_{sector}_{product}_{timestamp}.json" in
+// the cache directory; `None` for names that can't safely be a path
+fn stats_path(layer: &Layer, timestamp: &str) -> Option<PathBuf> {
+    if !is_valid_key(&cache_key(&layer.sat, &layer.sector, &layer.product, timestamp, ZOOM, 0, 0)) {
+        return None;
+    }
+    let name = format!("{}_{}_{}_{}.json", layer.sat, layer.sector, layer.product, timestamp);
+    Some(CACHE_DIR.join("stats").join(name))
+}
+
+// The numbers for `image`, counting only pixels on the Earth: within the
+// limb (`limb` as from geos::limb) for a full disk, everywhere otherwise
+fn measure(image: &RgbImage, limb: Option<(f64, f64)>, test: Option<CloudTest>) -> FrameStats {
+    let half = image.width() as f64 / 2.0;
+    let mut histogram = vec![0u64; LEVELS];
+    let (mut pixels, mut sum, mut cloud) = (0u64, 0.0f64, 0u64);
+    for (x, y, pixel) in image.enumerate_pixels() {
+        if let Some((reach_x, reach_y)) = limb {
+            let (dx, dy) = ((x as f64 + 0.5 - half) / (reach_x * half), (y as f64 + 0.5 - half) / (reach_y * half));
+            if dx.hypot(dy) > 1.0 {
+                continue;
+            }
+        }
+        let [r, g, b] = pixel.0.map(f64::from);
+        // Rec. 709 luma
+        let luminance = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+        histogram[(luminance.round() as usize).min(LEVELS - 1)] += 1;
+        sum += luminance;
+        pixels += 1;
+        let cloudy = match test {
+            Some(CloudTest::Cold) => brightness_temperature(luminance.round() as u8) < CLOUD_TEMPERATURE,
+            Some(CloudTest::BrightAndGrey) => {
+                let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+                luminance > CLOUD_LUMINANCE && (max - min) / max < CLOUD_SATURATION
+            }
+            None => false,
+        };
+        cloud += cloudy as u64;
+    }
+    let fraction = |count: u64| if pixels == 0 { 0.0 } else { count as f64 / pixels as f64 };
+    FrameStats {
+        pixels,
+        mean: if pixels == 0 { 0.0 } else { sum / pixels as f64 / 255.0 },
+        histogram,
+        cloud_fraction: test.map(|_| fraction(cloud)),
+    }
+}
+
+// A frame's stats from the cache, or measured from its tiles and cached
+// if none were missing, and whether they were complete. `None` if it has
+// no tiles to be had.
+async fn frame_stats(layer: &Layer, timestamp: &str) -> Option<(FrameStats, bool)> {
+    let path = stats_path(layer, timestamp);
+    if let Some(path) = &path {
+        if let Ok(text) = tokio::fs::read(path).await {
+            if let Ok(stats) = serde_json::from_slice(&text) {
+                return Some((stats, true));
+            }
+        }
+    }
+    let tile_size = layer.tile_grid().1;
+    let tiles = disk_tiles(layer, timestamp, ZOOM).await;
+    let missing = tiles.iter().filter(|t| t.is_none()).count();
+    if missing == tiles.len() {
+        return None;
+    }
+    let limb = (layer.sector == DEFAULT_SECTOR).then(|| satellites::find(&layer.sat).map(geos::limb)).flatten();
+    let test = cloud_test(layer);
+    let stats = tokio::task::spawn_blocking(move || measure(&assemble(&tiles, tile_size << ZOOM, tile_size), limb, test)).await.ok()?;
+    if let (Some(path), 0) = (path, missing) {
+        let saved = match (path.parent(), serde_json::to_vec(&stats)) {
+            (Some(dir), Ok(body)) => tokio::fs::create_dir_all(dir).await.is_ok() && tokio::fs::write(&path, body).await.is_ok(),
+            _ => false,
+        };
+        if !saved {
+            debug!(?path, "Couldn't cache frame stats");
+        }
+    }
+    Some((stats, missing == 0))
+}
+
+// The level below which `share` of the pixels fall, from 0 to 1
+fn percentile(histogram: &[u64], pixels: u64, share: f64) -> f64 {
+    let target = (pixels as f64 * share).ceil() as u64;
+    let mut seen = 0;
+    for (level, count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen >= target.max(1) {
+            return level as f64 / (LEVELS - 1) as f64;
+        }
+    }
+    1.0
+}
+
+fn round(value: f64) -> f64 {
+    (value * 10000.0).round() / 10000.0
+}
+
+// One frame's entry in a response, its histogram in `bins` bins
+fn describe(timestamp: &str, stats: &FrameStats, bins: usize) -> Value {
+    let histogram: Vec<u64> = stats.histogram.chunks(LEVELS / bins).map(|chunk| chunk.iter().sum()).collect();
+    json!({
+        "timestamp": timestamp.parse::<u64>().ok(),
+        "time": parse_time(timestamp).map(iso),
+        "pixels": stats.pixels,
+        "mean": round(stats.mean),
+        "p2": round(percentile(&stats.histogram, stats.pixels, 0.02)),
+        "median": round(percentile(&stats.histogram, stats.pixels, 0.5)),
+        "p98": round(percentile(&stats.histogram, stats.pixels, 0.98)),
+        "cloud_fraction": stats.cloud_fraction.map(round),
+        "histogram": histogram,
+    })
+}
+
+/// `GET /api/stats?sat=19&t=20241016120000`: a frame's (the newest
+/// without `t`) luminance histogram in `bins` bins (64 by default; a power
+/// of two up to 256), its mean, 2nd and 98th percentile and median
+/// brightness from 0 to 1, and a rough cloud fraction, counting only the
+/// Earth in a full disk. With `from` and `to` (and optionally `step`) as
+/// for `/api/frames/range` instead of `t`, it's up to 144 frames, oldest
+/// first, leaving out any with no tiles to be had. Made from the zoom 0
+/// tiles; takes the same `sector`, `product` and `cdn` as `/slider-tile`.
+/// Complete frames' stats are cached.
+pub async fn handle_stats(Query(params): Query<Params>) -> Response {
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let bins = match params.get("bins").map(|b| b.parse::<usize>()) {
+        None => DEFAULT_BINS,
+        Some(Ok(bins)) if bins.is_power_of_two() && bins <= LEVELS => bins,
+        Some(_) => return (StatusCode::BAD_REQUEST, format!("bins must be a power of two up to {}", LEVELS)).into_response(),
+    };
+    let time = match params.get("t") {
+        Some(t) => match parse_time(t) {
+            Some(time) => Some(time),
+            None => return (StatusCode::BAD_REQUEST, "t must be a frame timestamp, e.g. 20241016120000").into_response(),
+        },
+        None => None,
+    };
+    Span::current().record("sat", layer.sat.as_str());
+
+    let timestamps = match (params.get("from"), params.get("to")) {
+        (None, None) => match frame_timestamp(&layer, &params, time).await {
+            Ok(timestamp) => vec![timestamp],
+            Err(response) => return response,
+        },
+        (Some(from), Some(to)) => {
+            let (Some((from, _)), Some((_, to))) = (time_bounds(from), time_bounds(to)) else {
+                return (StatusCode::BAD_REQUEST, "from and to must be times, e.g. 2024-09-26T00:00Z").into_response();
+            };
+            if from > to || days_between(day(from), day(to)).is_none() {
+                return (StatusCode::BAD_REQUEST, "from and to must be in order and at most a week apart").into_response();
+            }
+            let mut timestamps = match frames_between(&layer, &params, from, to).await {
+                Ok((timestamps, _)) => timestamps,
+                Err(response) => return response,
+            };
+            if let Some(step) = params.get("step") {
+                let Some(step) = parse_step(step) else {
+                    return (StatusCode::BAD_REQUEST, "Invalid step").into_response();
+                };
+                thin(&mut timestamps, from, step);
+            }
+            if timestamps.is_empty() {
+                return (StatusCode::NOT_FOUND, "No frames in that time range").into_response();
+            }
+            if timestamps.len() > MAX_FRAMES {
+                let message = format!("{} frames in that range, more than {}; narrow it or add a step", timestamps.len(), MAX_FRAMES);
+                return (StatusCode::BAD_REQUEST, message).into_response();
+            }
+            timestamps.iter().map(u64::to_string).collect()
+        }
+        _ => return (StatusCode::BAD_REQUEST, "from and to go together").into_response(),
+    };
+
+    let series = params.contains_key("from");
+    let measured = join_all(timestamps.iter().map(|timestamp| frame_stats(&layer, timestamp))).await;
+    let complete = measured.iter().all(|m| m.as_ref().is_some_and(|(_, complete)| *complete));
+    let frames: Vec<Value> = timestamps
+        .iter()
+        .zip(&measured)
+        .filter_map(|(timestamp, measured)| Some(describe(timestamp, &measured.as_ref()?.0, bins)))
+        .collect();
+    if frames.is_empty() {
+        return if offline::enabled() { offline::unavailable() } else { bad_gateway("No tiles for that frame") };
+    }
+    info!(sat = layer.sat, product = layer.product, frames = frames.len(), "Measured frame stats");
+
+    let body = if series {
+        json!({ "sat": layer.sat, "sector": layer.sector, "product": layer.product, "frames": frames })
+    } else {
+        let mut body = frames.into_iter().next().unwrap_or_default();
+        body["sat"] = json!(layer.sat);
+        body["sector"] = json!(layer.sector);
+        body["product"] = json!(layer.product);
+        body
+    };
+    let cache_control = if complete && time.is_some() && !series {
+        format!("public, max-age={}, immutable", CONFIG.tile_max_age)
+    } else {
+        "no-cache".to_string()
+    };
+    let response = ([(header::CACHE_CONTROL, cache_control)], Json(body)).into_response();
+    let response = if offline::enabled() { offline::mark(response) } else { response };
+    layer.mark(response)
+}
@@ -0,0 +1,224 @@
+use std::io::Read;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use axum::body::Bytes;
+use axum::extract::Query;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tracing::{debug, info, warn};
+use crate::config::CONFIG;
+use crate::upstream::{self, HTTP_CLIENT};
+use crate::{bad_gateway, offline, Params, MAX_TILE_BYTES};
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CurrentStorms {
+    active_storms: Vec<Storm>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Storm {
+    /// e.g. "al052024": basin, number, year
+    id: String,
+    name: String,
+    /// "TD", "TS", "HU", "STS", "PTC", ...
+    classification: String,
+    /// Maximum sustained wind, knots
+    intensity: String,
+    /// Central pressure, mb
+    pressure: String,
+    latitude_numeric: f64,
+    longitude_numeric: f64,
+    movement_dir: Option<f64>,
+    movement_speed: Option<f64>,
+    last_update: String,
+    forecast_track: Option<Product>,
+    track_cone: Option<Product>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Product {
+    kmz_file: String,
+}
+
+lazy_static::lazy_static! {
+    // The GeoJSON for every active storm, and when it was built
+    static ref STORMS: Mutex<Option<(Instant, Value)>> = Mutex::new(None);
+}
+
+// The files in a zip archive (KMZ) with the contents of the first one
+// whose name ends in `suffix`, stored or deflated
+fn unzip(data: &[u8], suffix: &str) -> Option<Vec<u8>> {
+    let u16_at = |at: usize| Some(u16::from_le_bytes(data.get(at..at + 2)?.try_into().ok()?) as usize);
+    let u32_at = |at: usize| Some(u32::from_le_bytes(data.get(at..at + 4)?.try_into().ok()?) as usize);
+    // The end-of-central-directory record, found from the end
+    let end = (0..data.len().saturating_sub(21)).rev().find(|&i| data[i..].starts_with(b"PK\x05\x06"))?;
+    let (count, mut entry) = (u16_at(end + 10)?, u32_at(end + 16)?);
+    for _ in 0..count {
+        if !data.get(entry..)?.starts_with(b"PK\x01\x02") {
+            return None;
+        }
+        let (method, compressed) = (u16_at(entry + 10)?, u32_at(entry + 20)?);
+        let (name_len, extra_len, comment_len) = (u16_at(entry + 28)?, u16_at(entry + 30)?, u16_at(entry + 32)?);
+        let local = u32_at(entry + 42)?;
+        let name = data.get(entry + 46..entry + 46 + name_len)?;
+        entry += 46 + name_len + extra_len + comment_len;
+        if !name.ends_with(suffix.as_bytes()) {
+            continue;
+        }
+        let start = local + 30 + u16_at(local + 26)? + u16_at(local + 28)?;
+        let body = data.get(start..start + compressed)?;
+        return match method {
+            0 => Some(body.to_vec()),
+            8 => {
+                let mut out = Vec::new();
+                flate2::read::DeflateDecoder::new(body).take(MAX_TILE_BYTES as u64).read_to_end(&mut out).ok()?;
+                Some(out)
+            }
+            _ => None,
+        };
+    }
+    None
+}
+
+// Every <coordinates> list in a KML document, as [lon, lat] pairs
+fn kml_coordinates(kml: &str) -> Vec<Vec<[f64; 2]>> {
+    kml.split("<coordinates>")
+        .skip(1)
+        .filter_map(|rest| rest.split("</coordinates>").next())
+        .map(|list| {
+            list.split_whitespace()
+                .filter_map(|point| {
+                    let mut parts = point.split(',').map(|v| v.parse::<f64>());
+                    Some([parts.next()?.ok()?, parts.next()?.ok()?])
+                })
+                .collect()
+        })
+        .filter(|points: &Vec<[f64; 2]>| !points.is_empty())
+        .collect()
+}
+
+// The coordinate lists in one of NHC's KMZ products, if it can be had
+async fn kmz_coordinates(url: &str) -> Option<Vec<Vec<[f64; 2]>>> {
+    debug!(url, "Fetching storm product");
+    let response = upstream::get(&HTTP_CLIENT, &[url.to_string()]).await.ok()?;
+    if !response.status().is_success() {
+        warn!(url, status = response.status().as_u16(), "Storm product unavailable");
+        return None;
+    }
+    let body = upstream::read_limited(response, MAX_TILE_BYTES).await.ok()?;
+    let kml = unzip(&body, ".kml")?;
+    Some(kml_coordinates(&String::from_utf8_lossy(&kml)))
+}
+
+// GeoJSON features for one storm: its position, and its forecast track and
+// cone where NHC has them
+async fn storm_features(storm: &Storm) -> Vec<Value> {
+    let basin = storm.id.get(..2).unwrap_or_default().to_ascii_lowercase();
+    let mut features = vec![json!({
+        "type": "Feature",
+        "geometry": { "type": "Point", "coordinates": [storm.longitude_numeric, storm.latitude_numeric] },
+        "properties": {
+            "kind": "position",
+            "id": storm.id,
+            "basin": basin,
+            "name": storm.name,
+            "classification": storm.classification,
+            "wind_kt": storm.intensity.parse::<f64>().ok(),
+            "pressure_mb": storm.pressure.parse::<f64>().ok(),
+            "movement_dir": storm.movement_dir,
+            "movement_kt": storm.movement_speed,
+            "updated": storm.last_update,
+        },
+    })];
+    if let Some(track) = &storm.forecast_track {
+        if let Some(lists) = kmz_coordinates(&track.kmz_file).await {
+            // The forecast points are single-coordinate placemarks, in order
+            let points: Vec<[f64; 2]> = lists.iter().filter(|l| l.len() == 1).map(|l| l[0]).collect();
+            if points.len() > 1 {
+                features.push(json!({
+                    "type": "Feature",
+                    "geometry": { "type": "LineString", "coordinates": points },
+                    "properties": { "kind": "track", "id": storm.id, "basin": basin },
+                }));
+            }
+        }
+    }
+    if let Some(cone) = &storm.track_cone {
+        if let Some(ring) = kmz_coordinates(&cone.kmz_file).await.and_then(|lists| lists.into_iter().max_by_key(Vec::len)) {
+            if ring.len() > 3 {
+                features.push(json!({
+                    "type": "Feature",
+                    "geometry": { "type": "Polygon", "coordinates": [ring] },
+                    "properties": { "kind": "cone", "id": storm.id, "basin": basin },
+                }));
+            }
+        }
+    }
+    features
+}
+
+/// Every active storm as a GeoJSON FeatureCollection, rebuilt once it's
+/// `storms_refresh` old. If NHC can't be reached, the last one keeps being used.
+pub async fn storms() -> Result<Value, &'static str> {
+    let cached = STORMS.lock().unwrap().clone();
+    if let Some((built, storms)) = &cached {
+        if built.elapsed() < Duration::from_secs(CONFIG.storms_refresh) {
+            return Ok(storms.clone());
+        }
+    }
+    let url = format!("{}/CurrentStorms.json", CONFIG.nhc_url);
+    debug!(url, "Fetching active storms");
+    let fetched = match upstream::get(&HTTP_CLIENT, &[url]).await {
+        Ok(r) if r.status().is_success() => r.bytes().await.map_err(|e| e.to_string()),
+        Ok(r) => Err(format!("status {}", r.status().as_u16())),
+        Err(e) => Err(e.to_string()),
+    };
+    let current = fetched.and_then(|body: Bytes| serde_json::from_slice::<CurrentStorms>(&body).map_err(|e| e.to_string()));
+    let current = match current {
+        Ok(current) => current,
+        Err(e) => {
+            warn!(error = %e, "Active storms unavailable");
+            return cached.map(|(_, storms)| storms).ok_or("Active storms unavailable");
+        }
+    };
+    let mut features = Vec::new();
+    for storm in &current.active_storms {
+        features.extend(storm_features(storm).await);
+    }
+    info!(storms = current.active_storms.len(), "Updated active storms");
+    let storms = json!({ "type": "FeatureCollection", "features": features });
+    *STORMS.lock().unwrap() = Some((Instant::now(), storms.clone()));
+    Ok(storms)
+}
+
+/// `GET /api/storms`: NHC's active tropical cyclones as GeoJSON, with
+/// `?basin=al,ep` (or `storm_basins`) picking basins: "al" Atlantic, "ep"
+/// eastern and "cp" central Pacific.
+pub async fn handle_storms(Query(params): Query<Params>) -> Response {
+    if offline::enabled() {
+        return offline::unavailable();
+    }
+    let mut storms = match storms().await {
+        Ok(storms) => storms,
+        Err(message) => return bad_gateway(message),
+    };
+    let basins: Vec<String> = match params.get("basin") {
+        Some(list) => list.split(',').map(|b| b.trim().to_ascii_lowercase()).filter(|b| !b.is_empty()).collect(),
+        None => CONFIG.storm_basins.clone(),
+    };
+    if !basins.is_empty() {
+        if let Some(features) = storms["features"].as_array_mut() {
+            features.retain(|f| f["properties"]["basin"].as_str().is_some_and(|b| basins.iter().any(|basin| basin == b)));
+        }
+    }
+    (
+        [(header::CONTENT_TYPE, "application/geo+json"), (header::CACHE_CONTROL, "no-cache")],
+        storms.to_string(),
+    )
+        .into_response()
+}
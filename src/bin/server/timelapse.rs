@@ -0,0 +1,563 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use axum::body::{to_bytes, Body, Bytes};
+use axum::extract::{Path, Query};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use futures_util::stream;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::imageops::{self, FilterType};
+use image::{Delay, DynamicImage, Frame, RgbImage};
+use serde::Serialize;
+use serde_json::json;
+use tokio::sync::{mpsc, Semaphore};
+use tracing::{info, warn, Span};
+use crate::cache::{cache_key, is_valid_key, CACHE_DIR};
+use crate::config::CONFIG;
+use crate::dates::iso;
+use crate::frames::{day, frames_between, parse_step, thin, time_bounds, Layer};
+use crate::fulldisk::{assemble, disk_tiles, MISSING_HEADER};
+use crate::prefetch::{days_between, JobState};
+use crate::{offline, Params};
+
+// Frames in one timelapse, a day of 10-minute scans and then some
+const MAX_FRAMES: usize = 240;
+const DEFAULT_SIZE: u32 = 512;
+const MAX_SIZE: u32 = 1024;
+const DEFAULT_FPS: u32 = 10;
+const MAX_FPS: u32 = 30;
+// NeuQuant's sampling for GIF palettes, 1 (best) to 30 (fastest)
+const GIF_SPEED: i32 = 10;
+// Jobs waiting or running at once, and finished ones kept with their
+// video for the status endpoint
+const MAX_JOBS_PENDING: usize = 8;
+const MAX_JOBS_KEPT: usize = 8;
+// Longest error message taken from a failed spec's response
+const MAX_ERROR_BYTES: usize = 4096;
+// A streamed timelapse goes out in chunks of this size, a few at most
+// waiting for the client
+const CHUNK_BYTES: usize = 64 * 1024;
+const MAX_CHUNKS_WAITING: usize = 4;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Gif,
+    Apng,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Gif => "image/gif",
+            Format::Apng => "image/apng",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Gif => "gif",
+            Format::Apng => "png",
+        }
+    }
+}
+
+// How the frames are drawn and encoded
+#[derive(Clone, Copy)]
+struct Settings {
+    format: Format,
+    size: u32,
+    fps: u32,
+    zoom: u32,
+    tile_size: u32,
+}
+
+// A timelapse to make: the layer, its frames oldest first, and how
+struct Spec {
+    layer: Layer,
+    timestamps: Vec<String>,
+    from: i64,
+    to: i64,
+    settings: Settings,
+}
+
+/// A timelapse job as reported by the status endpoint.
+#[derive(Clone, Serialize)]
+pub struct Job {
+    pub id: u64,
+    pub sat: String,
+    pub product: String,
+    pub from: String,
+    pub to: String,
+    pub format: Format,
+    pub size: u32,
+    pub fps: u32,
+    pub state: JobState,
+    pub frames: usize,
+    pub frames_done: usize,
+    /// Tiles that couldn't be had and were left black
+    pub missing_tiles: usize,
+    pub bytes: Option<usize>,
+    /// Where to download the video once it's done
+    pub video: Option<String>,
+    pub error: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref JOBS: Mutex<BTreeMap<u64, Job>> = Mutex::new(BTreeMap::new());
+    static ref VIDEOS: Mutex<BTreeMap<u64, Bytes>> = Mutex::new(BTreeMap::new());
+    // Timelapses take a while and a lot of tiles, so they're made one at a
+    // time, jobs and direct requests alike
+    static ref RENDER_SLOT: Semaphore = Semaphore::new(1);
+}
+
+static NEXT_JOB_ID: AtomicU64 = AtomicU64::new(1);
+
+fn bad_request(message: impl Into<String>) -> Response {
+    (StatusCode::BAD_REQUEST, message.into()).into_response()
+}
+
+// A number parameter within `range`, or `default` if it's absent
+fn bounded(params: &Params, name: &str, default: u32, range: std::ops::RangeInclusive<u32>) -> Result<u32, String> {
+    match params.get(name) {
+        None => Ok(default),
+        Some(text) => match text.parse() {
+            Ok(value) if range.contains(&value) => Ok(value),
+            _ => Err(format!("{} must be from {} to {}", name, range.start(), range.end())),
+        },
+    }
+}
+
+// The timelapse the query parameters ask for, with its frames looked up
+async fn spec(params: &Params) -> Result<Spec, Response> {
+    let Some(layer) = Layer::from_params(params) else {
+        return Err(bad_request("Invalid sector or product"));
+    };
+    let format = match params.get("format").map(String::as_str) {
+        // There's no video encoder, so MP4 falls back to GIF
+        None | Some("gif") | Some("mp4") => Format::Gif,
+        Some("apng") | Some("png") => Format::Apng,
+        Some(_) => return Err(bad_request("format must be gif, apng or mp4")),
+    };
+    let fps = bounded(params, "fps", DEFAULT_FPS, 1..=MAX_FPS).map_err(bad_request)?;
+    let size = bounded(params, "size", DEFAULT_SIZE, 64..=MAX_SIZE).map_err(bad_request)?;
+    // The first zoom whose disk is at least as big, scaled down from there
+    let (max_zoom, tile_size) = layer.tile_grid();
+    let zoom = (0..=max_zoom).find(|&z| tile_size << z >= size).unwrap_or(max_zoom);
+
+    let (Some((from, _)), Some((_, to))) =
+        (params.get("from").and_then(|f| time_bounds(f)), params.get("to").and_then(|t| time_bounds(t)))
+    else {
+        return Err(bad_request("from and to must be times, e.g. 2024-09-26T00:00Z"));
+    };
+    if from > to || days_between(day(from), day(to)).is_none() {
+        return Err(bad_request("from and to must be in order and at most a week apart"));
+    }
+    let mut timestamps = frames_between(&layer, params, from, to).await?.0;
+    if let Some(step) = params.get("step") {
+        let Some(step) = parse_step(step) else {
+            return Err(bad_request("Invalid step"));
+        };
+        thin(&mut timestamps, from, step);
+    }
+    if timestamps.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "No frames in that time range").into_response());
+    }
+    if timestamps.len() > MAX_FRAMES {
+        return Err(bad_request(format!("{} frames in that range, more than {}; narrow it or add a step", timestamps.len(), MAX_FRAMES)));
+    }
+    let timestamps = timestamps.iter().map(u64::to_string).collect();
+    Ok(Spec { layer, timestamps, from, to, settings: Settings { format, size, fps, zoom, tile_size } })
+}
+
+// Where encoded frames go
+enum Sink<'a, W: Write> {
+    Gif(GifEncoder<&'a mut W>),
+    Apng(png::Writer<&'a mut W>),
+}
+
+impl<W: Write> Sink<'_, W> {
+    fn write(&mut self, image: &RgbImage, fps: u32) -> Result<(), String> {
+        match self {
+            Sink::Gif(encoder) => {
+                let rgba = DynamicImage::ImageRgb8(image.clone()).to_rgba8();
+                let frame = Frame::from_parts(rgba, 0, 0, Delay::from_numer_denom_ms(1000, fps));
+                encoder.encode_frame(frame).map_err(|e| e.to_string())
+            }
+            Sink::Apng(writer) => writer.write_image_data(image.as_raw()).map_err(|e| e.to_string()),
+        }
+    }
+
+    fn finish(self) -> Result<(), String> {
+        match self {
+            // The trailer is written when the encoder is dropped
+            Sink::Gif(encoder) => {
+                drop(encoder);
+                Ok(())
+            }
+            Sink::Apng(writer) => writer.finish().map_err(|e| e.to_string()),
+        }
+    }
+}
+
+// Sends what's written to a streamed response in CHUNK_BYTES pieces,
+// failing once the client has gone
+struct Chunks {
+    sender: mpsc::Sender<io::Result<Bytes>>,
+    buffer: Vec<u8>,
+}
+
+impl Write for Chunks {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(data);
+        if self.buffer.len() >= CHUNK_BYTES {
+            self.flush()?;
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let chunk = Bytes::from(std::mem::take(&mut self.buffer));
+        self.sender.blocking_send(Ok(chunk)).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "client went away"))
+    }
+}
+
+// Encode the frames' tiles into `out` as they arrive, `frames` of them,
+// calling `progress` with how many are done after each. A frame without a
+// single tile repeats the one before, keeping the animation's timing.
+fn encode<W: Write>(
+    mut tiles: mpsc::Receiver<Vec<Option<Bytes>>>,
+    frames: usize,
+    settings: Settings,
+    mut out: W,
+    progress: impl Fn(usize),
+) -> Result<W, String> {
+    let Settings { format, size, fps, zoom, tile_size } = settings;
+    let mut sink = match format {
+        Format::Gif => {
+            let mut encoder = GifEncoder::new_with_speed(&mut out, GIF_SPEED);
+            encoder.set_repeat(Repeat::Infinite).map_err(|e| e.to_string())?;
+            Sink::Gif(encoder)
+        }
+        Format::Apng => {
+            let mut encoder = png::Encoder::new(&mut out, size, size);
+            encoder.set_color(png::ColorType::Rgb);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_animated(frames as u32, 0).map_err(|e| e.to_string())?;
+            encoder.set_frame_delay(1, fps as u16).map_err(|e| e.to_string())?;
+            Sink::Apng(encoder.write_header().map_err(|e| e.to_string())?)
+        }
+    };
+    let mut image = RgbImage::new(size, size);
+    let mut done = 0;
+    progress(done);
+    while let Some(tiles) = tiles.blocking_recv() {
+        if tiles.iter().any(Option::is_some) {
+            let disk = assemble(&tiles, tile_size << zoom, tile_size);
+            image = imageops::resize(&disk, size, size, FilterType::Triangle);
+        }
+        sink.write(&image, fps)?;
+        done += 1;
+        progress(done);
+    }
+    if done < frames {
+        return Err("stopped before the last frame".to_string());
+    }
+    sink.finish()?;
+    out.flush().map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+// Make the timelapse into `out`, downloading each frame's tiles while the
+// one before is encoded. Returns `out` and how many tiles were left black.
+async fn render<W: Write + Send + 'static>(
+    spec: &Spec,
+    out: W,
+    progress: impl Fn(usize) + Send + 'static,
+) -> Result<(W, usize), String> {
+    let _slot = RENDER_SLOT.acquire().await.map_err(|e| e.to_string())?;
+    let (sender, receiver) = mpsc::channel(2);
+    let (frames, settings) = (spec.timestamps.len(), spec.settings);
+    let encoder = tokio::task::spawn_blocking(move || encode(receiver, frames, settings, out, progress));
+    let mut missing = 0;
+    for timestamp in &spec.timestamps {
+        let tiles = disk_tiles(&spec.layer, timestamp, settings.zoom).await;
+        missing += tiles.iter().filter(|t| t.is_none()).count();
+        if sender.send(tiles).await.is_err() {
+            break;
+        }
+    }
+    drop(sender);
+    let video = encoder.await.map_err(|e| e.to_string())??;
+    Ok((video, missing))
+}
+
+fn filename(spec: &Spec) -> String {
+    let (first, last) = (&spec.timestamps[0], &spec.timestamps[spec.timestamps.len() - 1]);
+    format!("peepsat-{}-{}-{}-{}.{}", spec.layer.sat, spec.layer.product, first, last, spec.settings.format.extension())
+}
+
+/// `GET /api/timelapse?sat=19&from=2024-10-16T00:00Z&to=2024-10-16T12:00Z&fps=20&format=gif`:
+/// the frames between `from` and `to` (at most a week apart, with `step`
+/// to thin them as for `/api/frames/range`) as an animated GIF or, with
+/// `format=apng`, an animated PNG, `size` pixels across (512 by default,
+/// at most 1024) at `fps` frames a second; `format=mp4` gets the GIF. Up
+/// to 240 frames; tiles come from the cache or upstream. It's streamed as
+/// it's encoded, so a failure partway cuts the response short, and the
+/// missing tiles aren't known in time to count in a header. For long ones,
+/// `POST` the same query to queue a job instead.
+pub async fn handle_timelapse(Query(params): Query<Params>) -> Response {
+    let spec = match spec(&params).await {
+        Ok(spec) => spec,
+        Err(response) => return response,
+    };
+    Span::current().record("sat", spec.layer.sat.as_str());
+    let (sender, receiver) = mpsc::channel(MAX_CHUNKS_WAITING);
+    let body = Body::from_stream(stream::unfold(receiver, |mut receiver| async move {
+        receiver.recv().await.map(|chunk| (chunk, receiver))
+    }));
+    let response = (
+        [
+            (header::CONTENT_TYPE, spec.settings.format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, format!("inline; filename=\"{}\"", filename(&spec))),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        body,
+    )
+        .into_response();
+    let response = if offline::enabled() { offline::mark(response) } else { response };
+    let response = spec.layer.mark(response);
+
+    tokio::spawn(async move {
+        let chunks = Chunks { sender: sender.clone(), buffer: Vec::new() };
+        match render(&spec, chunks, |_| {}).await {
+            Ok((_, missing)) => info!(sat = spec.layer.sat, frames = spec.timestamps.len(), missing, "Made timelapse"),
+            Err(e) => {
+                warn!(sat = spec.layer.sat, error = %e, "Timelapse failed");
+                // Ends the body with an error, so the client doesn't take
+                // what it has for the whole animation
+                let _ = sender.send(Err(io::Error::other(e))).await;
+            }
+        }
+    });
+    response
+}
+
+fn update_job(id: u64, f: impl FnOnce(&mut Job)) {
+    if let Some(job) = JOBS.lock().unwrap().get_mut(&id) {
+        f(job);
+    }
+}
+
+/// `POST /api/timelapse` with `GET`'s query queues the timelapse as a job,
+/// answering 202 with its id and status URL.
+pub async fn handle_timelapse_job(Query(params): Query<Params>) -> Response {
+    let spec = match spec(&params).await {
+        Ok(spec) => spec,
+        Err(response) => return response,
+    };
+    let id = NEXT_JOB_ID.fetch_add(1, Ordering::Relaxed);
+    {
+        let mut jobs = JOBS.lock().unwrap();
+        let pending = jobs.values().filter(|j| matches!(j.state, JobState::Queued | JobState::Running)).count();
+        if pending >= MAX_JOBS_PENDING {
+            return (StatusCode::SERVICE_UNAVAILABLE, Json(json!({ "error": "too many timelapses queued" }))).into_response();
+        }
+        while jobs.len() >= MAX_JOBS_KEPT {
+            let finished = jobs.iter().find(|(_, j)| matches!(j.state, JobState::Done | JobState::Failed));
+            match finished.map(|(id, _)| *id) {
+                Some(old) => {
+                    jobs.remove(&old);
+                    VIDEOS.lock().unwrap().remove(&old);
+                }
+                None => break,
+            };
+        }
+        let Settings { format, size, fps, .. } = spec.settings;
+        jobs.insert(id, Job {
+            id,
+            sat: spec.layer.sat.clone(),
+            product: spec.layer.product.clone(),
+            from: iso(spec.from),
+            to: iso(spec.to),
+            format,
+            size,
+            fps,
+            state: JobState::Queued,
+            frames: spec.timestamps.len(),
+            frames_done: 0,
+            missing_tiles: 0,
+            bytes: None,
+            video: None,
+            error: None,
+        });
+    }
+    info!(id, sat = spec.layer.sat, frames = spec.timestamps.len(), "Timelapse job queued");
+
+    tokio::spawn(async move {
+        let progress = move |done| update_job(id, |job| {
+            job.state = JobState::Running;
+            job.frames_done = done;
+        });
+        match render(&spec, Vec::new(), progress).await {
+            Ok((video, missing)) => {
+                info!(id, sat = spec.layer.sat, missing, bytes = video.len(), "Timelapse job finished");
+                let bytes = video.len();
+                VIDEOS.lock().unwrap().insert(id, Bytes::from(video));
+                update_job(id, |job| {
+                    job.state = JobState::Done;
+                    job.missing_tiles = missing;
+                    job.bytes = Some(bytes);
+                    job.video = Some(format!("{}/api/timelapse/{}/video", CONFIG.base_path, id));
+                });
+            }
+            Err(e) => {
+                warn!(id, sat = spec.layer.sat, error = %e, "Timelapse job failed");
+                update_job(id, |job| {
+                    job.state = JobState::Failed;
+                    job.error = Some(e);
+                });
+            }
+        }
+    });
+    (
+        StatusCode::ACCEPTED,
+        Json(json!({ "id": id, "status": format!("{}/api/timelapse/{}", CONFIG.base_path, id) })),
+    )
+        .into_response()
+}
+
+/// `GET /api/timelapse/{id}`
+pub async fn handle_timelapse_status(Path(id): Path<u64>) -> Response {
+    match JOBS.lock().unwrap().get(&id) {
+        Some(job) => Json(job.clone()).into_response(),
+        None => (StatusCode::NOT_FOUND, Json(json!({ "error": "no such job" }))).into_response(),
+    }
+}
+
+/// `GET /api/timelapse/{id}/video`: a finished job's timelapse.
+pub async fn handle_timelapse_video(Path(id): Path<u64>) -> Response {
+    let Some(job) = JOBS.lock().unwrap().get(&id).cloned() else {
+        return (StatusCode::NOT_FOUND, Json(json!({ "error": "no such job" }))).into_response();
+    };
+    let Some(video) = VIDEOS.lock().unwrap().get(&id).cloned() else {
+        return (StatusCode::CONFLICT, Json(json!({ "error": "job not finished" }))).into_response();
+    };
+    (
+        [
+            (header::CONTENT_TYPE, job.format.content_type().to_string()),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+            (MISSING_HEADER, job.missing_tiles.to_string()),
+        ],
+        video,
+    )
+        .into_response()
+}
+
+// Daily timelapses are saved as "timelapses/{sat}_{sector}_{product}_{date}.{gif,png}"
+// in the cache directory; `None` for names that can't safely be a path
+fn daily_path(layer: &Layer, date: u32, format: Format) -> Option<PathBuf> {
+    if !is_valid_key(&cache_key(&layer.sat, &layer.sector, &layer.product, &format!("{}000000", date), 0, 0, 0)) {
+        return None;
+    }
+    let name = format!("{}_{}_{}_{}.{}", layer.sat, layer.sector, layer.product, date, format.extension());
+    Some(CACHE_DIR.join("timelapses").join(name))
+}
+
+/// Make the timelapse of the UTC day `date` (YYYYMMDD) that `params`
+/// describe, as `/api/timelapse` takes them but without `from` and `to`,
+/// and save it for `/api/timelapse/daily`. Returns how many frames it has.
+pub async fn make_daily(params: &Params, date: u32) -> Result<usize, String> {
+    let mut params = params.clone();
+    params.insert("from".to_string(), date.to_string());
+    params.insert("to".to_string(), date.to_string());
+    let spec = match spec(&params).await {
+        Ok(spec) => spec,
+        Err(response) => {
+            let status = response.status();
+            let body = to_bytes(response.into_body(), MAX_ERROR_BYTES).await.unwrap_or_default();
+            return Err(format!("{}: {}", status, String::from_utf8_lossy(&body)));
+        }
+    };
+    let path = daily_path(&spec.layer, date, spec.settings.format).ok_or("Invalid sector or product")?;
+    let (video, missing) = render(&spec, Vec::new(), |_| {}).await?;
+    if let Some(dir) = path.parent() {
+        tokio::fs::create_dir_all(dir).await.map_err(|e| e.to_string())?;
+    }
+    tokio::fs::write(&path, &video).await.map_err(|e| e.to_string())?;
+    info!(sat = spec.layer.sat, date, frames = spec.timestamps.len(), missing, bytes = video.len(), "Made daily timelapse");
+    Ok(spec.timestamps.len())
+}
+
+// The layer ("{sat}_{sector}_{product}") and day of a saved daily
+// timelapse's file name
+fn daily_name_parts(name: &str) -> Option<(&str, u32)> {
+    let (layer, day) = name.rsplit_once('.')?.0.rsplit_once('_')?;
+    Some((layer, day.parse().ok()?))
+}
+
+// Saved daily timelapses, with their layer and day
+fn saved_daily() -> Vec<(String, u32, PathBuf)> {
+    let Ok(entries) = std::fs::read_dir(CACHE_DIR.join("timelapses")) else {
+        return Vec::new();
+    };
+    entries
+        .flatten()
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let (layer, day) = daily_name_parts(&name)?;
+            Some((layer.to_string(), day, entry.path()))
+        })
+        .collect()
+}
+
+/// Delete saved daily timelapses of days before `date` (YYYYMMDD),
+/// returning how many went.
+pub fn prune_daily(date: u32) -> usize {
+    saved_daily().into_iter().filter(|(_, day, path)| *day < date && std::fs::remove_file(path).is_ok()).count()
+}
+
+/// `GET /api/timelapse/daily?sat=19&date=20241016`: the day's timelapse
+/// the scheduler made (the newest one without `date`), for the same
+/// `sector` and `product` as `/slider-tile`.
+pub async fn handle_daily(Query(params): Query<Params>) -> Response {
+    let Some(layer) = Layer::from_params(&params) else {
+        return bad_request("Invalid sector or product");
+    };
+    Span::current().record("sat", layer.sat.as_str());
+    let date = match params.get("date").map(|d| d.parse::<u32>()) {
+        None => None,
+        Some(Ok(date)) => Some(date),
+        Some(Err(_)) => return bad_request("date must be YYYYMMDD"),
+    };
+    let wanted = format!("{}_{}_{}", layer.sat, layer.sector, layer.product);
+    let found = saved_daily()
+        .into_iter()
+        .filter(|(layer, day, _)| *layer == wanted && date.is_none_or(|date| date == *day))
+        .max_by_key(|(_, day, _)| *day);
+    let Some((_, _, path)) = found else {
+        return (StatusCode::NOT_FOUND, "No daily timelapse for that layer and day").into_response();
+    };
+    let Ok(video) = tokio::fs::read(&path).await else {
+        return (StatusCode::NOT_FOUND, "No daily timelapse for that layer and day").into_response();
+    };
+    let format = if path.extension().is_some_and(|e| e == "png") { Format::Apng } else { Format::Gif };
+    let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+    let response = (
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, format!("inline; filename=\"peepsat-{}\"", name)),
+            (header::CACHE_CONTROL, "no-cache".to_string()),
+        ],
+        video,
+    )
+        .into_response();
+    layer.mark(response)
+}
@@ -0,0 +1,69 @@
+use std::net::SocketAddr;
+use axum::Router;
+use axum::extract::Request;
+use axum::http::{header, StatusCode, Uri};
+use axum::response::{IntoResponse, Redirect, Response};
+use axum_server::Handle;
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::{error, info};
+use crate::config::CONFIG;
+
+/// Serve `app` over HTTPS until `shutdown` resolves, then drain connections.
+pub async fn serve(app: Router, listener: std::net::TcpListener, shutdown: impl std::future::Future<Output = ()> + Send + 'static) {
+    let _ = rustls::crypto::ring::default_provider().install_default();
+
+    let (Some(cert), Some(key)) = (&CONFIG.tls_cert, &CONFIG.tls_key) else {
+        unreachable!("tls::serve called without a certificate and key");
+    };
+    let tls_config = match RustlsConfig::from_pem_file(cert, key).await {
+        Ok(config) => config,
+        Err(e) => {
+            error!(?cert, ?key, error = %e, "Failed to load TLS certificate");
+            std::process::exit(1);
+        }
+    };
+
+    let addr = listener.local_addr().unwrap();
+    if let Some(port) = CONFIG.http_redirect_port {
+        let redirect_addr = SocketAddr::new(addr.ip(), port);
+        let listener = tokio::net::TcpListener::bind(redirect_addr).await.unwrap();
+        info!("Redirecting http://{} to HTTPS", redirect_addr);
+        let redirect = Router::new().fallback(redirect_to_https);
+        tokio::spawn(async move { axum::serve(listener, redirect).await });
+    }
+
+    let handle = Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown.await;
+        shutdown_handle.graceful_shutdown(None);
+    });
+
+    info!("Server running on https://{}", addr);
+    axum_server::from_tcp_rustls(listener, tls_config)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
+}
+
+async fn redirect_to_https(request: Request) -> Response {
+    let Some(host) = request.headers().get(header::HOST).and_then(|h| h.to_str().ok()) else {
+        return (StatusCode::BAD_REQUEST, "Missing Host header").into_response();
+    };
+    // Swap whatever port the client used for the HTTPS one
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !port.ends_with(']') => name,
+        _ => host,
+    };
+    let authority = if CONFIG.port == 443 {
+        host.to_string()
+    } else {
+        format!("{}:{}", host, CONFIG.port)
+    };
+    let path = request.uri().path_and_query().map_or("/", |p| p.as_str());
+    match Uri::builder().scheme("https").authority(authority).path_and_query(path).build() {
+        Ok(uri) => Redirect::permanent(&uri.to_string()).into_response(),
+        Err(_) => (StatusCode::BAD_REQUEST, "Bad Host header").into_response(),
+    }
+}
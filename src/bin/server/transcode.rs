@@ -0,0 +1,199 @@
+use std::io::Cursor;
+use axum::body::{to_bytes, Body};
+use axum::extract::{Query, Request};
+use axum::http::{header, HeaderMap, HeaderValue, StatusCode};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use image::{DynamicImage, ImageOutputFormat};
+use image_webp::{ColorType, WebPEncoder};
+use imgref::Img;
+use rgb::FromSlice;
+use tracing::warn;
+use crate::config::CONFIG;
+use crate::Params;
+
+// Largest PNG re-encoded on the way out, well over a stitched zoom 3 disk
+const MAX_TRANSCODE_BYTES: usize = 512 * 1024 * 1024;
+// rav1e's speed, 1-10; anything slower is too slow to encode per request
+const AVIF_SPEED: u8 = 8;
+
+/// Re-encode a PNG (or any decodable image) as lossless WebP.
+pub fn to_webp(data: &[u8]) -> Result<Vec<u8>, String> {
+    let image = image::load_from_memory(data).map_err(|e| e.to_string())?;
+    let (width, height) = (image.width(), image.height());
+    let mut out = Vec::new();
+    let encoder = WebPEncoder::new(&mut out);
+    let result = if image.color().has_alpha() {
+        encoder.encode(image.to_rgba8().as_raw(), width, height, ColorType::Rgba8)
+    } else {
+        encoder.encode(image.to_rgb8().as_raw(), width, height, ColorType::Rgb8)
+    };
+    result.map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+/// Re-encode an image as lossy WebP at `quality` (0-100).
+pub fn to_lossy_webp(data: &[u8], quality: u8) -> Result<Vec<u8>, String> {
+    let image = image::load_from_memory(data).map_err(|e| e.to_string())?;
+    let (width, height) = (image.width(), image.height());
+    let result = if image.color().has_alpha() {
+        webp::Encoder::from_rgba(image.to_rgba8().as_raw(), width, height).encode_simple(false, quality as f32)
+    } else {
+        webp::Encoder::from_rgb(image.to_rgb8().as_raw(), width, height).encode_simple(false, quality as f32)
+    };
+    result.map(|webp| webp.to_vec()).map_err(|e| format!("{:?}", e))
+}
+
+/// Re-encode an image as AVIF at `quality` (0-100).
+pub fn to_avif(data: &[u8], quality: u8) -> Result<Vec<u8>, String> {
+    let image = image::load_from_memory(data).map_err(|e| e.to_string())?;
+    let (width, height) = (image.width() as usize, image.height() as usize);
+    let encoder = ravif::Encoder::new().with_quality(quality as f32).with_speed(AVIF_SPEED);
+    let result = if image.color().has_alpha() {
+        encoder.encode_rgba(Img::new(image.to_rgba8().as_raw().as_rgba(), width, height))
+    } else {
+        encoder.encode_rgb(Img::new(image.to_rgb8().as_raw().as_rgb(), width, height))
+    };
+    result.map(|avif| avif.avif_file).map_err(|e| e.to_string())
+}
+
+/// Decode any supported image and re-encode it as PNG, for clients that
+/// can't take the stored format.
+pub fn to_png(data: &[u8]) -> Result<Vec<u8>, String> {
+    let image: DynamicImage = image::load_from_memory(data).map_err(|e| e.to_string())?;
+    let mut out = Cursor::new(Vec::new());
+    image.write_to(&mut out, ImageOutputFormat::Png).map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
+
+/// The q-value the Accept header gives `mime` (e.g. "image/webp"), 0 when
+/// it isn't listed. Only the exact type counts: browsers send `*/*` too,
+/// and that's no promise they can decode WebP.
+fn accept_quality(headers: &HeaderMap, mime: &str) -> f32 {
+    let Some(accept) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+        return 0.0;
+    };
+    accept
+        .split(',')
+        .filter_map(|entry| {
+            let mut params = entry.split(';').map(str::trim);
+            if !params.next()?.eq_ignore_ascii_case(mime) {
+                return None;
+            }
+            let q = params.find_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q=")));
+            Some(q.map_or(1.0, |q| q.parse().unwrap_or(0.0)))
+        })
+        .fold(0.0, f32::max)
+}
+
+/// Whether the Accept header takes `mime` with a q-value above 0.
+pub fn accepts(headers: &HeaderMap, mime: &str) -> bool {
+    accept_quality(headers, mime) > 0.0
+}
+
+#[derive(Clone, Copy)]
+enum Target {
+    Webp,
+    Avif,
+}
+
+impl Target {
+    fn content_type(self) -> &'static str {
+        match self {
+            Target::Webp => "image/webp",
+            Target::Avif => "image/avif",
+        }
+    }
+
+    // Added to a PNG's ETag for this version of it, so the two aren't confused
+    fn etag_suffix(self) -> &'static str {
+        match self {
+            Target::Webp => "-webp",
+            Target::Avif => "-avif",
+        }
+    }
+
+    fn encode(self, png: &[u8]) -> Result<Vec<u8>, String> {
+        match self {
+            Target::Webp if CONFIG.transcode_quality == 100 => to_webp(png),
+            Target::Webp => to_lossy_webp(png, CONFIG.transcode_quality),
+            Target::Avif => to_avif(png, CONFIG.transcode_quality),
+        }
+    }
+}
+
+// What to send for the request's Accept header: whichever of the enabled
+// formats it rates highest, AVIF on a tie since it's the smaller
+fn negotiate(headers: &HeaderMap) -> Option<Target> {
+    let avif = if CONFIG.transcode_avif { accept_quality(headers, "image/avif") } else { 0.0 };
+    let webp = if CONFIG.transcode_webp { accept_quality(headers, "image/webp") } else { 0.0 };
+    if avif > 0.0 && avif >= webp {
+        Some(Target::Avif)
+    } else if webp > 0.0 {
+        Some(Target::Webp)
+    } else {
+        None
+    }
+}
+
+// A PNG response's ETag with the target's suffix inside the quotes
+fn target_etag(etag: &HeaderValue, target: Target) -> Option<HeaderValue> {
+    let etag = etag.to_str().ok()?.strip_suffix('"')?;
+    HeaderValue::from_str(&format!("{}{}\"", etag, target.etag_suffix())).ok()
+}
+
+/// Send PNG tiles and images as WebP or AVIF to clients that ask, with
+/// `format=webp` / `format=avif` or, when `transcode_webp` / `transcode_avif`
+/// is set, an Accept header taking it. The PNG is re-encoded at
+/// `transcode_quality` on the way out; anything else, including tiles
+/// cached as WebP already, passes through.
+pub async fn layer(mut request: Request, next: Next) -> Response {
+    let format = Query::<Params>::try_from_uri(request.uri()).ok().and_then(|Query(params)| params.get("format").cloned());
+    let target = match format.as_deref() {
+        Some("webp") => Some(Target::Webp),
+        Some("avif") => Some(Target::Avif),
+        Some(_) => None,
+        None => negotiate(request.headers()),
+    };
+    let Some(target) = target else {
+        return next.run(request).await;
+    };
+    // Handlers only know the PNG's ETag
+    let if_none_match = request.headers().get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok());
+    if let Some(value) = if_none_match.and_then(|v| HeaderValue::from_str(&v.replace(&format!("{}\"", target.etag_suffix()), "\"")).ok()) {
+        request.headers_mut().insert(header::IF_NONE_MATCH, value);
+    }
+
+    let response = next.run(request).await;
+    if response.headers().get(header::CONTENT_TYPE).is_none_or(|t| t != "image/png") {
+        return response;
+    }
+    let (mut parts, body) = response.into_parts();
+    if let Some(etag) = parts.headers.get(header::ETAG).and_then(|etag| target_etag(etag, target)) {
+        parts.headers.insert(header::ETAG, etag);
+    }
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(target.content_type()));
+    let varies = parts.headers.get_all(header::VARY).iter().any(|v| v.to_str().is_ok_and(|v| v.to_ascii_lowercase().contains("accept")));
+    if !varies {
+        parts.headers.append(header::VARY, HeaderValue::from_static("Accept"));
+    }
+    if parts.status == StatusCode::NOT_MODIFIED {
+        return Response::from_parts(parts, Body::empty());
+    }
+    let Ok(png) = to_bytes(body, MAX_TRANSCODE_BYTES).await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to read image").into_response();
+    };
+    match tokio::task::spawn_blocking(move || target.encode(&png).map_err(|e| (e, png))).await {
+        Ok(Ok(encoded)) => {
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Response::from_parts(parts, Body::from(encoded))
+        }
+        Ok(Err((e, png))) => {
+            warn!(error = %e, format = target.content_type(), "Transcoding failed, sending PNG");
+            parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("image/png"));
+            parts.headers.remove(header::ETAG);
+            Response::from_parts(parts, Body::from(png))
+        }
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image").into_response(),
+    }
+}
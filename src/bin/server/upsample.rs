@@ -0,0 +1,67 @@
+use std::io::Cursor;
+use axum::body::Bytes;
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use axum::response::Response;
+use image::imageops::FilterType;
+use image::ImageOutputFormat;
+use tracing::{debug, Span};
+use crate::cache::TileFormat;
+use crate::frames::Layer;
+use crate::fulldisk::tile_bytes;
+use crate::{offline, tile_response, Params, Tile};
+
+/// Set on a tile made by scaling up part of a shallower zoom's, to the
+/// zoom it came from.
+pub const HEADER: HeaderName = HeaderName::from_static("x-peepsat-upsampled");
+
+// The part of `parent`, `levels` zooms shallower, over the tile at `row`
+// and `column`, scaled up to a whole tile, as a PNG
+fn enlarge(parent: &[u8], levels: u32, row: u32, column: u32, tile_size: u32) -> Result<Vec<u8>, String> {
+    let parent = image::load_from_memory(parent).map_err(|e| e.to_string())?;
+    let mask = (1 << levels) - 1;
+    let edge = |i: u32| (i * parent.width()) >> levels;
+    let (left, top) = (edge(column & mask), edge(row & mask));
+    let (width, height) = (edge((column & mask) + 1) - left, edge((row & mask) + 1) - top);
+    let part = parent.crop_imm(left, top, width.max(1), height.max(1));
+    let tile = part.resize_exact(tile_size, tile_size, FilterType::CatmullRom);
+    let mut out = Cursor::new(Vec::new());
+    tile.write_to(&mut out, ImageOutputFormat::Png).map_err(|e| e.to_string())?;
+    Ok(out.into_inner())
+}
+
+/// A stand-in for a `/slider-tile` tile that isn't there (yet, as is
+/// common just after a frame is posted): the matching part of the nearest
+/// shallower zoom's tile that can be had, scaled up, marked with `HEADER`
+/// and not to be cached. `None` if none of them can be had either.
+pub async fn from_parent(params: &Params, headers: &HeaderMap) -> Option<Response> {
+    let layer = Layer::from_params(params)?;
+    let (max_zoom, tile_size) = layer.tile_grid();
+    let number = |name: &str, default: u32| params.get(name).and_then(|s| s.parse().ok()).unwrap_or(default);
+    let zoom = number("z", 4).min(max_zoom);
+    let (x, y) = (number("x", 0), number("y", 0));
+    let timestamp = params.get("t")?;
+    let date = params.get("d").map_or(timestamp.get(..8)?, String::as_str);
+
+    for levels in 1..=zoom {
+        let tile = Tile {
+            sat: &layer.sat,
+            sector: &layer.sector,
+            product: &layer.product,
+            timestamp,
+            zoom: zoom - levels,
+            x: x >> levels,
+            y: y >> levels,
+        };
+        let Some(parent) = tile_bytes(&layer.cdn, tile, date).await else {
+            continue;
+        };
+        let png = tokio::task::spawn_blocking(move || enlarge(&parent, levels, x, y, tile_size)).await.ok()?.ok()?;
+        debug!(sat = layer.sat, zoom, x, y, from = zoom - levels, "Upsampled missing tile");
+        Span::current().record("cache", "UPSAMPLED");
+        // Timestamp "0" keeps the browser from keeping it as the real tile
+        let mut response = tile_response(Bytes::from(png), TileFormat::Png, "UPSAMPLED", "0", headers);
+        response.headers_mut().insert(HEADER, HeaderValue::from(zoom - levels));
+        return Some(if offline::enabled() { offline::mark(response) } else { response });
+    }
+    None
+}
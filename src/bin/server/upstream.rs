@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use axum::body::Bytes;
+use tracing::{debug, warn};
+use crate::config::CONFIG;
+use crate::metrics;
+use tokio::sync::oneshot;
+
+lazy_static::lazy_static! {
+    // HTTP client that follows redirects
+    pub static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::limited(10))
+        .timeout(CONFIG.upstream_timeout())
+        .build()
+        .unwrap();
+    // HTTP client for NICT (accepts self-signed certs)
+    pub static ref NICT_CLIENT: reqwest::Client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::limited(10))
+        .timeout(CONFIG.upstream_timeout())
+        .danger_accept_invalid_certs(true)
+        .build()
+        .unwrap();
+    static ref SLOTS: Mutex<Slots> = Mutex::new(Slots {
+        free: CONFIG.upstream_concurrency,
+        interactive: VecDeque::new(),
+        background: VecDeque::new(),
+    });
+}
+
+/// Who a tile fetch is for. Background work only gets a connection when no
+/// viewer is waiting for one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Priority {
+    Interactive,
+    Background,
+}
+
+// Free upstream connections and the fetches queued for one, in order
+struct Slots {
+    free: usize,
+    interactive: VecDeque<oneshot::Sender<()>>,
+    background: VecDeque<oneshot::Sender<()>>,
+}
+
+/// The right to one upstream connection, given back on drop.
+pub struct Slot(());
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        release();
+    }
+}
+
+// Hand the slot straight to the next waiter that's still there
+fn release() {
+    let mut slots = SLOTS.lock().unwrap();
+    while let Some(tx) = slots.interactive.pop_front().or_else(|| slots.background.pop_front()) {
+        if tx.send(()).is_ok() {
+            return;
+        }
+    }
+    slots.free += 1;
+}
+
+// A queued fetch; if it's abandoned after being handed a slot, the slot
+// goes back
+struct Waiter(Option<oneshot::Receiver<()>>);
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        metrics::UPSTREAM_QUEUED.dec();
+        if let Some(mut rx) = self.0.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                release();
+            }
+        }
+    }
+}
+
+/// Wait for one of the `upstream_concurrency` connection slots, interactive
+/// fetches first and each priority in arrival order. `None` when the limit
+/// is off.
+pub async fn acquire(priority: Priority) -> Option<Slot> {
+    if CONFIG.upstream_concurrency == 0 {
+        return None;
+    }
+    let rx = {
+        let mut slots = SLOTS.lock().unwrap();
+        if slots.free > 0 {
+            slots.free -= 1;
+            return Some(Slot(()));
+        }
+        let (tx, rx) = oneshot::channel();
+        match priority {
+            Priority::Interactive => slots.interactive.push_back(tx),
+            Priority::Background => slots.background.push_back(tx),
+        }
+        rx
+    };
+    metrics::UPSTREAM_QUEUED.inc();
+    let mut waiter = Waiter(Some(rx));
+    // Senders are only dropped after sending, so this can't fail
+    let _ = waiter.0.as_mut().unwrap().await;
+    waiter.0 = None;
+    Some(Slot(()))
+}
+
+/// Full URLs for `path` on the requested CDN followed by the configured
+/// fallback CDNs, in the order they should be tried.
+pub fn with_fallbacks(cdn: &str, path: &str) -> Vec<String> {
+    let mut bases = vec![cdn.trim_end_matches('/')];
+    for fallback in &CONFIG.fallback_cdns {
+        let fallback = fallback.trim_end_matches('/');
+        if !bases.contains(&fallback) {
+            bases.push(fallback);
+        }
+    }
+    bases.iter().map(|base| format!("{}{}", base, path)).collect()
+}
+
+// Throttling and server errors are worth another attempt; 404 means the
+// tile really isn't there (yet) and retrying won't change that.
+pub fn is_retryable(status: reqwest::StatusCode) -> bool {
+    status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+fn backoff_delay(attempt: u32) -> Duration {
+    let base = CONFIG.retry_base_delay_ms.saturating_mul(1 << attempt.min(10));
+    // Jitter so simultaneous tile failures don't retry in lockstep
+    Duration::from_millis(base / 2 + fastrand::u64(0..=base / 2))
+}
+
+/// GET the first URL that answers with a non-retryable response, retrying
+/// each with exponential backoff before failing over to the next. If every
+/// URL fails, the last upstream response (or error) is returned.
+pub async fn get(client: &reqwest::Client, urls: &[String]) -> reqwest::Result<reqwest::Response> {
+    let mut last = None;
+    for url in urls {
+        for attempt in 0..=CONFIG.upstream_retries {
+            if attempt > 0 {
+                let delay = backoff_delay(attempt - 1);
+                debug!(url = %url, ?delay, attempt = attempt + 1, "Retrying upstream");
+                tokio::time::sleep(delay).await;
+            }
+            let start = Instant::now();
+            let result = client.get(url).send().await;
+            metrics::observe_upstream(start, result.as_ref().ok().map(|r| r.status()));
+            match result {
+                Ok(r) if !is_retryable(r.status()) => return Ok(r),
+                Ok(r) => {
+                    warn!(url = %url, status = r.status().as_u16(), "Upstream returned error");
+                    last = Some(Ok(r));
+                }
+                Err(e) => {
+                    warn!(url = %url, error = %e, "Upstream request failed");
+                    last = Some(Err(e));
+                }
+            }
+        }
+    }
+    last.expect("at least one upstream URL")
+}
+
+/// The response body, read a chunk at a time and abandoned as soon as it
+/// passes `limit` bytes instead of buffering whatever upstream sends.
+pub async fn read_limited(mut response: reqwest::Response, limit: usize) -> Result<Bytes, String> {
+    if response.content_length().is_some_and(|len| len > limit as u64) {
+        return Err(format!("body larger than {} bytes", limit));
+    }
+    let mut body = Vec::with_capacity(response.content_length().unwrap_or(0) as usize);
+    while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+        if body.len() + chunk.len() > limit {
+            return Err(format!("body larger than {} bytes", limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(body))
+}
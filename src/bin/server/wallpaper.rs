@@ -0,0 +1,194 @@
+use std::io::Cursor;
+use axum::body::Bytes;
+use axum::extract::Query;
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, ImageOutputFormat, Rgb, RgbImage};
+use tracing::{info, warn, Span};
+use crate::cache::DEFAULT_SECTOR;
+use crate::config::CONFIG;
+use crate::dates::parse_time;
+use crate::frames::Layer;
+use crate::fulldisk::{self, assemble, disk_tiles, JPEG_QUALITY, MISSING_HEADER, STITCH_SLOT};
+use crate::reproject::frame_timestamp;
+use crate::{bad_gateway, geos, offline, satellites, Params};
+
+const DEFAULT_WIDTH: u32 = 1920;
+const DEFAULT_HEIGHT: u32 = 1080;
+// 8K
+const MAX_SIDE: u32 = 7680;
+// How much of the shorter side the disk spans, in percent, as satpaper
+const DEFAULT_DISK_SIZE: u32 = 95;
+
+// How the disk is laid out on the background
+struct Layout {
+    width: u32,
+    height: u32,
+    // The disk image's side and its centre, in pixels
+    side: u32,
+    centre: (f64, f64),
+    background: Rgb<u8>,
+    // The Earth's reach across the disk image, east-west and north-south
+    limb: (f64, f64),
+}
+
+// RRGGBB, with or without a leading #
+fn parse_colour(text: &str) -> Option<Rgb<u8>> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    if hex.len() != 6 {
+        return None;
+    }
+    let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+    Some(Rgb([channel(0)?, channel(2)?, channel(4)?]))
+}
+
+// A number parameter within `range`, or `default` if it's absent
+fn number(params: &Params, name: &str, default: u32, range: std::ops::RangeInclusive<u32>) -> Result<u32, String> {
+    match params.get(name) {
+        None => Ok(default),
+        Some(text) => match text.parse() {
+            Ok(value) if range.contains(&value) => Ok(value),
+            _ => Err(format!("{} must be from {} to {}", name, range.start(), range.end())),
+        },
+    }
+}
+
+// The disk scaled to the layout's size and drawn on its background, the
+// space around the Earth's limb (black in SLIDER's images) replaced by the
+// background too, with the edge antialiased
+fn compose(disk: &RgbImage, layout: &Layout) -> RgbImage {
+    let disk = imageops::resize(disk, layout.side, layout.side, FilterType::Lanczos3);
+    let half = layout.side as f64 / 2.0;
+    let (left, top) = (layout.centre.0 - half, layout.centre.1 - half);
+    let (radius_x, radius_y) = (layout.limb.0 * half, layout.limb.1 * half);
+    let background = layout.background;
+    RgbImage::from_fn(layout.width, layout.height, |x, y| {
+        let (dx, dy) = (x as f64 + 0.5 - layout.centre.0, y as f64 + 0.5 - layout.centre.1);
+        // Distance inside the limb in pixels, roughly, for a one-pixel fade
+        let inside = (1.0 - (dx / radius_x).hypot(dy / radius_y)) * radius_x.min(radius_y);
+        let coverage = (inside + 0.5).clamp(0.0, 1.0);
+        if coverage == 0.0 {
+            return background;
+        }
+        let (u, v) = ((x as f64 - left) as i64, (y as f64 - top) as i64);
+        let Some(pixel) = u32::try_from(u).ok().zip(u32::try_from(v).ok()).and_then(|(u, v)| disk.get_pixel_checked(u, v)) else {
+            return background;
+        };
+        let blend = |i: usize| (pixel[i] as f64 * coverage + background[i] as f64 * (1.0 - coverage)).round() as u8;
+        Rgb([blend(0), blend(1), blend(2)])
+    })
+}
+
+/// `GET /api/wallpaper?sat=19&width=3840&height=2160&disk_size=90&bg=0b0b10`:
+/// the newest full disk (or frame `t`) on a plain background, ready to set
+/// as a desktop wallpaper, like satpaper makes. `disk_size` is how much of
+/// the shorter side the disk spans, in percent (95 by default), and `x`
+/// and `y` where its centre is, in percent across and down (50 by
+/// default). `bg` is the background as RRGGBB (black by default) and
+/// `format` is `png`, `jpeg`, `webp` or `avif`. Takes the same `product` and
+/// `cdn` as `/slider-tile`.
+pub async fn handle_wallpaper(Query(mut params): Query<Params>) -> Response {
+    params.insert("sector".to_string(), DEFAULT_SECTOR.to_string());
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Invalid sector or product").into_response();
+    };
+    let Some(satellite) = satellites::find(&layer.sat) else {
+        return (StatusCode::BAD_REQUEST, "Unknown satellite").into_response();
+    };
+    let jpeg = match params.get("format").map(String::as_str) {
+        // WebP and AVIF are the PNG re-encoded by transcode::layer
+        None | Some("png") | Some("webp") | Some("avif") => false,
+        Some("jpeg") | Some("jpg") => true,
+        Some(_) => return (StatusCode::BAD_REQUEST, "format must be png, jpeg, webp or avif").into_response(),
+    };
+    let numbers = (|| {
+        let width = number(&params, "width", DEFAULT_WIDTH, 16..=MAX_SIDE)?;
+        let height = number(&params, "height", DEFAULT_HEIGHT, 16..=MAX_SIDE)?;
+        let disk_size = number(&params, "disk_size", DEFAULT_DISK_SIZE, 1..=100)?;
+        let x = number(&params, "x", 50, 0..=100)?;
+        let y = number(&params, "y", 50, 0..=100)?;
+        Ok::<_, String>((width, height, disk_size, x, y))
+    })();
+    let (width, height, disk_size, x, y) = match numbers {
+        Ok(numbers) => numbers,
+        Err(message) => return (StatusCode::BAD_REQUEST, message).into_response(),
+    };
+    let background = match params.get("bg") {
+        None => Rgb([0, 0, 0]),
+        Some(bg) => match parse_colour(bg) {
+            Some(colour) => colour,
+            None => return (StatusCode::BAD_REQUEST, "bg must be a colour as RRGGBB, e.g. 0b0b10").into_response(),
+        },
+    };
+    let time = match params.get("t") {
+        Some(t) => match parse_time(t) {
+            Some(time) => Some(time),
+            None => return (StatusCode::BAD_REQUEST, "t must be a frame timestamp, e.g. 20241016120000").into_response(),
+        },
+        None => None,
+    };
+    Span::current().record("sat", layer.sat.as_str());
+
+    let side = (width.min(height) * disk_size / 100).max(1);
+    let layout = Layout {
+        width,
+        height,
+        side,
+        centre: (width as f64 * x as f64 / 100.0, height as f64 * y as f64 / 100.0),
+        background,
+        limb: geos::limb(satellite),
+    };
+    let (max_zoom, tile_size) = layer.tile_grid();
+    // The first zoom at least as big, within what /api/fulldisk stitches
+    let deepest = (0..=max_zoom).rev().find(|&z| tile_size << z <= fulldisk::MAX_SIDE).unwrap_or(0);
+    let zoom = (0..=deepest).find(|&z| tile_size << z >= side).unwrap_or(deepest);
+
+    let timestamp = match frame_timestamp(&layer, &params, time).await {
+        Ok(timestamp) => timestamp,
+        Err(response) => return response,
+    };
+    let Ok(_slot) = STITCH_SLOT.acquire().await else {
+        return bad_gateway("Failed");
+    };
+    let tiles = disk_tiles(&layer, &timestamp, zoom).await;
+    let missing = tiles.iter().filter(|t| t.is_none()).count();
+    if missing == tiles.len() {
+        return if offline::enabled() { offline::unavailable() } else { bad_gateway("No tiles for that frame") };
+    }
+
+    let render = move || {
+        let disk = assemble(&tiles, tile_size << zoom, tile_size);
+        let image = compose(&disk, &layout);
+        let format = if jpeg { ImageOutputFormat::Jpeg(JPEG_QUALITY) } else { ImageOutputFormat::Png };
+        let mut out = Cursor::new(Vec::new());
+        DynamicImage::ImageRgb8(image).write_to(&mut out, format).map(|_| out.into_inner()).map_err(|e| e.to_string())
+    };
+    let image = match tokio::task::spawn_blocking(render).await {
+        Ok(Ok(image)) => Bytes::from(image),
+        Ok(Err(e)) => {
+            warn!(error = %e, "Wallpaper encoding failed");
+            return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image").into_response();
+        }
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to encode image").into_response(),
+    };
+    info!(sat = layer.sat, timestamp, width, height, side, zoom, missing, bytes = image.len(), "Made wallpaper");
+
+    let cache_control = if missing == 0 && time.is_some() {
+        format!("public, max-age={}, immutable", CONFIG.tile_max_age)
+    } else {
+        "no-cache".to_string()
+    };
+    let content_type = if jpeg { "image/jpeg" } else { "image/png" };
+    let response = (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (header::CACHE_CONTROL, cache_control),
+            (MISSING_HEADER, missing.to_string()),
+        ],
+        image,
+    )
+        .into_response();
+    let response = if offline::enabled() { offline::mark(response) } else { response };
+    layer.mark(response)
+}
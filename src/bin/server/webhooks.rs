@@ -0,0 +1,115 @@
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, warn};
+use crate::config::{Webhook, CONFIG};
+use crate::events::{self, with_links, NewFrame, Subscription};
+use crate::upstream::HTTP_CLIENT;
+use crate::{satellites, Params};
+
+// Each body's HMAC-SHA256 under the webhook's secret, as "sha256={hex}"
+const SIGNATURE_HEADER: &str = "x-peepsat-signature";
+const EVENT_HEADER: &str = "x-peepsat-event";
+// Waits before each retry of a delivery that failed
+const RETRY_DELAYS: [Duration; 2] = [Duration::from_secs(5), Duration::from_secs(30)];
+
+// Where a webhook goes, for logs: its host, since the rest of a chat
+// webhook's URL is its secret
+fn host(webhook: &Webhook) -> String {
+    reqwest::Url::parse(&webhook.url).ok().and_then(|url| url.host_str().map(str::to_string)).unwrap_or_default()
+}
+
+// The body announcing `frame` in the webhook's format
+fn body(webhook: &Webhook, frame: &NewFrame) -> Value {
+    let data = with_links(&frame.data);
+    let field = |name: &str| data.get(name).and_then(Value::as_str).unwrap_or_default();
+    let name = satellites::find(field("sat")).map_or(field("sat"), |s| s.name);
+    let text = format!("New {} {} frame, {}: {}", name, satellites::product_name(field("product")), field("time"), field("preview_url"));
+    match webhook.format.as_str() {
+        "slack" => json!({ "text": text }),
+        "discord" => json!({ "content": text }),
+        _ => {
+            let mut body = json!({ "event": "frame" });
+            if let (Some(body), Some(fields)) = (body.as_object_mut(), data.as_object()) {
+                body.extend(fields.iter().map(|(k, v)| (k.clone(), v.clone())));
+            }
+            body
+        }
+    }
+}
+
+fn signature(secret: &str, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(body);
+    let digest = mac.finalize().into_bytes();
+    format!("sha256={}", digest.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+}
+
+// POST `frame` to the webhook, retrying a couple of times on network
+// errors, 5xx replies and 429s
+async fn deliver(webhook: &Webhook, frame: &NewFrame) {
+    let body = body(webhook, frame).to_string();
+    for attempt in 0..=RETRY_DELAYS.len() {
+        if attempt > 0 {
+            tokio::time::sleep(RETRY_DELAYS[attempt - 1]).await;
+        }
+        let mut request = HTTP_CLIENT
+            .post(&webhook.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, "frame")
+            .body(body.clone());
+        if let Some(secret) = &webhook.secret {
+            request = request.header(SIGNATURE_HEADER, signature(secret, body.as_bytes()));
+        }
+        match request.send().await {
+            Ok(response) if response.status().is_success() => {
+                debug!(host = host(webhook), timestamp = frame.timestamp, "Webhook delivered");
+                return;
+            }
+            // The target turned it down; sending it again won't help
+            Ok(response) if response.status().is_client_error() && response.status() != reqwest::StatusCode::TOO_MANY_REQUESTS => {
+                warn!(host = host(webhook), status = response.status().as_u16(), "Webhook rejected");
+                return;
+            }
+            Ok(response) => warn!(host = host(webhook), status = response.status().as_u16(), attempt, "Webhook failed"),
+            Err(e) => warn!(host = host(webhook), error = %e, attempt, "Webhook failed"),
+        }
+    }
+}
+
+/// POST each new frame of each of `webhooks`' satellites to its URL, one
+/// delivery at a time per webhook, so a slow one holds up only itself.
+pub fn spawn_senders() {
+    for webhook in &CONFIG.webhooks {
+        let sats = events::announced_satellites(&webhook.satellites);
+        let mut params = Params::from([("sats".to_string(), sats.join(","))]);
+        if let Some(product) = &webhook.product {
+            params.insert("product".to_string(), product.clone());
+        }
+        let layers = match events::layers(&params) {
+            Ok(layers) => layers,
+            Err(message) => {
+                warn!(host = host(webhook), error = message, "Webhook: not sending");
+                continue;
+            }
+        };
+        info!(host = host(webhook), ?sats, "Sending new frames to webhook");
+        tokio::spawn(async move {
+            let mut frames = events::new_frames();
+            // Followed for as long as the server runs
+            let Some(subscription) = Subscription::new(&layers) else {
+                warn!("Webhook: too many layers being followed");
+                return;
+            };
+            loop {
+                match frames.recv().await {
+                    Ok(frame) if frame.claimed && subscription.wants(&frame) => deliver(webhook, &frame).await,
+                    Ok(_) | Err(RecvError::Lagged(_)) => {}
+                    Err(RecvError::Closed) => return,
+                }
+            }
+        });
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use axum::extract::Query;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use futures_util::future::join_all;
+use crate::cache::DEFAULT_SECTOR;
+use crate::config::CONFIG;
+use crate::dates::{iso, parse_time};
+use crate::frames::{timestamps_in, Layer};
+use crate::satellites::{self, Satellite, SATELLITES};
+use crate::xyz::{reprojected_tile, MAX_ZOOM};
+use crate::{get_cdn_url, slider_latest, Params};
+
+/// The one tile matrix set: Google Maps-compatible Web Mercator, whose
+/// zoom z is a 2^z by 2^z grid of 256-pixel tiles.
+pub const TILE_MATRIX_SET: &str = "WebMercatorQuad";
+// Another name clients use for it
+const GOOGLE_TILE_MATRIX_SET: &str = "GoogleMapsCompatible";
+pub const ZOOM_0_SCALE: f64 = 559082264.0287178;
+pub const WORLD_EDGE: f64 = 20037508.3427892;
+/// Latitude and longitude a full disk reaches out to from under the
+/// satellite.
+pub const DISK_REACH: f64 = 81.3;
+// The most frame times listed for each layer, a loop's worth
+const MAX_TIMES: usize = 24;
+
+/// Layers are "{sat}_{product}", e.g. "19_geocolor"; satellite keys have
+/// no underscores.
+pub fn layer_id(satellite: &Satellite, product: &str) -> String {
+    format!("{}_{}", satellite.key, product)
+}
+
+/// The full-disk layer named `id`, with the query parameters it stands
+/// for; `None` if there's no such satellite or product.
+pub fn find_layer(id: &str) -> Option<(Layer, Params)> {
+    let (sat, product) = id.split_once('_')?;
+    satellites::find(sat)?;
+    let mut params = Params::new();
+    params.insert("sat".to_string(), sat.to_string());
+    params.insert("sector".to_string(), DEFAULT_SECTOR.to_string());
+    params.insert("product".to_string(), product.to_string());
+    Some((Layer::from_params(&params)?, params))
+}
+
+/// `text` escaped for XML text and attributes.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// The server's address as the client reached it.
+pub fn base_url(headers: &HeaderMap) -> String {
+    let host = headers.get(header::HOST).and_then(|h| h.to_str().ok()).unwrap_or("localhost");
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|h| h.to_str().ok())
+        .unwrap_or(if CONFIG.tls_cert.is_some() { "https" } else { "http" });
+    format!("{}://{}", scheme, host)
+}
+
+// An OWS exception report, as WMTS clients expect errors
+fn exception(status: StatusCode, code: &str, locator: &str, text: &str) -> Response {
+    let body = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ExceptionReport xmlns="http://www.opengis.net/ows/1.1" version="1.1.0" xml:lang="en">
+  <Exception exceptionCode="{}" locator="{}"><ExceptionText>{}</ExceptionText></Exception>
+</ExceptionReport>
+"#,
+        code,
+        escape(locator),
+        escape(text)
+    );
+    (status, [(header::CONTENT_TYPE, "application/xml")], body).into_response()
+}
+
+/// The recent frame times of a satellite's default product, newest first.
+pub async fn frame_times(satellite: &Satellite) -> Vec<i64> {
+    let mut params = Params::new();
+    params.insert("sat".to_string(), satellite.key.to_string());
+    let cdn = get_cdn_url(satellite.key, &params);
+    let latest = slider_latest(satellite.key.to_string(), cdn, &params).await;
+    if !latest.status().is_success() {
+        return Vec::new();
+    }
+    let mut times: Vec<i64> = timestamps_in(latest)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|t| parse_time(&t.to_string()))
+        .collect();
+    times.sort_unstable_by(|a, b| b.cmp(a));
+    times.truncate(MAX_TIMES);
+    times
+}
+
+fn capabilities(base: &str, times: &[Vec<i64>]) -> String {
+    let mut xml = String::from(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1" xmlns:xlink="http://www.w3.org/1999/xlink" version="1.0.0">
+  <ows:ServiceIdentification>
+    <ows:Title>peepsat</ows:Title>
+    <ows:Abstract>Geostationary satellite imagery reprojected to Web Mercator</ows:Abstract>
+    <ows:ServiceType>OGC WMTS</ows:ServiceType>
+    <ows:ServiceTypeVersion>1.0.0</ows:ServiceTypeVersion>
+  </ows:ServiceIdentification>
+  <ows:OperationsMetadata>
+"#,
+    );
+    for operation in ["GetCapabilities", "GetTile"] {
+        let _ = write!(
+            xml,
+            r#"    <ows:Operation name="{}">
+      <ows:DCP><ows:HTTP><ows:Get xlink:href="{}/wmts?">
+        <ows:Constraint name="GetEncoding"><ows:AllowedValues><ows:Value>KVP</ows:Value></ows:AllowedValues></ows:Constraint>
+      </ows:Get></ows:HTTP></ows:DCP>
+    </ows:Operation>
+"#,
+            operation,
+            escape(base)
+        );
+    }
+    xml.push_str("  </ows:OperationsMetadata>\n  <Contents>\n");
+
+    for (satellite, times) in SATELLITES.iter().zip(times) {
+        let west = (satellite.longitude - DISK_REACH).max(-180.0);
+        let east = (satellite.longitude + DISK_REACH).min(180.0);
+        let (default, values) = match times.first() {
+            Some(&newest) => (iso(newest), times.iter().map(|&t| format!("<Value>{}</Value>", iso(t))).collect()),
+            None => ("current".to_string(), "<Value>current</Value>".to_string()),
+        };
+        for product in satellites::products(satellite) {
+            let id = layer_id(satellite, &product);
+            let _ = write!(
+                xml,
+                r#"    <Layer>
+      <ows:Title>{name} {product_name}</ows:Title>
+      <ows:WGS84BoundingBox><ows:LowerCorner>{west:.1} -{reach}</ows:LowerCorner><ows:UpperCorner>{east:.1} {reach}</ows:UpperCorner></ows:WGS84BoundingBox>
+      <ows:Identifier>{id}</ows:Identifier>
+      <Style isDefault="true"><ows:Identifier>default</ows:Identifier></Style>
+      <Format>image/png</Format>
+      <Dimension>
+        <ows:Identifier>Time</ows:Identifier>
+        <UOM>ISO8601</UOM>
+        <Default>{default}</Default>
+        <Current>true</Current>
+        {values}
+      </Dimension>
+      <TileMatrixSetLink><TileMatrixSet>{set}</TileMatrixSet></TileMatrixSetLink>
+      <ResourceURL format="image/png" resourceType="tile" template="{base}/xyz/{sat}/{{Time}}/{{TileMatrix}}/{{TileCol}}/{{TileRow}}.png?product={product}"/>
+    </Layer>
+"#,
+                name = escape(satellite.name),
+                product_name = escape(&satellites::product_name(&product)),
+                reach = DISK_REACH,
+                set = TILE_MATRIX_SET,
+                base = escape(base),
+                sat = satellite.key,
+            );
+        }
+    }
+
+    let _ = writeln!(
+        xml,
+        "    <TileMatrixSet>\n      <ows:Identifier>{}</ows:Identifier>\n      <ows:SupportedCRS>urn:ogc:def:crs:EPSG::3857</ows:SupportedCRS>\n      <WellKnownScaleSet>urn:ogc:def:wkss:OGC:1.0:GoogleMapsCompatible</WellKnownScaleSet>",
+        TILE_MATRIX_SET
+    );
+    for zoom in 0..=MAX_ZOOM {
+        let _ = writeln!(
+            xml,
+            "      <TileMatrix><ows:Identifier>{zoom}</ows:Identifier><ScaleDenominator>{scale}</ScaleDenominator><TopLeftCorner>-{edge} {edge}</TopLeftCorner><TileWidth>256</TileWidth><TileHeight>256</TileHeight><MatrixWidth>{size}</MatrixWidth><MatrixHeight>{size}</MatrixHeight></TileMatrix>",
+            scale = ZOOM_0_SCALE / (1u64 << zoom) as f64,
+            edge = WORLD_EDGE,
+            size = 1u32 << zoom,
+        );
+    }
+    xml.push_str("    </TileMatrixSet>\n  </Contents>\n</Capabilities>\n");
+    xml
+}
+
+async fn get_tile(params: &HashMap<String, String>, headers: &HeaderMap) -> Response {
+    if let Some(name) = ["layer", "tilematrixset", "tilematrix", "tilerow", "tilecol"].into_iter().find(|n| !params.contains_key(*n)) {
+        return exception(StatusCode::BAD_REQUEST, "MissingParameterValue", name, &format!("{} is required", name));
+    }
+    let get = |name: &str| params[name].as_str();
+    let (layer_name, set, matrix) = (get("layer"), get("tilematrixset"), get("tilematrix"));
+    let Some((layer, layer_params)) = find_layer(layer_name) else {
+        return exception(StatusCode::BAD_REQUEST, "InvalidParameterValue", "layer", "Unknown layer");
+    };
+    if set != TILE_MATRIX_SET && set != GOOGLE_TILE_MATRIX_SET {
+        return exception(StatusCode::BAD_REQUEST, "InvalidParameterValue", "tilematrixset", "Unknown tile matrix set");
+    }
+    if params.get("format").is_some_and(|f| f != "image/png") {
+        return exception(StatusCode::BAD_REQUEST, "InvalidParameterValue", "format", "Only image/png is offered");
+    }
+    // Some clients send the matrix as "{set}:{zoom}"
+    let matrix = matrix.rsplit(':').next().unwrap_or(matrix);
+    let Some(zoom) = matrix.parse::<u32>().ok().filter(|&z| z <= MAX_ZOOM) else {
+        return exception(StatusCode::BAD_REQUEST, "InvalidParameterValue", "tilematrix", "Unknown tile matrix");
+    };
+    let (Ok(y), Ok(x)) = (get("tilerow").parse::<u32>(), get("tilecol").parse::<u32>()) else {
+        return exception(StatusCode::BAD_REQUEST, "InvalidParameterValue", "tilerow", "tilerow and tilecol must be numbers");
+    };
+    if x >= 1 << zoom || y >= 1 << zoom {
+        return exception(StatusCode::BAD_REQUEST, "TileOutOfRange", if x >= 1 << zoom { "tilecol" } else { "tilerow" }, "Tile out of range");
+    }
+    let time = match params.get("time").map(String::as_str) {
+        None | Some("") | Some("current") | Some("default") => None,
+        Some(text) => match parse_time(text) {
+            Some(time) => Some(time),
+            None => return exception(StatusCode::BAD_REQUEST, "InvalidParameterValue", "time", "time must be an ISO 8601 time"),
+        },
+    };
+    reprojected_tile(&layer, &layer_params, time, zoom, x, y, headers).await
+}
+
+/// `GET /wmts?SERVICE=WMTS&REQUEST=GetCapabilities|GetTile`: the
+/// reprojected full disks as an OGC WMTS 1.0 service (KVP, with RESTful
+/// tile URLs pointing at `/xyz`). Each satellite and product is a layer
+/// with a Time dimension listing its recent frames, in the Web Mercator
+/// tile matrix set.
+pub async fn handle_wmts(Query(params): Query<Params>, headers: HeaderMap) -> Response {
+    // KVP parameter names aren't case-sensitive
+    let params: HashMap<String, String> = params.into_iter().map(|(k, v)| (k.to_ascii_lowercase(), v)).collect();
+    if params.get("service").is_some_and(|s| !s.eq_ignore_ascii_case("WMTS")) {
+        return exception(StatusCode::BAD_REQUEST, "InvalidParameterValue", "service", "service must be WMTS");
+    }
+    match params.get("request").map(String::as_str) {
+        Some(r) if r.eq_ignore_ascii_case("GetCapabilities") => {
+            let times = join_all(SATELLITES.iter().map(frame_times)).await;
+            (
+                [(header::CONTENT_TYPE, "application/xml"), (header::CACHE_CONTROL, "no-cache")],
+                capabilities(&base_url(&headers), &times),
+            )
+                .into_response()
+        }
+        Some(r) if r.eq_ignore_ascii_case("GetTile") => get_tile(&params, &headers).await,
+        Some(r) => exception(StatusCode::BAD_REQUEST, "OperationNotSupported", r, "Unsupported request"),
+        None => exception(StatusCode::BAD_REQUEST, "MissingParameterValue", "request", "request is required"),
+    }
+}
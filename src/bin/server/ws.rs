@@ -0,0 +1,149 @@
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::Query;
+use axum::response::Response;
+use serde_json::{json, Value};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info};
+use crate::admin::queue_prefetch;
+use crate::config::CONFIG;
+use crate::events::{self, Subscription};
+use crate::{prefetch, Params};
+
+// Longest message taken from a client; they're all small JSON objects
+const MAX_MESSAGE_BYTES: usize = 16 * 1024;
+
+// A client message's fields as query parameters, so they're read as the
+// HTTP endpoints read them: strings as they are, numbers as digits and
+// lists joined with commas
+fn message_params(message: &Value) -> Params {
+    let Some(fields) = message.as_object() else {
+        return Params::new();
+    };
+    let text = |value: &Value| match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    };
+    fields
+        .iter()
+        .filter_map(|(name, value)| {
+            let value = match value {
+                Value::Array(items) => Some(items.iter().filter_map(text).collect::<Vec<_>>().join(",")),
+                value => text(value),
+            };
+            Some((name.clone(), value?))
+        })
+        .collect()
+}
+
+fn error(message: &str) -> Value {
+    json!({ "type": "error", "message": message })
+}
+
+fn frame(data: &Value) -> Value {
+    let mut message = json!({ "type": "frame" });
+    if let (Some(message), Some(fields)) = (message.as_object_mut(), data.as_object()) {
+        message.extend(fields.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+    message
+}
+
+// Follow the layers `params` ask for instead of the current ones, with
+// the replies to send: their newest frames, then what's followed
+async fn subscribe(subscription: &mut Option<Subscription>, params: &Params) -> Vec<Value> {
+    let layers = match events::layers(params) {
+        Ok(layers) => layers,
+        Err(message) => return vec![error(&message)],
+    };
+    // The old layers go first, so a client can't hold more than its own
+    *subscription = None;
+    *subscription = Subscription::new(&layers);
+    if subscription.is_none() {
+        return vec![error("Too many layers being followed")];
+    }
+    let mut replies: Vec<Value> = events::newest(&layers).await.iter().map(|f| frame(&f.data)).collect();
+    let followed: Vec<Value> = layers.iter().map(|(l, _)| json!({ "sat": l.sat, "sector": l.sector, "product": l.product })).collect();
+    replies.push(json!({ "type": "subscribed", "layers": followed }));
+    replies
+}
+
+// The replies to one client message
+async fn handle_message(subscription: &mut Option<Subscription>, text: &str) -> Vec<Value> {
+    let Ok(message) = serde_json::from_str::<Value>(text) else {
+        return vec![error("Messages must be JSON objects")];
+    };
+    let params = message_params(&message);
+    match message.get("type").and_then(Value::as_str) {
+        Some("subscribe") => subscribe(subscription, &params).await,
+        Some("unsubscribe") => {
+            *subscription = None;
+            vec![json!({ "type": "subscribed", "layers": [] })]
+        }
+        Some("prefetch") if CONFIG.offline => vec![error("offline")],
+        Some("prefetch") => match queue_prefetch(&params) {
+            Ok(id) => vec![json!({ "type": "prefetch_queued", "id": id })],
+            Err(message) => vec![error(message)],
+        },
+        Some("ping") => vec![json!({ "type": "pong" })],
+        _ => vec![error("type must be subscribe, unsubscribe, prefetch or ping")],
+    }
+}
+
+async fn send(socket: &mut WebSocket, message: &Value) -> bool {
+    socket.send(Message::Text(message.to_string().into())).await.is_ok()
+}
+
+// Relay new frames and prefetch progress to the client and answer its
+// messages, until either side hangs up
+async fn run(mut socket: WebSocket, params: Params) {
+    let mut frames = events::new_frames();
+    let mut jobs = prefetch::progress();
+    let mut subscription = None;
+    if params.contains_key("sat") || params.contains_key("sats") {
+        for reply in subscribe(&mut subscription, &params).await {
+            if !send(&mut socket, &reply).await {
+                return;
+            }
+        }
+    }
+    loop {
+        let replies = tokio::select! {
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => handle_message(&mut subscription, &text).await,
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by the library
+                Some(Ok(_)) => continue,
+            },
+            new_frame = frames.recv() => match new_frame {
+                Ok(new_frame) if subscription.as_ref().is_some_and(|s| s.wants(&new_frame)) => vec![frame(&new_frame.data)],
+                Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+            job = jobs.recv() => match job {
+                Ok(job) => vec![json!({ "type": "prefetch", "job": job })],
+                Err(RecvError::Lagged(_)) => continue,
+                Err(RecvError::Closed) => break,
+            },
+        };
+        for reply in &replies {
+            if !send(&mut socket, reply).await {
+                return;
+            }
+        }
+    }
+    debug!("WebSocket closed");
+}
+
+/// `GET /ws`: a WebSocket carrying JSON messages both ways, each an object
+/// with a `type`. The server sends `frame` (a new frame of a layer being
+/// followed, as `/api/events` describes it), `prefetch` (a prefetch job's
+/// progress, as `/api/prefetch/{id}` gives it, for every client),
+/// `subscribed`, `prefetch_queued`, `pong` and `error`. Clients send
+/// `subscribe` (with `sats`, `sector` and `product` as for `/api/events`,
+/// replacing what they followed), `unsubscribe`, `prefetch` (with `sat`,
+/// `from`, `to` and `z` as for `/api/prefetch`) and `ping`. Query
+/// parameters as for `/api/events` subscribe from the start.
+pub async fn handle_ws(upgrade: WebSocketUpgrade, Query(params): Query<Params>) -> Response {
+    info!("WebSocket opened");
+    upgrade.max_message_size(MAX_MESSAGE_BYTES).on_upgrade(move |socket| run(socket, params))
+}
@@ -0,0 +1,121 @@
+use std::f64::consts::PI;
+use axum::body::Bytes;
+use axum::extract::{Path, Query};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use tracing::Span;
+use crate::cache::{cache_key, get_cached_tile, put_cached_tile, TileFormat, DEFAULT_SECTOR};
+use crate::dates::parse_time;
+use crate::frames::Layer;
+use crate::reproject::{self, frame_timestamp, mercator_latitude, reproject, Projection, View};
+use crate::{cached_tile_response, mosaic, offline, tile_response, Params};
+
+// Reprojected tiles are cached as "xyz/{product}/{sat}_{timestamp}_{z}_{x}_{y}"
+const CACHE_SECTOR: &str = "xyz";
+const TILE_SIZE: u32 = 256;
+/// Deepest zoom served; past this the full-disk tiles are only magnified.
+pub const MAX_ZOOM: u32 = 10;
+// The most full-disk tiles resampled for one of ours
+const MAX_SOURCE_TILES: usize = 16;
+
+/// Web Mercator tile `zoom`/`x`/`y` as a view to reproject onto.
+pub fn tile_view(zoom: u32, x: u32, y: u32) -> View {
+    let tiles = (1u32 << zoom) as f64;
+    let longitude = |x: u32| x as f64 / tiles * 360.0 - 180.0;
+    let latitude = |y: u32| mercator_latitude(PI * (1.0 - 2.0 * y as f64 / tiles));
+    View {
+        projection: Projection::Mercator,
+        bbox: (longitude(x), latitude(y + 1), longitude(x + 1), latitude(y)),
+        width: TILE_SIZE,
+        height: TILE_SIZE,
+    }
+}
+
+/// `GET /xyz/{sat}/{t}/{z}/{x}/{y}.png`: a frame's full disk reprojected
+/// onto a Web Mercator tile, `x` being the column and `y` the row as in
+/// Leaflet and MapLibre. `t` is a frame timestamp or `latest`; takes the
+/// same `product` and `cdn` as `/slider-tile`. Transparent where the
+/// satellite can't see. `mosaic` in place of the satellite gives the
+/// several-satellite mosaic instead.
+pub async fn handle_xyz_tile(
+    Path((sat, t, zoom, x, file)): Path<(String, String, u32, u32, String)>,
+    Query(mut params): Query<Params>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(y) = file.strip_suffix(".png").and_then(|y| y.parse::<u32>().ok()) else {
+        return (StatusCode::NOT_FOUND, "Not found").into_response();
+    };
+    if zoom > MAX_ZOOM {
+        return (StatusCode::BAD_REQUEST, format!("z is at most {}", MAX_ZOOM)).into_response();
+    }
+    if x >= 1 << zoom || y >= 1 << zoom {
+        return (StatusCode::BAD_REQUEST, "Tile out of range").into_response();
+    }
+    let time = match t.as_str() {
+        "latest" | "current" => None,
+        t => match parse_time(t) {
+            Some(time) => Some(time),
+            None => return (StatusCode::BAD_REQUEST, "t must be a frame timestamp, e.g. 20241016120000, or latest").into_response(),
+        },
+    };
+    if sat == mosaic::SAT {
+        return mosaic::tile(&params, time, zoom, x, y, &headers).await;
+    }
+    params.insert("sat".to_string(), sat);
+    params.insert("sector".to_string(), DEFAULT_SECTOR.to_string());
+    let Some(layer) = Layer::from_params(&params) else {
+        return (StatusCode::BAD_REQUEST, "Unknown satellite or product").into_response();
+    };
+    reprojected_tile(&layer, &params, time, zoom, x, y, &headers).await
+}
+
+/// Web Mercator tile `zoom`/`x`/`y` of `layer`'s full disk at `time`, or
+/// the newest frame if that's `None`, from the cache or resampled from the
+/// full-disk tiles under it. `params` are the query parameters the layer
+/// came from.
+pub async fn reprojected_tile(
+    layer: &Layer,
+    params: &Params,
+    time: Option<i64>,
+    zoom: u32,
+    x: u32,
+    y: u32,
+    headers: &HeaderMap,
+) -> Response {
+    let span = Span::current();
+    span.record("sat", layer.sat.as_str());
+    span.record("x", x);
+    span.record("y", y);
+    span.record("z", zoom);
+
+    let timestamp = match frame_timestamp(layer, params, time).await {
+        Ok(timestamp) => timestamp,
+        Err(response) => return response,
+    };
+    // The newest frame moves on, so only fixed frames are kept by the browser
+    let response_timestamp = if time.is_none() { "0" } else { timestamp.as_str() };
+
+    let key = cache_key(&layer.sat, CACHE_SECTOR, &layer.product, &timestamp, zoom, x, y);
+    if let Some((data, format)) = get_cached_tile(&key).await {
+        span.record("cache", "HIT");
+        let response = cached_tile_response(data, format, response_timestamp, headers).await;
+        let response = if offline::enabled() { offline::mark(response) } else { response };
+        return layer.mark(response);
+    }
+    span.record("cache", "MISS");
+
+    let reprojection = match reproject(layer, &timestamp, tile_view(zoom, x, y), MAX_SOURCE_TILES).await {
+        Ok(reprojection) => reprojection,
+        Err(response) => return response,
+    };
+    let complete = reprojection.missing == 0;
+    let png = Bytes::from(reproject::png(reprojection.image));
+    // Tiles with holes are made again once the rest of the frame turns up,
+    // and ones off the disk are cheaper to make than to store
+    if complete && reprojection.sources > 0 {
+        put_cached_tile(&key, &png).await;
+    }
+    let response = tile_response(png, TileFormat::Png, "MISS", if complete { response_timestamp } else { "0" }, headers);
+    let response = if offline::enabled() { offline::mark(response) } else { response };
+    layer.mark(response)
+}
@@ -0,0 +1,74 @@
+use flate2::Crc;
+use crate::dates::civil_from_days;
+
+// Just enough of the zip format for a KMZ: stored (uncompressed) files,
+// which suits images that are compressed already, in one archive under
+// 4 GB
+const LOCAL_HEADER: u32 = 0x04034b50;
+const CENTRAL_HEADER: u32 = 0x02014b50;
+const END_OF_DIRECTORY: u32 = 0x06054b50;
+// Version 2.0, the oldest with everything used here
+const VERSION: u16 = 20;
+
+// MS-DOS time and date fields of a time, which go back to 1980
+fn dos_time(seconds: i64) -> (u16, u16) {
+    let (year, month, day) = civil_from_days(seconds.div_euclid(86400));
+    let time = seconds.rem_euclid(86400);
+    let dos_time = ((time / 3600) << 11) | ((time / 60 % 60) << 5) | (time % 60 / 2);
+    let dos_date = ((year.clamp(1980, 2107) - 1980) << 9) | (month << 5) | day;
+    (dos_time as u16, dos_date as u16)
+}
+
+/// A zip archive of `files` (name and contents), in that order, all
+/// dated `modified` (seconds since the epoch).
+pub fn stored(files: &[(String, Vec<u8>)], modified: i64) -> Vec<u8> {
+    let (time, date) = dos_time(modified);
+    let mut out = Vec::with_capacity(files.iter().map(|(name, data)| 100 + 2 * name.len() + data.len()).sum());
+    let mut directory = Vec::new();
+    for (name, data) in files {
+        let mut crc = Crc::new();
+        crc.update(data);
+        let offset = out.len() as u32;
+        // Fields the local and central headers share, from the version
+        // needed to extract on
+        let mut common = Vec::with_capacity(26);
+        common.extend_from_slice(&VERSION.to_le_bytes());
+        // No flags; stored
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&0u16.to_le_bytes());
+        common.extend_from_slice(&time.to_le_bytes());
+        common.extend_from_slice(&date.to_le_bytes());
+        common.extend_from_slice(&crc.sum().to_le_bytes());
+        common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        common.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        // No extra field
+        common.extend_from_slice(&0u16.to_le_bytes());
+
+        out.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        out.extend_from_slice(&common);
+        out.extend_from_slice(name.as_bytes());
+        out.extend_from_slice(data);
+
+        directory.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        directory.extend_from_slice(&VERSION.to_le_bytes());
+        directory.extend_from_slice(&common);
+        // No comment, first disk, no attributes, then where the file is
+        directory.extend_from_slice(&[0u8; 10]);
+        directory.extend_from_slice(&offset.to_le_bytes());
+        directory.extend_from_slice(name.as_bytes());
+    }
+
+    let directory_offset = out.len() as u32;
+    out.extend_from_slice(&directory);
+    out.extend_from_slice(&END_OF_DIRECTORY.to_le_bytes());
+    // This disk and the directory's both the first
+    out.extend_from_slice(&[0u8; 4]);
+    out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    out.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+    out.extend_from_slice(&directory_offset.to_le_bytes());
+    // No comment
+    out.extend_from_slice(&0u16.to_le_bytes());
+    out
+}
@@ -0,0 +1,309 @@
+use cgmath::{Deg, InnerSpace, Matrix4, One, Quaternion, Rad, Rotation, Rotation3, Vector2, Vector3, Zero};
+use wasm_bindgen::prelude::*;
+
+const FOV: Deg<f32> = Deg(45.0);
+/// From the Earth's centre, in Earth radii
+pub const DEFAULT_DISTANCE: f32 = 3.0;
+// Closest the camera gets to the surface, Earth radii
+const MIN_ALTITUDE: f32 = 0.02;
+const MAX_DISTANCE: f32 = 10.0;
+// How far the globe can be dragged off centre, Earth radii
+const MAX_PAN: f32 = 1.5;
+// How quickly motion dies away once let go, per second
+const DAMPING: f32 = 4.0;
+// Slower than this, per second, counts as stopped
+const REST: f32 = 1e-3;
+// A drag held still this long before release doesn't fling, ms
+const FLING_WINDOW: f64 = 50.0;
+// Zoom per pixel of wheel, as a factor of altitude on a log scale
+const WHEEL_ZOOM: f32 = 0.002;
+
+/// Where the camera is, as `WgpuApp::get_camera` gives it and
+/// `WgpuApp::set_camera` takes it.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug)]
+pub struct CameraView {
+    /// Point on the Earth in the middle of the view, degrees
+    pub longitude: f64,
+    pub latitude: f64,
+    /// Compass direction at the top of the view, degrees east of north
+    pub heading: f64,
+    /// From the Earth's centre, in Earth radii
+    pub distance: f64,
+    /// How far the globe is moved off the middle of the view, Earth radii
+    pub pan_x: f64,
+    pub pan_y: f64,
+}
+
+#[wasm_bindgen]
+impl CameraView {
+    #[wasm_bindgen(constructor)]
+    pub fn new(longitude: f64, latitude: f64, heading: f64, distance: f64, pan_x: f64, pan_y: f64) -> CameraView {
+        CameraView { longitude, latitude, heading, distance, pan_x, pan_y }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Drag {
+    /// Turn the globe as if it were a trackball under the pointer
+    Orbit,
+    /// Move the globe across the view
+    Pan,
+}
+
+// A drag in progress: what it does, and where and when (ms) the pointer
+// last was, in normalised device coordinates
+struct Dragging {
+    kind: Drag,
+    at: Vector2<f32>,
+    time: f64,
+}
+
+/// An orbiting camera looking at the globe, which keeps moving for a
+/// little while after it's let go.
+pub struct Camera {
+    // Turns the globe's frame (north +y, 0°E +z) into the view's
+    rotation: Quaternion<f32>,
+    distance: f32,
+    pan: Vector2<f32>,
+    aspect: f32,
+    dragging: Option<Dragging>,
+    // Angular velocity in view space: axis scaled by radians per second
+    spin: Vector3<f32>,
+    pan_velocity: Vector2<f32>,
+    // Change in the log of altitude per second, and where it heads
+    zoom_velocity: f32,
+    zoom_toward: Vector2<f32>,
+    // Moved since the last frame was drawn
+    changed: bool,
+}
+
+impl Camera {
+    pub fn new() -> Camera {
+        Camera {
+            rotation: Quaternion::one(),
+            distance: DEFAULT_DISTANCE,
+            pan: Vector2::zero(),
+            aspect: 1.0,
+            dragging: None,
+            spin: Vector3::zero(),
+            pan_velocity: Vector2::zero(),
+            zoom_velocity: 0.0,
+            zoom_toward: Vector2::zero(),
+            changed: true,
+        }
+    }
+
+    pub fn set_aspect(&mut self, aspect: f32) {
+        if aspect != self.aspect {
+            self.aspect = aspect;
+            self.changed = true;
+        }
+    }
+
+    /// Whether the view has changed since this was last asked.
+    pub fn take_changed(&mut self) -> bool {
+        std::mem::take(&mut self.changed)
+    }
+
+    /// Projection and view together, with OpenGL clip space conventions.
+    pub fn view_projection(&self) -> Matrix4<f32> {
+        let near = ((self.distance - 1.0) * 0.5).max(0.005);
+        let projection = cgmath::perspective(FOV, self.aspect, near, self.distance + 2.0);
+        let view = Matrix4::from_translation(self.pan.extend(-self.distance)) * Matrix4::from(self.rotation);
+        projection * view
+    }
+
+    pub fn view(&self) -> CameraView {
+        // The globe's point nearest the camera, and its north in the view
+        let facing = self.rotation.invert().rotate_vector(Vector3::unit_z());
+        let north = self.rotation.rotate_vector(Vector3::unit_y());
+        CameraView {
+            longitude: facing.x.atan2(facing.z).to_degrees() as f64,
+            latitude: facing.y.clamp(-1.0, 1.0).asin().to_degrees() as f64,
+            heading: (-north.x).atan2(north.y).to_degrees() as f64,
+            distance: self.distance as f64,
+            pan_x: self.pan.x as f64,
+            pan_y: self.pan.y as f64,
+        }
+    }
+
+    /// Jump to `view`, stopping any motion.
+    pub fn set_view(&mut self, view: &CameraView) {
+        self.rotation = Quaternion::from_angle_z(Deg(view.heading as f32))
+            * Quaternion::from_angle_x(Deg(view.latitude as f32))
+            * Quaternion::from_angle_y(Deg(-view.longitude as f32));
+        self.distance = (view.distance as f32).clamp(1.0 + MIN_ALTITUDE, MAX_DISTANCE);
+        self.pan = Vector2::new(view.pan_x as f32, view.pan_y as f32);
+        self.clamp_pan();
+        self.stop();
+        self.changed = true;
+    }
+
+    /// Turn to face `longitude`, `latitude` with north up.
+    pub fn look_at(&mut self, longitude: f64, latitude: f64) {
+        let view = CameraView { longitude, latitude, heading: 0.0, ..self.view() };
+        self.set_view(&view);
+    }
+
+    pub fn stop(&mut self) {
+        self.spin = Vector3::zero();
+        self.pan_velocity = Vector2::zero();
+        self.zoom_velocity = 0.0;
+    }
+
+    pub fn dragging(&self) -> bool {
+        self.dragging.is_some()
+    }
+
+    /// Start a drag at `at`, in normalised device coordinates, at `time` ms.
+    pub fn begin_drag(&mut self, kind: Drag, at: Vector2<f32>, time: f64) {
+        self.stop();
+        self.dragging = Some(Dragging { kind, at, time });
+    }
+
+    pub fn drag_to(&mut self, at: Vector2<f32>, time: f64) {
+        let Some(Dragging { kind, at: from, time: then }) = self.dragging else {
+            return;
+        };
+        // Events can share a timestamp
+        let seconds = ((time - then) / 1000.0).max(0.001) as f32;
+        match kind {
+            Drag::Orbit => {
+                let turn = Quaternion::from_arc(self.arcball(from), self.arcball(at), None);
+                // Slower close up, where a small turn goes a long way
+                let speed = ((self.distance - 1.0) / (DEFAULT_DISTANCE - 1.0)).min(1.0);
+                let turn = Quaternion::one().slerp(turn, speed);
+                self.rotate(turn);
+                self.spin = axis_angle(turn) / seconds;
+            }
+            Drag::Pan => {
+                let moved = self.to_world(at - from);
+                self.pan_by(moved);
+                self.pan_velocity = moved / seconds;
+            }
+        }
+        self.dragging = Some(Dragging { kind, at, time });
+    }
+
+    /// Stop dragging without flinging, as when a second finger comes down.
+    pub fn cancel_drag(&mut self) {
+        self.dragging = None;
+        self.stop();
+    }
+
+    /// Let go at `time` ms, flinging the globe on if it was still moving.
+    pub fn end_drag(&mut self, time: f64) {
+        if let Some(dragging) = self.dragging.take() {
+            if time - dragging.time > FLING_WINDOW {
+                self.stop();
+            }
+        }
+    }
+
+    /// Zoom in (negative) or out by `pixels` of mouse wheel, toward `at`.
+    pub fn zoom(&mut self, pixels: f32, at: Vector2<f32>) {
+        // Given the damping, this adds up to `pixels * WHEEL_ZOOM` in all
+        self.zoom_velocity += pixels * WHEEL_ZOOM * DAMPING;
+        self.zoom_toward = at;
+    }
+
+    /// Two fingers moved from `from` to `to`, in normalised device
+    /// coordinates: zoom by how far they spread, turn the view as they
+    /// twist, and move the globe with the point between them.
+    pub fn pinch(&mut self, from: [Vector2<f32>; 2], to: [Vector2<f32>; 2]) {
+        // Square up the coordinates, so that spread and angle mean the same
+        // across the view as up it
+        let square = |p: Vector2<f32>| Vector2::new(p.x * self.aspect, p.y);
+        let (before, after) = (square(from[1]) - square(from[0]), square(to[1]) - square(to[0]));
+        let (middle_before, middle_after) = ((from[0] + from[1]) / 2.0, (to[0] + to[1]) / 2.0);
+
+        self.pan_by(self.to_world(middle_after - middle_before));
+        if before.magnitude() > f32::EPSILON && after.magnitude() > f32::EPSILON {
+            self.zoom_toward = middle_after;
+            self.zoom_by((before.magnitude() / after.magnitude()).ln());
+            let twist = after.y.atan2(after.x) - before.y.atan2(before.x);
+            self.rotate(Quaternion::from_angle_z(Rad(twist)));
+        }
+    }
+
+    /// Move on by `seconds` of momentum.
+    pub fn step(&mut self, seconds: f32) {
+        let decay = (-DAMPING * seconds).exp();
+        if self.dragging.is_none() {
+            let angle = self.spin.magnitude();
+            if angle > REST {
+                self.rotate(Quaternion::from_axis_angle(self.spin / angle, Rad(angle * seconds)));
+                self.spin *= decay;
+            } else {
+                self.spin = Vector3::zero();
+            }
+            if self.pan_velocity.magnitude() > REST {
+                self.pan_by(self.pan_velocity * seconds);
+                self.pan_velocity *= decay;
+            } else {
+                self.pan_velocity = Vector2::zero();
+            }
+        }
+        if self.zoom_velocity.abs() > REST {
+            self.zoom_by(self.zoom_velocity * seconds);
+            self.zoom_velocity *= decay;
+        } else {
+            self.zoom_velocity = 0.0;
+        }
+    }
+
+    fn rotate(&mut self, turn: Quaternion<f32>) {
+        self.rotation = (turn * self.rotation).normalize();
+        self.changed = true;
+    }
+
+    fn pan_by(&mut self, offset: Vector2<f32>) {
+        self.pan += offset;
+        self.clamp_pan();
+        self.changed = true;
+    }
+
+    fn clamp_pan(&mut self) {
+        self.pan.x = self.pan.x.clamp(-MAX_PAN, MAX_PAN);
+        self.pan.y = self.pan.y.clamp(-MAX_PAN, MAX_PAN);
+    }
+
+    // Scale altitude by e^`amount`, keeping what's under `zoom_toward` there
+    fn zoom_by(&mut self, amount: f32) {
+        let altitude = ((self.distance - 1.0) * amount.exp()).clamp(MIN_ALTITUDE, MAX_DISTANCE - 1.0);
+        let distance = 1.0 + altitude;
+        // Whatever its depth, a point stays put on screen if the globe
+        // moves this far across as the camera moves in
+        self.pan_by(self.to_world(self.zoom_toward) * (distance - self.distance) / self.distance);
+        self.distance = distance;
+        self.changed = true;
+    }
+
+    // A distance across the screen in normalised device coordinates as
+    // one across the plane through the globe's centre
+    fn to_world(&self, ndc: Vector2<f32>) -> Vector2<f32> {
+        let half_height = self.distance * (Rad::from(FOV).0 / 2.0).tan();
+        Vector2::new(ndc.x * half_height * self.aspect, ndc.y * half_height)
+    }
+
+    // A point on screen as one on a ball filling the view's shorter side
+    fn arcball(&self, ndc: Vector2<f32>) -> Vector3<f32> {
+        let p = Vector2::new(ndc.x * self.aspect.max(1.0), ndc.y / self.aspect.min(1.0));
+        let d2 = p.magnitude2();
+        if d2 <= 1.0 {
+            p.extend((1.0 - d2).sqrt())
+        } else {
+            p.normalize().extend(0.0)
+        }
+    }
+}
+
+// A rotation's axis scaled by its angle in radians
+fn axis_angle(turn: Quaternion<f32>) -> Vector3<f32> {
+    let sin = turn.v.magnitude();
+    if sin < f32::EPSILON {
+        return Vector3::zero();
+    }
+    turn.v / sin * 2.0 * sin.atan2(turn.s)
+}
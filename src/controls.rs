@@ -0,0 +1,155 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use cgmath::Vector2;
+use wasm_bindgen::prelude::*;
+use web_sys::{AddEventListenerOptions, Event, EventTarget, HtmlCanvasElement, MouseEvent, PointerEvent, WheelEvent};
+use crate::camera::{Camera, Drag};
+
+// Wheel deltas in lines, for browsers that give them that way, in pixels
+const LINE_PIXELS: f64 = 16.0;
+
+type Listener = Closure<dyn FnMut(Event)>;
+
+/// Pointer and wheel listeners steering `camera` from the canvas, removed
+/// again when dropped. A mouse or one finger turns the globe, and two
+/// fingers pinch, twist and move it.
+pub struct Controls {
+    canvas: HtmlCanvasElement,
+    // The canvas's own touch-action, put back when we're done
+    touch_action: String,
+    listeners: Vec<(EventTarget, &'static str, Listener)>,
+}
+
+// The pointers down on the canvas, by id, and where they are. Only the
+// first two count; a third finger is ignored.
+#[derive(Default)]
+struct Pointers {
+    down: Vec<(i32, Vector2<f32>)>,
+}
+
+impl Pointers {
+    fn positions(&self) -> Option<[Vector2<f32>; 2]> {
+        match self.down[..] {
+            [(_, a), (_, b)] => Some([a, b]),
+            _ => None,
+        }
+    }
+}
+
+impl Controls {
+    pub fn attach(canvas: &HtmlCanvasElement, camera: Rc<RefCell<Camera>>) -> Result<Controls, JsValue> {
+        // The browser would otherwise take touches for scrolling and zooming
+        // the page, and cancel our pointers when it does
+        let style = canvas.style();
+        let touch_action = style.get_property_value("touch-action")?;
+        style.set_property("touch-action", "none")?;
+        let mut controls = Controls { canvas: canvas.clone(), touch_action, listeners: Vec::new() };
+        let pointers = Rc::new(RefCell::new(Pointers::default()));
+
+        let (target, cam, fingers) = (canvas.clone(), camera.clone(), pointers.clone());
+        controls.listen(canvas, "pointerdown", true, move |event: PointerEvent| {
+            let kind = match (event.pointer_type().as_str(), event.button()) {
+                ("mouse", 0) if !event.shift_key() => Drag::Orbit,
+                ("mouse", 0..=2) => Drag::Pan,
+                ("mouse", _) => return,
+                _ => Drag::Orbit,
+            };
+            let mut pointers = fingers.borrow_mut();
+            if pointers.down.len() == 2 {
+                return;
+            }
+            // No text selection or middle-click scrolling
+            event.prevent_default();
+            // Keep getting its moves once it's off the canvas
+            let _ = target.set_pointer_capture(event.pointer_id());
+            let at = device_coordinates(&target, &event);
+            pointers.down.push((event.pointer_id(), at));
+            let mut camera = cam.borrow_mut();
+            if pointers.down.len() == 1 {
+                camera.begin_drag(kind, at, event.time_stamp());
+            } else {
+                camera.cancel_drag();
+            }
+        })?;
+
+        let (target, cam, fingers) = (canvas.clone(), camera.clone(), pointers.clone());
+        controls.listen(canvas, "pointermove", false, move |event: PointerEvent| {
+            let mut pointers = fingers.borrow_mut();
+            let before = pointers.positions();
+            let Some(pointer) = pointers.down.iter_mut().find(|(id, _)| *id == event.pointer_id()) else {
+                return;
+            };
+            pointer.1 = device_coordinates(&target, &event);
+            let at = pointer.1;
+            let mut camera = cam.borrow_mut();
+            match (before, pointers.positions()) {
+                (Some(before), Some(after)) => camera.pinch(before, after),
+                _ => camera.drag_to(at, event.time_stamp()),
+            }
+        })?;
+
+        for kind in ["pointerup", "pointercancel"] {
+            let (cam, fingers) = (camera.clone(), pointers.clone());
+            controls.listen(canvas, kind, false, move |event: PointerEvent| {
+                let mut pointers = fingers.borrow_mut();
+                let Some(index) = pointers.down.iter().position(|(id, _)| *id == event.pointer_id()) else {
+                    return;
+                };
+                pointers.down.remove(index);
+                let mut camera = cam.borrow_mut();
+                match pointers.down[..] {
+                    // Back to one finger: carry on turning from where it is
+                    [(_, at)] => camera.begin_drag(Drag::Orbit, at, event.time_stamp()),
+                    _ => camera.end_drag(event.time_stamp()),
+                }
+            })?;
+        }
+
+        let (target, cam) = (canvas.clone(), camera);
+        controls.listen(canvas, "wheel", true, move |event: WheelEvent| {
+            event.prevent_default();
+            let pixels = match event.delta_mode() {
+                WheelEvent::DOM_DELTA_LINE => event.delta_y() * LINE_PIXELS,
+                WheelEvent::DOM_DELTA_PAGE => event.delta_y() * target.client_height() as f64,
+                _ => event.delta_y(),
+            };
+            cam.borrow_mut().zoom(pixels as f32, device_coordinates(&target, &event));
+        })?;
+
+        // The right button pans
+        controls.listen(canvas, "contextmenu", true, |event: Event| event.prevent_default())?;
+        Ok(controls)
+    }
+
+    // `cancels` if `handler` prevents the default, which a passive
+    // listener can't
+    fn listen<E: JsCast>(&mut self, target: &EventTarget, kind: &'static str, cancels: bool, mut handler: impl FnMut(E) + 'static) -> Result<(), JsValue> {
+        let listener: Listener = Closure::new(move |event: Event| {
+            if let Ok(event) = event.dyn_into::<E>() {
+                handler(event);
+            }
+        });
+        let options = AddEventListenerOptions::new();
+        options.set_passive(!cancels);
+        target.add_event_listener_with_callback_and_add_event_listener_options(kind, listener.as_ref().unchecked_ref(), &options)?;
+        self.listeners.push((target.clone(), kind, listener));
+        Ok(())
+    }
+}
+
+impl Drop for Controls {
+    fn drop(&mut self) {
+        for (target, kind, listener) in &self.listeners {
+            let _ = target.remove_event_listener_with_callback(kind, listener.as_ref().unchecked_ref());
+        }
+        let _ = self.canvas.style().set_property("touch-action", &self.touch_action);
+    }
+}
+
+// Where `event` happened on `canvas`, from -1 to 1 across and up it
+fn device_coordinates(canvas: &HtmlCanvasElement, event: &MouseEvent) -> Vector2<f32> {
+    let rect = canvas.get_bounding_client_rect();
+    let x = (event.client_x() as f64 - rect.left()) / rect.width().max(1.0);
+    let y = (event.client_y() as f64 - rect.top()) / rect.height().max(1.0);
+    Vector2::new((2.0 * x - 1.0) as f32, (1.0 - 2.0 * y) as f32)
+}
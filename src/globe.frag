@@ -0,0 +1,16 @@
+#version 300 es
+precision mediump float;
+
+uniform sampler2D imagery;
+
+in vec2 imagery_uv;
+in float seen;
+
+out vec4 color;
+
+// What's out of the satellite's sight, as in shader.wgsl
+const vec3 UNSEEN = vec3(0.02, 0.02, 0.03);
+
+void main() {
+    color = vec4(mix(UNSEEN, texture(imagery, imagery_uv).rgb, seen), 1.0);
+}
@@ -0,0 +1,15 @@
+#version 300 es
+
+uniform mat4 mvp;
+
+layout(location = 0) in vec3 position;
+layout(location = 1) in vec3 uv;
+
+out vec2 imagery_uv;
+out float seen;
+
+void main() {
+    gl_Position = mvp * vec4(position, 1.0);
+    imagery_uv = uv.xy;
+    seen = uv.z;
+}
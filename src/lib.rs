@@ -1,39 +1,291 @@
+// The renderers are only built on the web, where there is a canvas for
+// them; native builds just type-check them
+#![cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use cgmath::Matrix4;
 use wasm_bindgen::prelude::*;
-use web_sys::CanvasRenderingContext2d;
+use animation::{Animation, FrameTimes};
+use camera::Camera;
+pub use animation::FrameStats;
+pub use camera::CameraView;
+
+mod animation;
+mod camera;
+mod controls;
+mod webgl;
+mod webgpu;
+
+const SPHERE_STACKS: u32 = 64;
+const SPHERE_SLICES: u32 = 128;
+
+// Earth and orbit per the CGMS geostationary projection, km
+const ORBIT_RADIUS: f64 = 42164.0;
+const EQUATOR_RADIUS: f64 = 6378.169;
+const POLAR_RADIUS: f64 = 6356.5838;
+
+/// A way of drawing the globe on the canvas.
+trait Renderer {
+    /// Which graphics API this draws with, for display.
+    fn backend(&self) -> &'static str;
+
+    /// Match a canvas that is now `width` by `height` pixels.
+    fn resize(&mut self, width: u32, height: u32);
+
+    /// Draw a frame. `view_projection` has OpenGL clip space conventions.
+    fn render(&mut self, view_projection: Matrix4<f32>) -> Result<(), String>;
+
+    /// Widest texture the GPU takes, in pixels.
+    fn max_texture_size(&self) -> u32;
+
+    /// Replace the sphere's texture coordinates, one `[u, v, seen]` per
+    /// vertex of `create_sphere`, as `geostationary_uvs` makes them.
+    fn set_uvs(&mut self, uvs: &[[f32; 3]]);
+
+    /// Start a new black `size` by `size` full-disk texture.
+    fn create_imagery(&mut self, size: u32);
+
+    /// Copy RGBA pixels into the full-disk texture at `x`, `y`.
+    fn write_imagery(&mut self, x: u32, y: u32, width: u32, height: u32, rgba: &[u8]);
+}
+
+/// The full-disk image being mapped onto the globe.
+struct Imagery {
+    tile_size: u32,
+    /// Tiles across the image, 2^zoom
+    grid: u32,
+}
 
 #[wasm_bindgen]
 pub struct WgpuApp {
+    scene: Rc<RefCell<Scene>>,
+    // Kept for as long as the app, as they steer its camera
+    _controls: controls::Controls,
+    animation: Option<Animation>,
+}
+
+/// What's drawn, shared between the app and its animation loop.
+struct Scene {
     canvas: web_sys::HtmlCanvasElement,
-    context: Option<CanvasRenderingContext2d>,
+    renderer: Box<dyn Renderer>,
+    width: u32,
+    height: u32,
+    camera: Rc<RefCell<Camera>>,
+    imagery: Option<Imagery>,
+    // Something other than the camera changed since the last frame
+    dirty: bool,
+    // When the last frame came, ms
+    last_frame: Option<f64>,
+    times: FrameTimes,
 }
 
+impl Scene {
+    /// Move the camera on to `now`, in ms as performance.now() gives it,
+    /// and draw a frame if anything has changed, or anyway if `force`.
+    fn frame(&mut self, now: f64, force: bool) -> Result<(), String> {
+        let seconds = match self.last_frame {
+            // After a pause, carry on from where things were
+            Some(last) => ((now - last) / 1000.0).clamp(0.0, 0.1),
+            None => 0.0,
+        };
+        self.last_frame = Some(now);
+
+        let (width, height) = (self.canvas.width(), self.canvas.height());
+        if width == 0 || height == 0 {
+            return Ok(());
+        }
+        if (width, height) != (self.width, self.height) {
+            self.renderer.resize(width, height);
+            (self.width, self.height) = (width, height);
+            self.dirty = true;
+        }
+        let mut camera = self.camera.borrow_mut();
+        camera.step(seconds as f32);
+        camera.set_aspect(width as f32 / height as f32);
+        // Not `||`, which would leave the camera's flag set
+        if !(camera.take_changed() | self.dirty | force) {
+            self.times.skipped();
+            return Ok(());
+        }
+        self.dirty = false;
+        let started = performance_now();
+        self.renderer.render(camera.view_projection())?;
+        let took = started.zip(performance_now()).map_or(0.0, |(started, done)| done - started);
+        self.times.drawn(now, took);
+        Ok(())
+    }
+}
+
+// performance.now(), ms
+fn performance_now() -> Option<f64> {
+    web_sys::window().and_then(|w| w.performance()).map(|p| p.now())
+}
+
+#[cfg(target_arch = "wasm32")]
 #[wasm_bindgen]
 impl WgpuApp {
-    #[wasm_bindgen(constructor)]
-    pub fn new(canvas: web_sys::HtmlCanvasElement) -> WgpuApp {
-        WgpuApp {
+    /// Set up rendering on `canvas`: WebGPU where the browser has it,
+    /// WebGL2 otherwise.
+    #[wasm_bindgen]
+    pub async fn init(canvas: web_sys::HtmlCanvasElement) -> Result<WgpuApp, JsValue> {
+        console_error_panic_hook::set_once();
+        let renderer: Box<dyn Renderer> = match webgpu::WebGpuRenderer::new(&canvas).await {
+            Ok(renderer) => Box::new(renderer),
+            Err(e) => {
+                web_sys::console::warn_1(&format!("{}, falling back to WebGL2", e).into());
+                Box::new(webgl::WebGlRenderer::new(&canvas)?)
+            }
+        };
+        let camera = Rc::new(RefCell::new(Camera::new()));
+        let controls = controls::Controls::attach(&canvas, camera.clone())?;
+        let scene = Scene {
+            width: canvas.width(),
+            height: canvas.height(),
             canvas,
-            context: None,
-        }
+            renderer,
+            camera,
+            imagery: None,
+            dirty: true,
+            last_frame: None,
+            times: FrameTimes::default(),
+        };
+        Ok(WgpuApp { scene: Rc::new(RefCell::new(scene)), _controls: controls, animation: None })
+    }
+}
+
+#[wasm_bindgen]
+impl WgpuApp {
+    /// `"webgpu"` or `"webgl2"`.
+    #[wasm_bindgen(getter)]
+    pub fn backend(&self) -> String {
+        self.scene.borrow().renderer.backend().to_string()
     }
 
+    /// Draw frames from requestAnimationFrame until `stop()`. Frames where
+    /// nothing has changed are skipped, leaving the GPU idle.
     #[wasm_bindgen]
-    pub fn init(&mut self) -> Result<(), JsValue> {
-        let context_obj = self.canvas.get_context("2d").map_err(|_| "Failed to get 2d context")?;
-        let context = context_obj.ok_or("Context is None")?.dyn_into::<CanvasRenderingContext2d>().map_err(|_| "Failed to cast context")?;
-        self.context = Some(context);
+    pub fn start(&mut self) -> Result<(), JsValue> {
+        if !self.running() {
+            self.animation = Some(Animation::start(self.scene.clone())?);
+        }
         Ok(())
     }
 
+    #[wasm_bindgen]
+    pub fn stop(&mut self) {
+        self.animation = None;
+    }
+
+    /// Whether frames are being drawn since `start()`. The loop stops by
+    /// itself if rendering fails, with the error in the console.
+    #[wasm_bindgen(getter)]
+    pub fn running(&self) -> bool {
+        self.animation.as_ref().is_some_and(Animation::running)
+    }
+
+    /// Draw a frame now, changed or not, resizing first if the canvas has
+    /// changed size and moving the camera on if it's coasting.
     #[wasm_bindgen]
     pub fn render(&mut self) -> Result<(), JsValue> {
-        let context = self.context.as_ref().unwrap();
-        context.set_fill_style(&"black".into());
-        let width = self.canvas.width() as f64;
-        let height = self.canvas.height() as f64;
-        context.fill_rect(0.0, 0.0, width, height);
+        let now = performance_now().unwrap_or_default();
+        self.scene.borrow_mut().frame(now, true)?;
+        Ok(())
+    }
+
+    /// Frame rate and timings, for a stats display.
+    #[wasm_bindgen]
+    pub fn stats(&self) -> FrameStats {
+        self.scene.borrow_mut().times.stats(performance_now().unwrap_or_default())
+    }
+
+    /// Where the camera is now.
+    #[wasm_bindgen]
+    pub fn get_camera(&self) -> CameraView {
+        self.scene.borrow().camera.borrow().view()
+    }
+
+    /// Move the camera straight to `view`, stopping it if it was coasting.
+    #[wasm_bindgen]
+    pub fn set_camera(&mut self, view: &CameraView) {
+        self.scene.borrow().camera.borrow_mut().set_view(view);
+    }
+
+    /// Map the full disk of a satellite over `longitude` onto the globe,
+    /// with `scan_angle`, `tile_size` and the zoom level whose tiles will
+    /// be loaded as `/api/catalog` gives them. Any earlier imagery is
+    /// dropped, and the camera turned to face the satellite.
+    #[wasm_bindgen]
+    pub fn set_satellite(&mut self, longitude: f64, scan_angle: f64, tile_size: u32, zoom: u32) -> Result<(), JsValue> {
+        let mut scene = self.scene.borrow_mut();
+        let max = scene.renderer.max_texture_size();
+        let size = (tile_size as u64) << zoom.min(32);
+        if size > max as u64 {
+            return Err(format!("Zoom {} is too big for a texture on this GPU, which takes {}px", zoom, max).into());
+        }
+        let (vertices, _) = create_sphere(1.0, SPHERE_STACKS, SPHERE_SLICES);
+        scene.renderer.set_uvs(&geostationary_uvs(&vertices, longitude, scan_angle));
+        scene.renderer.create_imagery(size as u32);
+        scene.imagery = Some(Imagery { tile_size, grid: 1 << zoom });
+        scene.camera.borrow_mut().look_at(longitude, 0.0);
+        scene.dirty = true;
+        Ok(())
+    }
+
+    /// Draw a full-disk tile onto the globe, as RGBA pixels (an
+    /// `ImageData`'s `data`). SLIDER names tiles row first, so `row` is its
+    /// `x` and `column` its `y`.
+    #[wasm_bindgen]
+    pub fn load_tile(&mut self, row: u32, column: u32, width: u32, height: u32, rgba: &[u8]) -> Result<(), JsValue> {
+        let mut scene = self.scene.borrow_mut();
+        let imagery = scene.imagery.as_ref().ok_or("No satellite set")?;
+        if row >= imagery.grid || column >= imagery.grid {
+            return Err(format!("No tile {}, {} at this zoom", row, column).into());
+        }
+        if width != imagery.tile_size || height != imagery.tile_size || rgba.len() != (width * height * 4) as usize {
+            return Err(format!("Tiles must be {}px RGBA", imagery.tile_size).into());
+        }
+        let (x, y) = (column * imagery.tile_size, row * imagery.tile_size);
+        scene.renderer.write_imagery(x, y, width, height, rgba);
+        scene.dirty = true;
         Ok(())
     }
+
+    /// Widest full-disk texture the GPU takes, for choosing a zoom level:
+    /// `tile_size << zoom` must fit.
+    #[wasm_bindgen(getter)]
+    pub fn max_texture_size(&self) -> u32 {
+        self.scene.borrow().renderer.max_texture_size()
+    }
+}
+
+/// Where each of `vertices`, on a unit sphere with north up and 0°E
+/// facing +z, appears in the full disk of a satellite over `longitude`, as
+/// `[u, v, seen]`. `seen` is 0 on the far side, where `u` and `v` are
+/// where the point would be were the Earth transparent, so they run on
+/// smoothly across the limb. After `geos::lat_lon_to_geos` in the server.
+fn geostationary_uvs(vertices: &[[f32; 3]], longitude: f64, scan_angle: f64) -> Vec<[f32; 3]> {
+    let eccentricity = 1.0 - (POLAR_RADIUS / EQUATOR_RADIUS).powi(2);
+    let flattening = (EQUATOR_RADIUS / POLAR_RADIUS).powi(2);
+    let span = 2.0 * scan_angle.to_radians();
+    vertices
+        .iter()
+        .map(|&[x, y, z]| {
+            let lat = (y as f64).clamp(-1.0, 1.0).asin();
+            let lon = (x as f64).atan2(z as f64);
+            let geocentric = (lat.tan() / flattening).atan();
+            let radius = POLAR_RADIUS / (1.0 - eccentricity * geocentric.cos().powi(2)).sqrt();
+            let delta = lon - longitude.to_radians();
+            let p1 = radius * geocentric.cos() * delta.cos();
+            let p2 = radius * geocentric.cos() * delta.sin();
+            let p3 = radius * geocentric.sin();
+            let seen = ORBIT_RADIUS * p1 > p1 * p1 + p2 * p2 + flattening * p3 * p3;
+            let d1 = ORBIT_RADIUS - p1;
+            let scan_x = (p2 / d1).atan();
+            let scan_y = (p3 / (d1 * d1 + p2 * p2 + p3 * p3).sqrt()).asin();
+            [(0.5 + scan_x / span) as f32, (0.5 - scan_y / span) as f32, if seen { 1.0 } else { 0.0 }]
+        })
+        .collect()
 }
 
 fn create_sphere(radius: f32, stacks: u32, slices: u32) -> (Vec<[f32; 3]>, Vec<u32>) {
@@ -60,4 +312,4 @@ fn create_sphere(radius: f32, stacks: u32, slices: u32) -> (Vec<[f32; 3]>, Vec<u
     }
 
     (vertices, indices)
-}
\ No newline at end of file
+}
@@ -17,12 +17,33 @@ struct Uniforms {
 @group(0) @binding(0)
 var<uniform> uniforms: Uniforms;
 
+// The full-disk image, and where each vertex falls in it
+@group(0) @binding(1)
+var imagery: texture_2d<f32>;
+@group(0) @binding(2)
+var imagery_sampler: sampler;
+
+struct GlobeVertex {
+    @builtin(position) position: vec4<f32>,
+    @location(0) uv: vec2<f32>,
+    // 1 where the satellite sees the Earth, 0 on the far side
+    @location(1) seen: f32,
+}
+
+// What's out of the satellite's sight
+const UNSEEN: vec3<f32> = vec3<f32>(0.02, 0.02, 0.03);
+
 @vertex
-fn globe_vs(@location(0) position: vec3<f32>) -> @builtin(position) vec4<f32> {
-    return uniforms.mvp * vec4<f32>(position, 1.0);
+fn globe_vs(@location(0) position: vec3<f32>, @location(1) uv: vec3<f32>) -> GlobeVertex {
+    var out: GlobeVertex;
+    out.position = uniforms.mvp * vec4<f32>(position, 1.0);
+    out.uv = uv.xy;
+    out.seen = uv.z;
+    return out;
 }
 
 @fragment
-fn globe_fs() -> @location(0) vec4<f32> {
-    return vec4<f32>(0.0, 1.0, 0.0, 0.5); // semi-transparent green
-}
\ No newline at end of file
+fn globe_fs(vertex: GlobeVertex) -> @location(0) vec4<f32> {
+    let imaged = textureSample(imagery, imagery_sampler, vertex.uv).rgb;
+    return vec4<f32>(mix(UNSEEN, imaged, vertex.seen), 1.0);
+}
@@ -0,0 +1,165 @@
+use cgmath::Matrix4;
+use wasm_bindgen::JsCast;
+use web_sys::{WebGl2RenderingContext as Gl, WebGlBuffer, WebGlProgram, WebGlShader, WebGlTexture, WebGlUniformLocation, WebGlVertexArrayObject};
+use crate::{create_sphere, Renderer, SPHERE_SLICES, SPHERE_STACKS};
+
+/// Draws the globe with plain WebGL2, for browsers without WebGPU. Mirrors
+/// the wgpu pipeline, with the shaders in GLSL.
+pub struct WebGlRenderer {
+    gl: Gl,
+    program: WebGlProgram,
+    mvp: WebGlUniformLocation,
+    vao: WebGlVertexArrayObject,
+    uv_buffer: WebGlBuffer,
+    index_count: i32,
+    imagery: Option<WebGlTexture>,
+    max_texture_size: u32,
+    width: i32,
+    height: i32,
+}
+
+impl WebGlRenderer {
+    pub fn new(canvas: &web_sys::HtmlCanvasElement) -> Result<WebGlRenderer, String> {
+        let gl = canvas
+            .get_context("webgl2")
+            .ok()
+            .flatten()
+            .ok_or("No WebGL2 either")?
+            .dyn_into::<Gl>()
+            .map_err(|_| "Failed to cast context")?;
+
+        let vertex = compile(&gl, Gl::VERTEX_SHADER, include_str!("globe.vert"))?;
+        let fragment = compile(&gl, Gl::FRAGMENT_SHADER, include_str!("globe.frag"))?;
+        let program = gl.create_program().ok_or("Failed to create program")?;
+        gl.attach_shader(&program, &vertex);
+        gl.attach_shader(&program, &fragment);
+        gl.link_program(&program);
+        if !gl.get_program_parameter(&program, Gl::LINK_STATUS).as_bool().unwrap_or(false) {
+            return Err(format!("Failed to link shaders: {}", gl.get_program_info_log(&program).unwrap_or_default()));
+        }
+        let mvp = gl.get_uniform_location(&program, "mvp").ok_or("Shader has no mvp uniform")?;
+
+        let (vertices, indices) = create_sphere(1.0, SPHERE_STACKS, SPHERE_SLICES);
+        let vao = gl.create_vertex_array().ok_or("Failed to create vertex array")?;
+        gl.bind_vertex_array(Some(&vao));
+        let vertex_buffer = gl.create_buffer().ok_or("Failed to create buffer")?;
+        gl.bind_buffer(Gl::ARRAY_BUFFER, Some(&vertex_buffer));
+        gl.buffer_data_with_u8_array(Gl::ARRAY_BUFFER, bytemuck::cast_slice(&vertices), Gl::STATIC_DRAW);
+        gl.enable_vertex_attrib_array(0);
+        gl.vertex_attrib_pointer_with_i32(0, 3, Gl::FLOAT, false, 0, 0);
+        // Nothing seen until there's a satellite
+        let uv_buffer = gl.create_buffer().ok_or("Failed to create buffer")?;
+        gl.bind_buffer(Gl::ARRAY_BUFFER, Some(&uv_buffer));
+        gl.buffer_data_with_u8_array(Gl::ARRAY_BUFFER, bytemuck::cast_slice(&vec![[0.0f32; 3]; vertices.len()]), Gl::STATIC_DRAW);
+        gl.enable_vertex_attrib_array(1);
+        gl.vertex_attrib_pointer_with_i32(1, 3, Gl::FLOAT, false, 0, 0);
+        let index_buffer = gl.create_buffer().ok_or("Failed to create buffer")?;
+        gl.bind_buffer(Gl::ELEMENT_ARRAY_BUFFER, Some(&index_buffer));
+        gl.buffer_data_with_u8_array(Gl::ELEMENT_ARRAY_BUFFER, bytemuck::cast_slice(&indices), Gl::STATIC_DRAW);
+        gl.bind_vertex_array(None);
+
+        gl.enable(Gl::DEPTH_TEST);
+        gl.depth_func(Gl::LESS);
+        gl.clear_color(0.0, 0.0, 0.0, 1.0);
+        let max_texture_size = gl.get_parameter(Gl::MAX_TEXTURE_SIZE).ok().and_then(|v| v.as_f64()).unwrap_or(2048.0) as u32;
+
+        let mut renderer = WebGlRenderer {
+            gl,
+            program,
+            mvp,
+            vao,
+            uv_buffer,
+            index_count: indices.len() as i32,
+            imagery: None,
+            max_texture_size,
+            width: canvas.width() as i32,
+            height: canvas.height() as i32,
+        };
+        renderer.create_imagery(1);
+        Ok(renderer)
+    }
+}
+
+impl Renderer for WebGlRenderer {
+    fn backend(&self) -> &'static str {
+        "webgl2"
+    }
+
+    fn resize(&mut self, width: u32, height: u32) {
+        self.width = width as i32;
+        self.height = height as i32;
+    }
+
+    fn render(&mut self, view_projection: Matrix4<f32>) -> Result<(), String> {
+        // Everything is gone until the browser restores the context
+        if self.gl.is_context_lost() {
+            return Ok(());
+        }
+        let gl = &self.gl;
+        let mvp: &[f32; 16] = view_projection.as_ref();
+        gl.viewport(0, 0, self.width, self.height);
+        gl.clear(Gl::COLOR_BUFFER_BIT | Gl::DEPTH_BUFFER_BIT);
+        gl.use_program(Some(&self.program));
+        gl.uniform_matrix4fv_with_f32_array(Some(&self.mvp), false, mvp);
+        gl.active_texture(Gl::TEXTURE0);
+        gl.bind_texture(Gl::TEXTURE_2D, self.imagery.as_ref());
+        gl.bind_vertex_array(Some(&self.vao));
+        gl.draw_elements_with_i32(Gl::TRIANGLES, self.index_count, Gl::UNSIGNED_INT, 0);
+        gl.bind_vertex_array(None);
+        Ok(())
+    }
+
+    fn max_texture_size(&self) -> u32 {
+        self.max_texture_size
+    }
+
+    fn set_uvs(&mut self, uvs: &[[f32; 3]]) {
+        self.gl.bind_buffer(Gl::ARRAY_BUFFER, Some(&self.uv_buffer));
+        self.gl.buffer_data_with_u8_array(Gl::ARRAY_BUFFER, bytemuck::cast_slice(uvs), Gl::STATIC_DRAW);
+    }
+
+    fn create_imagery(&mut self, size: u32) {
+        let gl = &self.gl;
+        if let Some(texture) = self.imagery.take() {
+            gl.delete_texture(Some(&texture));
+        }
+        // WebGL zero-fills new textures, so this starts out black. Plain
+        // RGBA8 rather than sRGB, as the canvas isn't sRGB either.
+        let texture = gl.create_texture();
+        gl.bind_texture(Gl::TEXTURE_2D, texture.as_ref());
+        gl.tex_storage_2d(Gl::TEXTURE_2D, 1, Gl::RGBA8, size as i32, size as i32);
+        gl.tex_parameteri(Gl::TEXTURE_2D, Gl::TEXTURE_MIN_FILTER, Gl::LINEAR as i32);
+        gl.tex_parameteri(Gl::TEXTURE_2D, Gl::TEXTURE_MAG_FILTER, Gl::LINEAR as i32);
+        gl.tex_parameteri(Gl::TEXTURE_2D, Gl::TEXTURE_WRAP_S, Gl::CLAMP_TO_EDGE as i32);
+        gl.tex_parameteri(Gl::TEXTURE_2D, Gl::TEXTURE_WRAP_T, Gl::CLAMP_TO_EDGE as i32);
+        self.imagery = texture;
+    }
+
+    fn write_imagery(&mut self, x: u32, y: u32, width: u32, height: u32, rgba: &[u8]) {
+        let gl = &self.gl;
+        gl.bind_texture(Gl::TEXTURE_2D, self.imagery.as_ref());
+        // Only fails for arguments we've already checked
+        let _ = gl.tex_sub_image_2d_with_i32_and_i32_and_u32_and_type_and_opt_u8_array(
+            Gl::TEXTURE_2D,
+            0,
+            x as i32,
+            y as i32,
+            width as i32,
+            height as i32,
+            Gl::RGBA,
+            Gl::UNSIGNED_BYTE,
+            Some(rgba),
+        );
+    }
+}
+
+fn compile(gl: &Gl, kind: u32, source: &str) -> Result<WebGlShader, String> {
+    let shader = gl.create_shader(kind).ok_or("Failed to create shader")?;
+    gl.shader_source(&shader, source);
+    gl.compile_shader(&shader);
+    if gl.get_shader_parameter(&shader, Gl::COMPILE_STATUS).as_bool().unwrap_or(false) {
+        Ok(shader)
+    } else {
+        Err(format!("Failed to compile shader: {}", gl.get_shader_info_log(&shader).unwrap_or_default()))
+    }
+}